@@ -0,0 +1,29 @@
+-- Initial schema for UTXO Recycler
+
+CREATE TABLE IF NOT EXISTS recycles (
+    id TEXT PRIMARY KEY,
+    lightning_address TEXT NOT NULL,
+    deposit_address TEXT NOT NULL UNIQUE,
+    address_index BIGINT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'awaiting_deposit',
+    deposit_txid TEXT,
+    deposit_amount_sats BIGINT,
+    deposit_confirmations BIGINT DEFAULT 0,
+    payout_amount_sats BIGINT,
+    payment_preimage TEXT,
+    payment_hash TEXT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    paid_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_recycles_status ON recycles(status);
+CREATE INDEX IF NOT EXISTS idx_recycles_deposit_address ON recycles(deposit_address);
+
+-- Track the next address index for the HD wallet
+CREATE TABLE IF NOT EXISTS wallet_state (
+    id BIGINT PRIMARY KEY CHECK (id = 1),
+    next_address_index BIGINT NOT NULL DEFAULT 0
+);
+
+INSERT INTO wallet_state (id, next_address_index) VALUES (1, 0) ON CONFLICT (id) DO NOTHING;
@@ -0,0 +1,20 @@
+-- Add blockheight cutoff and input size validation for UTXO eligibility
+-- Only UTXOs created before the cutoff block AND with small inputs are eligible for payout
+-- UTXOs at or after the cutoff, or with large inputs, are treated as donations
+
+-- The block height where the deposit transaction was confirmed
+ALTER TABLE recycles ADD COLUMN deposit_block_height BIGINT;
+
+-- Whether this deposit is eligible for payout (created before cutoff, inputs are dust)
+-- 1 = eligible for payout, 0 = donation (no payout)
+ALTER TABLE recycles ADD COLUMN is_eligible BIGINT DEFAULT 1;
+
+-- The reason for donation status (NULL if eligible)
+-- Values: 'block_height' (after cutoff), 'input_too_large' (input over MAX_INPUT_SATS)
+ALTER TABLE recycles ADD COLUMN donation_reason TEXT;
+
+-- The maximum input UTXO value in the deposit transaction (for auditing)
+ALTER TABLE recycles ADD COLUMN max_input_sats BIGINT;
+
+-- Add index for querying donations
+CREATE INDEX IF NOT EXISTS idx_recycles_is_eligible ON recycles(is_eligible);
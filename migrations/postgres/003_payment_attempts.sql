@@ -0,0 +1,3 @@
+-- Track payment attempts to enforce retry limits
+-- After MAX_PAYMENT_ATTEMPTS, recycle is marked as failed
+ALTER TABLE recycles ADD COLUMN payment_attempts BIGINT DEFAULT 0;
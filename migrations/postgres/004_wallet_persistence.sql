@@ -0,0 +1,8 @@
+-- Persist BDK wallet state (revealed indices, checkpoints, transactions) across restarts
+-- Each row is a JSON-encoded bdk_wallet::ChangeSet, merged back together on load
+
+CREATE TABLE IF NOT EXISTS bdk_changesets (
+    id BIGSERIAL PRIMARY KEY,
+    changeset TEXT NOT NULL,
+    created_at TEXT NOT NULL
+);
@@ -0,0 +1,27 @@
+-- Track every deposit (UTXO) sent to a recycle address, keyed by outpoint
+-- The recycles table keeps aggregate deposit fields for display and stats
+
+CREATE TABLE IF NOT EXISTS deposits (
+    txid TEXT NOT NULL,
+    vout BIGINT NOT NULL,
+    recycle_id TEXT NOT NULL REFERENCES recycles(id),
+    amount_sats BIGINT NOT NULL,
+    -- Deposit status: 'confirming', 'confirmed' (eligible, awaiting payout), 'paid', 'donation'
+    status TEXT NOT NULL DEFAULT 'confirming',
+    confirmations BIGINT NOT NULL DEFAULT 0,
+    -- The block height where the deposit transaction was confirmed
+    block_height BIGINT,
+    -- Eligibility result: NULL until checked, 1 = eligible, 0 = donation
+    is_eligible BIGINT,
+    donation_reason TEXT,
+    -- Maximum creation height and value of the deposit transaction's inputs (for auditing)
+    input_creation_height BIGINT,
+    max_input_sats BIGINT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (txid, vout)
+);
+
+CREATE INDEX IF NOT EXISTS idx_deposits_recycle_id ON deposits(recycle_id);
+
+CREATE INDEX IF NOT EXISTS idx_deposits_status ON deposits(status);
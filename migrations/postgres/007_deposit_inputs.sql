@@ -0,0 +1,21 @@
+-- Per-input eligibility. Each input of a deposit transaction is checked on its own and
+-- the deposit is paid only on the share of its value attributable to eligible inputs
+
+CREATE TABLE IF NOT EXISTS deposit_inputs (
+    deposit_txid TEXT NOT NULL,
+    deposit_vout BIGINT NOT NULL,
+    input_index BIGINT NOT NULL,
+    -- The output this input spends
+    prev_txid TEXT NOT NULL,
+    prev_vout BIGINT NOT NULL,
+    -- Value and creation height of the spent output (NULL if they couldn't be looked up)
+    value_sats BIGINT,
+    creation_height BIGINT,
+    is_eligible BIGINT NOT NULL,
+    -- Why the input is ineligible: 'block_height', 'block_height_unknown' or 'input_too_large'
+    reason TEXT,
+    PRIMARY KEY (deposit_txid, deposit_vout, input_index)
+);
+
+-- The part of the deposit amount attributable to eligible inputs (NULL until checked)
+ALTER TABLE deposits ADD COLUMN eligible_sats BIGINT;
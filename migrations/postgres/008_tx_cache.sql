@@ -0,0 +1,15 @@
+-- Persisted cache for eligibility lookups. Transactions never change once fetched, and
+-- confirmation heights are only stored once buried deep enough not to be reorged
+
+CREATE TABLE IF NOT EXISTS tx_cache (
+    txid TEXT PRIMARY KEY,
+    -- Consensus-encoded transaction
+    raw_tx BYTEA NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS tx_heights (
+    txid TEXT PRIMARY KEY,
+    block_height BIGINT NOT NULL,
+    created_at TEXT NOT NULL
+);
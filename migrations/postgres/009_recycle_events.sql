@@ -0,0 +1,15 @@
+-- Log of every recycle status transition: who moved it (user, deposit_monitor,
+-- payment_processor or admin), from which status and why
+
+CREATE TABLE IF NOT EXISTS recycle_events (
+    id BIGSERIAL PRIMARY KEY,
+    recycle_id TEXT NOT NULL REFERENCES recycles(id),
+    -- NULL for the event recording the recycle's creation
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    reason TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_recycle_events_recycle_id ON recycle_events(recycle_id);
@@ -0,0 +1,11 @@
+-- Let the address of an expired, never-funded recycle be handed to a new recycle.
+-- The released recycle keeps its row (and event log) with address_released_at set,
+-- so deposit_address is only unique among recycles still holding their address.
+
+-- When the address was handed to a newer recycle (NULL while this one holds it)
+ALTER TABLE recycles ADD COLUMN address_released_at TEXT;
+
+ALTER TABLE recycles DROP CONSTRAINT IF EXISTS recycles_deposit_address_key;
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_recycles_held_deposit_address
+    ON recycles(deposit_address) WHERE address_released_at IS NULL;
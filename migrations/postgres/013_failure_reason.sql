@@ -0,0 +1,3 @@
+-- Why the payout of a failed recycle was given up, shown on its page. Cleared when
+-- the payout is retried.
+ALTER TABLE recycles ADD COLUMN failure_reason TEXT;
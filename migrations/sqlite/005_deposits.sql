@@ -0,0 +1,49 @@
+-- Track every deposit (UTXO) sent to a recycle address, keyed by outpoint
+-- The recycles table keeps aggregate deposit fields for display and stats
+
+CREATE TABLE IF NOT EXISTS deposits (
+    txid TEXT NOT NULL,
+    vout INTEGER NOT NULL,
+    recycle_id TEXT NOT NULL REFERENCES recycles(id),
+    amount_sats INTEGER NOT NULL,
+    -- Deposit status: 'confirming', 'confirmed' (eligible, awaiting payout), 'paid', 'donation'
+    status TEXT NOT NULL DEFAULT 'confirming',
+    confirmations INTEGER NOT NULL DEFAULT 0,
+    -- The block height where the deposit transaction was confirmed
+    block_height INTEGER,
+    -- Eligibility result: NULL until checked, 1 = eligible, 0 = donation
+    is_eligible INTEGER,
+    donation_reason TEXT,
+    -- Maximum creation height and value of the deposit transaction's inputs (for auditing)
+    input_creation_height INTEGER,
+    max_input_sats INTEGER,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (txid, vout)
+);
+
+CREATE INDEX IF NOT EXISTS idx_deposits_recycle_id ON deposits(recycle_id);
+
+CREATE INDEX IF NOT EXISTS idx_deposits_status ON deposits(status);
+
+-- Backfill deposits recorded before this table existed. The output index was never
+-- stored, so vout = -1 marks the row until the deposit monitor sees the real outpoint.
+-- Migrations re-run on every startup, so only backfill recycles without deposit rows.
+INSERT INTO deposits (
+    txid, vout, recycle_id, amount_sats, status, confirmations, is_eligible,
+    donation_reason, input_creation_height, max_input_sats, created_at, updated_at
+)
+SELECT
+    deposit_txid, -1, id, deposit_amount_sats,
+    CASE status
+        WHEN 'paid' THEN 'paid'
+        WHEN 'donation' THEN 'donation'
+        WHEN 'confirming' THEN 'confirming'
+        ELSE 'confirmed'
+    END,
+    COALESCE(deposit_confirmations, 0),
+    CASE WHEN status = 'confirming' AND deposit_block_height IS NULL THEN NULL ELSE is_eligible END,
+    donation_reason, deposit_block_height, max_input_sats, created_at, updated_at
+FROM recycles
+WHERE deposit_txid IS NOT NULL AND deposit_amount_sats IS NOT NULL
+  AND NOT EXISTS (SELECT 1 FROM deposits WHERE deposits.recycle_id = recycles.id);
@@ -0,0 +1,25 @@
+-- Consolidation sweeps: collected deposit UTXOs spent to cold storage
+-- PSBTs are built unsigned (the wallet descriptor is an xpub) and signed offline
+
+CREATE TABLE IF NOT EXISTS sweeps (
+    id TEXT PRIMARY KEY,
+    -- Sweep status: 'unsigned' (awaiting signature), 'broadcast', 'cancelled'
+    status TEXT NOT NULL DEFAULT 'unsigned',
+    destination_address TEXT NOT NULL,
+    fee_rate_sat_vb INTEGER NOT NULL,
+    input_count INTEGER NOT NULL,
+    total_input_sats INTEGER NOT NULL,
+    fee_sats INTEGER NOT NULL,
+    -- Base64 PSBT exported for offline signing
+    unsigned_psbt TEXT NOT NULL,
+    -- Txid of the sweep transaction (known before signing for segwit inputs)
+    txid TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    broadcast_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_sweeps_status ON sweeps(status);
+
+-- The sweep that spends this deposit (NULL while unswept)
+ALTER TABLE deposits ADD COLUMN sweep_id TEXT REFERENCES sweeps(id);
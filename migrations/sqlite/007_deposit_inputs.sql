@@ -0,0 +1,25 @@
+-- Per-input eligibility. Each input of a deposit transaction is checked on its own and
+-- the deposit is paid only on the share of its value attributable to eligible inputs
+
+CREATE TABLE IF NOT EXISTS deposit_inputs (
+    deposit_txid TEXT NOT NULL,
+    deposit_vout INTEGER NOT NULL,
+    input_index INTEGER NOT NULL,
+    -- The output this input spends
+    prev_txid TEXT NOT NULL,
+    prev_vout INTEGER NOT NULL,
+    -- Value and creation height of the spent output (NULL if they couldn't be looked up)
+    value_sats INTEGER,
+    creation_height INTEGER,
+    is_eligible INTEGER NOT NULL,
+    -- Why the input is ineligible: 'block_height', 'block_height_unknown' or 'input_too_large'
+    reason TEXT,
+    PRIMARY KEY (deposit_txid, deposit_vout, input_index)
+);
+
+-- The part of the deposit amount attributable to eligible inputs (NULL until checked)
+ALTER TABLE deposits ADD COLUMN eligible_sats INTEGER;
+
+-- Deposits checked before per-input eligibility were all-or-nothing
+UPDATE deposits SET eligible_sats = CASE WHEN is_eligible = 1 THEN amount_sats ELSE 0 END
+WHERE eligible_sats IS NULL AND is_eligible IS NOT NULL;
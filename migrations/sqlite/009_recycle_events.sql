@@ -0,0 +1,20 @@
+-- Log of every recycle status transition: who moved it (user, deposit_monitor,
+-- payment_processor or admin), from which status and why
+
+CREATE TABLE IF NOT EXISTS recycle_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    recycle_id TEXT NOT NULL REFERENCES recycles(id),
+    -- NULL for the event recording the recycle's creation
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    actor TEXT NOT NULL,
+    reason TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_recycle_events_recycle_id ON recycle_events(recycle_id);
+
+-- Recycles created before the log existed start from their current status
+INSERT INTO recycle_events (recycle_id, from_status, to_status, actor, reason, created_at)
+SELECT id, NULL, status, 'migration', 'status before the event log', updated_at FROM recycles
+WHERE id NOT IN (SELECT recycle_id FROM recycle_events);
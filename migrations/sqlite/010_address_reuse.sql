@@ -0,0 +1,51 @@
+-- Let the address of an expired, never-funded recycle be handed to a new recycle.
+-- The released recycle keeps its row (and event log) with address_released_at set,
+-- so deposit_address is only unique among recycles still holding their address.
+-- SQLite can't drop a UNIQUE constraint, so the table is rebuilt (the runner turns
+-- foreign keys off around it and checks them before committing).
+
+CREATE TABLE recycles_new (
+    id TEXT PRIMARY KEY,
+    lightning_address TEXT NOT NULL,
+    deposit_address TEXT NOT NULL,
+    address_index INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'awaiting_deposit',
+    deposit_txid TEXT,
+    deposit_amount_sats INTEGER,
+    deposit_confirmations INTEGER DEFAULT 0,
+    payout_amount_sats INTEGER,
+    payment_preimage TEXT,
+    payment_hash TEXT,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    paid_at TEXT,
+    deposit_block_height INTEGER,
+    is_eligible INTEGER DEFAULT 1,
+    donation_reason TEXT,
+    max_input_sats INTEGER,
+    payment_attempts INTEGER DEFAULT 0,
+    -- When the address was handed to a newer recycle (NULL while this one holds it)
+    address_released_at TEXT
+);
+
+INSERT INTO recycles_new (
+    id, lightning_address, deposit_address, address_index, status, deposit_txid,
+    deposit_amount_sats, deposit_confirmations, payout_amount_sats, payment_preimage,
+    payment_hash, created_at, updated_at, paid_at, deposit_block_height, is_eligible,
+    donation_reason, max_input_sats, payment_attempts
+)
+SELECT
+    id, lightning_address, deposit_address, address_index, status, deposit_txid,
+    deposit_amount_sats, deposit_confirmations, payout_amount_sats, payment_preimage,
+    payment_hash, created_at, updated_at, paid_at, deposit_block_height, is_eligible,
+    donation_reason, max_input_sats, payment_attempts
+FROM recycles;
+
+DROP TABLE recycles;
+ALTER TABLE recycles_new RENAME TO recycles;
+
+CREATE INDEX IF NOT EXISTS idx_recycles_status ON recycles(status);
+CREATE INDEX IF NOT EXISTS idx_recycles_deposit_address ON recycles(deposit_address);
+CREATE INDEX IF NOT EXISTS idx_recycles_is_eligible ON recycles(is_eligible);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_recycles_held_deposit_address
+    ON recycles(deposit_address) WHERE address_released_at IS NULL;
@@ -0,0 +1,4 @@
+-- SHA-256 of the secret handed to a recycle's creator, who needs it to cancel the
+-- recycle before any deposit arrives. NULL for recycles created before cancellation
+-- existed, which can't be cancelled.
+ALTER TABLE recycles ADD COLUMN cancel_secret_hash TEXT;
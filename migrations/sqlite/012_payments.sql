@@ -0,0 +1,28 @@
+-- Every Lightning payment attempted for a recycle, recorded before the invoice is sent
+-- so an attempt whose outcome was never learned (a timeout, a crash) is looked up
+-- rather than paid again with a fresh invoice
+
+CREATE TABLE IF NOT EXISTS payments (
+    payment_hash TEXT PRIMARY KEY,
+    recycle_id TEXT NOT NULL REFERENCES recycles(id),
+    bolt11 TEXT NOT NULL,
+    amount_msats INTEGER NOT NULL,
+    -- Payout the payment makes for the deposits it covers
+    payout_amount_sats INTEGER NOT NULL,
+    -- Payment status: 'pending' (sent or about to be, outcome unknown), 'succeeded', 'failed'
+    status TEXT NOT NULL DEFAULT 'pending',
+    preimage TEXT,
+    -- Why the payment failed
+    error TEXT,
+    -- When the invoice expires. A payment the backend has no record of can't be made after.
+    expires_at TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_payments_recycle_id ON payments(recycle_id);
+
+CREATE INDEX IF NOT EXISTS idx_payments_status ON payments(status);
+
+-- The payment paying out this deposit (NULL until one is attempted, and again after it fails)
+ALTER TABLE deposits ADD COLUMN payment_hash TEXT REFERENCES payments(payment_hash);
@@ -1,28 +1,53 @@
-use crate::db::{RecycleRepository, RecycleStatus};
+use crate::config::Config;
+use crate::db::{
+    hash_cancel_secret, Deposit, DepositInput, DepositRepository, DepositStatus, PaymentRepository, Recycle,
+    RecycleStatus, SweepRepository,
+};
+use crate::deposit;
 use crate::lightning::LnurlClient;
+use crate::sweep;
+use crate::wallet::{ChainServerStatus, InputDetails};
 use crate::AppState;
 use askama::Template;
 use axum::{
     extract::{ConnectInfo, Path, Query, State},
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::{Html, IntoResponse, Response},
     routing::{get, post},
     Form, Json, Router,
 };
+use bdk_wallet::bitcoin::consensus::encode::deserialize_hex;
+use bdk_wallet::bitcoin::{OutPoint, Psbt, Transaction};
 use qrcode::{render::svg, QrCode};
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+/// Most inputs a single eligibility check will look up
+const MAX_ELIGIBILITY_INPUTS: usize = 100;
+
 pub fn create_router() -> Router<Arc<AppState>> {
     Router::new()
         .route("/", get(index_page))
         .route("/confirm", post(confirm_page))
         .route("/recycle/:id", get(recycle_page))
+        .route("/recycle/:id/deposit", get(deposit_page).post(submit_deposit_form))
         .route("/api/recycle", post(create_recycle))
         .route("/api/recycle/:id", get(get_recycle))
+        .route("/api/recycle/:id/deposit", post(submit_deposit))
+        .route("/api/recycle/:id/cancel", post(cancel_recycle))
+        .route("/api/eligibility", post(check_eligibility))
         .route("/health", get(health_check))
         .route("/admin/stats", get(admin_stats))
+        .route("/admin/recycles/:id/events", get(recycle_events))
+        .route("/admin/recycles/:id/payments", get(recycle_payments))
+        .route("/admin/recycles/:id/retry", post(retry_recycle))
+        .route("/admin/sweeps", get(list_sweeps).post(create_sweep))
+        .route("/admin/sweeps/:id", get(get_sweep))
+        .route("/admin/sweeps/:id/broadcast", post(broadcast_sweep))
+        .route("/admin/sweeps/:id/cancel", post(cancel_sweep))
 }
 
 // Helper to convert payout_multiplier (1.01) to percent (101)
@@ -35,6 +60,7 @@ fn payout_percent(multiplier: f64) -> u32 {
 #[template(path = "index.html")]
 struct IndexTemplate {
     payout_percent: u32,
+    network_banner: Option<String>,
 }
 
 #[derive(Template)]
@@ -45,11 +71,13 @@ struct ConfirmTemplate {
     max_input_sats: u64,
     payout_percent: u32,
     required_confirmations: u32,
+    network_banner: Option<String>,
 }
 
 #[derive(Template)]
 #[template(path = "recycle.html")]
 struct RecycleTemplate {
+    recycle_id: String,
     lightning_address: String,
     deposit_address: String,
     qr_code_svg: String,
@@ -69,7 +97,137 @@ struct RecycleTemplate {
     recorded_max_input: Option<u64>,
     payout_amount_sats: Option<u64>,
     payment_preimage: Option<String>,
+    /// Why the payout was given up, for a failed recycle
+    failure_reason: Option<String>,
     is_pending: bool,
+    /// The expired or cancelled recycle's address was handed to a newer recycle
+    address_released: bool,
+    /// The visitor created the recycle and it can still be cancelled
+    can_cancel: bool,
+    deposits: Vec<DepositView>,
+    network_banner: Option<String>,
+    explorer_tx_url: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "deposit.html")]
+struct DepositTemplate {
+    recycle_id: String,
+    deposit_address: String,
+    cutoff_block_height: u32,
+    max_input_sats: u64,
+    payout_percent: u32,
+    /// The submitted transaction, kept so the user can fix and resubmit it
+    transaction: String,
+    error: Option<String>,
+    inputs: Vec<InputEligibility>,
+    network_banner: Option<String>,
+}
+
+impl DepositTemplate {
+    fn new(state: &AppState, recycle_id: String, deposit_address: String) -> Self {
+        Self {
+            recycle_id,
+            deposit_address,
+            cutoff_block_height: state.config.cutoff_block_height,
+            max_input_sats: state.config.max_input_sats,
+            payout_percent: payout_percent(state.config.payout_multiplier),
+            transaction: String::new(),
+            error: None,
+            inputs: Vec::new(),
+            network_banner: state.config.network_banner(),
+        }
+    }
+}
+
+// A deposit row on the recycle page
+struct DepositView {
+    txid: String,
+    outpoint: String,
+    amount_sats: u64,
+    confirmations: u32,
+    status: String,
+    status_class: String,
+    donation_reason: Option<String>,
+    // Eligible share, set only when some but not all of the deposit is eligible
+    partial_eligible_sats: Option<u64>,
+    inputs: Vec<InputView>,
+}
+
+// A row in a deposit's input breakdown
+struct InputView {
+    prev_txid: String,
+    outpoint: String,
+    value: String,
+    creation_height: String,
+    verdict: &'static str,
+    verdict_class: &'static str,
+}
+
+// Human-readable eligibility verdict for an input
+fn verdict(is_eligible: bool, reason: Option<&str>) -> &'static str {
+    match reason {
+        None if is_eligible => "Eligible",
+        Some("block_height") => "Too recent",
+        Some("input_too_large") => "Too large",
+        Some("value_unknown") => "Unknown value",
+        _ => "Unknown age",
+    }
+}
+
+impl From<&DepositInput> for InputView {
+    fn from(input: &DepositInput) -> Self {
+        Self {
+            outpoint: input.prev_outpoint(),
+            prev_txid: input.prev_txid.clone(),
+            value: input
+                .value_sats
+                .map_or_else(|| "unknown".to_string(), |v| format!("{} sats", v)),
+            creation_height: input
+                .creation_height
+                .map_or_else(|| "unknown".to_string(), |h| h.to_string()),
+            verdict: verdict(input.is_eligible, input.reason.as_deref()),
+            verdict_class: if input.is_eligible {
+                "input-eligible"
+            } else {
+                "input-ineligible"
+            },
+        }
+    }
+}
+
+impl DepositView {
+    fn new(deposit: Deposit, inputs: &[DepositInput]) -> Self {
+        let status_class = match deposit.status {
+            DepositStatus::Confirming => "status-confirming",
+            DepositStatus::Confirmed => "status-confirmed",
+            DepositStatus::Paid => "status-paid",
+            DepositStatus::Donation => "status-donation",
+            DepositStatus::Reorged => "status-reorged",
+            DepositStatus::DoubleSpent => "status-double-spent",
+        };
+
+        let inputs = inputs
+            .iter()
+            .filter(|input| input.belongs_to(&deposit))
+            .map(InputView::from)
+            .collect();
+        let partial_eligible_sats = deposit
+            .eligible_sats
+            .filter(|&eligible| eligible > 0 && eligible < deposit.amount_sats);
+
+        Self {
+            outpoint: deposit.outpoint(),
+            txid: deposit.txid,
+            amount_sats: deposit.amount_sats,
+            confirmations: deposit.confirmations,
+            status: deposit.status.display_name().to_string(),
+            status_class: status_class.to_string(),
+            donation_reason: deposit.donation_reason,
+            partial_eligible_sats,
+            inputs,
+        }
+    }
 }
 
 // API types
@@ -79,6 +237,7 @@ pub struct CreateRecycleRequest {
     pub confirmed: Option<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize)]
 pub struct CreateRecycleResponse {
     pub id: String,
@@ -86,6 +245,12 @@ pub struct CreateRecycleResponse {
     pub lightning_address: String,
 }
 
+/// The creator's cancel secret. Browsers send it as a cookie instead.
+#[derive(Deserialize)]
+pub struct CancelRecycleRequest {
+    pub secret: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct RecycleResponse {
     pub id: String,
@@ -97,6 +262,177 @@ pub struct RecycleResponse {
     pub deposit_confirmations: u32,
     pub payout_amount_sats: Option<u64>,
     pub payment_preimage: Option<String>,
+    /// Payment hash of the paid invoice, which the preimage hashes to
+    pub payment_hash: Option<String>,
+    /// Set once an expired recycle's address was handed to a newer recycle
+    pub address_released_at: Option<String>,
+    pub deposits: Vec<DepositResponse>,
+}
+
+#[derive(Serialize)]
+pub struct DepositResponse {
+    pub txid: String,
+    pub vout: u32,
+    pub amount_sats: u64,
+    pub confirmations: u32,
+    pub status: String,
+    pub is_eligible: Option<bool>,
+    pub donation_reason: Option<String>,
+    /// Portion of the deposit backed by eligible inputs
+    pub eligible_sats: Option<u64>,
+    pub inputs: Vec<DepositInput>,
+}
+
+impl DepositResponse {
+    fn new(deposit: Deposit, inputs: &[DepositInput]) -> Self {
+        Self {
+            inputs: inputs
+                .iter()
+                .filter(|input| input.belongs_to(&deposit))
+                .cloned()
+                .collect(),
+            txid: deposit.txid,
+            vout: deposit.vout,
+            amount_sats: deposit.amount_sats,
+            confirmations: deposit.confirmations,
+            status: deposit.status.as_str().to_string(),
+            is_eligible: deposit.is_eligible,
+            donation_reason: deposit.donation_reason,
+            eligible_sats: deposit.eligible_sats,
+        }
+    }
+}
+
+/// UTXOs to check before depositing: a list of "txid:vout" outpoints, or the inputs of
+/// an unsigned transaction (hex) or PSBT (base64).
+#[derive(Deserialize)]
+pub struct EligibilityRequest {
+    pub outpoints: Option<Vec<String>>,
+    pub tx: Option<String>,
+    pub psbt: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct EligibilityResponse {
+    pub cutoff_block_height: u32,
+    pub max_input_sats: u64,
+    /// Whether every input passes, i.e. a deposit spending them would be paid in full
+    pub all_eligible: bool,
+    pub eligible_input_sats: u64,
+    pub total_input_sats: u64,
+    pub inputs: Vec<InputEligibility>,
+}
+
+#[derive(Serialize)]
+pub struct InputEligibility {
+    pub outpoint: String,
+    pub value_sats: Option<u64>,
+    pub creation_height: Option<u32>,
+    pub is_eligible: bool,
+    /// Reason the input is ineligible: "block_height", "block_height_unknown", "value_unknown" or "input_too_large"
+    pub reason: Option<&'static str>,
+}
+
+impl InputEligibility {
+    fn verdict(&self) -> &'static str {
+        verdict(self.is_eligible, self.reason)
+    }
+}
+
+impl EligibilityResponse {
+    fn new<'a>(
+        config: &Config,
+        checked: impl IntoIterator<Item = (&'a InputDetails, Option<&'static str>)>,
+    ) -> Self {
+        let inputs: Vec<InputEligibility> = checked
+            .into_iter()
+            .map(|(input, reason)| InputEligibility {
+                outpoint: input.outpoint.to_string(),
+                value_sats: input.value_sats,
+                creation_height: input.creation_height,
+                is_eligible: reason.is_none(),
+                reason,
+            })
+            .collect();
+
+        let total_input_sats = inputs.iter().filter_map(|input| input.value_sats).sum();
+        let eligible_input_sats = inputs
+            .iter()
+            .filter(|input| input.is_eligible)
+            .filter_map(|input| input.value_sats)
+            .sum();
+
+        Self {
+            cutoff_block_height: config.cutoff_block_height,
+            max_input_sats: config.max_input_sats,
+            all_eligible: inputs.iter().all(|input| input.is_eligible),
+            eligible_input_sats,
+            total_input_sats,
+            inputs,
+        }
+    }
+}
+
+/// A fully signed deposit transaction: raw transaction hex or PSBT base64.
+#[derive(Deserialize)]
+pub struct SubmitDepositRequest {
+    pub transaction: String,
+}
+
+#[derive(Serialize)]
+pub struct SubmitDepositResponse {
+    pub txid: String,
+    /// Amount paid to the deposit address
+    pub amount_sats: u64,
+    pub eligibility: EligibilityResponse,
+}
+
+/// Why a submitted deposit was not broadcast, with the per-input verdicts if it got
+/// as far as checking them.
+#[derive(Serialize)]
+struct DepositRejection {
+    #[serde(skip)]
+    status: StatusCode,
+    error: String,
+    eligibility: Option<EligibilityResponse>,
+}
+
+impl DepositRejection {
+    fn new(status: StatusCode, error: impl Into<String>) -> Self {
+        Self {
+            status,
+            error: error.into(),
+            eligibility: None,
+        }
+    }
+}
+
+impl EligibilityRequest {
+    /// The outpoints to check, from whichever form the request used.
+    fn outpoints(&self) -> Result<Vec<OutPoint>, String> {
+        match (&self.outpoints, &self.tx, &self.psbt) {
+            (Some(outpoints), None, None) => outpoints
+                .iter()
+                .map(|outpoint| {
+                    OutPoint::from_str(outpoint.trim())
+                        .map_err(|e| format!("Invalid outpoint {}: {}", outpoint, e))
+                })
+                .collect(),
+            (None, Some(tx), None) => deserialize_hex::<Transaction>(tx.trim())
+                .map(|tx| tx.input.iter().map(|input| input.previous_output).collect())
+                .map_err(|e| format!("Invalid transaction: {}", e)),
+            (None, None, Some(psbt)) => Psbt::from_str(psbt.trim())
+                .map(|psbt| {
+                    psbt.unsigned_tx
+                        .input
+                        .iter()
+                        .map(|input| input.previous_output)
+                        .collect()
+                })
+                .map_err(|e| format!("Invalid PSBT: {}", e)),
+            _ => Err("Provide exactly one of outpoints, tx or psbt".to_string()),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -111,6 +447,12 @@ struct HealthResponse {
     db: &'static str,
     last_sync: Option<String>,
     last_sync_ago_secs: Option<i64>,
+    chain_backend: &'static str,
+    /// The server currently serving chain requests, if the backend uses a server pool
+    chain_server: Option<String>,
+    chain_servers: Vec<ChainServerStatus>,
+    /// Whether this instance runs the deposit monitor and payment processor
+    runs_workers: bool,
 }
 
 // Admin stats response
@@ -122,6 +464,16 @@ struct AdminStatsResponse {
     total_paid_out_sats: i64,
     total_donations_sats: i64,
     net_sats: i64,
+    lightning: LightningStats,
+}
+
+/// The payout backend's spendable balance, as it reports it
+#[derive(Serialize)]
+struct LightningStats {
+    backend: &'static str,
+    /// None if the backend couldn't be asked, see `error`
+    balance_sats: Option<u64>,
+    error: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -129,9 +481,14 @@ struct StatusCounts {
     awaiting_deposit: i64,
     confirming: i64,
     confirmed: i64,
+    paying: i64,
     paid: i64,
     failed: i64,
     donation: i64,
+    reorged: i64,
+    double_spent: i64,
+    expired: i64,
+    cancelled: i64,
 }
 
 #[derive(Deserialize)]
@@ -139,10 +496,24 @@ struct AdminQuery {
     token: Option<String>,
 }
 
+#[derive(Deserialize)]
+struct CreateSweepQuery {
+    token: Option<String>,
+    /// Feerate in sat/vB (defaults to SWEEP_FEE_RATE)
+    fee_rate: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct BroadcastSweepRequest {
+    /// Base64 PSBT signed by the offline signer
+    psbt: String,
+}
+
 // Handlers
 async fn index_page(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     HtmlTemplate(IndexTemplate {
         payout_percent: payout_percent(state.config.payout_multiplier),
+        network_banner: state.config.network_banner(),
     })
 }
 
@@ -177,7 +548,7 @@ async fn confirm_page(
     }
 
     // Validate the lightning address is reachable
-    let lnurl_client = LnurlClient::new();
+    let lnurl_client = LnurlClient::new(state.config.lnurl_allow_http);
     if let Err(e) = lnurl_client.fetch_pay_params(&lightning_address).await {
         return (
             StatusCode::BAD_REQUEST,
@@ -196,6 +567,7 @@ async fn confirm_page(
         max_input_sats: state.config.max_input_sats,
         payout_percent: payout_percent(state.config.payout_multiplier),
         required_confirmations: state.config.required_confirmations,
+        network_banner: state.config.network_banner(),
     })
     .into_response()
 }
@@ -203,8 +575,9 @@ async fn confirm_page(
 async fn recycle_page(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let recycle = match RecycleRepository::find_by_id(&state.db, &id).await {
+    let recycle = match state.recycles.find_by_id(&id).await {
         Ok(Some(r)) => r,
         Ok(None) => {
             return (StatusCode::NOT_FOUND, Html("Recycle not found".to_string())).into_response()
@@ -218,6 +591,28 @@ async fn recycle_page(
         }
     };
 
+    let deposits = match DepositRepository::find_by_recycle(&state.db, &recycle.id).await {
+        Ok(deposits) => deposits,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Html(format!("Error: {}", e)),
+            )
+                .into_response()
+        }
+    };
+
+    let inputs = match DepositRepository::find_inputs_by_recycle(&state.db, &recycle.id).await {
+        Ok(inputs) => inputs,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Html(format!("Error: {}", e)),
+            )
+                .into_response()
+        }
+    };
+
     // Generate QR code
     let qr_code_svg = match QrCode::new(recycle.deposit_address.to_uppercase()) {
         Ok(code) => code
@@ -232,16 +627,29 @@ async fn recycle_page(
         RecycleStatus::AwaitingDeposit => "status-awaiting",
         RecycleStatus::Confirming => "status-confirming",
         RecycleStatus::Confirmed => "status-confirmed",
+        RecycleStatus::Paying => "status-paying",
         RecycleStatus::Paid => "status-paid",
         RecycleStatus::Failed => "status-failed",
         RecycleStatus::Donation => "status-donation",
+        RecycleStatus::Reorged => "status-reorged",
+        RecycleStatus::DoubleSpent => "status-double-spent",
+        RecycleStatus::Expired => "status-expired",
+        RecycleStatus::Cancelled => "status-cancelled",
     };
 
     let is_pending = matches!(
         recycle.status,
-        RecycleStatus::AwaitingDeposit | RecycleStatus::Confirming | RecycleStatus::Confirmed
+        RecycleStatus::AwaitingDeposit
+            | RecycleStatus::Confirming
+            | RecycleStatus::Confirmed
+            | RecycleStatus::Paying
+            | RecycleStatus::Reorged
     );
 
+    let can_cancel = recycle.status == RecycleStatus::AwaitingDeposit
+        && cancel_secret_from_cookies(&headers, &recycle.id).is_some_and(|secret| recycle.cancel_secret_matches(&secret));
+
+    #[allow(clippy::manual_checked_ops)]
     let confirmation_percent = if state.config.required_confirmations > 0 {
         (recycle.deposit_confirmations * 100 / state.config.required_confirmations).min(100)
     } else {
@@ -249,6 +657,7 @@ async fn recycle_page(
     };
 
     let template = RecycleTemplate {
+        recycle_id: recycle.id,
         lightning_address: recycle.lightning_address,
         deposit_address: recycle.deposit_address,
         qr_code_svg,
@@ -268,7 +677,16 @@ async fn recycle_page(
         recorded_max_input: recycle.max_input_sats,
         payout_amount_sats: recycle.payout_amount_sats,
         payment_preimage: recycle.payment_preimage,
+        failure_reason: recycle.failure_reason,
         is_pending,
+        address_released: recycle.address_released_at.is_some(),
+        can_cancel,
+        deposits: deposits
+            .into_iter()
+            .map(|deposit| DepositView::new(deposit, &inputs))
+            .collect(),
+        network_banner: state.config.network_banner(),
+        explorer_tx_url: state.config.explorer_tx_url(),
     };
 
     HtmlTemplate(template).into_response()
@@ -317,7 +735,7 @@ async fn create_recycle(
     }
 
     // Validate the lightning address is reachable
-    let lnurl_client = LnurlClient::new();
+    let lnurl_client = LnurlClient::new(state.config.lnurl_allow_http);
     if let Err(e) = lnurl_client.fetch_pay_params(&lightning_address).await {
         return (
             StatusCode::BAD_REQUEST,
@@ -328,28 +746,28 @@ async fn create_recycle(
             .into_response();
     }
 
-    // Get next address index
-    let address_index = match RecycleRepository::increment_address_index(&state.db).await {
-        Ok(idx) => idx,
-        Err(e) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(ErrorResponse {
-                    error: format!("Failed to get address index: {}", e),
-                }),
-            )
-                .into_response();
+    // Create recycle record at a reusable or the next deposit address. The creator's
+    // browser keeps the secret that allows cancelling it.
+    let id = uuid::Uuid::new_v4().to_string();
+    let cancel_secret = uuid::Uuid::new_v4().simple().to_string();
+    let cancel_secret_hash = hash_cancel_secret(&cancel_secret);
+    let created = match create_at_reusable_address(&state, &id, &lightning_address, &cancel_secret_hash).await {
+        Ok(Some(recycle)) => Ok(recycle),
+        Ok(None) => {
+            state
+                .recycles
+                .create(&id, &lightning_address, &cancel_secret_hash, &|index| state.wallet.address_at(index))
+                .await
         }
+        Err(e) => Err(e),
     };
-
-    // Generate deposit address
-    let deposit_address = match state.wallet.get_address(address_index).await {
-        Ok(addr) => addr,
+    let recycle = match created {
+        Ok(recycle) => recycle,
         Err(e) => {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ErrorResponse {
-                    error: format!("Failed to generate deposit address: {}", e),
+                    error: format!("Failed to create recycle: {}", e),
                 }),
             )
                 .into_response();
@@ -357,38 +775,200 @@ async fn create_recycle(
     };
 
     // Reveal the address in the wallet for monitoring
+    let address_index = recycle.address_index;
     if let Err(e) = state.wallet.reveal_addresses_up_to(address_index).await {
         tracing::warn!("Failed to reveal address: {}", e);
     }
 
-    // Create recycle record
-    let id = uuid::Uuid::new_v4().to_string();
-    match RecycleRepository::create(&state.db, &id, &lightning_address, &deposit_address, address_index).await {
-        Ok(_) => {
-            // Redirect to the recycle page
+    // Subscribe to the address so the deposit is noticed as soon as it is broadcast
+    let watch_state = Arc::clone(&state);
+    tokio::spawn(async move {
+        if let Err(e) = watch_state.wallet.watch_addresses(&[address_index]).await {
+            tracing::warn!("Failed to watch address {}: {}", address_index, e);
+        }
+    });
+
+    // Redirect to the recycle page
+    (
+        StatusCode::SEE_OTHER,
+        [
+            ("Location", format!("/recycle/{}", id)),
             (
-                StatusCode::SEE_OTHER,
-                [("Location", format!("/recycle/{}", id))],
-                "",
+                "Set-Cookie",
+                cancel_cookie(&id, &cancel_secret, state.config.recycle_expiry_secs),
+            ),
+        ],
+        "",
+    )
+        .into_response()
+}
+
+/// Cookie holding the cancel secret of recycle `id`. It only needs to outlive the wait
+/// for a deposit, since a funded or expired recycle can't be cancelled.
+fn cancel_cookie(id: &str, secret: &str, max_age_secs: u64) -> String {
+    format!(
+        "recycle_{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict",
+        id, secret, max_age_secs
+    )
+}
+
+fn cancel_secret_from_cookies(headers: &HeaderMap, id: &str) -> Option<String> {
+    let name = format!("recycle_{}", id);
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, secret)| secret.to_string())
+}
+
+// Cancel a recycle still awaiting its deposit, for its creator only
+async fn cancel_recycle(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Form(request): Form<CancelRecycleRequest>,
+) -> Response {
+    // Rate limiting
+    let ip = addr.ip();
+    if let Err(retry_after) = state.rate_limiter.check(ip).await {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [("Retry-After", retry_after.to_string())],
+            Html(format!(
+                "<h1>Rate Limited</h1><p>Too many requests. Please try again in {} seconds.</p><p><a href='/recycle/{}'>Go back</a></p>",
+                retry_after, id
+            )),
+        )
+            .into_response();
+    }
+
+    let recycle = match state.recycles.find_by_id(&id).await {
+        Ok(Some(recycle)) => recycle,
+        Ok(None) => return (StatusCode::NOT_FOUND, Html("Recycle not found".to_string())).into_response(),
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Html(format!("Error: {}", e)),
             )
                 .into_response()
         }
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(ErrorResponse {
-                error: format!("Failed to create recycle: {}", e),
-            }),
+    };
+
+    let secret = request.secret.or_else(|| cancel_secret_from_cookies(&headers, &id));
+    if !secret.is_some_and(|secret| recycle.cancel_secret_matches(&secret)) {
+        return (
+            StatusCode::FORBIDDEN,
+            Html(format!(
+                "<h1>Not Allowed</h1><p>Only the browser that created this recycle can cancel it.</p><p><a href='/recycle/{}'>Go back</a></p>",
+                id
+            )),
         )
-            .into_response(),
+            .into_response();
+    }
+
+    if let Err(e) = state.recycles.cancel(&id).await {
+        return (
+            StatusCode::CONFLICT,
+            Html(format!(
+                "<h1>Cannot Cancel</h1><p>{}</p><p><a href='/recycle/{}'>Go back</a></p>",
+                e, id
+            )),
+        )
+            .into_response();
     }
+
+    (
+        StatusCode::SEE_OTHER,
+        [
+            ("Location", format!("/recycle/{}", id)),
+            ("Set-Cookie", cancel_cookie(&id, "", 0)),
+        ],
+        "",
+    )
+        .into_response()
+}
+
+/// Most expired recycles considered for address reuse per create
+const REUSE_CANDIDATES: u32 = 5;
+
+/// Create the recycle at the address of an expired or cancelled recycle that was never
+/// funded, once its cool-off has passed, so abandoned recycles don't keep using up address indexes.
+/// Returns None if no address is ready for reuse.
+async fn create_at_reusable_address(
+    state: &AppState,
+    id: &str,
+    lightning_address: &str,
+    cancel_secret_hash: &str,
+) -> anyhow::Result<Option<Recycle>> {
+    // Only the instance running the deposit monitor has a synced wallet to check
+    // candidates against
+    if !state.runs_workers.load(Ordering::Relaxed) {
+        return Ok(None);
+    }
+
+    let expired_before = chrono::Utc::now() - chrono::Duration::seconds(state.config.address_reuse_cooloff_secs as i64);
+
+    for expired in state.recycles.find_reusable(expired_before, REUSE_CANDIDATES).await? {
+        // The database only knows deposits the monitor has recorded, the wallet may
+        // have seen one since
+        if !state.wallet.is_address_unused(expired.address_index).await {
+            continue;
+        }
+        if let Some(recycle) =
+            state.recycles.create_reusing(id, lightning_address, cancel_secret_hash, &expired).await?
+        {
+            tracing::info!(
+                "Recycle {} reuses address index {} of {} recycle {}",
+                id,
+                expired.address_index,
+                expired.status.as_str(),
+                expired.id
+            );
+            return Ok(Some(recycle));
+        }
+    }
+
+    Ok(None)
 }
 
 async fn get_recycle(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
 ) -> impl IntoResponse {
-    match RecycleRepository::find_by_id(&state.db, &id).await {
-        Ok(Some(recycle)) => (
+    let recycle = match state.recycles.find_by_id(&id).await {
+        Ok(Some(recycle)) => recycle,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse {
+                    error: "Recycle not found".to_string(),
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: format!("Database error: {}", e),
+                }),
+            )
+                .into_response()
+        }
+    };
+
+    let deposits = async {
+        let deposits = DepositRepository::find_by_recycle(&state.db, &recycle.id).await?;
+        let inputs = DepositRepository::find_inputs_by_recycle(&state.db, &recycle.id).await?;
+        anyhow::Ok((deposits, inputs))
+    };
+
+    match deposits.await {
+        Ok((deposits, inputs)) => (
             StatusCode::OK,
             Json(RecycleResponse {
                 id: recycle.id,
@@ -400,23 +980,224 @@ async fn get_recycle(
                 deposit_confirmations: recycle.deposit_confirmations,
                 payout_amount_sats: recycle.payout_amount_sats,
                 payment_preimage: recycle.payment_preimage,
+                payment_hash: recycle.payment_hash,
+                address_released_at: recycle.address_released_at.map(|at| at.to_rfc3339()),
+                deposits: deposits
+                    .into_iter()
+                    .map(|deposit| DepositResponse::new(deposit, &inputs))
+                    .collect(),
             }),
         )
             .into_response(),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
             Json(ErrorResponse {
-                error: "Recycle not found".to_string(),
+                error: format!("Database error: {}", e),
             }),
         )
             .into_response(),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Check UTXOs against the payout rules before they are deposited
+async fn check_eligibility(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Json(request): Json<EligibilityRequest>,
+) -> Response {
+    // Rate limiting - every input costs chain backend lookups
+    let ip = addr.ip();
+    if let Err(retry_after) = state.rate_limiter.check(ip).await {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [("Retry-After", retry_after.to_string())],
             Json(ErrorResponse {
-                error: format!("Database error: {}", e),
+                error: format!("Too many requests. Please try again in {} seconds.", retry_after),
+            }),
+        )
+            .into_response();
+    }
+
+    let outpoints = match request.outpoints() {
+        Ok(outpoints) => outpoints,
+        Err(error) => return (StatusCode::BAD_REQUEST, Json(ErrorResponse { error })).into_response(),
+    };
+
+    if outpoints.is_empty() || outpoints.len() > MAX_ELIGIBILITY_INPUTS {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("Provide between 1 and {} inputs", MAX_ELIGIBILITY_INPUTS),
             }),
+        )
+            .into_response();
+    }
+
+    let details = match state.wallet.get_outpoint_details(outpoints).await {
+        Ok(details) => details,
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ErrorResponse {
+                    error: format!("Failed to look up inputs: {}", e),
+                }),
+            )
+                .into_response()
+        }
+    };
+
+    let checked = details.iter().map(|input| {
+        let reason = input.ineligibility_reason(
+            state.config.cutoff_block_height,
+            state.config.max_input_sats,
+        );
+        (input, reason)
+    });
+
+    (
+        StatusCode::OK,
+        Json(EligibilityResponse::new(&state.config, checked)),
+    )
+        .into_response()
+}
+
+// Verify a signed deposit and broadcast it if every input is eligible
+async fn submit_deposit(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+    Json(request): Json<SubmitDepositRequest>,
+) -> Response {
+    // Rate limiting
+    let ip = addr.ip();
+    if let Err(retry_after) = state.rate_limiter.check(ip).await {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [("Retry-After", retry_after.to_string())],
+            Json(ErrorResponse {
+                error: format!("Too many requests. Please try again in {} seconds.", retry_after),
+            }),
+        )
+            .into_response();
+    }
+
+    match verify_and_broadcast_deposit(&state, &id, &request.transaction).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(rejection) => (rejection.status, Json(rejection)).into_response(),
+    }
+}
+
+async fn deposit_page(State(state): State<Arc<AppState>>, Path(id): Path<String>) -> Response {
+    match state.recycles.find_by_id(&id).await {
+        Ok(Some(recycle)) if recycle.address_released_at.is_some() => (
+            StatusCode::GONE,
+            Html(format!(
+                "<h1>Recycle Expired</h1><p>This recycle's deposit address now belongs to a newer recycle.</p><p><a href='/recycle/{}'>Go back</a></p>",
+                recycle.id
+            )),
+        )
+            .into_response(),
+        Ok(Some(recycle)) => HtmlTemplate(DepositTemplate::new(&state, recycle.id, recycle.deposit_address))
+            .into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, Html("Recycle not found".to_string())).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Html(format!("Error: {}", e)),
+        )
+            .into_response(),
+    }
+}
+
+async fn submit_deposit_form(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path(id): Path<String>,
+    Form(request): Form<SubmitDepositRequest>,
+) -> Response {
+    // Rate limiting
+    let ip = addr.ip();
+    if let Err(retry_after) = state.rate_limiter.check(ip).await {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [("Retry-After", retry_after.to_string())],
+            Html(format!(
+                "<h1>Rate Limited</h1><p>Too many requests. Please try again in {} seconds.</p><p><a href='/recycle/{}/deposit'>Go back</a></p>",
+                retry_after, id
+            )),
+        )
+            .into_response();
+    }
+
+    match verify_and_broadcast_deposit(&state, &id, &request.transaction).await {
+        // The deposit shows up on the recycle page once the monitor sees it in the mempool
+        Ok(_) => (
+            StatusCode::SEE_OTHER,
+            [("Location", format!("/recycle/{}", id))],
+            "",
         )
             .into_response(),
+        Err(rejection) => {
+            let deposit_address = match state.recycles.find_by_id(&id).await {
+                Ok(Some(recycle)) => recycle.deposit_address,
+                _ => return (rejection.status, Html(rejection.error)).into_response(),
+            };
+            let mut template = DepositTemplate::new(&state, id, deposit_address);
+            template.transaction = request.transaction;
+            template.error = Some(rejection.error);
+            template.inputs = rejection
+                .eligibility
+                .map(|eligibility| eligibility.inputs)
+                .unwrap_or_default();
+            (rejection.status, HtmlTemplate(template)).into_response()
+        }
+    }
+}
+
+/// Check a signed deposit for a recycle and broadcast it, or explain why it was rejected.
+async fn verify_and_broadcast_deposit(
+    state: &AppState,
+    id: &str,
+    raw: &str,
+) -> Result<SubmitDepositResponse, DepositRejection> {
+    let recycle = match state.recycles.find_by_id(id).await {
+        Ok(Some(recycle)) => recycle,
+        Ok(None) => return Err(DepositRejection::new(StatusCode::NOT_FOUND, "Recycle not found")),
+        Err(e) => {
+            return Err(DepositRejection::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Database error: {}", e),
+            ))
+        }
+    };
+
+    let checked = deposit::check_signed_deposit(state, &recycle, raw)
+        .await
+        .map_err(|e| DepositRejection::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+    let eligibility = EligibilityResponse::new(
+        &state.config,
+        checked.inputs.iter().map(|(input, reason)| (input, *reason)),
+    );
+
+    if !checked.is_eligible() {
+        return Err(DepositRejection {
+            status: StatusCode::UNPROCESSABLE_ENTITY,
+            error: "Deposit has ineligible inputs and was not broadcast".to_string(),
+            eligibility: Some(eligibility),
+        });
+    }
+
+    let amount_sats = checked.amount_sats;
+    match deposit::broadcast_deposit(state, &recycle, checked).await {
+        Ok(txid) => Ok(SubmitDepositResponse {
+            txid: txid.to_string(),
+            amount_sats,
+            eligibility,
+        }),
+        Err(e) => Err(DepositRejection {
+            status: StatusCode::BAD_REQUEST,
+            error: format!("Failed to broadcast deposit: {}", e),
+            eligibility: Some(eligibility),
+        }),
     }
 }
 
@@ -438,7 +1219,19 @@ async fn health_check(State(state): State<Arc<AppState>>) -> impl IntoResponse {
         None => (None, None),
     };
 
-    let overall_status = if db_status == "ok" { "ok" } else { "degraded" };
+    // Chain servers (empty for single-server backends)
+    let chain_servers = state.wallet.chain_server_status();
+    let chain_server = chain_servers
+        .iter()
+        .find(|server| server.active)
+        .map(|server| server.url.clone());
+    let chain_ok = chain_servers.is_empty() || chain_servers.iter().any(|server| server.healthy);
+
+    let overall_status = if db_status == "ok" && chain_ok {
+        "ok"
+    } else {
+        "degraded"
+    };
 
     (
         if overall_status == "ok" {
@@ -451,6 +1244,10 @@ async fn health_check(State(state): State<Arc<AppState>>) -> impl IntoResponse {
             db: db_status,
             last_sync: last_sync_str,
             last_sync_ago_secs: last_sync_ago,
+            chain_backend: state.wallet.chain_backend(),
+            chain_server,
+            chain_servers,
+            runs_workers: state.runs_workers.load(Ordering::Relaxed),
         }),
     )
 }
@@ -460,32 +1257,25 @@ async fn admin_stats(
     State(state): State<Arc<AppState>>,
     Query(query): Query<AdminQuery>,
 ) -> Response {
-    // Check admin token
-    match &state.config.admin_token {
-        Some(expected_token) => {
-            if query.token.as_ref() != Some(expected_token) {
-                return (
-                    StatusCode::UNAUTHORIZED,
-                    Json(ErrorResponse {
-                        error: "Invalid or missing admin token".to_string(),
-                    }),
-                )
-                    .into_response();
-            }
-        }
-        None => {
-            return (
-                StatusCode::NOT_FOUND,
-                Json(ErrorResponse {
-                    error: "Admin endpoint not configured".to_string(),
-                }),
-            )
-                .into_response();
-        }
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
     }
 
+    let lightning = match state.lightning.get_balance().await {
+        Ok(balance_msat) => LightningStats {
+            backend: state.lightning.name(),
+            balance_sats: Some(balance_msat / 1000),
+            error: None,
+        },
+        Err(e) => LightningStats {
+            backend: state.lightning.name(),
+            balance_sats: None,
+            error: Some(e.to_string()),
+        },
+    };
+
     // Query stats from database
-    let stats = match get_admin_stats(&state.db).await {
+    let stats = match get_admin_stats(&state.db, lightning).await {
         Ok(s) => s,
         Err(e) => {
             return (
@@ -501,7 +1291,244 @@ async fn admin_stats(
     (StatusCode::OK, Json(stats)).into_response()
 }
 
-async fn get_admin_stats(db: &sqlx::SqlitePool) -> anyhow::Result<AdminStatsResponse> {
+// Returns an error response if the request lacks a valid admin token
+fn admin_token_rejection(state: &AppState, token: Option<&str>) -> Option<Response> {
+    match &state.config.admin_token {
+        Some(expected_token) if token == Some(expected_token.as_str()) => None,
+        Some(_) => Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                Json(ErrorResponse {
+                    error: "Invalid or missing admin token".to_string(),
+                }),
+            )
+                .into_response(),
+        ),
+        None => Some(
+            (
+                StatusCode::NOT_FOUND,
+                Json(ErrorResponse {
+                    error: "Admin endpoint not configured".to_string(),
+                }),
+            )
+                .into_response(),
+        ),
+    }
+}
+
+// Recycle status history, oldest transition first
+async fn recycle_events(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<AdminQuery>,
+) -> Response {
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
+    }
+
+    match state.recycles.find_events(&id).await {
+        Ok(events) if events.is_empty() => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Recycle not found".to_string(),
+            }),
+        )
+            .into_response(),
+        Ok(events) => (StatusCode::OK, Json(events)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: format!("Database error: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+// Lightning payments attempted for a recycle, oldest first
+async fn recycle_payments(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<AdminQuery>,
+) -> Response {
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
+    }
+
+    let payments = async {
+        let recycle = state.recycles.find_by_id(&id).await?;
+        match recycle {
+            Some(_) => PaymentRepository::find_by_recycle(&state.db, &id).await.map(Some),
+            None => Ok(None),
+        }
+    };
+
+    match payments.await {
+        Ok(Some(payments)) => (StatusCode::OK, Json(payments)).into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Recycle not found".to_string(),
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: format!("Database error: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+// Move a failed recycle back to confirmed so the payment processor tries again
+async fn retry_recycle(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<AdminQuery>,
+) -> Response {
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
+    }
+
+    let retried = async {
+        state.recycles.retry_payment(&id).await?;
+        state.recycles.find_by_id(&id).await
+    };
+
+    match retried.await {
+        Ok(Some(recycle)) => (StatusCode::OK, Json(recycle)).into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Recycle not found".to_string(),
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::CONFLICT,
+            Json(ErrorResponse {
+                error: format!("Failed to retry recycle: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+// Consolidation sweep endpoints
+async fn list_sweeps(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AdminQuery>,
+) -> Response {
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
+    }
+
+    match SweepRepository::list(&state.db).await {
+        Ok(sweeps) => (StatusCode::OK, Json(sweeps)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: format!("Database error: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn create_sweep(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CreateSweepQuery>,
+) -> Response {
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
+    }
+
+    match sweep::create_sweep(&state, query.fee_rate).await {
+        Ok(sweep) => (StatusCode::CREATED, Json(sweep)).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("Failed to create sweep: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn get_sweep(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<AdminQuery>,
+) -> Response {
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
+    }
+
+    match SweepRepository::find_by_id(&state.db, &id).await {
+        Ok(Some(sweep)) => (StatusCode::OK, Json(sweep)).into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "Sweep not found".to_string(),
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(ErrorResponse {
+                error: format!("Database error: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn broadcast_sweep(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<AdminQuery>,
+    Json(request): Json<BroadcastSweepRequest>,
+) -> Response {
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
+    }
+
+    match sweep::broadcast_sweep(&state, &id, &request.psbt).await {
+        Ok(sweep) => (StatusCode::OK, Json(sweep)).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("Failed to broadcast sweep: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn cancel_sweep(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<AdminQuery>,
+) -> Response {
+    if let Some(response) = admin_token_rejection(&state, query.token.as_deref()) {
+        return response;
+    }
+
+    match SweepRepository::cancel(&state.db, &id).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(ErrorResponse {
+                error: format!("Failed to cancel sweep: {}", e),
+            }),
+        )
+            .into_response(),
+    }
+}
+
+async fn get_admin_stats(db: &sqlx::AnyPool, lightning: LightningStats) -> anyhow::Result<AdminStatsResponse> {
     // Get counts by status
     let counts: Vec<(String, i64)> = sqlx::query_as(
         "SELECT status, COUNT(*) as count FROM recycles GROUP BY status"
@@ -513,9 +1540,14 @@ async fn get_admin_stats(db: &sqlx::SqlitePool) -> anyhow::Result<AdminStatsResp
         awaiting_deposit: 0,
         confirming: 0,
         confirmed: 0,
+        paying: 0,
         paid: 0,
         failed: 0,
         donation: 0,
+        reorged: 0,
+        double_spent: 0,
+        expired: 0,
+        cancelled: 0,
     };
 
     for (status, count) in &counts {
@@ -523,32 +1555,43 @@ async fn get_admin_stats(db: &sqlx::SqlitePool) -> anyhow::Result<AdminStatsResp
             "awaiting_deposit" => status_counts.awaiting_deposit = *count,
             "confirming" => status_counts.confirming = *count,
             "confirmed" => status_counts.confirmed = *count,
+            "paying" => status_counts.paying = *count,
             "paid" => status_counts.paid = *count,
             "failed" => status_counts.failed = *count,
             "donation" => status_counts.donation = *count,
+            "reorged" => status_counts.reorged = *count,
+            "double_spent" => status_counts.double_spent = *count,
+            "expired" => status_counts.expired = *count,
+            "cancelled" => status_counts.cancelled = *count,
             _ => {}
         }
     }
 
     let total_recycles: i64 = counts.iter().map(|(_, c)| c).sum();
 
-    // Get total deposited (from paid recycles)
+    // Get total deposited (eligible share of paid deposits, the rest counts as donations).
+    // Sums are cast back to BIGINT since Postgres widens them to NUMERIC.
     let total_deposited: (i64,) = sqlx::query_as(
-        "SELECT COALESCE(SUM(deposit_amount_sats), 0) FROM recycles WHERE status = 'paid'"
+        "SELECT CAST(COALESCE(SUM(COALESCE(eligible_sats, amount_sats)), 0) AS BIGINT) FROM deposits WHERE status = 'paid'"
     )
     .fetch_one(db)
     .await?;
 
     // Get total paid out
     let total_paid_out: (i64,) = sqlx::query_as(
-        "SELECT COALESCE(SUM(payout_amount_sats), 0) FROM recycles WHERE status = 'paid'"
+        "SELECT CAST(COALESCE(SUM(payout_amount_sats), 0) AS BIGINT) FROM recycles WHERE status = 'paid'"
     )
     .fetch_one(db)
     .await?;
 
-    // Get total donations
+    // Get total donations (ineligible deposits, including those on paid recycles, plus
+    // the ineligible share of partly eligible paid deposits)
     let total_donations: (i64,) = sqlx::query_as(
-        "SELECT COALESCE(SUM(deposit_amount_sats), 0) FROM recycles WHERE status = 'donation'"
+        r#"
+        SELECT CAST(COALESCE(SUM(CASE WHEN status = 'donation' THEN amount_sats
+                                      ELSE amount_sats - COALESCE(eligible_sats, amount_sats) END), 0) AS BIGINT)
+        FROM deposits WHERE status IN ('donation', 'paid')
+        "#
     )
     .fetch_one(db)
     .await?;
@@ -563,6 +1606,7 @@ async fn get_admin_stats(db: &sqlx::SqlitePool) -> anyhow::Result<AdminStatsResp
         total_paid_out_sats: total_paid_out.0,
         total_donations_sats: total_donations.0,
         net_sats,
+        lightning,
     })
 }
 
@@ -1,4 +1,8 @@
+use bdk_bitcoind_rpc::bitcoincore_rpc::Auth;
+use bdk_wallet::bitcoin::{Address, Network};
 use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 /// The cutoff block height for UTXO eligibility.
 /// Only UTXOs created BEFORE this block are eligible for payout.
@@ -10,13 +14,124 @@ pub const DEFAULT_CUTOFF_BLOCK_HEIGHT: u32 = 930_400;
 /// This ensures we're only accepting true dust consolidation, not regular transactions.
 pub const DEFAULT_MAX_INPUT_SATS: u64 = 1_000;
 
+/// Default feerate for consolidation sweeps, in sat/vB.
+pub const DEFAULT_SWEEP_FEE_RATE: u64 = 2;
+
+/// Default time a recycle waits for its first deposit before it expires (24 hours).
+pub const DEFAULT_RECYCLE_EXPIRY_SECS: u64 = 24 * 60 * 60;
+
+/// Default time an expired, never-funded recycle keeps its address before the address
+/// may be handed to a new recycle (7 days), leaving room for slow or late deposits.
+pub const DEFAULT_ADDRESS_REUSE_COOLOFF_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// Default block height the bitcoind RPC backend starts scanning from on a fresh mainnet
+/// wallet. Recycle addresses are new, so there is nothing to find before the service
+/// existed. Test networks scan from genesis.
+pub const DEFAULT_BITCOIND_START_HEIGHT: u32 = 900_000;
+
+/// Which blockchain backend the wallet syncs against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainBackend {
+    Electrum,
+    Esplora,
+    BitcoindRpc,
+}
+
+impl ChainBackend {
+    pub fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "electrum" => Ok(ChainBackend::Electrum),
+            "esplora" => Ok(ChainBackend::Esplora),
+            "bitcoind" | "bitcoind_rpc" => Ok(ChainBackend::BitcoindRpc),
+            other => Err(anyhow::anyhow!(
+                "Unknown CHAIN_BACKEND '{}' (expected electrum, esplora or bitcoind)",
+                other
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChainBackend::Electrum => "electrum",
+            ChainBackend::Esplora => "esplora",
+            ChainBackend::BitcoindRpc => "bitcoind",
+        }
+    }
+}
+
+/// Which Lightning node or wallet payouts are sent from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightningBackendKind {
+    Nwc,
+    Lnd,
+    Cln,
+    Lnbits,
+}
+
+impl LightningBackendKind {
+    pub fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "nwc" => Ok(LightningBackendKind::Nwc),
+            "lnd" => Ok(LightningBackendKind::Lnd),
+            "cln" | "core_lightning" => Ok(LightningBackendKind::Cln),
+            "lnbits" => Ok(LightningBackendKind::Lnbits),
+            other => Err(anyhow::anyhow!(
+                "Unknown LIGHTNING_BACKEND '{}' (expected nwc, lnd, cln or lnbits)",
+                other
+            )),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LightningBackendKind::Nwc => "nwc",
+            LightningBackendKind::Lnd => "lnd",
+            LightningBackendKind::Cln => "cln",
+            LightningBackendKind::Lnbits => "lnbits",
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Config {
     pub database_url: String,
-    pub nwc_uri: String,
+    /// Lightning backend payouts are sent from: nwc (default), lnd, cln or lnbits
+    pub lightning_backend: LightningBackendKind,
+    /// Nostr Wallet Connect URI (required when LIGHTNING_BACKEND=nwc)
+    pub nwc_uri: Option<String>,
+    /// LND REST URL and credentials (used when LIGHTNING_BACKEND=lnd). The macaroon is
+    /// given hex-encoded or as a file path; the TLS certificate is needed for LND's
+    /// self-signed certificate.
+    pub lnd_rest_url: Option<String>,
+    pub lnd_macaroon: Option<String>,
+    pub lnd_macaroon_path: Option<String>,
+    pub lnd_tls_cert_path: Option<String>,
+    /// Core Lightning REST (clnrest) URL, rune and optional TLS certificate
+    /// (used when LIGHTNING_BACKEND=cln)
+    pub cln_rest_url: Option<String>,
+    pub cln_rune: Option<String>,
+    pub cln_tls_cert_path: Option<String>,
+    /// LNbits instance URL and wallet admin key (used when LIGHTNING_BACKEND=lnbits)
+    pub lnbits_url: Option<String>,
+    pub lnbits_api_key: Option<String>,
     pub wallet_descriptor: String,
-    pub electrum_url: String,
+    /// Bitcoin network: bitcoin (default), testnet, testnet4, signet or regtest
+    pub network: Network,
+    /// Blockchain backend: electrum (default), esplora or bitcoind
+    pub chain_backend: ChainBackend,
+    /// Electrum servers in order of preference; requests fail over between them
+    pub electrum_urls: Vec<String>,
     pub tor_proxy: Option<String>,
+    /// Esplora HTTP API base URL (used when CHAIN_BACKEND=esplora)
+    pub esplora_url: String,
+    /// Bitcoin Core RPC URL (used when CHAIN_BACKEND=bitcoind)
+    pub bitcoind_rpc_url: String,
+    /// Bitcoin Core RPC credentials. The cookie file takes precedence over user/password.
+    pub bitcoind_rpc_user: Option<String>,
+    pub bitcoind_rpc_pass: Option<String>,
+    pub bitcoind_rpc_cookie: Option<String>,
+    /// Block height the bitcoind backend starts scanning from on a fresh wallet
+    pub bitcoind_start_height: u32,
     pub payout_multiplier: f64,
     pub required_confirmations: u32,
     pub server_host: String,
@@ -33,22 +148,72 @@ pub struct Config {
     pub rate_limit_max_requests: u32,
     /// Rate limit: window duration in seconds (default: 60)
     pub rate_limit_window_secs: u64,
+    /// Cold-storage address that consolidation sweeps pay to. Sweeps are disabled if not set.
+    pub sweep_address: Option<String>,
+    /// Default feerate for consolidation sweeps in sat/vB (default: 2)
+    pub sweep_fee_rate: u64,
+    /// Seconds a recycle waits for its first deposit before it expires (default: 86400)
+    pub recycle_expiry_secs: u64,
+    /// Seconds after expiry before an unfunded recycle's address is reused (default: 604800)
+    pub address_reuse_cooloff_secs: u64,
+    /// Fetch LNURL-pay endpoints over plain HTTP, for a local stand-in server in tests.
+    /// Refused on mainnet.
+    pub lnurl_allow_http: bool,
+}
+
+/// The database URL on its own, for the `migrate` command which needs nothing else
+pub fn database_url_from_env() -> String {
+    env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:utxo_recycler.db?mode=rwc".to_string())
 }
 
 impl Config {
     pub fn from_env() -> anyhow::Result<Self> {
         dotenvy::dotenv().ok();
 
-        Ok(Self {
-            database_url: env::var("DATABASE_URL")
-                .unwrap_or_else(|_| "sqlite:utxo_recycler.db?mode=rwc".to_string()),
-            nwc_uri: env::var("NWC_URI")
-                .map_err(|_| anyhow::anyhow!("NWC_URI environment variable required"))?,
+        let network = parse_network(&env::var("NETWORK").unwrap_or_else(|_| "bitcoin".to_string()))?;
+
+        let config = Self {
+            database_url: database_url_from_env(),
+            lightning_backend: LightningBackendKind::from_str(
+                &env::var("LIGHTNING_BACKEND").unwrap_or_else(|_| "nwc".to_string()),
+            )?,
+            nwc_uri: env::var("NWC_URI").ok(),
+            lnd_rest_url: env::var("LND_REST_URL").ok(),
+            lnd_macaroon: env::var("LND_MACAROON").ok(),
+            lnd_macaroon_path: env::var("LND_MACAROON_PATH").ok(),
+            lnd_tls_cert_path: env::var("LND_TLS_CERT_PATH").ok(),
+            cln_rest_url: env::var("CLN_REST_URL").ok(),
+            cln_rune: env::var("CLN_RUNE").ok(),
+            cln_tls_cert_path: env::var("CLN_TLS_CERT_PATH").ok(),
+            lnbits_url: env::var("LNBITS_URL").ok(),
+            lnbits_api_key: env::var("LNBITS_API_KEY").ok(),
             wallet_descriptor: env::var("WALLET_DESCRIPTOR")
                 .map_err(|_| anyhow::anyhow!("WALLET_DESCRIPTOR environment variable required"))?,
-            electrum_url: env::var("ELECTRUM_URL")
-                .unwrap_or_else(|_| "ssl://electrum.blockstream.info:50002".to_string()),
+            network,
+            chain_backend: ChainBackend::from_str(
+                &env::var("CHAIN_BACKEND").unwrap_or_else(|_| "electrum".to_string()),
+            )?,
+            electrum_urls: env::var("ELECTRUM_URL")
+                .unwrap_or_else(|_| default_electrum_url(network).to_string())
+                .split(',')
+                .map(|url| url.trim().to_string())
+                .filter(|url| !url.is_empty())
+                .collect(),
             tor_proxy: env::var("TOR_PROXY").ok(),
+            esplora_url: env::var("ESPLORA_URL")
+                .unwrap_or_else(|_| default_esplora_url(network).to_string()),
+            bitcoind_rpc_url: env::var("BITCOIND_RPC_URL")
+                .unwrap_or_else(|_| default_bitcoind_rpc_url(network).to_string()),
+            bitcoind_rpc_user: env::var("BITCOIND_RPC_USER").ok(),
+            bitcoind_rpc_pass: env::var("BITCOIND_RPC_PASS").ok(),
+            bitcoind_rpc_cookie: env::var("BITCOIND_RPC_COOKIE").ok(),
+            bitcoind_start_height: env::var("BITCOIND_START_HEIGHT")
+                .ok()
+                .and_then(|height| height.parse().ok())
+                .unwrap_or(match network {
+                    Network::Bitcoin => DEFAULT_BITCOIND_START_HEIGHT,
+                    _ => 0,
+                }),
             payout_multiplier: env::var("PAYOUT_MULTIPLIER")
                 .unwrap_or_else(|_| "1.01".to_string())
                 .parse()
@@ -79,6 +244,116 @@ impl Config {
                 .unwrap_or_else(|_| "60".to_string())
                 .parse()
                 .unwrap_or(60),
-        })
+            sweep_address: env::var("SWEEP_ADDRESS").ok(),
+            sweep_fee_rate: env::var("SWEEP_FEE_RATE")
+                .unwrap_or_else(|_| DEFAULT_SWEEP_FEE_RATE.to_string())
+                .parse()
+                .unwrap_or(DEFAULT_SWEEP_FEE_RATE),
+            recycle_expiry_secs: env::var("RECYCLE_EXPIRY_SECS")
+                .unwrap_or_else(|_| DEFAULT_RECYCLE_EXPIRY_SECS.to_string())
+                .parse()
+                .unwrap_or(DEFAULT_RECYCLE_EXPIRY_SECS),
+            address_reuse_cooloff_secs: env::var("ADDRESS_REUSE_COOLOFF_SECS")
+                .unwrap_or_else(|_| DEFAULT_ADDRESS_REUSE_COOLOFF_SECS.to_string())
+                .parse()
+                .unwrap_or(DEFAULT_ADDRESS_REUSE_COOLOFF_SECS),
+            lnurl_allow_http: env::var("LNURL_ALLOW_HTTP")
+                .map(|value| value == "true" || value == "1")
+                .unwrap_or(false),
+        };
+
+        if config.lnurl_allow_http && network == Network::Bitcoin {
+            return Err(anyhow::anyhow!("LNURL_ALLOW_HTTP is only allowed on test networks"));
+        }
+
+        // Only Electrum connections go through the proxy. Starting with another backend
+        // would silently connect in the clear, revealing the operator's IP.
+        if config.tor_proxy.is_some() && config.chain_backend != ChainBackend::Electrum {
+            return Err(anyhow::anyhow!(
+                "TOR_PROXY is only supported with CHAIN_BACKEND=electrum, not {}",
+                config.chain_backend.as_str()
+            ));
+        }
+
+        // Refuse a sweep destination on the wrong network rather than failing at sweep time
+        if let Some(ref sweep_address) = config.sweep_address {
+            Address::from_str(sweep_address)
+                .map_err(|e| anyhow::anyhow!("Invalid SWEEP_ADDRESS: {}", e))?
+                .require_network(network)
+                .map_err(|_| anyhow::anyhow!("SWEEP_ADDRESS is not a {} address", network))?;
+        }
+
+        Ok(config)
+    }
+
+    /// Banner text shown on every page when not running on mainnet.
+    pub fn network_banner(&self) -> Option<String> {
+        match self.network {
+            Network::Bitcoin => None,
+            network => Some(network.to_string().to_uppercase()),
+        }
+    }
+
+    /// Block explorer URL prefix for transaction links.
+    pub fn explorer_tx_url(&self) -> &'static str {
+        match self.network {
+            Network::Testnet => "https://mempool.space/testnet/tx/",
+            Network::Testnet4 => "https://mempool.space/testnet4/tx/",
+            Network::Signet => "https://mempool.space/signet/tx/",
+            _ => "https://mempool.space/tx/",
+        }
+    }
+
+    /// Credentials for the bitcoind RPC backend.
+    pub fn bitcoind_rpc_auth(&self) -> Auth {
+        if let Some(ref cookie) = self.bitcoind_rpc_cookie {
+            return Auth::CookieFile(PathBuf::from(cookie));
+        }
+        match (&self.bitcoind_rpc_user, &self.bitcoind_rpc_pass) {
+            (Some(user), Some(pass)) => Auth::UserPass(user.clone(), pass.clone()),
+            _ => Auth::None,
+        }
+    }
+}
+
+fn parse_network(s: &str) -> anyhow::Result<Network> {
+    match s {
+        "mainnet" => Ok(Network::Bitcoin),
+        other => Network::from_str(other).map_err(|_| {
+            anyhow::anyhow!(
+                "Unknown NETWORK '{}' (expected bitcoin, testnet, testnet4, signet or regtest)",
+                other
+            )
+        }),
+    }
+}
+
+fn default_electrum_url(network: Network) -> &'static str {
+    match network {
+        Network::Testnet => "ssl://electrum.blockstream.info:60002",
+        Network::Testnet4 => "ssl://mempool.space:40002",
+        Network::Signet => "ssl://mempool.space:60602",
+        Network::Regtest => "tcp://127.0.0.1:60401",
+        _ => "ssl://electrum.blockstream.info:50002",
+    }
+}
+
+fn default_esplora_url(network: Network) -> &'static str {
+    match network {
+        Network::Testnet => "https://blockstream.info/testnet/api",
+        Network::Testnet4 => "https://mempool.space/testnet4/api",
+        Network::Signet => "https://mempool.space/signet/api",
+        Network::Regtest => "http://127.0.0.1:3002",
+        _ => "https://blockstream.info/api",
+    }
+}
+
+fn default_bitcoind_rpc_url(network: Network) -> &'static str {
+    match network {
+        Network::Testnet => "http://127.0.0.1:18332",
+        Network::Testnet4 => "http://127.0.0.1:48332",
+        Network::Signet => "http://127.0.0.1:38332",
+        Network::Regtest => "http://127.0.0.1:18443",
+        _ => "http://127.0.0.1:8332",
     }
 }
@@ -0,0 +1,503 @@
+use anyhow::{anyhow, Context, Result};
+use bdk_wallet::bitcoin::hashes::{sha256, Hash};
+use chrono::Utc;
+use super::DatabaseBackend;
+use sqlx::{AnyConnection, AnyPool, Connection};
+use std::collections::{HashMap, HashSet};
+
+/// Every schema migration, in the order they are applied, with one file per backend
+/// under `migrations/`. Once a migration has shipped its files must not change, since
+/// applied migrations are verified by checksum.
+const SQLITE_MIGRATIONS: &[(i64, &str, &str)] = &[
+    (1, "initial", include_str!("../../migrations/sqlite/001_initial.sql")),
+    (2, "blockheight_cutoff", include_str!("../../migrations/sqlite/002_blockheight_cutoff.sql")),
+    (3, "payment_attempts", include_str!("../../migrations/sqlite/003_payment_attempts.sql")),
+    (4, "wallet_persistence", include_str!("../../migrations/sqlite/004_wallet_persistence.sql")),
+    (5, "deposits", include_str!("../../migrations/sqlite/005_deposits.sql")),
+    (6, "sweeps", include_str!("../../migrations/sqlite/006_sweeps.sql")),
+    (7, "deposit_inputs", include_str!("../../migrations/sqlite/007_deposit_inputs.sql")),
+    (8, "tx_cache", include_str!("../../migrations/sqlite/008_tx_cache.sql")),
+    (9, "recycle_events", include_str!("../../migrations/sqlite/009_recycle_events.sql")),
+    (10, "address_reuse", include_str!("../../migrations/sqlite/010_address_reuse.sql")),
+    (11, "recycle_cancellation", include_str!("../../migrations/sqlite/011_recycle_cancellation.sql")),
+    (12, "payments", include_str!("../../migrations/sqlite/012_payments.sql")),
+    (13, "failure_reason", include_str!("../../migrations/sqlite/013_failure_reason.sql")),
+];
+
+const POSTGRES_MIGRATIONS: &[(i64, &str, &str)] = &[
+    (1, "initial", include_str!("../../migrations/postgres/001_initial.sql")),
+    (2, "blockheight_cutoff", include_str!("../../migrations/postgres/002_blockheight_cutoff.sql")),
+    (3, "payment_attempts", include_str!("../../migrations/postgres/003_payment_attempts.sql")),
+    (4, "wallet_persistence", include_str!("../../migrations/postgres/004_wallet_persistence.sql")),
+    (5, "deposits", include_str!("../../migrations/postgres/005_deposits.sql")),
+    (6, "sweeps", include_str!("../../migrations/postgres/006_sweeps.sql")),
+    (7, "deposit_inputs", include_str!("../../migrations/postgres/007_deposit_inputs.sql")),
+    (8, "tx_cache", include_str!("../../migrations/postgres/008_tx_cache.sql")),
+    (9, "recycle_events", include_str!("../../migrations/postgres/009_recycle_events.sql")),
+    (10, "address_reuse", include_str!("../../migrations/postgres/010_address_reuse.sql")),
+    (11, "recycle_cancellation", include_str!("../../migrations/postgres/011_recycle_cancellation.sql")),
+    (12, "payments", include_str!("../../migrations/postgres/012_payments.sql")),
+    (13, "failure_reason", include_str!("../../migrations/postgres/013_failure_reason.sql")),
+];
+
+/// What each migration that predates `schema_migrations` created, as `table` or
+/// `table.column`. A legacy database is checked against these to tell which of them
+/// it already has.
+const LEGACY_OBJECTS: &[(i64, &[&str])] = &[
+    (1, &["recycles", "wallet_state"]),
+    (
+        2,
+        &[
+            "recycles.deposit_block_height",
+            "recycles.is_eligible",
+            "recycles.donation_reason",
+            "recycles.max_input_sats",
+        ],
+    ),
+    (3, &["recycles.payment_attempts"]),
+    (4, &["bdk_changesets"]),
+    (5, &["deposits"]),
+    (6, &["sweeps", "deposits.sweep_id"]),
+    (7, &["deposit_inputs", "deposits.eligible_sats"]),
+    (8, &["tx_cache", "tx_heights"]),
+    (9, &["recycle_events"]),
+];
+
+/// Key of the Postgres advisory lock held while migrating ("utxomigr")
+const MIGRATION_LOCK: i64 = 0x7574_786f_6d69_6772;
+
+/// Apply every migration not yet recorded in `schema_migrations`, each in its own
+/// transaction. Fails on the first migration that doesn't apply cleanly, or if an
+/// already applied migration's file has changed since.
+///
+/// On Postgres the run holds an advisory lock, so replicas starting together migrate
+/// one after the other and the later ones find nothing left to do.
+pub async fn run_migrations(db: &AnyPool, backend: DatabaseBackend) -> Result<()> {
+    let mut conn = db.acquire().await?;
+    if backend == DatabaseBackend::Sqlite {
+        return migrate(&mut conn, backend).await;
+    }
+
+    sqlx::query("SELECT pg_advisory_lock($1)")
+        .bind(MIGRATION_LOCK)
+        .execute(&mut *conn)
+        .await?;
+    let result = migrate(&mut conn, backend).await;
+
+    let unlocked: Result<(bool,), _> = sqlx::query_as("SELECT pg_advisory_unlock($1)")
+        .bind(MIGRATION_LOCK)
+        .fetch_one(&mut *conn)
+        .await;
+    if unlocked.is_err() {
+        // The lock lives as long as the session, so don't hand it back to the pool
+        let _ = conn.detach().close().await;
+    }
+
+    result
+}
+
+async fn migrate(conn: &mut AnyConnection, backend: DatabaseBackend) -> Result<()> {
+    let migrations = match backend {
+        DatabaseBackend::Sqlite => SQLITE_MIGRATIONS,
+        DatabaseBackend::Postgres => POSTGRES_MIGRATIONS,
+    };
+
+    // SQLite databases created before migrations were tracked had every migration
+    // re-run on each startup. They are adopted by recording the migrations whose
+    // tables and columns are all there, and applying the rest.
+    let legacy = backend == DatabaseBackend::Sqlite
+        && !sqlite_table_exists(conn, "schema_migrations").await?
+        && sqlite_table_exists(conn, "recycles").await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        )
+        "#,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let applied: HashMap<i64, String> = sqlx::query_as("SELECT version, checksum FROM schema_migrations")
+        .fetch_all(&mut *conn)
+        .await?
+        .into_iter()
+        .collect();
+
+    if let Some(latest) = applied.keys().max() {
+        if let Some(&(known, _, _)) = migrations.last() {
+            if *latest > known {
+                return Err(anyhow!(
+                    "Database is at schema version {} but this build only knows up to {}",
+                    latest,
+                    known
+                ));
+            }
+        }
+    }
+
+    for &(version, name, sql) in migrations {
+        let checksum = sha256::Hash::hash(sql.as_bytes()).to_string();
+
+        if let Some(applied_checksum) = applied.get(&version) {
+            if *applied_checksum != checksum {
+                return Err(anyhow!(
+                    "Migration {:03}_{} has changed since it was applied (checksum {}, expected {})",
+                    version,
+                    name,
+                    checksum,
+                    applied_checksum
+                ));
+            }
+            continue;
+        }
+
+        if legacy && legacy_migration_present(conn, version, name).await? {
+            record(&mut *conn, version, name, &checksum).await?;
+            tracing::info!("Adopted migration {:03}_{} already present in the database", version, name);
+            continue;
+        }
+
+        apply(conn, backend, version, name, sql, &checksum)
+            .await
+            .with_context(|| format!("Migration {:03}_{} failed", version, name))?;
+
+        tracing::info!("Applied migration {:03}_{}", version, name);
+    }
+
+    Ok(())
+}
+
+/// Whether a legacy database already has everything a pre-tracking migration
+/// created. Fails if it has only some of it, since that is schema drift the
+/// migration can't be re-run over.
+async fn legacy_migration_present(conn: &mut AnyConnection, version: i64, name: &str) -> Result<bool> {
+    let Some((_, objects)) = LEGACY_OBJECTS.iter().find(|(legacy, _)| *legacy == version) else {
+        return Ok(false);
+    };
+
+    let mut missing = Vec::new();
+    for object in objects.iter() {
+        let present = match object.split_once('.') {
+            Some((table, column)) => sqlite_column_exists(conn, table, column).await?,
+            None => sqlite_table_exists(conn, object).await?,
+        };
+        if !present {
+            missing.push(*object);
+        }
+    }
+
+    if missing.is_empty() {
+        Ok(true)
+    } else if missing.len() == objects.len() {
+        Ok(false)
+    } else {
+        Err(anyhow!(
+            "Migration {:03}_{} is only partly applied to this database (missing {}), fix the schema by hand",
+            version,
+            name,
+            missing.join(", ")
+        ))
+    }
+}
+
+async fn apply(
+    conn: &mut AnyConnection,
+    backend: DatabaseBackend,
+    version: i64,
+    name: &str,
+    sql: &str,
+    checksum: &str,
+) -> Result<()> {
+    // Rebuilding a referenced table (SQLite can't alter most constraints in place)
+    // would trip the foreign keys, so they are checked once before committing instead.
+    // The pragma has no effect inside a transaction, so it is set on the connection.
+    let sqlite = backend == DatabaseBackend::Sqlite;
+    if sqlite {
+        sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await?;
+    }
+    let result = apply_sql(conn, version, name, sql, checksum, sqlite).await;
+    if sqlite {
+        sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await?;
+    }
+
+    result
+}
+
+async fn apply_sql(
+    conn: &mut AnyConnection,
+    version: i64,
+    name: &str,
+    sql: &str,
+    checksum: &str,
+    check_foreign_keys: bool,
+) -> Result<()> {
+    let mut tx = conn.begin().await?;
+
+    // Violations the database already had aren't this migration's doing, so only new
+    // ones fail it
+    let before = if check_foreign_keys {
+        foreign_key_violations(&mut tx).await?
+    } else {
+        HashSet::new()
+    };
+
+    // The file is sent as is, so statements may contain semicolons (in string literals,
+    // triggers or function bodies)
+    sqlx::raw_sql(sql).execute(&mut *tx).await?;
+
+    if check_foreign_keys {
+        let after = foreign_key_violations(&mut tx).await?;
+        let mut tables: Vec<&str> = after.difference(&before).map(|(table, _)| table.as_str()).collect();
+        if !tables.is_empty() {
+            tables.sort_unstable();
+            tables.dedup();
+            return Err(anyhow!(
+                "Migration left rows with dangling foreign keys in {}",
+                tables.join(", ")
+            ));
+        }
+    }
+
+    record(&mut *tx, version, name, checksum).await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn record<'e>(
+    executor: impl sqlx::Executor<'e, Database = sqlx::Any>,
+    version: i64,
+    name: &str,
+    checksum: &str,
+) -> Result<()> {
+    sqlx::query("INSERT INTO schema_migrations (version, name, checksum, applied_at) VALUES ($1, $2, $3, $4)")
+        .bind(version)
+        .bind(name)
+        .bind(checksum)
+        .bind(Utc::now().to_rfc3339())
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+/// Rows with a dangling foreign key, as (table, rowid)
+async fn foreign_key_violations(conn: &mut AnyConnection) -> Result<HashSet<(String, i64)>> {
+    let rows: Vec<(String, Option<i64>)> = sqlx::query_as("SELECT \"table\", rowid FROM pragma_foreign_key_check")
+        .fetch_all(&mut *conn)
+        .await?;
+
+    Ok(rows.into_iter().map(|(table, rowid)| (table, rowid.unwrap_or(-1))).collect())
+}
+
+async fn sqlite_table_exists(conn: &mut AnyConnection, name: &str) -> Result<bool> {
+    let row: Option<(String,)> = sqlx::query_as("SELECT name FROM sqlite_master WHERE type = 'table' AND name = $1")
+        .bind(name)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+async fn sqlite_column_exists(conn: &mut AnyConnection, table: &str, column: &str) -> Result<bool> {
+    let row: Option<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info($1) WHERE name = $2")
+        .bind(table)
+        .bind(column)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    Ok(row.is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::connect;
+    use crate::db::testing::empty_databases;
+
+    async fn sqlite_db(dir: &tempfile::TempDir) -> AnyPool {
+        let url = format!("sqlite:{}?mode=rwc", dir.path().join("test.db").display());
+        connect(&url, 1).await.unwrap().0
+    }
+
+    async fn versions(db: &AnyPool) -> Vec<i64> {
+        sqlx::query_as::<_, (i64,)>("SELECT version FROM schema_migrations ORDER BY version")
+            .fetch_all(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(version,)| version)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn applies_every_migration_once() {
+        for test in empty_databases(1).await {
+            run_migrations(&test.db, test.backend).await.unwrap();
+            let expected: Vec<i64> = SQLITE_MIGRATIONS.iter().map(|(version, _, _)| *version).collect();
+            assert_eq!(versions(&test.db).await, expected, "{}", test.backend.as_str());
+
+            // A second run finds nothing to do
+            run_migrations(&test.db, test.backend).await.unwrap();
+            assert_eq!(versions(&test.db).await, expected, "{}", test.backend.as_str());
+            test.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn replicas_starting_together_migrate_once() {
+        for test in empty_databases(4).await {
+            // Only Postgres is shared by several replicas
+            if test.backend == DatabaseBackend::Postgres {
+                let run = || run_migrations(&test.db, DatabaseBackend::Postgres);
+                let (first, second, third) = tokio::join!(run(), run(), run());
+                first.unwrap();
+                second.unwrap();
+                third.unwrap();
+
+                let expected: Vec<i64> = POSTGRES_MIGRATIONS.iter().map(|(version, _, _)| *version).collect();
+                assert_eq!(versions(&test.db).await, expected);
+            }
+            test.close().await;
+        }
+    }
+
+    #[test]
+    fn both_backends_have_the_same_versions() {
+        let sqlite: Vec<(i64, &str)> = SQLITE_MIGRATIONS.iter().map(|(v, n, _)| (*v, *n)).collect();
+        let postgres: Vec<(i64, &str)> = POSTGRES_MIGRATIONS.iter().map(|(v, n, _)| (*v, *n)).collect();
+        assert_eq!(sqlite, postgres);
+        assert!(sqlite.windows(2).all(|pair| pair[1].0 == pair[0].0 + 1));
+    }
+
+    #[tokio::test]
+    async fn changed_migration_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sqlite_db(&dir).await;
+        run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap();
+
+        sqlx::query("UPDATE schema_migrations SET checksum = 'edited' WHERE version = 3")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let error = run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap_err().to_string();
+        assert!(error.contains("003_payment_attempts has changed"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn newer_schema_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sqlite_db(&dir).await;
+        run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap();
+
+        record(&db, 999, "from_the_future", "checksum").await.unwrap();
+
+        let error = run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap_err().to_string();
+        assert!(error.contains("schema version 999"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn statements_may_contain_semicolons() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sqlite_db(&dir).await;
+        run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap();
+
+        let sql = "CREATE TABLE notes (body TEXT NOT NULL);\nINSERT INTO notes (body) VALUES ('one; two');";
+        let mut conn = db.acquire().await.unwrap();
+        apply(&mut conn, DatabaseBackend::Sqlite, 100, "notes", sql, "checksum").await.unwrap();
+        drop(conn);
+
+        let (body,): (String,) = sqlx::query_as("SELECT body FROM notes").fetch_one(&db).await.unwrap();
+        assert_eq!(body, "one; two");
+    }
+
+    #[tokio::test]
+    async fn failed_migration_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sqlite_db(&dir).await;
+        run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap();
+
+        let sql = "CREATE TABLE notes (body TEXT NOT NULL);\nINSERT INTO missing_table VALUES (1);";
+        let mut conn = db.acquire().await.unwrap();
+        assert!(apply(&mut conn, DatabaseBackend::Sqlite, 100, "notes", sql, "checksum").await.is_err());
+
+        assert!(!sqlite_table_exists(&mut conn, "notes").await.unwrap());
+        drop(conn);
+        assert!(!versions(&db).await.contains(&100));
+    }
+
+    #[tokio::test]
+    async fn only_new_foreign_key_violations_fail_a_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sqlite_db(&dir).await;
+        run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap();
+
+        // A violation the database already had
+        let mut conn = db.acquire().await.unwrap();
+        sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await.unwrap();
+        sqlx::query(
+            "INSERT INTO recycle_events (recycle_id, to_status, actor, created_at) VALUES ('gone', 'paid', 'admin', 'now')",
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+
+        let sql = "CREATE TABLE notes (body TEXT NOT NULL)";
+        apply(&mut conn, DatabaseBackend::Sqlite, 100, "notes", sql, "checksum").await.unwrap();
+
+        let sql = "CREATE TABLE tags (recycle_id TEXT REFERENCES recycles(id));\nINSERT INTO tags VALUES ('gone');";
+        let error = apply(&mut conn, DatabaseBackend::Sqlite, 101, "tags", sql, "checksum").await.unwrap_err().to_string();
+        assert!(error.contains("dangling foreign keys in tags"), "{}", error);
+    }
+
+    /// Leave only the tables a database from before migration tracking had
+    async fn make_legacy(db: &AnyPool, up_to: i64) {
+        let mut conn = db.acquire().await.unwrap();
+        for &(version, _, sql) in SQLITE_MIGRATIONS.iter().filter(|(version, _, _)| *version <= up_to) {
+            apply(&mut conn, DatabaseBackend::Sqlite, version, "legacy", sql, "checksum").await.unwrap();
+        }
+        sqlx::query("DROP TABLE schema_migrations").execute(&mut *conn).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn legacy_database_is_adopted() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sqlite_db(&dir).await;
+        sqlx::query("CREATE TABLE schema_migrations (version BIGINT PRIMARY KEY, name TEXT NOT NULL, checksum TEXT NOT NULL, applied_at TEXT NOT NULL)")
+            .execute(&db)
+            .await
+            .unwrap();
+        make_legacy(&db, 5).await;
+
+        run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap();
+
+        let expected: Vec<i64> = SQLITE_MIGRATIONS.iter().map(|(version, _, _)| *version).collect();
+        assert_eq!(versions(&db).await, expected);
+        let mut conn = db.acquire().await.unwrap();
+        assert!(sqlite_table_exists(&mut conn, "payments").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn partly_applied_legacy_migration_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = sqlite_db(&dir).await;
+        sqlx::query("CREATE TABLE schema_migrations (version BIGINT PRIMARY KEY, name TEXT NOT NULL, checksum TEXT NOT NULL, applied_at TEXT NOT NULL)")
+            .execute(&db)
+            .await
+            .unwrap();
+        make_legacy(&db, 1).await;
+        sqlx::query("ALTER TABLE recycles ADD COLUMN deposit_block_height INTEGER")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let error = run_migrations(&db, DatabaseBackend::Sqlite).await.unwrap_err().to_string();
+        assert!(error.contains("002_blockheight_cutoff is only partly applied"), "{}", error);
+    }
+}
@@ -1,3 +1,11 @@
+pub mod backend;
+pub mod migrations;
 pub mod models;
+pub mod store;
+#[cfg(test)]
+pub mod testing;
 
+pub use backend::*;
+pub use migrations::*;
 pub use models::*;
+pub use store::*;
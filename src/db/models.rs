@@ -1,6 +1,12 @@
+use bdk_wallet::bitcoin::hashes::{sha256, Hash};
+use bdk_wallet::bitcoin::hex::FromHex;
 use chrono::{DateTime, Utc};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, SqlitePool};
+use sqlx::{Any, AnyConnection, AnyPool, FromRow, Transaction};
+use std::marker::PhantomData;
+
+use super::{Dialect, RecycleStore};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -8,11 +14,25 @@ pub enum RecycleStatus {
     AwaitingDeposit,
     Confirming,
     Confirmed,
+    /// A payment for the confirmed deposits has been recorded and sent, and its outcome
+    /// isn't known yet. No new payment is made until it is.
+    Paying,
     Paid,
     Failed,
     /// Deposit received but UTXO was created after the cutoff block.
     /// No payout will be made - kept as donation.
     Donation,
+    /// A deposit's block was reorged away and its transaction is no longer in the
+    /// mempool. No payout is made for it unless it reappears.
+    Reorged,
+    /// Every unpaid deposit was replaced or double-spent. No payout will be made.
+    DoubleSpent,
+    /// No deposit arrived within `RECYCLE_EXPIRY_SECS`. A late deposit is still
+    /// processed, until the address is handed to a new recycle.
+    Expired,
+    /// Cancelled by its creator before any deposit arrived. Like an expired recycle,
+    /// a late deposit is still processed until the address is handed on.
+    Cancelled,
 }
 
 impl RecycleStatus {
@@ -21,32 +41,87 @@ impl RecycleStatus {
             Self::AwaitingDeposit => "awaiting_deposit",
             Self::Confirming => "confirming",
             Self::Confirmed => "confirmed",
+            Self::Paying => "paying",
             Self::Paid => "paid",
             Self::Failed => "failed",
             Self::Donation => "donation",
+            Self::Reorged => "reorged",
+            Self::DoubleSpent => "double_spent",
+            Self::Expired => "expired",
+            Self::Cancelled => "cancelled",
         }
     }
 
-    pub fn from_str(s: &str) -> Self {
+    pub fn from_str(s: &str) -> anyhow::Result<Self> {
         match s {
-            "awaiting_deposit" => Self::AwaitingDeposit,
-            "confirming" => Self::Confirming,
-            "confirmed" => Self::Confirmed,
-            "paid" => Self::Paid,
-            "failed" => Self::Failed,
-            "donation" => Self::Donation,
-            _ => Self::Failed,
+            "awaiting_deposit" => Ok(Self::AwaitingDeposit),
+            "confirming" => Ok(Self::Confirming),
+            "confirmed" => Ok(Self::Confirmed),
+            "paying" => Ok(Self::Paying),
+            "paid" => Ok(Self::Paid),
+            "failed" => Ok(Self::Failed),
+            "donation" => Ok(Self::Donation),
+            "reorged" => Ok(Self::Reorged),
+            "double_spent" => Ok(Self::DoubleSpent),
+            "expired" => Ok(Self::Expired),
+            "cancelled" => Ok(Self::Cancelled),
+            other => Err(anyhow::anyhow!("Unknown recycle status: {}", other)),
         }
     }
 
+    /// Whether a recycle may move from this status to `next`. Most statuses follow the
+    /// recycle's deposits (see `RecycleStore::refresh_from_deposits`). A payout is
+    /// only ever started from `confirmed`, nothing returns to `awaiting_deposit`, only an
+    /// unfunded recycle expires or is cancelled, and a failed recycle only moves when an
+    /// admin retries the payout. Donated, double-spent, expired and cancelled recycles
+    /// are settled: they only reopen to `confirming`, for a deposit that (re)appears at
+    /// their address. Anything not listed is refused.
+    pub fn can_transition_to(self, next: Self) -> bool {
+        use RecycleStatus::*;
+
+        matches!(
+            (self, next),
+            // The first deposit arrives, or none does in time
+            (AwaitingDeposit, Confirming | Confirmed | Donation | Expired | Cancelled)
+                // Deposits confirm, turn out ineligible, drop out of the chain or are
+                // replaced. Paid when a later deposit to a paid address drops out.
+                | (Confirming, Confirmed | Donation | Reorged | DoubleSpent | Paid)
+                // Ready for payout: paying or failed, or held back when a deposit changes
+                | (Confirmed, Paying | Failed | Confirming | Donation | Reorged | DoubleSpent | Paid)
+                // Settled from the payment's outcome, then following any deposits that
+                // changed while it was in flight
+                | (Paying, Paid | Confirmed | Confirming | Reorged)
+                // An admin retried the payout
+                | (Failed, Confirmed)
+                // A later deposit to the same address
+                | (Paid, Confirming | Confirmed)
+                // The reorged deposit reappears, or the others settle without it
+                | (Reorged, Confirming | Confirmed | Paid | Donation | DoubleSpent)
+                // A deposit (re)appears at a settled recycle's address
+                | (Donation | DoubleSpent | Expired | Cancelled, Confirming)
+        )
+    }
+
+    /// Whether a signed deposit submitted for a recycle in this status may be broadcast:
+    /// only while it awaits its deposit or its deposits are still on their way to a
+    /// payout. A settled, failed or paying recycle takes no new deposits through the service.
+    pub fn accepts_deposits(self) -> bool {
+        matches!(self, Self::AwaitingDeposit | Self::Confirming | Self::Confirmed)
+    }
+
     pub fn display_name(&self) -> &'static str {
         match self {
             Self::AwaitingDeposit => "Awaiting Deposit",
             Self::Confirming => "Confirming",
             Self::Confirmed => "Confirmed",
+            Self::Paying => "Paying",
             Self::Paid => "Paid",
             Self::Failed => "Failed",
             Self::Donation => "Donation Received",
+            Self::Reorged => "Reorged",
+            Self::DoubleSpent => "Double-Spent",
+            Self::Expired => "Expired",
+            Self::Cancelled => "Cancelled",
         }
     }
 }
@@ -72,6 +147,9 @@ pub struct RecycleRow {
     pub created_at: String,
     pub updated_at: String,
     pub paid_at: Option<String>,
+    pub address_released_at: Option<String>,
+    pub cancel_secret_hash: Option<String>,
+    pub failure_reason: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -100,16 +178,27 @@ pub struct Recycle {
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub paid_at: Option<DateTime<Utc>>,
+    /// When the expired recycle's deposit address was handed to a newer recycle.
+    /// Deposits to it no longer count for this one.
+    pub address_released_at: Option<DateTime<Utc>>,
+    /// SHA-256 of the creator's cancel secret, None for recycles created before
+    /// cancellation existed
+    #[serde(skip)]
+    pub cancel_secret_hash: Option<String>,
+    /// Why the payout was given up, while the recycle is failed
+    pub failure_reason: Option<String>,
 }
 
-impl From<RecycleRow> for Recycle {
-    fn from(row: RecycleRow) -> Self {
-        Self {
+impl TryFrom<RecycleRow> for Recycle {
+    type Error = anyhow::Error;
+
+    fn try_from(row: RecycleRow) -> anyhow::Result<Self> {
+        Ok(Self {
             id: row.id,
             lightning_address: row.lightning_address,
             deposit_address: row.deposit_address,
             address_index: row.address_index as u32,
-            status: RecycleStatus::from_str(&row.status),
+            status: RecycleStatus::from_str(&row.status)?,
             deposit_txid: row.deposit_txid,
             deposit_amount_sats: row.deposit_amount_sats.map(|v| v as u64),
             deposit_confirmations: row.deposit_confirmations.unwrap_or(0) as u32,
@@ -132,280 +221,2118 @@ impl From<RecycleRow> for Recycle {
                     .map(|dt| dt.with_timezone(&Utc))
                     .ok()
             }),
+            address_released_at: row.address_released_at.and_then(|s| {
+                DateTime::parse_from_rfc3339(&s)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .ok()
+            }),
+            cancel_secret_hash: row.cancel_secret_hash,
+            failure_reason: row.failure_reason,
+        })
+    }
+}
+
+/// Convert rows, logging and skipping any that can't be read (such as an unknown
+/// status), so one bad row doesn't stall every worker scanning the table. `kind` and
+/// `key` name the skipped row in the log.
+fn readable<R, T>(rows: Vec<R>, kind: &str, key: impl Fn(&R) -> String) -> Vec<T>
+where
+    T: TryFrom<R, Error = anyhow::Error>,
+{
+    rows.into_iter()
+        .filter_map(|row| {
+            let id = key(&row);
+            match T::try_from(row) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    tracing::error!("Skipping {} {} that can't be read: {}", kind, id, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+impl Recycle {
+    /// Whether `secret` is the cancel secret handed to the recycle's creator.
+    pub fn cancel_secret_matches(&self, secret: &str) -> bool {
+        self.cancel_secret_hash.as_deref() == Some(hash_cancel_secret(secret).as_str())
+    }
+}
+
+/// Only the hash of a cancel secret is stored, so a leaked database can't cancel recycles.
+pub fn hash_cancel_secret(secret: &str) -> String {
+    sha256::Hash::hash(secret.as_bytes()).to_string()
+}
+
+/// Who moved a recycle to a new status, as recorded in its event log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Actor {
+    /// The public API, e.g. creating a recycle
+    User,
+    DepositMonitor,
+    PaymentProcessor,
+    Admin,
+}
+
+impl Actor {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::User => "user",
+            Self::DepositMonitor => "deposit_monitor",
+            Self::PaymentProcessor => "payment_processor",
+            Self::Admin => "admin",
         }
     }
 }
 
-pub struct RecycleRepository;
+#[derive(Debug, Clone, FromRow)]
+pub struct RecycleEventRow {
+    pub id: i64,
+    pub recycle_id: String,
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub actor: String,
+    pub reason: Option<String>,
+    pub created_at: String,
+}
 
-impl RecycleRepository {
-    pub async fn create(
-        pool: &SqlitePool,
-        id: &str,
-        lightning_address: &str,
-        deposit_address: &str,
-        address_index: u32,
-    ) -> anyhow::Result<Recycle> {
+/// One status transition of a recycle.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecycleEvent {
+    pub id: i64,
+    pub recycle_id: String,
+    /// None for the event recording the recycle's creation
+    pub from_status: Option<String>,
+    pub to_status: String,
+    pub actor: String,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<RecycleEventRow> for RecycleEvent {
+    fn from(row: RecycleEventRow) -> Self {
+        Self {
+            id: row.id,
+            recycle_id: row.recycle_id,
+            from_status: row.from_status,
+            to_status: row.to_status,
+            actor: row.actor,
+            reason: row.reason,
+            created_at: DateTime::parse_from_rfc3339(&row.created_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+        }
+    }
+}
+
+/// Fields of a recycle being created.
+struct NewRecycle<'a> {
+    id: &'a str,
+    lightning_address: &'a str,
+    deposit_address: &'a str,
+    address_index: u32,
+    cancel_secret_hash: &'a str,
+}
+
+/// The recycle store's SQL, shared by the SQLite and Postgres implementations. `D`
+/// supplies what they do differently (see `Dialect`).
+pub struct RecycleRepository<D> {
+    db: AnyPool,
+    dialect: PhantomData<D>,
+}
+
+impl<D: Dialect> RecycleRepository<D> {
+    pub fn new(db: AnyPool) -> Self {
+        Self {
+            db,
+            dialect: PhantomData,
+        }
+    }
+
+    /// Start a transaction that reads a recycle's status and then writes.
+    async fn begin(&self) -> anyhow::Result<Transaction<'static, Any>> {
+        Ok(self.db.begin_with(D::BEGIN).await?)
+    }
+
+    async fn insert(conn: &mut AnyConnection, new: &NewRecycle<'_>, reason: &str) -> anyhow::Result<()> {
         let now = Utc::now().to_rfc3339();
-        let status = RecycleStatus::AwaitingDeposit.as_str();
 
         sqlx::query(
             r#"
-            INSERT INTO recycles (id, lightning_address, deposit_address, address_index, status, created_at, updated_at)
-            VALUES (?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO recycles (id, lightning_address, deposit_address, address_index, status, cancel_secret_hash, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
             "#,
         )
-        .bind(id)
-        .bind(lightning_address)
-        .bind(deposit_address)
-        .bind(address_index as i64)
-        .bind(status)
+        .bind(new.id)
+        .bind(new.lightning_address)
+        .bind(new.deposit_address)
+        .bind(new.address_index as i64)
+        .bind(RecycleStatus::AwaitingDeposit.as_str())
+        .bind(new.cancel_secret_hash)
         .bind(&now)
         .bind(&now)
-        .execute(pool)
+        .execute(&mut *conn)
         .await?;
 
-        Self::find_by_id(pool, id)
-            .await?
-            .ok_or_else(|| anyhow::anyhow!("Failed to create recycle"))
+        Self::record_event(conn, new.id, None, RecycleStatus::AwaitingDeposit, Actor::User, reason).await
     }
 
-    pub async fn find_by_id(pool: &SqlitePool, id: &str) -> anyhow::Result<Option<Recycle>> {
-        let row: Option<RecycleRow> = sqlx::query_as("SELECT * FROM recycles WHERE id = ?")
-            .bind(id)
-            .fetch_optional(pool)
-            .await?;
+    /// `refresh_from_deposits` within the caller's transaction, so a payment's outcome
+    /// and the status following from it are written together.
+    async fn refresh(conn: &mut AnyConnection, id: &str, actor: Actor) -> anyhow::Result<()> {
+        // Read (and on Postgres lock) the status first, so the deposits read next are
+        // no older than it
+        let current = Self::current_status(conn, id).await?;
+        let deposits = DepositRepository::find_by_recycle_in(conn, id).await?;
+        if deposits.is_empty() {
+            return Ok(());
+        }
 
-        Ok(row.map(Recycle::from))
-    }
+        let has_status = |status: DepositStatus| deposits.iter().any(|d| d.status == status);
+        let (status, reason) = if has_status(DepositStatus::Confirming) {
+            (RecycleStatus::Confirming, "deposit awaiting confirmations")
+        } else if has_status(DepositStatus::Confirmed) {
+            (RecycleStatus::Confirmed, "eligible deposit ready for payout")
+        } else if has_status(DepositStatus::Reorged) {
+            (RecycleStatus::Reorged, "deposit reorged out of the chain")
+        } else if has_status(DepositStatus::Paid) {
+            (RecycleStatus::Paid, "every eligible deposit paid")
+        } else if has_status(DepositStatus::Donation) {
+            (RecycleStatus::Donation, "no eligible deposits")
+        } else {
+            (RecycleStatus::DoubleSpent, "every deposit double-spent")
+        };
 
-    pub async fn find_by_deposit_address(
-        pool: &SqlitePool,
-        address: &str,
-    ) -> anyhow::Result<Option<Recycle>> {
-        let row: Option<RecycleRow> =
-            sqlx::query_as("SELECT * FROM recycles WHERE deposit_address = ?")
-                .bind(address)
-                .fetch_optional(pool)
-                .await?;
+        let all_donations = deposits.iter().all(|d| d.status == DepositStatus::Donation);
+        let donation_reason = if all_donations {
+            deposits.iter().find_map(|d| d.donation_reason.clone())
+        } else {
+            None
+        };
+
+        let now = Utc::now().to_rfc3339();
+
+        // The aggregates are only written while the status is still the one just read
+        let updated = sqlx::query(
+            r#"
+            UPDATE recycles
+            SET deposit_txid = $1, deposit_amount_sats = $2, deposit_confirmations = $3,
+                deposit_block_height = $4, max_input_sats = $5, is_eligible = $6,
+                donation_reason = $7, updated_at = $8
+            WHERE id = $9 AND status = $10
+            "#,
+        )
+        .bind(&deposits[0].txid)
+        .bind(deposits.iter().map(|d| d.amount_sats).sum::<u64>() as i64)
+        .bind(deposits.iter().map(|d| d.confirmations).min().unwrap_or(0) as i64)
+        .bind(deposits.iter().filter_map(|d| d.input_creation_height).max().map(|h| h as i64))
+        .bind(deposits.iter().filter_map(|d| d.max_input_sats).max().map(|v| v as i64))
+        .bind(if all_donations { 0 } else { 1 })
+        .bind(donation_reason)
+        .bind(&now)
+        .bind(id)
+        .bind(current.as_str())
+        .execute(&mut *conn)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Recycle {} changed status while refreshing", id));
+        }
+
+        let held = current == RecycleStatus::Failed
+            || (current == RecycleStatus::Paying && actor != Actor::PaymentProcessor);
+        if status != current && !held {
+            // A settled recycle reopens through confirming, even if the deposit that
+            // (re)appeared has since been checked or confirmed
+            let mut from = current;
+            if !current.can_transition_to(status) && current.can_transition_to(RecycleStatus::Confirming) {
+                Self::transition(conn, id, current, RecycleStatus::Confirming, actor, "deposit received").await?;
+                from = RecycleStatus::Confirming;
+            }
+            Self::transition(conn, id, from, status, actor, reason).await?;
+        }
 
-        Ok(row.map(Recycle::from))
+        Ok(())
     }
 
-    pub async fn find_by_status(
-        pool: &SqlitePool,
-        status: RecycleStatus,
-    ) -> anyhow::Result<Vec<Recycle>> {
-        let rows: Vec<RecycleRow> = sqlx::query_as("SELECT * FROM recycles WHERE status = ?")
-            .bind(status.as_str())
-            .fetch_all(pool)
+    async fn current_status(conn: &mut AnyConnection, id: &str) -> anyhow::Result<RecycleStatus> {
+        let row: Option<(String,)> = sqlx::query_as(D::SELECT_STATUS)
+            .bind(id)
+            .fetch_optional(&mut *conn)
             .await?;
 
-        Ok(rows.into_iter().map(Recycle::from).collect())
+        match row {
+            Some((status,)) => RecycleStatus::from_str(&status),
+            None => Err(anyhow::anyhow!("Recycle {} not found", id)),
+        }
     }
 
-    pub async fn find_pending_deposits(pool: &SqlitePool) -> anyhow::Result<Vec<Recycle>> {
-        let rows: Vec<RecycleRow> =
-            sqlx::query_as("SELECT * FROM recycles WHERE status IN ('awaiting_deposit', 'confirming')")
-                .fetch_all(pool)
-                .await?;
+    /// Move a recycle from `from` to `to` and log the transition. Fails if the move
+    /// isn't allowed, or if the recycle is no longer in `from` (changed concurrently).
+    async fn transition(
+        conn: &mut AnyConnection,
+        id: &str,
+        from: RecycleStatus,
+        to: RecycleStatus,
+        actor: Actor,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        if !from.can_transition_to(to) {
+            return Err(anyhow::anyhow!(
+                "Illegal status transition for recycle {}: {} -> {}",
+                id,
+                from.as_str(),
+                to.as_str()
+            ));
+        }
+
+        let updated = sqlx::query("UPDATE recycles SET status = $1, updated_at = $2 WHERE id = $3 AND status = $4")
+            .bind(to.as_str())
+            .bind(Utc::now().to_rfc3339())
+            .bind(id)
+            .bind(from.as_str())
+            .execute(&mut *conn)
+            .await?;
+        if updated.rows_affected() == 0 {
+            return Err(anyhow::anyhow!(
+                "Recycle {} is no longer {}, not moving it to {}",
+                id,
+                from.as_str(),
+                to.as_str()
+            ));
+        }
+
+        Self::record_event(conn, id, Some(from), to, actor, reason).await?;
 
-        Ok(rows.into_iter().map(Recycle::from).collect())
+        tracing::info!(
+            "Recycle {} {} -> {} ({}: {})",
+            id,
+            from.as_str(),
+            to.as_str(),
+            actor.as_str(),
+            reason
+        );
+
+        Ok(())
     }
 
-    pub async fn update_deposit_detected(
-        pool: &SqlitePool,
+    async fn record_event(
+        conn: &mut AnyConnection,
         id: &str,
-        txid: &str,
-        amount_sats: u64,
-        confirmations: u32,
-        block_height: Option<u32>,
-        max_input_sats: Option<u64>,
-        required_confirmations: u32,
+        from: Option<RecycleStatus>,
+        to: RecycleStatus,
+        actor: Actor,
+        reason: &str,
     ) -> anyhow::Result<()> {
-        let now = Utc::now().to_rfc3339();
-        let status = if confirmations >= required_confirmations {
-            RecycleStatus::Confirmed.as_str()
-        } else {
-            RecycleStatus::Confirming.as_str()
-        };
-
         sqlx::query(
             r#"
-            UPDATE recycles
-            SET status = ?, deposit_txid = ?, deposit_amount_sats = ?, deposit_confirmations = ?,
-                deposit_block_height = ?, max_input_sats = ?, is_eligible = 1, updated_at = ?
-            WHERE id = ?
+            INSERT INTO recycle_events (recycle_id, from_status, to_status, actor, reason, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
             "#,
         )
-        .bind(status)
-        .bind(txid)
-        .bind(amount_sats as i64)
-        .bind(confirmations as i64)
-        .bind(block_height.map(|h| h as i64))
-        .bind(max_input_sats.map(|v| v as i64))
-        .bind(&now)
         .bind(id)
-        .execute(pool)
+        .bind(from.map(|status| status.as_str()))
+        .bind(to.as_str())
+        .bind(actor.as_str())
+        .bind(reason)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&mut *conn)
         .await?;
 
         Ok(())
     }
+}
 
-    /// Mark a deposit as a donation. No payout will be processed.
-    /// reason: "block_height" (after cutoff) or "input_too_large" (input > max allowed)
-    pub async fn update_as_donation(
-        pool: &SqlitePool,
+#[async_trait]
+impl<D: Dialect> RecycleStore for RecycleRepository<D> {
+    async fn create(
+        &self,
         id: &str,
-        txid: &str,
-        amount_sats: u64,
-        block_height: Option<u32>,
-        max_input_sats: Option<u64>,
-        reason: &str,
-    ) -> anyhow::Result<()> {
-        let now = Utc::now().to_rfc3339();
+        lightning_address: &str,
+        cancel_secret_hash: &str,
+        derive_address: &(dyn Fn(u32) -> anyhow::Result<String> + Send + Sync),
+    ) -> anyhow::Result<Recycle> {
+        let mut tx = self.begin().await?;
 
-        sqlx::query(
-            r#"
-            UPDATE recycles
-            SET status = 'donation', deposit_txid = ?, deposit_amount_sats = ?,
-                deposit_block_height = ?, max_input_sats = ?, is_eligible = 0,
-                donation_reason = ?, updated_at = ?
-            WHERE id = ?
-            "#,
+        let (next,): (i64,) = sqlx::query_as(
+            "UPDATE wallet_state SET next_address_index = next_address_index + 1 WHERE id = 1 RETURNING next_address_index",
         )
-        .bind(txid)
-        .bind(amount_sats as i64)
-        .bind(block_height.map(|h| h as i64))
-        .bind(max_input_sats.map(|v| v as i64))
-        .bind(reason)
-        .bind(&now)
-        .bind(id)
-        .execute(pool)
+        .fetch_one(&mut *tx)
         .await?;
+        let address_index = (next - 1) as u32;
+        let deposit_address = derive_address(address_index)?;
 
-        Ok(())
+        let new = NewRecycle {
+            id,
+            lightning_address,
+            deposit_address: &deposit_address,
+            address_index,
+            cancel_secret_hash,
+        };
+        Self::insert(&mut tx, &new, "created").await?;
+        tx.commit().await?;
+
+        self.find_by_id(id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Failed to create recycle"))
     }
 
-    pub async fn update_confirmations(
-        pool: &SqlitePool,
+    async fn create_reusing(
+        &self,
         id: &str,
-        confirmations: u32,
-        required_confirmations: u32,
-    ) -> anyhow::Result<()> {
+        lightning_address: &str,
+        cancel_secret_hash: &str,
+        expired: &Recycle,
+    ) -> anyhow::Result<Option<Recycle>> {
         let now = Utc::now().to_rfc3339();
-        let status = if confirmations >= required_confirmations {
-            RecycleStatus::Confirmed.as_str()
-        } else {
-            RecycleStatus::Confirming.as_str()
-        };
+        let mut tx = self.begin().await?;
 
-        sqlx::query(
+        let released = sqlx::query(
             r#"
-            UPDATE recycles
-            SET status = ?, deposit_confirmations = ?, updated_at = ?
-            WHERE id = ?
+            UPDATE recycles SET address_released_at = $1
+            WHERE id = $2 AND status IN ('expired', 'cancelled') AND address_released_at IS NULL
+              AND NOT EXISTS (SELECT 1 FROM deposits WHERE deposits.recycle_id = recycles.id)
             "#,
         )
-        .bind(status)
-        .bind(confirmations as i64)
         .bind(&now)
-        .bind(id)
-        .execute(pool)
+        .bind(&expired.id)
+        .execute(&mut *tx)
         .await?;
+        if released.rows_affected() == 0 {
+            return Ok(None);
+        }
+
+        let reason = format!("created, reusing the address of {} recycle {}", expired.status.as_str(), expired.id);
+        let new = NewRecycle {
+            id,
+            lightning_address,
+            deposit_address: &expired.deposit_address,
+            address_index: expired.address_index,
+            cancel_secret_hash,
+        };
+        Self::insert(&mut tx, &new, &reason).await?;
+        tx.commit().await?;
+
+        self.find_by_id(id).await
+    }
+
+    async fn cancel(&self, id: &str) -> anyhow::Result<()> {
+        let mut tx = self.begin().await?;
+        let current = Self::current_status(&mut tx, id).await?;
+        if current != RecycleStatus::AwaitingDeposit {
+            return Err(anyhow::anyhow!(
+                "Recycle is {}, only a recycle awaiting its deposit can be cancelled",
+                current.as_str()
+            ));
+        }
+
+        Self::transition(&mut tx, id, current, RecycleStatus::Cancelled, Actor::User, "cancelled by creator").await?;
+        tx.commit().await?;
 
         Ok(())
     }
 
-    pub async fn mark_paid(
-        pool: &SqlitePool,
-        id: &str,
-        payout_amount_sats: u64,
-        payment_preimage: &str,
-        payment_hash: &str,
-    ) -> anyhow::Result<()> {
-        let now = Utc::now().to_rfc3339();
+    async fn expire_unfunded(
+        &self,
+        created_before: DateTime<Utc>,
+        reason: &str,
+    ) -> anyhow::Result<usize> {
+        let ids: Vec<(String,)> = sqlx::query_as(
+            r#"
+            SELECT id FROM recycles
+            WHERE status = 'awaiting_deposit' AND created_at < $1
+              AND NOT EXISTS (SELECT 1 FROM deposits WHERE deposits.recycle_id = recycles.id)
+            "#,
+        )
+        .bind(created_before.to_rfc3339())
+        .fetch_all(&self.db)
+        .await?;
 
-        sqlx::query(
+        let mut expired = 0;
+        for (id,) in ids {
+            let mut tx = self.begin().await?;
+            // A deposit may have moved it on since, then it simply isn't expired
+            match Self::transition(
+                &mut tx,
+                &id,
+                RecycleStatus::AwaitingDeposit,
+                RecycleStatus::Expired,
+                Actor::DepositMonitor,
+                reason,
+            )
+            .await
+            {
+                Ok(()) => {
+                    tx.commit().await?;
+                    expired += 1;
+                }
+                Err(e) => tracing::debug!("Not expiring recycle {}: {}", id, e),
+            }
+        }
+
+        Ok(expired)
+    }
+
+    async fn find_reusable(
+        &self,
+        expired_before: DateTime<Utc>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<Recycle>> {
+        let rows: Vec<RecycleRow> = sqlx::query_as(
             r#"
-            UPDATE recycles
-            SET status = 'paid', payout_amount_sats = ?, payment_preimage = ?, payment_hash = ?, updated_at = ?, paid_at = ?
-            WHERE id = ?
+            SELECT * FROM recycles
+            WHERE status IN ('expired', 'cancelled') AND address_released_at IS NULL AND updated_at < $1
+              AND NOT EXISTS (SELECT 1 FROM deposits WHERE deposits.recycle_id = recycles.id)
+            ORDER BY address_index
+            LIMIT $2
             "#,
         )
-        .bind(payout_amount_sats as i64)
-        .bind(payment_preimage)
-        .bind(payment_hash)
-        .bind(&now)
-        .bind(&now)
-        .bind(id)
-        .execute(pool)
+        .bind(expired_before.to_rfc3339())
+        .bind(limit as i64)
+        .fetch_all(&self.db)
         .await?;
 
-        Ok(())
+        Ok(readable(rows, "recycle", |row| row.id.clone()))
     }
 
-    pub async fn mark_failed(pool: &SqlitePool, id: &str) -> anyhow::Result<()> {
-        let now = Utc::now().to_rfc3339();
+    async fn max_address_index(&self) -> anyhow::Result<Option<u32>> {
+        let (max,): (Option<i64>,) = sqlx::query_as("SELECT MAX(address_index) FROM recycles")
+            .fetch_one(&self.db)
+            .await?;
 
-        sqlx::query(
+        Ok(max.map(|index| index as u32))
+    }
+
+    async fn find_by_id(&self, id: &str) -> anyhow::Result<Option<Recycle>> {
+        let row: Option<RecycleRow> = sqlx::query_as("SELECT * FROM recycles WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+
+        row.map(Recycle::try_from).transpose()
+    }
+
+    async fn find_by_deposit_address(
+        &self,
+        address: &str,
+    ) -> anyhow::Result<Option<Recycle>> {
+        let row: Option<RecycleRow> =
+            sqlx::query_as("SELECT * FROM recycles WHERE deposit_address = $1")
+                .bind(address)
+                .fetch_optional(&self.db)
+                .await?;
+
+        row.map(Recycle::try_from).transpose()
+    }
+
+    async fn find_by_status(
+        &self,
+        status: RecycleStatus,
+    ) -> anyhow::Result<Vec<Recycle>> {
+        let rows: Vec<RecycleRow> = sqlx::query_as("SELECT * FROM recycles WHERE status = $1")
+            .bind(status.as_str())
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(readable(rows, "recycle", |row| row.id.clone()))
+    }
+
+    async fn find_pending_deposits(
+        &self,
+        include_idle: bool,
+        settled_since: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Recycle>> {
+        let rows: Vec<RecycleRow> = sqlx::query_as(
             r#"
-            UPDATE recycles
-            SET status = 'failed', updated_at = ?
-            WHERE id = ?
+            SELECT * FROM recycles
+            WHERE (status IN ('awaiting_deposit', 'confirming', 'confirmed', 'paying', 'reorged')
+                   OR (status IN ('paid', 'donation', 'double_spent')
+                       AND ($1 = 1 OR (SELECT MAX(created_at) FROM recycle_events
+                                       WHERE recycle_events.recycle_id = recycles.id) >= $2))
+                   OR ($1 = 1 AND status IN ('expired', 'cancelled')))
+              AND address_released_at IS NULL
             "#,
         )
-        .bind(&now)
-        .bind(id)
-        .execute(pool)
+        .bind(include_idle as i32)
+        .bind(settled_since.to_rfc3339())
+        .fetch_all(&self.db)
         .await?;
 
+        Ok(readable(rows, "recycle", |row| row.id.clone()))
+    }
+
+    async fn refresh_from_deposits(&self, id: &str, actor: Actor) -> anyhow::Result<()> {
+        let mut tx = self.begin().await?;
+        Self::refresh(&mut tx, id, actor).await?;
+        tx.commit().await?;
         Ok(())
     }
 
-    /// Increment payment attempts counter and return new count.
-    /// Used to enforce MAX_PAYMENT_ATTEMPTS limit.
-    pub async fn increment_payment_attempts(pool: &SqlitePool, id: &str) -> anyhow::Result<u32> {
+    async fn start_payment(
+        &self,
+        payment: &NewPayment<'_>,
+        deposits: &[Deposit],
+    ) -> anyhow::Result<()> {
         let now = Utc::now().to_rfc3339();
+        let mut tx = self.begin().await?;
 
         sqlx::query(
             r#"
-            UPDATE recycles
-            SET payment_attempts = COALESCE(payment_attempts, 0) + 1, updated_at = ?
-            WHERE id = ?
+            INSERT INTO payments (payment_hash, recycle_id, bolt11, amount_msats, payout_amount_sats, status,
+                                  expires_at, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, 'pending', $6, $7, $8)
             "#,
         )
+        .bind(payment.payment_hash)
+        .bind(payment.recycle_id)
+        .bind(payment.bolt11)
+        .bind(payment.amount_msats as i64)
+        .bind(payment.payout_amount_sats as i64)
+        .bind(payment.expires_at.to_rfc3339())
         .bind(&now)
-        .bind(id)
-        .execute(pool)
+        .bind(&now)
+        .execute(&mut *tx)
         .await?;
 
-        // Fetch the new count
-        let row: (i64,) = sqlx::query_as(
-            "SELECT COALESCE(payment_attempts, 0) FROM recycles WHERE id = ?"
+        for deposit in deposits {
+            let result = sqlx::query(
+                r#"
+                UPDATE deposits
+                SET payment_hash = $1, updated_at = $2
+                WHERE txid = $3 AND vout = $4 AND status = 'confirmed' AND payment_hash IS NULL
+                "#,
+            )
+            .bind(payment.payment_hash)
+            .bind(&now)
+            .bind(&deposit.txid)
+            .bind(deposit.vout as i64)
+            .execute(&mut *tx)
+            .await?;
+
+            if result.rows_affected() == 0 {
+                return Err(anyhow::anyhow!(
+                    "Deposit {} is no longer confirmed or is already being paid",
+                    deposit.outpoint()
+                ));
+            }
+        }
+
+        let reason = format!("paying invoice {}", payment.payment_hash);
+        Self::transition(
+            &mut tx,
+            payment.recycle_id,
+            RecycleStatus::Confirmed,
+            RecycleStatus::Paying,
+            Actor::PaymentProcessor,
+            &reason,
         )
-        .bind(id)
-        .fetch_one(pool)
         .await?;
 
-        Ok(row.0 as u32)
+        tx.commit().await?;
+        Ok(())
     }
 
-    pub async fn get_next_address_index(pool: &SqlitePool) -> anyhow::Result<u32> {
-        let row: (i64,) = sqlx::query_as("SELECT next_address_index FROM wallet_state WHERE id = 1")
-            .fetch_one(pool)
-            .await?;
+    async fn mark_paid(&self, payment_hash: &str, payment_preimage: &str) -> anyhow::Result<()> {
+        let payment = PaymentRepository::find_by_hash(&self.db, payment_hash)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Payment {} not found", payment_hash))?;
 
-        Ok(row.0 as u32)
-    }
+        let now = Utc::now().to_rfc3339();
+        let mut tx = self.begin().await?;
 
-    pub async fn increment_address_index(pool: &SqlitePool) -> anyhow::Result<u32> {
-        let current = Self::get_next_address_index(pool).await?;
+        let updated = sqlx::query(
+            r#"
+            UPDATE payments
+            SET status = 'succeeded', preimage = $1, updated_at = $2
+            WHERE payment_hash = $3 AND status = 'pending'
+            "#,
+        )
+        .bind(payment_preimage)
+        .bind(&now)
+        .bind(payment_hash)
+        .execute(&mut *tx)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Payment {} is no longer pending", payment_hash));
+        }
 
-        sqlx::query("UPDATE wallet_state SET next_address_index = next_address_index + 1 WHERE id = 1")
-            .execute(pool)
+        // Whatever the deposit monitor saw meanwhile, the deposits were paid for
+        sqlx::query("UPDATE deposits SET status = 'paid', updated_at = $1 WHERE payment_hash = $2")
+            .bind(&now)
+            .bind(payment_hash)
+            .execute(&mut *tx)
             .await?;
 
-        Ok(current)
+        sqlx::query(
+            r#"
+            UPDATE recycles
+            SET payout_amount_sats = COALESCE(payout_amount_sats, 0) + $1,
+                payment_preimage = $2, payment_hash = $3, updated_at = $4, paid_at = $5
+            WHERE id = $6
+            "#,
+        )
+        .bind(payment.payout_amount_sats as i64)
+        .bind(payment_preimage)
+        .bind(payment_hash)
+        .bind(&now)
+        .bind(&now)
+        .bind(&payment.recycle_id)
+        .execute(&mut *tx)
+        .await?;
+
+        // Deposits that arrived while the payment was in flight keep the recycle open
+        Self::refresh(&mut tx, &payment.recycle_id, Actor::PaymentProcessor).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn fail_payment(&self, payment_hash: &str, error: &str) -> anyhow::Result<()> {
+        let payment = PaymentRepository::find_by_hash(&self.db, payment_hash)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Payment {} not found", payment_hash))?;
+
+        let now = Utc::now().to_rfc3339();
+        let mut tx = self.begin().await?;
+
+        let updated = sqlx::query(
+            r#"
+            UPDATE payments
+            SET status = 'failed', error = $1, updated_at = $2
+            WHERE payment_hash = $3 AND status = 'pending'
+            "#,
+        )
+        .bind(error)
+        .bind(&now)
+        .bind(payment_hash)
+        .execute(&mut *tx)
+        .await?;
+        if updated.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Payment {} is no longer pending", payment_hash));
+        }
+
+        sqlx::query("UPDATE deposits SET payment_hash = NULL, updated_at = $1 WHERE payment_hash = $2")
+            .bind(&now)
+            .bind(payment_hash)
+            .execute(&mut *tx)
+            .await?;
+
+        let current = Self::current_status(&mut tx, &payment.recycle_id).await?;
+        if current == RecycleStatus::Paying {
+            let reason = format!("payment failed: {}", error);
+            Self::transition(
+                &mut tx,
+                &payment.recycle_id,
+                current,
+                RecycleStatus::Confirmed,
+                Actor::PaymentProcessor,
+                &reason,
+            )
+            .await?;
+        }
+
+        // Deposits may have changed while the payment was in flight
+        Self::refresh(&mut tx, &payment.recycle_id, Actor::PaymentProcessor).await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn mark_failed(&self, id: &str, reason: &str) -> anyhow::Result<()> {
+        let mut tx = self.begin().await?;
+        let current = Self::current_status(&mut tx, id).await?;
+        Self::transition(&mut tx, id, current, RecycleStatus::Failed, Actor::PaymentProcessor, reason)
+            .await?;
+        sqlx::query("UPDATE recycles SET failure_reason = $1 WHERE id = $2")
+            .bind(reason)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn retry_payment(&self, id: &str) -> anyhow::Result<()> {
+        let mut tx = self.begin().await?;
+        let current = Self::current_status(&mut tx, id).await?;
+        if current != RecycleStatus::Failed {
+            return Err(anyhow::anyhow!("Recycle is {}, expected failed", current.as_str()));
+        }
+
+        sqlx::query("UPDATE recycles SET payment_attempts = 0, failure_reason = NULL WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        Self::transition(
+            &mut tx,
+            id,
+            current,
+            RecycleStatus::Confirmed,
+            Actor::Admin,
+            "payout retried",
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn find_events(&self, id: &str) -> anyhow::Result<Vec<RecycleEvent>> {
+        let rows: Vec<RecycleEventRow> =
+            sqlx::query_as("SELECT * FROM recycle_events WHERE recycle_id = $1 ORDER BY id")
+                .bind(id)
+                .fetch_all(&self.db)
+                .await?;
+
+        Ok(rows.into_iter().map(RecycleEvent::from).collect())
+    }
+
+    async fn increment_payment_attempts(&self, id: &str) -> anyhow::Result<u32> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query(
+            r#"
+            UPDATE recycles
+            SET payment_attempts = COALESCE(payment_attempts, 0) + 1, updated_at = $1
+            WHERE id = $2
+            "#,
+        )
+        .bind(&now)
+        .bind(id)
+        .execute(&self.db)
+        .await?;
+
+        // Fetch the new count
+        let row: (i64,) = sqlx::query_as(
+            "SELECT COALESCE(payment_attempts, 0) FROM recycles WHERE id = $1"
+        )
+        .bind(id)
+        .fetch_one(&self.db)
+        .await?;
+
+        Ok(row.0 as u32)
+    }
+
+    async fn reconcile_address_index(
+        &self,
+        last_revealed: Option<u32>,
+    ) -> anyhow::Result<Option<(u32, u32)>> {
+        let (next, max_used): (i64, Option<i64>) = sqlx::query_as(
+            r#"
+            SELECT next_address_index, (SELECT MAX(address_index) FROM recycles)
+            FROM wallet_state WHERE id = 1
+            "#,
+        )
+        .fetch_one(&self.db)
+        .await?;
+
+        let required = max_used
+            .into_iter()
+            .chain(last_revealed.map(i64::from))
+            .max()
+            .map(|index| index + 1)
+            .unwrap_or(0);
+        if next >= required {
+            return Ok(None);
+        }
+
+        // Only ever moves forward, in case another instance allocated meanwhile
+        sqlx::query("UPDATE wallet_state SET next_address_index = $1 WHERE id = 1 AND next_address_index < $1")
+            .bind(required)
+            .execute(&self.db)
+            .await?;
+
+        Ok(Some((next as u32, required as u32)))
+    }
+
+    async fn repair_payment_hashes(&self) -> anyhow::Result<usize> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            "SELECT id, payment_preimage FROM recycles WHERE payment_preimage IS NOT NULL AND payment_hash = payment_preimage",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        let mut repaired = 0;
+        for (id, preimage) in rows {
+            let Ok(bytes) = Vec::<u8>::from_hex(&preimage) else {
+                tracing::warn!("Recycle {} has a payment preimage that isn't hex, leaving its hash", id);
+                continue;
+            };
+            sqlx::query("UPDATE recycles SET payment_hash = $1 WHERE id = $2")
+                .bind(sha256::Hash::hash(&bytes).to_string())
+                .bind(&id)
+                .execute(&self.db)
+                .await?;
+            repaired += 1;
+        }
+
+        Ok(repaired)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepositStatus {
+    /// Waiting for confirmations (eligibility is checked once the tx is in a block)
+    Confirming,
+    /// Eligible and sufficiently confirmed, waiting to be included in a payout
+    Confirmed,
+    Paid,
+    /// Ineligible deposit kept as a donation
+    Donation,
+    /// Confirmed once, but its block was reorged away and the transaction is no
+    /// longer in the mempool either. Goes back to confirming if it reappears.
+    Reorged,
+    /// Replaced by a conflicting transaction, or dropped before it ever confirmed.
+    /// Goes back to confirming if it reappears.
+    DoubleSpent,
+}
+
+impl DepositStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Confirming => "confirming",
+            Self::Confirmed => "confirmed",
+            Self::Paid => "paid",
+            Self::Donation => "donation",
+            Self::Reorged => "reorged",
+            Self::DoubleSpent => "double_spent",
+        }
+    }
+
+    pub fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "confirming" => Ok(Self::Confirming),
+            "confirmed" => Ok(Self::Confirmed),
+            "paid" => Ok(Self::Paid),
+            "donation" => Ok(Self::Donation),
+            "reorged" => Ok(Self::Reorged),
+            "double_spent" => Ok(Self::DoubleSpent),
+            other => Err(anyhow::anyhow!("Unknown deposit status: {}", other)),
+        }
+    }
+
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            Self::Confirming => "Confirming",
+            Self::Confirmed => "Confirmed",
+            Self::Paid => "Paid",
+            Self::Donation => "Donation",
+            Self::Reorged => "Reorged",
+            Self::DoubleSpent => "Double-Spent",
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct DepositRow {
+    pub txid: String,
+    pub vout: i64,
+    pub recycle_id: String,
+    pub amount_sats: i64,
+    pub status: String,
+    pub confirmations: i64,
+    pub block_height: Option<i64>,
+    pub is_eligible: Option<i64>,
+    pub donation_reason: Option<String>,
+    pub input_creation_height: Option<i64>,
+    pub max_input_sats: Option<i64>,
+    pub sweep_id: Option<String>,
+    pub eligible_sats: Option<i64>,
+    pub payment_hash: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// A single UTXO received at a recycle's deposit address.
+#[derive(Debug, Clone, Serialize)]
+pub struct Deposit {
+    pub txid: String,
+    pub vout: u32,
+    pub recycle_id: String,
+    pub amount_sats: u64,
+    pub status: DepositStatus,
+    pub confirmations: u32,
+    /// The block height where the deposit transaction was confirmed
+    pub block_height: Option<u32>,
+    /// None until eligibility has been checked (once the deposit confirms)
+    pub is_eligible: Option<bool>,
+    /// Reason for donation status: "block_height", "block_height_unknown", "value_unknown" or "input_too_large"
+    pub donation_reason: Option<String>,
+    /// Latest block height at which one of the deposit transaction's inputs was created
+    pub input_creation_height: Option<u32>,
+    /// Maximum input UTXO value in the deposit transaction
+    pub max_input_sats: Option<u64>,
+    /// The consolidation sweep spending this deposit, if any
+    pub sweep_id: Option<String>,
+    /// Share of the amount attributable to eligible inputs, the part the payout covers.
+    /// None until eligibility has been checked.
+    pub eligible_sats: Option<u64>,
+    /// The payment paying out this deposit, once one has been attempted
+    pub payment_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<DepositRow> for Deposit {
+    type Error = anyhow::Error;
+
+    fn try_from(row: DepositRow) -> anyhow::Result<Self> {
+        Ok(Self {
+            txid: row.txid,
+            vout: row.vout as u32,
+            recycle_id: row.recycle_id,
+            amount_sats: row.amount_sats as u64,
+            status: DepositStatus::from_str(&row.status)?,
+            confirmations: row.confirmations as u32,
+            block_height: row.block_height.map(|v| v as u32),
+            is_eligible: row.is_eligible.map(|v| v == 1),
+            donation_reason: row.donation_reason,
+            input_creation_height: row.input_creation_height.map(|v| v as u32),
+            max_input_sats: row.max_input_sats.map(|v| v as u64),
+            sweep_id: row.sweep_id,
+            eligible_sats: row.eligible_sats.map(|v| v as u64),
+            payment_hash: row.payment_hash,
+            created_at: DateTime::parse_from_rfc3339(&row.created_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+            updated_at: DateTime::parse_from_rfc3339(&row.updated_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+        })
+    }
+}
+
+impl Deposit {
+    pub fn outpoint(&self) -> String {
+        format!("{}:{}", self.txid, self.vout)
+    }
+
+    /// The amount the payout covers: the eligible share once checked, otherwise the
+    /// whole amount (deposits recorded before per-input eligibility).
+    pub fn payable_sats(&self) -> u64 {
+        self.eligible_sats.unwrap_or(self.amount_sats)
+    }
+
+    /// Backfilled rows have no output index (vout = -1) until the monitor sees the
+    /// real outpoint.
+    pub fn vout_known(&self) -> bool {
+        self.vout != u32::MAX
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct DepositInputRow {
+    pub deposit_txid: String,
+    pub deposit_vout: i64,
+    pub input_index: i64,
+    pub prev_txid: String,
+    pub prev_vout: i64,
+    pub value_sats: Option<i64>,
+    pub creation_height: Option<i64>,
+    pub is_eligible: i64,
+    pub reason: Option<String>,
+}
+
+/// The eligibility verdict for one input of a deposit transaction.
+#[derive(Debug, Clone, Serialize)]
+pub struct DepositInput {
+    #[serde(skip)]
+    pub deposit_txid: String,
+    #[serde(skip)]
+    pub deposit_vout: u32,
+    pub input_index: u32,
+    pub prev_txid: String,
+    pub prev_vout: u32,
+    /// Value of the spent output, if it could be looked up
+    pub value_sats: Option<u64>,
+    /// Block height the spent output was created in, if known
+    pub creation_height: Option<u32>,
+    pub is_eligible: bool,
+    /// Reason the input is ineligible: "block_height", "block_height_unknown", "value_unknown" or "input_too_large"
+    pub reason: Option<String>,
+}
+
+impl From<DepositInputRow> for DepositInput {
+    fn from(row: DepositInputRow) -> Self {
+        Self {
+            deposit_txid: row.deposit_txid,
+            deposit_vout: row.deposit_vout as u32,
+            input_index: row.input_index as u32,
+            prev_txid: row.prev_txid,
+            prev_vout: row.prev_vout as u32,
+            value_sats: row.value_sats.map(|v| v as u64),
+            creation_height: row.creation_height.map(|v| v as u32),
+            is_eligible: row.is_eligible == 1,
+            reason: row.reason,
+        }
+    }
+}
+
+impl DepositInput {
+    pub fn prev_outpoint(&self) -> String {
+        format!("{}:{}", self.prev_txid, self.prev_vout)
+    }
+
+    /// Whether this input belongs to the given deposit.
+    pub fn belongs_to(&self, deposit: &Deposit) -> bool {
+        self.deposit_txid == deposit.txid && self.deposit_vout == deposit.vout
+    }
+}
+
+pub struct DepositRepository;
+
+impl DepositRepository {
+    pub async fn find_by_recycle(pool: &AnyPool, recycle_id: &str) -> anyhow::Result<Vec<Deposit>> {
+        let mut conn = pool.acquire().await?;
+        Self::find_by_recycle_in(&mut conn, recycle_id).await
+    }
+
+    async fn find_by_recycle_in(conn: &mut AnyConnection, recycle_id: &str) -> anyhow::Result<Vec<Deposit>> {
+        let rows: Vec<DepositRow> =
+            sqlx::query_as("SELECT * FROM deposits WHERE recycle_id = $1 ORDER BY created_at, txid, vout")
+                .bind(recycle_id)
+                .fetch_all(&mut *conn)
+                .await?;
+
+        Ok(readable(rows, "deposit", |row| format!("{}:{}", row.txid, row.vout)))
+    }
+
+    /// Per-input eligibility verdicts for every deposit of a recycle.
+    pub async fn find_inputs_by_recycle(
+        pool: &AnyPool,
+        recycle_id: &str,
+    ) -> anyhow::Result<Vec<DepositInput>> {
+        let rows: Vec<DepositInputRow> = sqlx::query_as(
+            r#"
+            SELECT deposit_inputs.* FROM deposit_inputs
+            JOIN deposits ON deposits.txid = deposit_inputs.deposit_txid
+                AND deposits.vout = deposit_inputs.deposit_vout
+            WHERE deposits.recycle_id = $1
+            ORDER BY deposit_inputs.deposit_txid, deposit_inputs.deposit_vout, deposit_inputs.input_index
+            "#,
+        )
+        .bind(recycle_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().map(DepositInput::from).collect())
+    }
+
+    /// Settled deposits (paid out or kept as donations) not yet claimed by a sweep.
+    pub async fn find_sweepable(pool: &AnyPool) -> anyhow::Result<Vec<Deposit>> {
+        let rows: Vec<DepositRow> = sqlx::query_as(
+            "SELECT * FROM deposits WHERE status IN ('paid', 'donation') AND sweep_id IS NULL AND vout >= 0 ORDER BY created_at",
+        )
+        .fetch_all(pool)
+        .await?;
+
+        Ok(readable(rows, "deposit", |row| format!("{}:{}", row.txid, row.vout)))
+    }
+
+    /// Insert a newly seen deposit, or refresh confirmations for a known one.
+    /// Rows backfilled without an output index (vout = -1) are claimed by the first
+    /// matching outpoint seen for the same transaction.
+    pub async fn record(
+        pool: &AnyPool,
+        recycle_id: &str,
+        txid: &str,
+        vout: u32,
+        amount_sats: u64,
+        confirmations: u32,
+        block_height: Option<u32>,
+    ) -> anyhow::Result<Deposit> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query(
+            r#"
+            UPDATE deposits SET vout = $1
+            WHERE recycle_id = $2 AND txid = $3 AND vout = -1
+              AND NOT EXISTS (SELECT 1 FROM deposits WHERE txid = $4 AND vout = $5)
+            "#,
+        )
+        .bind(vout as i64)
+        .bind(recycle_id)
+        .bind(txid)
+        .bind(txid)
+        .bind(vout as i64)
+        .execute(pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO deposits (txid, vout, recycle_id, amount_sats, status, confirmations, block_height, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, 'confirming', $5, $6, $7, $8)
+            ON CONFLICT (txid, vout) DO UPDATE
+            SET confirmations = excluded.confirmations, block_height = excluded.block_height,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(txid)
+        .bind(vout as i64)
+        .bind(recycle_id)
+        .bind(amount_sats as i64)
+        .bind(confirmations as i64)
+        .bind(block_height.map(|h| h as i64))
+        .bind(&now)
+        .bind(&now)
+        .execute(pool)
+        .await?;
+
+        let row: DepositRow = sqlx::query_as("SELECT * FROM deposits WHERE txid = $1 AND vout = $2")
+            .bind(txid)
+            .bind(vout as i64)
+            .fetch_one(pool)
+            .await?;
+
+        Deposit::try_from(row)
+    }
+
+    /// Record the eligibility check result for a deposit along with the verdict for each
+    /// of its inputs. A deposit with no eligible share becomes a donation; no payout will
+    /// be processed for it. A partly eligible deposit is paid on `eligible_sats` only.
+    /// reason: "block_height" (after cutoff), "block_height_unknown", "value_unknown" or "input_too_large"
+    pub async fn update_eligibility(
+        pool: &AnyPool,
+        deposit: &Deposit,
+        eligible_sats: u64,
+        donation_reason: Option<&str>,
+        inputs: &[DepositInput],
+    ) -> anyhow::Result<()> {
+        let now = Utc::now().to_rfc3339();
+        let (status, is_eligible) = match donation_reason {
+            Some(_) => (DepositStatus::Donation, 0),
+            None => (deposit.status, 1),
+        };
+        let input_creation_height = inputs.iter().filter_map(|input| input.creation_height).max();
+        let max_input_sats = inputs.iter().filter_map(|input| input.value_sats).max();
+
+        let mut tx = pool.begin().await?;
+
+        sqlx::query("DELETE FROM deposit_inputs WHERE deposit_txid = $1 AND deposit_vout = $2")
+            .bind(&deposit.txid)
+            .bind(deposit.vout as i64)
+            .execute(&mut *tx)
+            .await?;
+
+        for input in inputs {
+            sqlx::query(
+                r#"
+                INSERT INTO deposit_inputs (deposit_txid, deposit_vout, input_index, prev_txid, prev_vout,
+                                            value_sats, creation_height, is_eligible, reason)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                "#,
+            )
+            .bind(&deposit.txid)
+            .bind(deposit.vout as i64)
+            .bind(input.input_index as i64)
+            .bind(&input.prev_txid)
+            .bind(input.prev_vout as i64)
+            .bind(input.value_sats.map(|v| v as i64))
+            .bind(input.creation_height.map(|h| h as i64))
+            .bind(if input.is_eligible { 1 } else { 0 })
+            .bind(&input.reason)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE deposits
+            SET status = $1, is_eligible = $2, donation_reason = $3, input_creation_height = $4,
+                max_input_sats = $5, eligible_sats = $6, updated_at = $7
+            WHERE txid = $8 AND vout = $9
+            "#,
+        )
+        .bind(status.as_str())
+        .bind(is_eligible)
+        .bind(donation_reason)
+        .bind(input_creation_height.map(|h| h as i64))
+        .bind(max_input_sats.map(|v| v as i64))
+        .bind(eligible_sats as i64)
+        .bind(&now)
+        .bind(&deposit.txid)
+        .bind(deposit.vout as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Send an unpaid deposit back to `confirming` after a reorg changed or removed its
+    /// block. Eligibility is checked again once it confirms, since the reorg may also
+    /// have moved the blocks its inputs were created in. A deposit with a payment in
+    /// flight is left alone until the payment settles: it is paid if the payment went
+    /// through, and reset on a later pass if it didn't.
+    pub async fn reset_to_confirming(pool: &AnyPool, deposit: &Deposit) -> anyhow::Result<()> {
+        let now = Utc::now().to_rfc3339();
+        let mut tx = pool.begin().await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE deposits
+            SET status = 'confirming', is_eligible = NULL, donation_reason = NULL,
+                input_creation_height = NULL, max_input_sats = NULL, eligible_sats = NULL,
+                updated_at = $1
+            WHERE txid = $2 AND vout = $3 AND status IN ('confirming', 'confirmed', 'reorged', 'double_spent')
+              AND payment_hash IS NULL
+            "#,
+        )
+        .bind(&now)
+        .bind(&deposit.txid)
+        .bind(deposit.vout as i64)
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            sqlx::query("DELETE FROM deposit_inputs WHERE deposit_txid = $1 AND deposit_vout = $2")
+                .bind(&deposit.txid)
+                .bind(deposit.vout as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Mark an unpaid deposit whose transaction is no longer confirmed or in the mempool
+    /// as `reorged` or `double_spent`, holding back any payout for it. Like
+    /// `reset_to_confirming`, a deposit with a payment in flight is left alone.
+    pub async fn mark_unsettled(
+        pool: &AnyPool,
+        deposit: &Deposit,
+        status: DepositStatus,
+    ) -> anyhow::Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query(
+            r#"
+            UPDATE deposits
+            SET status = $1, confirmations = 0, block_height = NULL, updated_at = $2
+            WHERE txid = $3 AND vout = $4 AND status IN ('confirming', 'confirmed', 'reorged', 'double_spent')
+              AND payment_hash IS NULL
+            "#,
+        )
+        .bind(status.as_str())
+        .bind(&now)
+        .bind(&deposit.txid)
+        .bind(deposit.vout as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Move an eligible deposit that reached the required confirmations to `confirmed`.
+    pub async fn mark_confirmed(pool: &AnyPool, deposit: &Deposit) -> anyhow::Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        sqlx::query(
+            r#"
+            UPDATE deposits
+            SET status = 'confirmed', updated_at = $1
+            WHERE txid = $2 AND vout = $3 AND status = 'confirming' AND is_eligible = 1
+            "#,
+        )
+        .bind(&now)
+        .bind(&deposit.txid)
+        .bind(deposit.vout as i64)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    /// Recorded before the invoice was sent, outcome not known yet
+    Pending,
+    Succeeded,
+    /// Definitely not made: the backend reported a failure, or has no record of the
+    /// payment and its invoice has expired
+    Failed,
+}
+
+impl PaymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+        }
+    }
+
+    pub fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "pending" => Ok(Self::Pending),
+            "succeeded" => Ok(Self::Succeeded),
+            "failed" => Ok(Self::Failed),
+            other => Err(anyhow::anyhow!("Unknown payment status: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct PaymentRow {
+    pub payment_hash: String,
+    pub recycle_id: String,
+    pub bolt11: String,
+    pub amount_msats: i64,
+    pub payout_amount_sats: i64,
+    pub status: String,
+    pub preimage: Option<String>,
+    pub error: Option<String>,
+    pub expires_at: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// A Lightning payment attempted for a recycle's payout.
+#[derive(Debug, Clone, Serialize)]
+pub struct Payment {
+    pub payment_hash: String,
+    pub recycle_id: String,
+    pub bolt11: String,
+    pub amount_msats: u64,
+    /// Payout the payment makes for the deposits it covers
+    pub payout_amount_sats: u64,
+    pub status: PaymentStatus,
+    pub preimage: Option<String>,
+    /// Why the payment failed
+    pub error: Option<String>,
+    /// When the invoice expires
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<PaymentRow> for Payment {
+    type Error = anyhow::Error;
+
+    fn try_from(row: PaymentRow) -> anyhow::Result<Self> {
+        Ok(Self {
+            payment_hash: row.payment_hash,
+            recycle_id: row.recycle_id,
+            bolt11: row.bolt11,
+            amount_msats: row.amount_msats as u64,
+            payout_amount_sats: row.payout_amount_sats as u64,
+            status: PaymentStatus::from_str(&row.status)?,
+            preimage: row.preimage,
+            error: row.error,
+            expires_at: DateTime::parse_from_rfc3339(&row.expires_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+            created_at: DateTime::parse_from_rfc3339(&row.created_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+            updated_at: DateTime::parse_from_rfc3339(&row.updated_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+        })
+    }
+}
+
+/// Fields of a payment about to be sent
+pub struct NewPayment<'a> {
+    pub recycle_id: &'a str,
+    pub bolt11: &'a str,
+    pub payment_hash: &'a str,
+    pub amount_msats: u64,
+    pub payout_amount_sats: u64,
+    pub expires_at: DateTime<Utc>,
+}
+
+pub struct PaymentRepository;
+
+impl PaymentRepository {
+    pub async fn find_by_hash(pool: &AnyPool, payment_hash: &str) -> anyhow::Result<Option<Payment>> {
+        let row: Option<PaymentRow> = sqlx::query_as("SELECT * FROM payments WHERE payment_hash = $1")
+            .bind(payment_hash)
+            .fetch_optional(pool)
+            .await?;
+
+        row.map(Payment::try_from).transpose()
+    }
+
+    /// Payments a recycle has attempted, oldest first.
+    pub async fn find_by_recycle(pool: &AnyPool, recycle_id: &str) -> anyhow::Result<Vec<Payment>> {
+        let rows: Vec<PaymentRow> =
+            sqlx::query_as("SELECT * FROM payments WHERE recycle_id = $1 ORDER BY created_at, payment_hash")
+                .bind(recycle_id)
+                .fetch_all(pool)
+                .await?;
+
+        Ok(readable(rows, "payment", |row| row.payment_hash.clone()))
+    }
+
+    /// Payments sent (or about to be) whose outcome isn't known yet.
+    pub async fn find_pending(pool: &AnyPool) -> anyhow::Result<Vec<Payment>> {
+        let rows: Vec<PaymentRow> =
+            sqlx::query_as("SELECT * FROM payments WHERE status = $1 ORDER BY created_at")
+                .bind(PaymentStatus::Pending.as_str())
+                .fetch_all(pool)
+                .await?;
+
+        Ok(readable(rows, "payment", |row| row.payment_hash.clone()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SweepStatus {
+    /// PSBT exported, waiting for the offline signature
+    Unsigned,
+    Broadcast,
+    /// Abandoned before broadcast; its deposits are released for a new sweep
+    Cancelled,
+}
+
+impl SweepStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Unsigned => "unsigned",
+            Self::Broadcast => "broadcast",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "unsigned" => Ok(Self::Unsigned),
+            "broadcast" => Ok(Self::Broadcast),
+            "cancelled" => Ok(Self::Cancelled),
+            other => Err(anyhow::anyhow!("Unknown sweep status: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct SweepRow {
+    pub id: String,
+    pub status: String,
+    pub destination_address: String,
+    pub fee_rate_sat_vb: i64,
+    pub input_count: i64,
+    pub total_input_sats: i64,
+    pub fee_sats: i64,
+    pub unsigned_psbt: String,
+    pub txid: String,
+    pub created_at: String,
+    pub updated_at: String,
+    pub broadcast_at: Option<String>,
+}
+
+/// A consolidation transaction spending collected deposits to cold storage.
+#[derive(Debug, Clone, Serialize)]
+pub struct Sweep {
+    pub id: String,
+    pub status: SweepStatus,
+    pub destination_address: String,
+    pub fee_rate_sat_vb: u64,
+    pub input_count: u32,
+    pub total_input_sats: u64,
+    pub fee_sats: u64,
+    /// Base64 PSBT for offline signing
+    pub unsigned_psbt: String,
+    pub txid: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub broadcast_at: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<SweepRow> for Sweep {
+    type Error = anyhow::Error;
+
+    fn try_from(row: SweepRow) -> anyhow::Result<Self> {
+        Ok(Self {
+            id: row.id,
+            status: SweepStatus::from_str(&row.status)?,
+            destination_address: row.destination_address,
+            fee_rate_sat_vb: row.fee_rate_sat_vb as u64,
+            input_count: row.input_count as u32,
+            total_input_sats: row.total_input_sats as u64,
+            fee_sats: row.fee_sats as u64,
+            unsigned_psbt: row.unsigned_psbt,
+            txid: row.txid,
+            created_at: DateTime::parse_from_rfc3339(&row.created_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+            updated_at: DateTime::parse_from_rfc3339(&row.updated_at)
+                .map(|dt| dt.with_timezone(&Utc))
+                .unwrap_or_else(|_| Utc::now()),
+            broadcast_at: row.broadcast_at.and_then(|s| {
+                DateTime::parse_from_rfc3339(&s)
+                    .map(|dt| dt.with_timezone(&Utc))
+                    .ok()
+            }),
+        })
+    }
+}
+
+/// Fields of a newly built sweep
+pub struct NewSweep<'a> {
+    pub id: &'a str,
+    pub destination_address: &'a str,
+    pub fee_rate_sat_vb: u64,
+    pub total_input_sats: u64,
+    pub fee_sats: u64,
+    pub unsigned_psbt: &'a str,
+    pub txid: &'a str,
+}
+
+pub struct SweepRepository;
+
+impl SweepRepository {
+    /// Record a new unsigned sweep and claim its deposits so they aren't swept twice.
+    pub async fn create(
+        pool: &AnyPool,
+        sweep: NewSweep<'_>,
+        deposits: &[Deposit],
+    ) -> anyhow::Result<Sweep> {
+        let now = Utc::now().to_rfc3339();
+        let mut tx = pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO sweeps (id, status, destination_address, fee_rate_sat_vb, input_count, total_input_sats,
+                                fee_sats, unsigned_psbt, txid, created_at, updated_at)
+            VALUES ($1, 'unsigned', $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            "#,
+        )
+        .bind(sweep.id)
+        .bind(sweep.destination_address)
+        .bind(sweep.fee_rate_sat_vb as i64)
+        .bind(deposits.len() as i64)
+        .bind(sweep.total_input_sats as i64)
+        .bind(sweep.fee_sats as i64)
+        .bind(sweep.unsigned_psbt)
+        .bind(sweep.txid)
+        .bind(&now)
+        .bind(&now)
+        .execute(&mut *tx)
+        .await?;
+
+        for deposit in deposits {
+            let result = sqlx::query(
+                "UPDATE deposits SET sweep_id = $1, updated_at = $2 WHERE txid = $3 AND vout = $4 AND sweep_id IS NULL",
+            )
+            .bind(sweep.id)
+            .bind(&now)
+            .bind(&deposit.txid)
+            .bind(deposit.vout as i64)
+            .execute(&mut *tx)
+            .await?;
+
+            if result.rows_affected() == 0 {
+                return Err(anyhow::anyhow!(
+                    "Deposit {} is already claimed by another sweep",
+                    deposit.outpoint()
+                ));
+            }
+        }
+
+        tx.commit().await?;
+
+        Self::find_by_id(pool, sweep.id)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Failed to create sweep"))
+    }
+
+    pub async fn find_by_id(pool: &AnyPool, id: &str) -> anyhow::Result<Option<Sweep>> {
+        let row: Option<SweepRow> = sqlx::query_as("SELECT * FROM sweeps WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?;
+
+        row.map(Sweep::try_from).transpose()
+    }
+
+    pub async fn list(pool: &AnyPool) -> anyhow::Result<Vec<Sweep>> {
+        let rows: Vec<SweepRow> = sqlx::query_as("SELECT * FROM sweeps ORDER BY created_at DESC")
+            .fetch_all(pool)
+            .await?;
+
+        Ok(readable(rows, "sweep", |row| row.id.clone()))
+    }
+
+    /// Mark an unsigned sweep as broadcast. Fails if it isn't unsigned any more, e.g.
+    /// because a concurrent submission of the same sweep marked it first.
+    pub async fn mark_broadcast(pool: &AnyPool, id: &str) -> anyhow::Result<()> {
+        let now = Utc::now().to_rfc3339();
+
+        let result = sqlx::query(
+            r#"
+            UPDATE sweeps
+            SET status = 'broadcast', updated_at = $1, broadcast_at = $2
+            WHERE id = $3 AND status = 'unsigned'
+            "#,
+        )
+        .bind(&now)
+        .bind(&now)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Sweep {} is no longer unsigned", id));
+        }
+
+        Ok(())
+    }
+
+    /// Cancel an unsigned sweep and release its deposits.
+    pub async fn cancel(pool: &AnyPool, id: &str) -> anyhow::Result<()> {
+        let now = Utc::now().to_rfc3339();
+        let mut tx = pool.begin().await?;
+
+        let result = sqlx::query(
+            "UPDATE sweeps SET status = 'cancelled', updated_at = $1 WHERE id = $2 AND status = 'unsigned'",
+        )
+        .bind(&now)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::anyhow!("Only unsigned sweeps can be cancelled"));
+        }
+
+        sqlx::query("UPDATE deposits SET sweep_id = NULL, updated_at = $1 WHERE sweep_id = $2")
+            .bind(&now)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [RecycleStatus; 11] = [
+        RecycleStatus::AwaitingDeposit,
+        RecycleStatus::Confirming,
+        RecycleStatus::Confirmed,
+        RecycleStatus::Paying,
+        RecycleStatus::Paid,
+        RecycleStatus::Failed,
+        RecycleStatus::Donation,
+        RecycleStatus::Reorged,
+        RecycleStatus::DoubleSpent,
+        RecycleStatus::Expired,
+        RecycleStatus::Cancelled,
+    ];
+
+    #[test]
+    fn status_strings_round_trip() {
+        for status in ALL {
+            assert_eq!(RecycleStatus::from_str(status.as_str()).unwrap(), status);
+        }
+        assert!(RecycleStatus::from_str("refunded").is_err());
+    }
+
+    #[test]
+    fn unknown_deposit_payment_and_sweep_statuses_are_errors() {
+        for status in [
+            DepositStatus::Confirming,
+            DepositStatus::Confirmed,
+            DepositStatus::Paid,
+            DepositStatus::Donation,
+            DepositStatus::Reorged,
+            DepositStatus::DoubleSpent,
+        ] {
+            assert_eq!(DepositStatus::from_str(status.as_str()).unwrap(), status);
+        }
+        for status in [PaymentStatus::Pending, PaymentStatus::Succeeded, PaymentStatus::Failed] {
+            assert_eq!(PaymentStatus::from_str(status.as_str()).unwrap(), status);
+        }
+        for status in [SweepStatus::Unsigned, SweepStatus::Broadcast, SweepStatus::Cancelled] {
+            assert_eq!(SweepStatus::from_str(status.as_str()).unwrap(), status);
+        }
+
+        // None of them falls back to a status that would be acted on
+        assert!(DepositStatus::from_str("refunded").is_err());
+        assert!(PaymentStatus::from_str("").is_err());
+        assert!(SweepStatus::from_str("signed").is_err());
+    }
+
+    #[test]
+    fn no_status_transitions_to_itself_or_back_to_awaiting_deposit() {
+        for status in ALL {
+            assert!(!status.can_transition_to(status), "{:?} -> itself", status);
+            assert!(!status.can_transition_to(RecycleStatus::AwaitingDeposit), "{:?} -> awaiting", status);
+        }
+    }
+
+    #[test]
+    fn payout_only_starts_from_confirmed() {
+        for status in ALL {
+            assert_eq!(
+                status.can_transition_to(RecycleStatus::Paying),
+                status == RecycleStatus::Confirmed,
+                "{:?} -> paying",
+                status
+            );
+            assert_eq!(
+                status.can_transition_to(RecycleStatus::Failed),
+                status == RecycleStatus::Confirmed,
+                "{:?} -> failed",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn payment_outcome_settles_paying() {
+        use RecycleStatus::*;
+        assert!(Paying.can_transition_to(Paid));
+        assert!(Paying.can_transition_to(Confirmed));
+        assert!(!Paying.can_transition_to(Donation));
+        assert!(!Paying.can_transition_to(Expired));
+    }
+
+    #[test]
+    fn failed_only_moves_on_retry() {
+        for status in ALL {
+            assert_eq!(
+                RecycleStatus::Failed.can_transition_to(status),
+                status == RecycleStatus::Confirmed,
+                "failed -> {:?}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn only_unfunded_recycles_expire_or_are_cancelled() {
+        for status in ALL {
+            let unfunded = status == RecycleStatus::AwaitingDeposit;
+            assert_eq!(status.can_transition_to(RecycleStatus::Expired), unfunded, "{:?} -> expired", status);
+            assert_eq!(status.can_transition_to(RecycleStatus::Cancelled), unfunded, "{:?} -> cancelled", status);
+        }
+    }
+
+    #[test]
+    fn settled_recycles_only_reopen_to_confirming() {
+        use RecycleStatus::*;
+        for settled in [Donation, DoubleSpent, Expired, Cancelled] {
+            for status in ALL {
+                assert_eq!(
+                    settled.can_transition_to(status),
+                    status == Confirming,
+                    "{:?} -> {:?}",
+                    settled,
+                    status
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn paid_only_reopens_for_a_later_deposit() {
+        use RecycleStatus::*;
+        for status in ALL {
+            assert_eq!(
+                Paid.can_transition_to(status),
+                matches!(status, Confirming | Confirmed),
+                "paid -> {:?}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn only_open_recycles_accept_deposits() {
+        use RecycleStatus::*;
+        for status in ALL {
+            assert_eq!(
+                status.accepts_deposits(),
+                matches!(status, AwaitingDeposit | Confirming | Confirmed),
+                "{:?}",
+                status
+            );
+        }
+    }
+
+    #[test]
+    fn deposits_move_an_open_recycle() {
+        use RecycleStatus::*;
+        assert!(AwaitingDeposit.can_transition_to(Confirming));
+        assert!(Confirming.can_transition_to(Confirmed));
+        assert!(Confirming.can_transition_to(Donation));
+        assert!(Confirmed.can_transition_to(Reorged));
+        assert!(Reorged.can_transition_to(Confirming));
+        assert!(Confirming.can_transition_to(DoubleSpent));
+        assert!(!AwaitingDeposit.can_transition_to(Paid));
+    }
+
+    use crate::db::testing::{databases, TestDb};
+
+    async fn create_recycle(test: &TestDb, id: &str) -> Recycle {
+        test.recycles
+            .create(id, "user@example.com", "secret-hash", &|index| Ok(format!("address-{}", index)))
+            .await
+            .unwrap()
+    }
+
+    /// Record a deposit to the recycle and take it through the monitor's steps up to
+    /// confirmed
+    async fn confirmed_deposit(test: &TestDb, recycle_id: &str, txid: &str, amount_sats: u64) -> Deposit {
+        let db = &test.db;
+        let deposit = DepositRepository::record(db, recycle_id, txid, 0, amount_sats, 0, None).await.unwrap();
+        DepositRepository::update_eligibility(db, &deposit, amount_sats, None, &[]).await.unwrap();
+        let deposit = DepositRepository::record(db, recycle_id, txid, 0, amount_sats, 3, Some(100)).await.unwrap();
+        DepositRepository::mark_confirmed(db, &deposit).await.unwrap();
+        test.recycles.refresh_from_deposits(recycle_id, Actor::DepositMonitor).await.unwrap();
+
+        DepositRepository::find_by_recycle(db, recycle_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|d| d.txid == txid)
+            .unwrap()
+    }
+
+    fn new_payment<'a>(recycle_id: &'a str, payment_hash: &'a str) -> NewPayment<'a> {
+        NewPayment {
+            recycle_id,
+            bolt11: "lnbcrt1invoice",
+            payment_hash,
+            amount_msats: 1_000_000,
+            payout_amount_sats: 1_000,
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+        }
+    }
+
+    async fn status(test: &TestDb, id: &str) -> RecycleStatus {
+        test.recycles.find_by_id(id).await.unwrap().unwrap().status
+    }
+
+    #[tokio::test]
+    async fn recycle_is_paid_for_its_confirmed_deposit() {
+        for test in databases().await {
+            let (db, recycles) = (&test.db, &test.recycles);
+            create_recycle(&test, "r1").await;
+            let deposit = confirmed_deposit(&test, "r1", "tx1", 1_000).await;
+            assert_eq!(deposit.status, DepositStatus::Confirmed, "{}", test.backend.as_str());
+            assert_eq!(status(&test, "r1").await, RecycleStatus::Confirmed);
+
+            recycles.start_payment(&new_payment("r1", "hash1"), &[deposit]).await.unwrap();
+            assert_eq!(status(&test, "r1").await, RecycleStatus::Paying);
+            assert_eq!(PaymentRepository::find_pending(db).await.unwrap().len(), 1);
+
+            recycles.mark_paid("hash1", "preimage1").await.unwrap();
+            let recycle = recycles.find_by_id("r1").await.unwrap().unwrap();
+            assert_eq!(recycle.status, RecycleStatus::Paid);
+            assert_eq!(recycle.payout_amount_sats, Some(1_000));
+            assert!(PaymentRepository::find_pending(db).await.unwrap().is_empty());
+            let deposits = DepositRepository::find_by_recycle(db, "r1").await.unwrap();
+            assert_eq!(deposits[0].status, DepositStatus::Paid);
+
+            // A payment can only be settled once
+            assert!(recycles.mark_paid("hash1", "preimage1").await.is_err());
+
+            let events: Vec<String> = recycles.find_events("r1")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|event| event.to_status)
+                .collect();
+            assert_eq!(events, ["awaiting_deposit", "confirmed", "paying", "paid"]);
+            test.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn reorg_while_paying_doesnt_pay_twice() {
+        for test in databases().await {
+            let (db, recycles) = (&test.db, &test.recycles);
+
+            // The payment went through despite the reorg
+            create_recycle(&test, "r1").await;
+            let deposit = confirmed_deposit(&test, "r1", "tx1", 1_000).await;
+            recycles.start_payment(&new_payment("r1", "hash1"), std::slice::from_ref(&deposit))
+                .await
+                .unwrap();
+            DepositRepository::reset_to_confirming(db, &deposit).await.unwrap();
+            DepositRepository::mark_unsettled(db, &deposit, DepositStatus::Reorged).await.unwrap();
+            recycles.refresh_from_deposits("r1", Actor::DepositMonitor).await.unwrap();
+            let deposits = DepositRepository::find_by_recycle(db, "r1").await.unwrap();
+            assert_eq!(deposits[0].status, DepositStatus::Confirmed, "{}", test.backend.as_str());
+            assert_eq!(status(&test, "r1").await, RecycleStatus::Paying);
+
+            // Its deposit can't be put in a second payment
+            let payment = new_payment("r1", "hash2");
+            assert!(recycles.start_payment(&payment, &deposits).await.is_err());
+
+            recycles.mark_paid("hash1", "preimage1").await.unwrap();
+            assert_eq!(status(&test, "r1").await, RecycleStatus::Paid);
+            let deposits = DepositRepository::find_by_recycle(db, "r1").await.unwrap();
+            assert_eq!(deposits[0].status, DepositStatus::Paid);
+            DepositRepository::reset_to_confirming(db, &deposit).await.unwrap();
+            assert_eq!(status(&test, "r1").await, RecycleStatus::Paid);
+
+            // The payment failed, so the reorg is only applied once it is settled
+            create_recycle(&test, "r2").await;
+            let deposit = confirmed_deposit(&test, "r2", "tx2", 1_000).await;
+            recycles.start_payment(&new_payment("r2", "hash3"), std::slice::from_ref(&deposit))
+                .await
+                .unwrap();
+            DepositRepository::reset_to_confirming(db, &deposit).await.unwrap();
+            recycles.fail_payment("hash3", "no route").await.unwrap();
+            assert_eq!(status(&test, "r2").await, RecycleStatus::Confirmed);
+
+            DepositRepository::reset_to_confirming(db, &deposit).await.unwrap();
+            recycles.refresh_from_deposits("r2", Actor::DepositMonitor).await.unwrap();
+            let deposits = DepositRepository::find_by_recycle(db, "r2").await.unwrap();
+            assert_eq!(deposits[0].status, DepositStatus::Confirming);
+            assert_eq!(status(&test, "r2").await, RecycleStatus::Confirming);
+            test.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_status_changes_wait_for_each_other() {
+        for test in databases().await {
+            let recycles = &test.recycles;
+            for i in 0..10 {
+                let id = format!("r{}", i);
+                let hash = format!("hash{}", i);
+                create_recycle(&test, &id).await;
+                let deposit = confirmed_deposit(&test, &id, &format!("tx{}", i), 1_000).await;
+                recycles.start_payment(&new_payment(&id, &hash), &[deposit]).await.unwrap();
+
+                // The deposit monitor refreshes the recycle while its payment settles
+                let (refreshed, paid) = tokio::join!(
+                    recycles.refresh_from_deposits(&id, Actor::DepositMonitor),
+                    recycles.mark_paid(&hash, "preimage"),
+                );
+                refreshed.unwrap();
+                paid.unwrap();
+                assert_eq!(status(&test, &id).await, RecycleStatus::Paid, "{}", test.backend.as_str());
+            }
+            test.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn failure_reason_is_kept_until_retried() {
+        for test in databases().await {
+            let recycles = &test.recycles;
+            create_recycle(&test, "r1").await;
+            confirmed_deposit(&test, "r1", "tx1", 1_000).await;
+
+            let reason = "LNURL invoice refused: invoice has no amount";
+            recycles.mark_failed("r1", reason).await.unwrap();
+            let recycle = recycles.find_by_id("r1").await.unwrap().unwrap();
+            assert_eq!(recycle.status, RecycleStatus::Failed, "{}", test.backend.as_str());
+            assert_eq!(recycle.failure_reason.as_deref(), Some(reason));
+
+            recycles.retry_payment("r1").await.unwrap();
+            let recycle = recycles.find_by_id("r1").await.unwrap().unwrap();
+            assert_eq!(recycle.status, RecycleStatus::Confirmed);
+            assert_eq!(recycle.failure_reason, None);
+            test.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn settled_recycles_are_only_watched_for_a_while() {
+        for test in databases().await {
+            let recycles = &test.recycles;
+            create_recycle(&test, "r1").await;
+            let deposit = confirmed_deposit(&test, "r1", "tx1", 1_000).await;
+            recycles.start_payment(&new_payment("r1", "hash1"), &[deposit]).await.unwrap();
+            recycles.mark_paid("hash1", "preimage1").await.unwrap();
+
+            let pending = |include_idle, settled_since| async move {
+                recycles.find_pending_deposits(include_idle, settled_since).await.unwrap().len()
+            };
+            let an_hour_ago = Utc::now() - chrono::Duration::hours(1);
+            let in_an_hour = Utc::now() + chrono::Duration::hours(1);
+            assert_eq!(pending(false, an_hour_ago).await, 1, "{}", test.backend.as_str());
+            assert_eq!(pending(false, in_an_hour).await, 0);
+            assert_eq!(pending(true, in_an_hour).await, 1);
+            test.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn unfunded_addresses_are_reused_once() {
+        for test in databases().await {
+            let recycles = &test.recycles;
+            let first = create_recycle(&test, "r1").await;
+            let second = create_recycle(&test, "r2").await;
+            assert_eq!((first.address_index, second.address_index), (0, 1), "{}", test.backend.as_str());
+            assert_eq!(recycles.max_address_index().await.unwrap(), Some(1));
+
+            recycles.cancel("r1").await.unwrap();
+            let pending = |include_idle| recycles.find_pending_deposits(include_idle, Utc::now());
+            assert_eq!(pending(false).await.unwrap().len(), 1);
+            assert_eq!(pending(true).await.unwrap().len(), 2);
+
+            let reusable = recycles.find_reusable(Utc::now() + chrono::Duration::seconds(1), 5)
+                .await
+                .unwrap();
+            assert_eq!(reusable.len(), 1);
+            let reused = recycles.create_reusing("r3", "user@example.com", "secret-hash", &reusable[0])
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(reused.address_index, 0);
+            assert_eq!(reused.deposit_address, first.deposit_address);
+
+            // The released address is neither handed out nor watched for r1 again
+            assert!(recycles.create_reusing("r4", "user@example.com", "secret-hash", &reusable[0])
+                .await
+                .unwrap()
+                .is_none());
+            let pending_ids: Vec<String> = pending(true).await.unwrap().into_iter().map(|r| r.id).collect();
+            assert!(!pending_ids.contains(&"r1".to_string()));
+            test.close().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn sweep_is_broadcast_once() {
+        for test in databases().await {
+            let (db, recycles) = (&test.db, &test.recycles);
+            create_recycle(&test, "r1").await;
+            let deposit = confirmed_deposit(&test, "r1", "tx1", 1_000).await;
+            recycles.start_payment(&new_payment("r1", "hash1"), &[deposit]).await.unwrap();
+            recycles.mark_paid("hash1", "preimage1").await.unwrap();
+
+            let sweepable = DepositRepository::find_sweepable(db).await.unwrap();
+            assert_eq!(sweepable.len(), 1, "{}", test.backend.as_str());
+            let sweep = NewSweep {
+                id: "s1",
+                destination_address: "destination",
+                fee_rate_sat_vb: 2,
+                total_input_sats: 1_000,
+                fee_sats: 200,
+                unsigned_psbt: "psbt",
+                txid: "sweep-tx",
+            };
+            SweepRepository::create(db, sweep, &sweepable).await.unwrap();
+            assert!(DepositRepository::find_sweepable(db).await.unwrap().is_empty());
+
+            SweepRepository::mark_broadcast(db, "s1").await.unwrap();
+            assert!(SweepRepository::mark_broadcast(db, "s1").await.is_err());
+            assert!(SweepRepository::cancel(db, "s1").await.is_err());
+            let sweep = SweepRepository::find_by_id(db, "s1").await.unwrap().unwrap();
+            assert_eq!(sweep.status, SweepStatus::Broadcast);
+            test.close().await;
+        }
     }
 }
@@ -0,0 +1,146 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use super::{Actor, Deposit, NewPayment, Recycle, RecycleEvent, RecycleStatus};
+
+/// Where recycles, their status transitions and payouts are stored. There is one
+/// implementation per database backend, picked from the `DATABASE_URL` scheme by
+/// `DatabaseBackend::recycle_store`. Both run the SQL in `RecycleRepository` and differ
+/// in how they keep concurrent status changes apart (see `Dialect`).
+///
+/// Every status change goes through `RecycleStatus::can_transition_to` and is recorded
+/// in the recycle's event log.
+#[async_trait]
+pub trait RecycleStore: Send + Sync {
+    /// Create a recycle at the next unused address index. The index is claimed in the
+    /// same transaction as the insert, so concurrent creates never share an address,
+    /// and `derive_address` turns it into the deposit address.
+    async fn create(
+        &self,
+        id: &str,
+        lightning_address: &str,
+        cancel_secret_hash: &str,
+        derive_address: &(dyn Fn(u32) -> anyhow::Result<String> + Send + Sync),
+    ) -> anyhow::Result<Recycle>;
+
+    /// Create a recycle at the deposit address of an expired or cancelled one,
+    /// releasing it from that recycle in the same transaction. Returns None if the old
+    /// recycle received a deposit or had its address taken by another create meanwhile.
+    async fn create_reusing(
+        &self,
+        id: &str,
+        lightning_address: &str,
+        cancel_secret_hash: &str,
+        expired: &Recycle,
+    ) -> anyhow::Result<Option<Recycle>>;
+
+    /// Cancel a recycle that is still awaiting its first deposit. The caller checks the
+    /// creator's secret.
+    async fn cancel(&self, id: &str) -> anyhow::Result<()>;
+
+    /// Expire every recycle created before `created_before` that is still awaiting
+    /// its first deposit. Returns how many were expired.
+    async fn expire_unfunded(&self, created_before: DateTime<Utc>, reason: &str) -> anyhow::Result<usize>;
+
+    /// Expired or cancelled recycles whose address can be handed to a new recycle:
+    /// never funded, still holding the address and expired or cancelled before
+    /// `expired_before`. Lowest address index first.
+    async fn find_reusable(&self, expired_before: DateTime<Utc>, limit: u32) -> anyhow::Result<Vec<Recycle>>;
+
+    /// Highest address index any recycle has used, None if there are no recycles.
+    async fn max_address_index(&self) -> anyhow::Result<Option<u32>>;
+
+    async fn find_by_id(&self, id: &str) -> anyhow::Result<Option<Recycle>>;
+
+    async fn find_by_deposit_address(&self, address: &str) -> anyhow::Result<Option<Recycle>>;
+
+    async fn find_by_status(&self, status: RecycleStatus) -> anyhow::Result<Vec<Recycle>>;
+
+    /// Recycles whose address may still receive deposits. Paid, donated and
+    /// double-spent recycles that settled after `settled_since` stay included so that
+    /// later deposits to the same address are processed too, and reorged ones in case
+    /// their deposits reappear. Expired and cancelled recycles, and ones settled before
+    /// `settled_since`, are rarely funded again, so they are only included with
+    /// `include_idle`, until their address is handed to a new recycle.
+    async fn find_pending_deposits(
+        &self,
+        include_idle: bool,
+        settled_since: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<Recycle>>;
+
+    /// Recompute the recycle's status and aggregate deposit fields from its deposits.
+    ///
+    /// Status priority: any deposit still confirming keeps the recycle confirming, then
+    /// any eligible unpaid deposit makes it confirmed (ready for payout), then reorged,
+    /// paid, donation and finally double-spent. A failed recycle keeps its status until
+    /// an admin retries it, and a paying one until the payment processor settles its
+    /// payment.
+    async fn refresh_from_deposits(&self, id: &str, actor: Actor) -> anyhow::Result<()>;
+
+    /// Record a payment for `deposits` and move the recycle to paying, before the invoice
+    /// is sent. From then on the payment is only settled from its outcome (see
+    /// `mark_paid` and `fail_payment`), so a retry never pays a second invoice for
+    /// deposits a first one may already have paid.
+    async fn start_payment(&self, payment: &NewPayment<'_>, deposits: &[Deposit]) -> anyhow::Result<()>;
+
+    /// Record that a pending payment succeeded, paying out the deposits it covers.
+    /// The payout amount accumulates so later deposits to the same address can be paid
+    /// too, and the recycle's status follows from its deposits in the same transaction.
+    async fn mark_paid(&self, payment_hash: &str, payment_preimage: &str) -> anyhow::Result<()>;
+
+    /// Record that a pending payment definitely wasn't made. Its deposits are released
+    /// and the recycle returns to confirmed, so the next attempt pays a new invoice.
+    async fn fail_payment(&self, payment_hash: &str, error: &str) -> anyhow::Result<()>;
+
+    /// Give up on a confirmed recycle's payout for an operator to look into, keeping
+    /// `reason` to show on its page.
+    async fn mark_failed(&self, id: &str, reason: &str) -> anyhow::Result<()>;
+
+    /// Give a failed recycle a fresh set of payment attempts.
+    async fn retry_payment(&self, id: &str) -> anyhow::Result<()>;
+
+    /// Status transitions of a recycle, oldest first.
+    async fn find_events(&self, id: &str) -> anyhow::Result<Vec<RecycleEvent>>;
+
+    /// Increment payment attempts counter and return new count.
+    /// Used to enforce MAX_PAYMENT_ATTEMPTS limit.
+    async fn increment_payment_attempts(&self, id: &str) -> anyhow::Result<u32>;
+
+    /// Make sure the next address index to hand out is past every index already used by
+    /// a recycle and every index revealed in the wallet, so no deposit address is ever
+    /// given out twice. Returns the old and new index if it had to be moved forward.
+    async fn reconcile_address_index(&self, last_revealed: Option<u32>) -> anyhow::Result<Option<(u32, u32)>>;
+
+    /// Recycles paid before the payment hash was taken from the invoice have the
+    /// preimage stored in its place. Replace it with the preimage's SHA-256, which is
+    /// what the invoice committed to. Returns how many were fixed.
+    async fn repair_payment_hashes(&self) -> anyhow::Result<usize>;
+}
+
+/// What the SQLite and Postgres recycle stores do differently.
+pub trait Dialect: Send + Sync + 'static {
+    /// Starts a transaction that reads a recycle's status and then changes it
+    const BEGIN: &'static str;
+    /// Reads a recycle's status within such a transaction
+    const SELECT_STATUS: &'static str;
+}
+
+/// SQLite has a single writer. Taking the write lock when the transaction starts,
+/// rather than at its first write, makes a concurrent one wait (up to the busy
+/// timeout) instead of failing when it tries to upgrade its read lock.
+pub struct SqliteDialect;
+
+impl Dialect for SqliteDialect {
+    const BEGIN: &'static str = "BEGIN IMMEDIATE";
+    const SELECT_STATUS: &'static str = "SELECT status FROM recycles WHERE id = $1";
+}
+
+/// Postgres lets replicas write concurrently, so the recycle's row is locked when its
+/// status is read. Another replica changing the same recycle waits for the transaction
+/// rather than finding its status changed under it.
+pub struct PostgresDialect;
+
+impl Dialect for PostgresDialect {
+    const BEGIN: &'static str = "BEGIN";
+    const SELECT_STATUS: &'static str = "SELECT status FROM recycles WHERE id = $1 FOR UPDATE";
+}
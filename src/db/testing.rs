@@ -0,0 +1,77 @@
+//! Databases for the repository and migration tests: always a SQLite file, and a
+//! Postgres database as well when `TEST_POSTGRES_URL` points at a server the tests may
+//! create databases on, e.g. `postgres://postgres@localhost/postgres`.
+
+use super::{connect, run_migrations, DatabaseBackend, RecycleStore};
+use sqlx::{AnyConnection, AnyPool, Connection};
+use std::sync::Arc;
+use tempfile::TempDir;
+
+pub struct TestDb {
+    pub db: AnyPool,
+    pub backend: DatabaseBackend,
+    /// The backend's recycle store, on `db`
+    pub recycles: Arc<dyn RecycleStore>,
+    /// Where the database was created: its file's directory, or the Postgres server
+    /// and database name to drop it again
+    location: Location,
+}
+
+enum Location {
+    Sqlite { _dir: TempDir },
+    Postgres { server: String, name: String },
+}
+
+impl TestDb {
+    fn new(db: AnyPool, backend: DatabaseBackend, location: Location) -> Self {
+        let recycles = backend.recycle_store(db.clone());
+        Self { db, backend, recycles, location }
+    }
+
+    /// Close the pool and drop the Postgres database
+    pub async fn close(self) {
+        self.db.close().await;
+        if let Location::Postgres { server, name } = self.location {
+            let mut conn = AnyConnection::connect(&server).await.unwrap();
+            sqlx::query(&format!("DROP DATABASE {} WITH (FORCE)", name))
+                .execute(&mut conn)
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// A migrated database for each backend under test
+pub async fn databases() -> Vec<TestDb> {
+    let databases = empty_databases(5).await;
+    for test in &databases {
+        run_migrations(&test.db, test.backend).await.unwrap();
+    }
+    databases
+}
+
+/// An empty database for each backend under test, with pools of `max_connections`
+pub async fn empty_databases(max_connections: u32) -> Vec<TestDb> {
+    let dir = tempfile::tempdir().unwrap();
+    let url = format!("sqlite:{}?mode=rwc", dir.path().join("test.db").display());
+    let (db, backend) = connect(&url, max_connections).await.unwrap();
+    let mut databases = vec![TestDb::new(db, backend, Location::Sqlite { _dir: dir })];
+
+    if let Ok(server) = std::env::var("TEST_POSTGRES_URL") {
+        let name = format!("utxo_recycler_test_{}", uuid::Uuid::new_v4().simple());
+        sqlx::any::install_default_drivers();
+        let mut conn = AnyConnection::connect(&server).await.unwrap();
+        sqlx::query(&format!("CREATE DATABASE {}", name))
+            .execute(&mut conn)
+            .await
+            .unwrap();
+        conn.close().await.unwrap();
+
+        let mut url = url::Url::parse(&server).unwrap();
+        url.set_path(&name);
+        let (db, backend) = connect(url.as_str(), max_connections).await.unwrap();
+        databases.push(TestDb::new(db, backend, Location::Postgres { server, name }));
+    }
+
+    databases
+}
@@ -0,0 +1,127 @@
+use crate::db::Recycle;
+use crate::wallet::InputDetails;
+use crate::AppState;
+use anyhow::{anyhow, Result};
+use bdk_wallet::bitcoin::consensus::encode::deserialize_hex;
+use bdk_wallet::bitcoin::secp256k1::Secp256k1;
+use bdk_wallet::bitcoin::{Address, Psbt, Transaction, Txid};
+use bdk_wallet::miniscript::psbt::PsbtExt;
+use std::str::FromStr;
+
+/// Most inputs a submitted deposit may spend, since each costs chain backend lookups
+pub const MAX_DEPOSIT_INPUTS: usize = 500;
+
+/// A signed deposit transaction whose inputs have been checked against the payout rules.
+pub struct CheckedDeposit {
+    pub tx: Transaction,
+    /// Total paid to the recycle's deposit address
+    pub amount_sats: u64,
+    /// Each input with the reason it fails the payout rules, None if eligible
+    pub inputs: Vec<(InputDetails, Option<&'static str>)>,
+}
+
+impl CheckedDeposit {
+    pub fn is_eligible(&self) -> bool {
+        self.inputs.iter().all(|(_, reason)| reason.is_none())
+    }
+}
+
+/// Parse a signed deposit, either a raw transaction (hex) or a PSBT (base64). PSBT
+/// inputs the user's wallet signed but didn't finalize are finalized here.
+fn parse_signed_transaction(raw: &str) -> Result<Transaction> {
+    let raw = raw.trim();
+    if !raw.is_empty() && raw.chars().all(|c| c.is_ascii_hexdigit()) {
+        return deserialize_hex(raw).map_err(|e| anyhow!("Invalid transaction: {}", e));
+    }
+
+    let mut psbt = Psbt::from_str(raw).map_err(|e| anyhow!("Invalid PSBT: {}", e))?;
+    let secp = Secp256k1::verification_only();
+    for index in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[index];
+        if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+            continue;
+        }
+        psbt.finalize_inp_mut(&secp, index)
+            .map_err(|e| anyhow!("PSBT input {} is not fully signed: {}", index, e))?;
+    }
+
+    Ok(psbt.extract_tx()?)
+}
+
+/// Verify a signed deposit for a recycle: it must pay the recycle's deposit address,
+/// and every input is looked up and checked against the payout rules. Nothing is
+/// broadcast here, so an ineligible deposit can be rejected without the user losing funds.
+pub async fn check_signed_deposit(
+    state: &AppState,
+    recycle: &Recycle,
+    raw: &str,
+) -> Result<CheckedDeposit> {
+    if !recycle.status.accepts_deposits() || recycle.address_released_at.is_some() {
+        return Err(anyhow!(
+            "Recycle is {}, it is no longer accepting deposits",
+            recycle.status.as_str()
+        ));
+    }
+
+    let tx = parse_signed_transaction(raw)?;
+    if tx.input.is_empty() || tx.input.len() > MAX_DEPOSIT_INPUTS {
+        return Err(anyhow!("Deposit must spend between 1 and {} inputs", MAX_DEPOSIT_INPUTS));
+    }
+
+    let deposit_script = Address::from_str(&recycle.deposit_address)?
+        .require_network(state.config.network)?
+        .script_pubkey();
+    let amount_sats: u64 = tx
+        .output
+        .iter()
+        .filter(|output| output.script_pubkey == deposit_script)
+        .map(|output| output.value.to_sat())
+        .sum();
+    if amount_sats == 0 {
+        return Err(anyhow!(
+            "Transaction does not pay the deposit address {}",
+            recycle.deposit_address
+        ));
+    }
+
+    let outpoints = tx.input.iter().map(|input| input.previous_output).collect();
+    let inputs = state
+        .wallet
+        .get_outpoint_details(outpoints)
+        .await?
+        .into_iter()
+        .map(|input| {
+            let reason = input.ineligibility_reason(
+                state.config.cutoff_block_height,
+                state.config.max_input_sats,
+            );
+            (input, reason)
+        })
+        .collect();
+
+    Ok(CheckedDeposit {
+        tx,
+        amount_sats,
+        inputs,
+    })
+}
+
+/// Broadcast a deposit that passed `check_signed_deposit`. The deposit monitor picks it
+/// up from the mempool like any other deposit.
+pub async fn broadcast_deposit(state: &AppState, recycle: &Recycle, deposit: CheckedDeposit) -> Result<Txid> {
+    if !deposit.is_eligible() {
+        return Err(anyhow!("Deposit has ineligible inputs, refusing to broadcast"));
+    }
+
+    let txid = state.wallet.broadcast(deposit.tx).await?;
+
+    tracing::info!(
+        "Broadcast submitted deposit {} for recycle {}: {} inputs, {} sats",
+        txid,
+        recycle.id,
+        deposit.inputs.len(),
+        deposit.amount_sats
+    );
+
+    Ok(txid)
+}
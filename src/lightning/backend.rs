@@ -0,0 +1,99 @@
+use crate::config::{Config, LightningBackendKind};
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use bitcoin::hex::DisplayHex;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{ClnRestClient, LnbitsClient, LndRestClient, NwcClient};
+
+/// How long to wait for a REST backend to settle a payment. Nodes answer once the
+/// payment has succeeded or failed, which can take a while on a long route.
+pub const PAYMENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct PaymentResult {
+    /// Hex preimage as reported by the backend. It only proves payment once checked
+    /// against the invoice's payment hash.
+    pub preimage: String,
+}
+
+/// What a backend knows about an outgoing payment, by payment hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentStatus {
+    Succeeded { preimage: String },
+    /// Still in flight
+    Pending,
+    Failed,
+    /// The backend has no record of the payment
+    NotFound,
+}
+
+/// A Lightning node or wallet that payouts are sent from.
+#[async_trait]
+pub trait LightningBackend: Send + Sync {
+    /// Short name for logs
+    fn name(&self) -> &'static str;
+
+    /// Pay a BOLT11 invoice. An error doesn't mean the payment failed, only that its
+    /// outcome isn't known.
+    async fn pay_invoice(&self, bolt11: &str) -> Result<PaymentResult>;
+
+    /// Look up an outgoing payment by its hex payment hash.
+    async fn lookup_payment(&self, payment_hash: &str) -> Result<PaymentStatus>;
+
+    /// Spendable balance in millisatoshis.
+    async fn get_balance(&self) -> Result<u64>;
+}
+
+/// Build the Lightning backend selected in the configuration.
+pub async fn lightning_backend_from_config(config: &Config) -> Result<Arc<dyn LightningBackend>> {
+    let kind = config.lightning_backend;
+    let required = |value: &'_ Option<String>, var: &str| -> Result<String> {
+        value
+            .clone()
+            .ok_or_else(|| anyhow!("{} environment variable required when LIGHTNING_BACKEND={}", var, kind.as_str()))
+    };
+
+    let backend: Arc<dyn LightningBackend> = match kind {
+        LightningBackendKind::Nwc => Arc::new(NwcClient::new(&required(&config.nwc_uri, "NWC_URI")?).await?),
+        LightningBackendKind::Lnd => {
+            let macaroon = match (&config.lnd_macaroon, &config.lnd_macaroon_path) {
+                (Some(macaroon), _) => macaroon.clone(),
+                (None, Some(path)) => {
+                    std::fs::read(path)
+                        .with_context(|| format!("Failed to read LND macaroon {}", path))?
+                        .to_lower_hex_string()
+                }
+                (None, None) => return Err(anyhow!("LND_MACAROON or LND_MACAROON_PATH required when LIGHTNING_BACKEND=lnd")),
+            };
+            Arc::new(LndRestClient::new(
+                &required(&config.lnd_rest_url, "LND_REST_URL")?,
+                &macaroon,
+                config.lnd_tls_cert_path.as_deref(),
+            )?)
+        }
+        LightningBackendKind::Cln => Arc::new(ClnRestClient::new(
+            &required(&config.cln_rest_url, "CLN_REST_URL")?,
+            &required(&config.cln_rune, "CLN_RUNE")?,
+            config.cln_tls_cert_path.as_deref(),
+        )?),
+        LightningBackendKind::Lnbits => Arc::new(LnbitsClient::new(
+            &required(&config.lnbits_url, "LNBITS_URL")?,
+            &required(&config.lnbits_api_key, "LNBITS_API_KEY")?,
+        )?),
+    };
+
+    Ok(backend)
+}
+
+/// HTTP client for a REST backend, trusting `tls_cert_path` in addition to the system
+/// roots so nodes with a self-signed certificate can be reached.
+pub(super) fn rest_client(tls_cert_path: Option<&str>) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().timeout(PAYMENT_TIMEOUT);
+    if let Some(path) = tls_cert_path {
+        let pem = std::fs::read(path).with_context(|| format!("Failed to read TLS certificate {}", path))?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+    }
+    Ok(builder.build()?)
+}
@@ -0,0 +1,276 @@
+use anyhow::{anyhow, Result};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hex::FromHex;
+use bitcoin::Network;
+use lightning_invoice::Bolt11InvoiceDescriptionRef;
+use std::str::FromStr;
+
+/// The fields of a BOLT11 invoice the service relies on. Decoding is left to the
+/// `lightning-invoice` crate, which refuses an invoice unless it is well-formed,
+/// carries a payment secret and is signed by its payee.
+#[derive(Debug, Clone)]
+pub struct Bolt11Invoice {
+    /// Currency prefix: bc, tb, tbs or bcrt
+    pub currency: String,
+    /// None for an invoice that lets the payer choose the amount
+    pub amount_msat: Option<u64>,
+    /// Unix time the invoice was created
+    pub timestamp: u64,
+    pub expiry_secs: u64,
+    pub payment_hash: sha256::Hash,
+    pub description_hash: Option<sha256::Hash>,
+}
+
+impl Bolt11Invoice {
+    /// Whether the hex `preimage` is the one the invoice's payment hash commits to,
+    /// i.e. proof that it was paid.
+    pub fn is_preimage(&self, preimage: &str) -> bool {
+        match Vec::<u8>::from_hex(preimage) {
+            Ok(bytes) => sha256::Hash::hash(&bytes) == self.payment_hash,
+            Err(_) => false,
+        }
+    }
+
+    /// Whether the invoice is payable on `network`. Testnet3 and testnet4 share a prefix.
+    pub fn is_for_network(&self, network: Network) -> bool {
+        let currency = match network {
+            Network::Testnet | Network::Testnet4 => "tb",
+            Network::Signet => "tbs",
+            Network::Regtest => "bcrt",
+            _ => "bc",
+        };
+        self.currency == currency
+    }
+
+    /// Unix time after which the invoice can no longer be paid
+    pub fn expires_at(&self) -> u64 {
+        self.timestamp.saturating_add(self.expiry_secs)
+    }
+}
+
+impl FromStr for Bolt11Invoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let s = s.strip_prefix("lightning:").unwrap_or(s);
+        let invoice = lightning_invoice::Bolt11Invoice::from_str(s)
+            .map_err(|e| anyhow!("Invalid BOLT11 invoice: {}", e))?;
+
+        let description_hash = match invoice.description() {
+            Bolt11InvoiceDescriptionRef::Hash(hash) => Some(hash.0),
+            Bolt11InvoiceDescriptionRef::Direct(_) => None,
+        };
+
+        Ok(Self {
+            currency: invoice.currency().to_string(),
+            amount_msat: invoice.amount_milli_satoshis(),
+            timestamp: invoice.duration_since_epoch().as_secs(),
+            expiry_secs: invoice.expiry_time().as_secs(),
+            payment_hash: *invoice.payment_hash(),
+            description_hash,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use lightning_invoice::{
+        Bolt11InvoiceDescription, Currency, Description, InvoiceBuilder, PaymentSecret, Sha256,
+        SignedRawBolt11Invoice,
+    };
+    use std::time::Duration;
+
+    /// An invoice to sign in tests, paying `TEST_PAYMENT_HASH`
+    pub(crate) struct TestInvoice {
+        pub currency: Currency,
+        pub amount_msat: Option<u64>,
+        pub timestamp: u64,
+        pub expiry_secs: u64,
+        /// A plain description is used without one
+        pub description_hash: Option<sha256::Hash>,
+        /// Payee the invoice names, which needn't be the key that signs it
+        pub payee: Option<PublicKey>,
+    }
+
+    pub(crate) const TEST_PAYMENT_HASH: [u8; 32] = [7; 32];
+
+    impl TestInvoice {
+        pub(crate) fn sign(&self, key: &SecretKey) -> String {
+            let description = match self.description_hash {
+                Some(hash) => Bolt11InvoiceDescription::Hash(Sha256(hash)),
+                None => Bolt11InvoiceDescription::Direct(Description::new("test".to_string()).unwrap()),
+            };
+            let mut builder = InvoiceBuilder::new(self.currency.clone())
+                .invoice_description(description)
+                .payment_hash(sha256::Hash::from_byte_array(TEST_PAYMENT_HASH))
+                .payment_secret(PaymentSecret([9; 32]))
+                .duration_since_epoch(Duration::from_secs(self.timestamp))
+                .min_final_cltv_expiry_delta(18)
+                .expiry_time(Duration::from_secs(self.expiry_secs));
+            if let Some(amount_msat) = self.amount_msat {
+                builder = builder.amount_milli_satoshis(amount_msat);
+            }
+            if let Some(payee) = self.payee {
+                builder = builder.payee_pub_key(payee);
+            }
+
+            builder
+                .build_signed(|message| Secp256k1::new().sign_ecdsa_recoverable(message, key))
+                .unwrap()
+                .to_string()
+        }
+    }
+
+    // Test vectors from the BOLT11 specification, all signed by this node
+    const SPEC_PAYEE: &str = "03e7156ae33b0a208d0744199163177e909e80176e55d97a2f221ede0f934dd9ad";
+    const SPEC_PAYMENT_HASH: &str = "0001020304050607080900010203040506070809000102030405060708090102";
+    const SPEC_TIMESTAMP: u64 = 1_496_314_658;
+
+    const DONATION: &str = "lnbc1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6twvus8g6rfwvs8qun0dfjkxaq9qrsgq357wnc5r2ueh7ck6q93dj32dlqnls087fxdwk8qakdyafkq3yap9us6v52vjjsrvywa6rt52cm9r9zqt8r2t7mlcwspyetp5h2tztugp9lfyql";
+    const COFFEE: &str = "lnbc2500u1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpu9qrsgquk0rl77nj30yxdy8j9vdx85fkpmdla2087ne0xh8nhedh8w27kyke0lp53ut353s06fv3qfegext0eh0ymjpf39tuven09sam30g4vgpfna3rh";
+    const NONSENSE: &str = "lnbc2500u1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpquwpc4curk03c9wlrswe78q4eyqc7d8d0xqzpu9qrsgqhtjpauu9ur7fw2thcl4y9vfvh4m9wlfyz2gem29g5ghe2aak2pm3ps8fdhtceqsaagty2vph7utlgj48u0ged6a337aewvraedendscp573dxr";
+    const HASHED_DESCRIPTION: &str = "lnbc20m1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqhp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqs9qrsgq7ea976txfraylvgzuxs8kgcw23ezlrszfnh8r6qtfpr6cxga50aj6txm9rxrydzd06dfeawfk6swupvz4erwnyutnjq7x39ymw6j38gp7ynn44";
+    const TESTNET: &str = "lntb20m1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygshp58yjmdan79s6qqdhdzgynm4zwqd5d7xmw5fk98klysy043l2ahrqspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqfpp3x9et2e20v6pu37c5d9vax37wxq72un989qrsgqdj545axuxtnfemtpwkc45hx9d2ft7x04mt8q7y6t0k2dge9e7h8kpy9p34ytyslj3yu569aalz2xdk8xkd7ltxqld94u8h2esmsmacgpghe9k8";
+
+    fn parse(invoice: &str) -> Bolt11Invoice {
+        invoice.parse().unwrap()
+    }
+
+    fn parse_error(invoice: &str) -> String {
+        invoice.parse::<Bolt11Invoice>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn reads_spec_invoices() {
+        let donation = parse(DONATION);
+        assert_eq!(donation.currency, "bc");
+        assert_eq!(donation.amount_msat, None);
+        assert_eq!(donation.timestamp, SPEC_TIMESTAMP);
+        assert_eq!(donation.expiry_secs, 3600);
+        assert_eq!(donation.payment_hash.to_string(), SPEC_PAYMENT_HASH);
+        assert_eq!(donation.description_hash, None);
+
+        let coffee = parse(COFFEE);
+        assert_eq!(coffee.amount_msat, Some(250_000_000));
+        assert_eq!(coffee.expiry_secs, 60);
+        assert_eq!(coffee.expires_at(), SPEC_TIMESTAMP + 60);
+        assert_eq!(parse(NONSENSE).amount_msat, Some(250_000_000));
+
+        let hashed = parse(HASHED_DESCRIPTION);
+        assert_eq!(hashed.amount_msat, Some(2_000_000_000));
+        assert_eq!(
+            hashed.description_hash,
+            Some(sha256::Hash::hash(
+                b"One piece of chocolate cake, one icecream cone, one pickle, one slice of swiss cheese, one slice of salami, one lollypop, one piece of cherry pie, one sausage, one cupcake, and one slice of watermelon"
+            ))
+        );
+    }
+
+    #[test]
+    fn accepts_uppercase_and_uri_prefix() {
+        assert_eq!(parse(&COFFEE.to_uppercase()).payment_hash, parse(COFFEE).payment_hash);
+        assert_eq!(parse(&format!("lightning:{}", COFFEE)).amount_msat, Some(250_000_000));
+    }
+
+    #[test]
+    fn checks_the_network() {
+        assert!(parse(COFFEE).is_for_network(Network::Bitcoin));
+        assert!(!parse(COFFEE).is_for_network(Network::Testnet));
+        assert!(!parse(COFFEE).is_for_network(Network::Regtest));
+
+        let testnet = parse(TESTNET);
+        assert_eq!(testnet.currency, "tb");
+        assert!(testnet.is_for_network(Network::Testnet));
+        assert!(testnet.is_for_network(Network::Testnet4));
+        assert!(!testnet.is_for_network(Network::Bitcoin));
+        assert!(!testnet.is_for_network(Network::Signet));
+    }
+
+    #[test]
+    fn checks_preimages() {
+        let invoice = parse(COFFEE);
+        assert!(!invoice.is_preimage(SPEC_PAYMENT_HASH));
+        assert!(!invoice.is_preimage("not hex"));
+
+        let preimage = [7u8; 32];
+        let invoice = Bolt11Invoice {
+            payment_hash: sha256::Hash::hash(&preimage),
+            ..invoice
+        };
+        assert!(invoice.is_preimage(&"07".repeat(32)));
+    }
+
+    #[test]
+    fn refuses_a_bad_checksum() {
+        let mut invoice = NONSENSE.to_string();
+        invoice.pop();
+        invoice.push('t');
+        assert!(parse_error(&invoice).contains("Invalid BOLT11 invoice"));
+    }
+
+    // The remaining vectors are the specification's invalid invoices
+
+    #[test]
+    fn refuses_malformed_strings() {
+        // No separator
+        assert!(parse_error("pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpquwpc4curk03c9wlrswe78q4eyqc7d8d0xqzpuyk0sg5g70me25alkluzd2x62aysf2pyy8edtjeevuv4p2d5p76r4zkmneet7uvyakky2zr4cusd45tftc9c5fh0nnqpnl2jfll544esqchsrny").contains("Invalid BOLT11 invoice"));
+        // Mixed case
+        assert!(parse_error("LNBC2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpquwpc4curk03c9wlrswe78q4eyqc7d8d0xqzpuyk0sg5g70me25alkluzd2x62aysf2pyy8edtjeevuv4p2d5p76r4zkmneet7uvyakky2zr4cusd45tftc9c5fh0nnqpnl2jfll544esqchsrny").contains("Invalid BOLT11 invoice"));
+        // Too short to hold a signature
+        assert!(
+            parse_error("lnbc1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2pkx2ctnv5sxxmmwwd5kgetjypeh2ursdae8g6na6hlh")
+                .contains("too short")
+        );
+        assert!(parse_error("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4").contains("Invalid BOLT11 invoice"));
+    }
+
+    #[test]
+    fn refuses_a_bad_signature() {
+        let invoice = "lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpusp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygs9qrsgqwgt7mcn5yqw3yx0w94pswkpq6j9uh6xfqqqtsk4tnarugeektd4hg5975x9am52rz4qskukxdmjemg92vvqz8nvmsye63r5ykel43pgz7zq0g2";
+        assert!(parse_error(invoice).contains("signature is invalid"));
+    }
+
+    #[test]
+    fn refuses_an_invoice_not_signed_by_its_payee() {
+        let key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let other = SecretKey::from_slice(&[2; 32]).unwrap();
+        let invoice = |payee: &SecretKey| TestInvoice {
+            currency: Currency::Regtest,
+            amount_msat: Some(1_000),
+            timestamp: SPEC_TIMESTAMP,
+            expiry_secs: 3600,
+            description_hash: None,
+            payee: Some(PublicKey::from_secret_key(&Secp256k1::new(), payee)),
+        };
+
+        assert_eq!(parse(&invoice(&key).sign(&key)).amount_msat, Some(1_000));
+        assert!(parse_error(&invoice(&other).sign(&key)).contains("signature is invalid"));
+    }
+
+    #[test]
+    fn refuses_an_invoice_without_a_payment_secret() {
+        // The specification's vectors from before payment secrets were required
+        let coffee = "lnbc2500u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpuaztrnwngzn3kdzw5hydlzf03qdgm2hdq27cqv3agm2awhz5se903vruatfhq77w3ls4evs3ch9zw97j25emudupq63nyw24cg27h2rspfj9srp";
+        assert!(parse_error(coffee).contains("missing the mandatory payment secret"));
+    }
+
+    #[test]
+    fn refuses_bad_multipliers() {
+        // An unknown multiplier and sub-millisatoshi precision
+        let unknown = "lnbc2500x1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpusp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygs9qrsgqrrzc4cvfue4zp3hggxp47ag7xnrlr8vgcmkjxk3j5jqethnumgkpqp23z9jclu3v0a7e0aruz366e9wqdykw6dxhdzcjjhldxq0w6wgqcnu43j";
+        assert!(parse_error(unknown).contains("unknown SI prefix"));
+        let precision = "lnbc2500000001p1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpusp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygs9qrsgq0lzc236j96a95uv0m3umg28gclm5lqxtqqwk32uuk4k6673k6n5kfvx3d2h8s295fad45fdhmusm8sjudfhlf6dcsxmfvkeywmjdkxcp99202x";
+        assert!(parse_error(precision).contains("whole number of millisatoshis"));
+    }
+
+    #[test]
+    fn spec_invoices_are_signed_by_the_spec_node() {
+        for invoice in [DONATION, COFFEE, NONSENSE, HASHED_DESCRIPTION, TESTNET] {
+            let signed = SignedRawBolt11Invoice::from_str(invoice).unwrap();
+            assert_eq!(signed.recover_payee_pub_key().unwrap().0.to_string(), SPEC_PAYEE);
+        }
+    }
+}
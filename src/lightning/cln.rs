@@ -0,0 +1,240 @@
+use super::{rest_client, LightningBackend, PaymentResult, PaymentStatus};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// Core Lightning's REST API (the `clnrest` plugin), authenticated with a rune that
+/// may call `pay`, `listpays` and `listfunds`.
+pub struct ClnRestClient {
+    client: reqwest::Client,
+    base_url: String,
+    rune: String,
+}
+
+#[derive(Deserialize)]
+struct ClnPayResponse {
+    status: String,
+    payment_preimage: Option<String>,
+    payment_hash: String,
+}
+
+#[derive(Deserialize)]
+struct ClnListPaysResponse {
+    pays: Vec<ClnPay>,
+}
+
+#[derive(Deserialize)]
+struct ClnPay {
+    status: String,
+    preimage: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClnListFundsResponse {
+    channels: Vec<ClnChannel>,
+}
+
+#[derive(Deserialize)]
+struct ClnChannel {
+    state: String,
+    our_amount_msat: u64,
+}
+
+impl ClnRestClient {
+    pub fn new(base_url: &str, rune: &str, tls_cert_path: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            client: rest_client(tls_cert_path)?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            rune: rune.to_string(),
+        })
+    }
+
+    /// Call an RPC method. Every method is a POST with its parameters as a JSON object.
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        let response = self
+            .client
+            .post(format!("{}/v1/{}", self.base_url, method))
+            .header("Rune", &self.rune)
+            .json(&params)
+            .send()
+            .await?;
+        let status = response.status();
+        let body: Value = response.json().await?;
+
+        if !status.is_success() {
+            let message = body["message"].as_str().unwrap_or("unknown error");
+            return Err(anyhow!("CLN {} failed ({}): {}", method, status, message));
+        }
+
+        Ok(serde_json::from_value(body)?)
+    }
+}
+
+#[async_trait]
+impl LightningBackend for ClnRestClient {
+    fn name(&self) -> &'static str {
+        "cln"
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<PaymentResult> {
+        let result: ClnPayResponse = self.call("pay", json!({ "bolt11": bolt11 })).await?;
+
+        match (result.status.as_str(), result.payment_preimage) {
+            ("complete", Some(preimage)) => Ok(PaymentResult { preimage }),
+            (status, _) => Err(anyhow!(
+                "CLN payment {} is {}. Payment status unknown.",
+                result.payment_hash,
+                status
+            )),
+        }
+    }
+
+    async fn lookup_payment(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        let result: ClnListPaysResponse = self.call("listpays", json!({ "payment_hash": payment_hash })).await?;
+
+        // A payment retried after failing is listed once per attempt
+        if let Some(preimage) = result
+            .pays
+            .iter()
+            .find(|pay| pay.status == "complete")
+            .and_then(|pay| pay.preimage.clone())
+        {
+            return Ok(PaymentStatus::Succeeded { preimage });
+        }
+        if result.pays.iter().any(|pay| pay.status == "pending") {
+            return Ok(PaymentStatus::Pending);
+        }
+        if result.pays.is_empty() {
+            return Ok(PaymentStatus::NotFound);
+        }
+        Ok(PaymentStatus::Failed)
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        let result: ClnListFundsResponse = self.call("listfunds", json!({})).await?;
+
+        Ok(result
+            .channels
+            .iter()
+            .filter(|channel| channel.state == "CHANNELD_NORMAL")
+            .map(|channel| channel.our_amount_msat)
+            .sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::testing::*;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use bitcoin::hex::{DisplayHex, FromHex};
+
+    type Response = Result<Json<Value>, (StatusCode, Json<Value>)>;
+
+    fn authorized(headers: &HeaderMap) -> Result<(), (StatusCode, Json<Value>)> {
+        match headers.get("Rune") {
+            Some(rune) if rune == TEST_SECRET => Ok(()),
+            _ => Err((
+                StatusCode::UNAUTHORIZED,
+                Json(json!({ "code": 1501, "message": "Not authorized: Not derived from master" })),
+            )),
+        }
+    }
+
+    async fn pay(headers: HeaderMap, Json(body): Json<Value>) -> Response {
+        authorized(&headers)?;
+        match body["bolt11"].as_str() {
+            Some(PAYABLE_INVOICE) => Ok(Json(json!({
+                "status": "complete",
+                "payment_preimage": TEST_PREIMAGE.to_lower_hex_string(),
+                "payment_hash": SUCCEEDED_HASH.to_lower_hex_string(),
+            }))),
+            _ => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "code": 210, "message": "Ran out of routes to try after 3 attempts" })),
+            )),
+        }
+    }
+
+    /// Every attempt at the payment, the earlier ones failed
+    async fn listpays(headers: HeaderMap, Json(body): Json<Value>) -> Response {
+        authorized(&headers)?;
+        let failed = json!({ "status": "failed" });
+        let hash = Vec::<u8>::from_hex(body["payment_hash"].as_str().unwrap()).unwrap();
+        let pays = match <[u8; 32]>::try_from(hash).unwrap() {
+            SUCCEEDED_HASH => vec![
+                failed,
+                json!({ "status": "complete", "preimage": TEST_PREIMAGE.to_lower_hex_string() }),
+            ],
+            PENDING_HASH => vec![failed, json!({ "status": "pending" })],
+            FAILED_HASH => vec![failed.clone(), failed],
+            _ => vec![],
+        };
+        Ok(Json(json!({ "pays": pays })))
+    }
+
+    /// Only channels in normal operation can send
+    async fn listfunds(headers: HeaderMap) -> Response {
+        authorized(&headers)?;
+        Ok(Json(json!({
+            "outputs": [{ "amount_msat": 50_000_000, "status": "confirmed" }],
+            "channels": [
+                { "state": "CHANNELD_NORMAL", "our_amount_msat": 1_500_123 },
+                { "state": "ONCHAIN", "our_amount_msat": 7_000_000 },
+                { "state": "CHANNELD_NORMAL", "our_amount_msat": 2_000 },
+            ],
+        })))
+    }
+
+    async fn cln(rune: &str) -> ClnRestClient {
+        let app = Router::new()
+            .route("/v1/pay", post(pay))
+            .route("/v1/listpays", post(listpays))
+            .route("/v1/listfunds", post(listfunds));
+        ClnRestClient::new(&serve(app).await, rune, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pays_invoices() {
+        let cln = cln(TEST_SECRET).await;
+
+        let result = cln.pay_invoice(PAYABLE_INVOICE).await.unwrap();
+        assert_eq!(result.preimage, TEST_PREIMAGE.to_lower_hex_string());
+
+        let error = cln.pay_invoice(UNROUTABLE_INVOICE).await.unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "CLN pay failed (500 Internal Server Error): Ran out of routes to try after 3 attempts"
+        );
+    }
+
+    #[tokio::test]
+    async fn looks_up_payments() {
+        let cln = cln(TEST_SECRET).await;
+        let lookup = |hash: [u8; 32]| {
+            let cln = &cln;
+            async move { cln.lookup_payment(&hash.to_lower_hex_string()).await.unwrap() }
+        };
+
+        let preimage = TEST_PREIMAGE.to_lower_hex_string();
+        assert_eq!(lookup(SUCCEEDED_HASH).await, PaymentStatus::Succeeded { preimage });
+        assert_eq!(lookup(PENDING_HASH).await, PaymentStatus::Pending);
+        assert_eq!(lookup(FAILED_HASH).await, PaymentStatus::Failed);
+        assert_eq!(lookup(UNKNOWN_HASH).await, PaymentStatus::NotFound);
+    }
+
+    #[tokio::test]
+    async fn reports_the_balance_of_normal_channels_in_msat() {
+        assert_eq!(cln(TEST_SECRET).await.get_balance().await.unwrap(), 1_502_123);
+    }
+
+    #[tokio::test]
+    async fn reports_refused_runes() {
+        let error = cln("0201036c6e65").await.get_balance().await.unwrap_err().to_string();
+        assert_eq!(error, "CLN listfunds failed (401 Unauthorized): Not authorized: Not derived from master");
+    }
+}
@@ -0,0 +1,223 @@
+use super::{rest_client, LightningBackend, PaymentResult, PaymentStatus};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// An LNbits wallet, authenticated with its admin key.
+pub struct LnbitsClient {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct LnbitsPayResponse {
+    payment_hash: String,
+}
+
+#[derive(Deserialize)]
+struct LnbitsPaymentResponse {
+    paid: bool,
+    /// Only sent by newer versions, which also report failed payments
+    status: Option<String>,
+    preimage: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LnbitsWalletResponse {
+    /// In millisatoshis
+    balance: i64,
+}
+
+impl LnbitsClient {
+    pub fn new(base_url: &str, api_key: &str) -> Result<Self> {
+        Ok(Self {
+            client: rest_client(None)?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.base_url, path))
+            .header("X-Api-Key", &self.api_key)
+    }
+}
+
+#[async_trait]
+impl LightningBackend for LnbitsClient {
+    fn name(&self) -> &'static str {
+        "lnbits"
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<PaymentResult> {
+        let response = self
+            .request(reqwest::Method::POST, "/api/v1/payments")
+            .json(&json!({ "out": true, "bolt11": bolt11 }))
+            .send()
+            .await?;
+        let status = response.status();
+        let body: Value = response.json().await?;
+        if !status.is_success() {
+            return Err(anyhow!("LNbits payment failed ({}): {}", status, detail(&body)));
+        }
+
+        // The response only carries the hash, the preimage comes from the payment record
+        let result: LnbitsPayResponse = serde_json::from_value(body)?;
+        match self.lookup_payment(&result.payment_hash).await? {
+            PaymentStatus::Succeeded { preimage } => Ok(PaymentResult { preimage }),
+            PaymentStatus::Failed => Err(anyhow!("LNbits payment {} failed", result.payment_hash)),
+            other => Err(anyhow!(
+                "LNbits payment {} is {:?}. Payment status unknown.",
+                result.payment_hash,
+                other
+            )),
+        }
+    }
+
+    async fn lookup_payment(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        let response = self
+            .request(reqwest::Method::GET, &format!("/api/v1/payments/{}", payment_hash))
+            .send()
+            .await?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(PaymentStatus::NotFound);
+        }
+        let body: Value = response.json().await?;
+        if !status.is_success() {
+            return Err(anyhow!("LNbits payment lookup failed ({}): {}", status, detail(&body)));
+        }
+
+        let payment: LnbitsPaymentResponse = serde_json::from_value(body)?;
+        Ok(match (payment.paid, payment.preimage, payment.status.as_deref()) {
+            (true, Some(preimage), _) => PaymentStatus::Succeeded { preimage },
+            (_, _, Some("failed")) => PaymentStatus::Failed,
+            _ => PaymentStatus::Pending,
+        })
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        let response = self.request(reqwest::Method::GET, "/api/v1/wallet").send().await?;
+        let status = response.status();
+        let body: Value = response.json().await?;
+        if !status.is_success() {
+            return Err(anyhow!("LNbits wallet request failed ({}): {}", status, detail(&body)));
+        }
+
+        let wallet: LnbitsWalletResponse = serde_json::from_value(body)?;
+        Ok(wallet.balance.max(0) as u64)
+    }
+}
+
+/// The reason LNbits gives for an error response
+fn detail(body: &Value) -> String {
+    match &body["detail"] {
+        Value::String(detail) => detail.clone(),
+        Value::Null => "unknown error".to_string(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::testing::*;
+    use axum::extract::Path;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use bitcoin::hex::{DisplayHex, FromHex};
+
+    type Response = Result<Json<Value>, (StatusCode, Json<Value>)>;
+
+    /// A payment in flight as LNbits reported it before payments had a `status`
+    const UNSTATUSED_PENDING_HASH: [u8; 32] = [0xee; 32];
+
+    fn authorized(headers: &HeaderMap) -> Result<(), (StatusCode, Json<Value>)> {
+        match headers.get("X-Api-Key") {
+            Some(key) if key == TEST_SECRET => Ok(()),
+            _ => Err((StatusCode::UNAUTHORIZED, Json(json!({ "detail": "Invalid adminkey." })))),
+        }
+    }
+
+    async fn pay(headers: HeaderMap, Json(body): Json<Value>) -> Response {
+        authorized(&headers)?;
+        match body["bolt11"].as_str() {
+            Some(PAYABLE_INVOICE) => Ok(Json(json!({ "payment_hash": SUCCEEDED_HASH.to_lower_hex_string() }))),
+            _ => Err((
+                StatusCode::from_u16(520).unwrap(),
+                Json(json!({ "detail": "Payment failed: no route" })),
+            )),
+        }
+    }
+
+    async fn payment(headers: HeaderMap, Path(hash): Path<String>) -> Response {
+        authorized(&headers)?;
+        let payment = match <[u8; 32]>::try_from(Vec::<u8>::from_hex(&hash).unwrap()).unwrap() {
+            SUCCEEDED_HASH => json!({
+                "paid": true,
+                "status": "success",
+                "preimage": TEST_PREIMAGE.to_lower_hex_string(),
+            }),
+            PENDING_HASH => json!({ "paid": false, "status": "pending", "preimage": null }),
+            FAILED_HASH => json!({ "paid": false, "status": "failed", "preimage": null }),
+            UNSTATUSED_PENDING_HASH => json!({ "paid": false, "preimage": null }),
+            _ => return Err((StatusCode::NOT_FOUND, Json(json!({ "detail": "Payment does not exist." })))),
+        };
+        Ok(Json(payment))
+    }
+
+    async fn wallet(headers: HeaderMap) -> Response {
+        authorized(&headers)?;
+        Ok(Json(json!({ "name": "recycler", "balance": 1_500_123 })))
+    }
+
+    async fn lnbits(api_key: &str) -> LnbitsClient {
+        let app = Router::new()
+            .route("/api/v1/payments", post(pay))
+            .route("/api/v1/payments/:hash", get(payment))
+            .route("/api/v1/wallet", get(wallet));
+        LnbitsClient::new(&serve(app).await, api_key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pays_invoices() {
+        let lnbits = lnbits(TEST_SECRET).await;
+
+        let result = lnbits.pay_invoice(PAYABLE_INVOICE).await.unwrap();
+        assert_eq!(result.preimage, TEST_PREIMAGE.to_lower_hex_string());
+
+        let error = lnbits.pay_invoice(UNROUTABLE_INVOICE).await.unwrap_err().to_string();
+        assert_eq!(error, "LNbits payment failed (520 <unknown status code>): Payment failed: no route");
+    }
+
+    #[tokio::test]
+    async fn looks_up_payments() {
+        let lnbits = lnbits(TEST_SECRET).await;
+        let lookup = |hash: [u8; 32]| {
+            let lnbits = &lnbits;
+            async move { lnbits.lookup_payment(&hash.to_lower_hex_string()).await.unwrap() }
+        };
+
+        let preimage = TEST_PREIMAGE.to_lower_hex_string();
+        assert_eq!(lookup(SUCCEEDED_HASH).await, PaymentStatus::Succeeded { preimage });
+        assert_eq!(lookup(PENDING_HASH).await, PaymentStatus::Pending);
+        assert_eq!(lookup(UNSTATUSED_PENDING_HASH).await, PaymentStatus::Pending);
+        assert_eq!(lookup(FAILED_HASH).await, PaymentStatus::Failed);
+        assert_eq!(lookup(UNKNOWN_HASH).await, PaymentStatus::NotFound);
+    }
+
+    #[tokio::test]
+    async fn reports_the_balance_in_msat() {
+        assert_eq!(lnbits(TEST_SECRET).await.get_balance().await.unwrap(), 1_500_123);
+    }
+
+    #[tokio::test]
+    async fn reports_refused_keys() {
+        let error = lnbits("0201036c6e65").await.get_balance().await.unwrap_err().to_string();
+        assert_eq!(error, "LNbits wallet request failed (401 Unauthorized): Invalid adminkey.");
+    }
+}
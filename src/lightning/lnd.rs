@@ -0,0 +1,260 @@
+use super::{rest_client, LightningBackend, PaymentResult, PaymentStatus};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+use base64::Engine;
+use bitcoin::hex::{DisplayHex, FromHex};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+/// LND's REST API, authenticated with a macaroon that may send payments and read
+/// balances (such as `admin.macaroon`).
+pub struct LndRestClient {
+    client: reqwest::Client,
+    base_url: String,
+    macaroon: String,
+}
+
+#[derive(Deserialize)]
+struct LndSendResponse {
+    #[serde(default)]
+    payment_error: String,
+    /// Base64 encoded, as are all bytes fields in LND's REST API
+    #[serde(default)]
+    payment_preimage: String,
+}
+
+#[derive(Deserialize)]
+struct LndPayment {
+    status: String,
+    /// Hex encoded
+    #[serde(default)]
+    payment_preimage: String,
+}
+
+#[derive(Deserialize)]
+struct LndChannelBalance {
+    local_balance: Option<LndAmount>,
+}
+
+#[derive(Deserialize)]
+struct LndAmount {
+    /// uint64 values are sent as strings
+    msat: String,
+}
+
+impl LndRestClient {
+    pub fn new(base_url: &str, macaroon_hex: &str, tls_cert_path: Option<&str>) -> Result<Self> {
+        Ok(Self {
+            client: rest_client(tls_cert_path)?,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            macaroon: macaroon_hex.to_string(),
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("{}{}", self.base_url, path))
+            .header("Grpc-Metadata-macaroon", &self.macaroon)
+    }
+}
+
+#[async_trait]
+impl LightningBackend for LndRestClient {
+    fn name(&self) -> &'static str {
+        "lnd"
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<PaymentResult> {
+        // SendPaymentSync applies LND's default fee limit and answers once the payment
+        // has succeeded or failed
+        let response = self
+            .request(reqwest::Method::POST, "/v1/channels/transactions")
+            .json(&json!({ "payment_request": bolt11 }))
+            .send()
+            .await?;
+        let body: Value = response.json().await?;
+        if let Some(message) = error_message(&body) {
+            return Err(anyhow!("LND payment failed: {}", message));
+        }
+
+        let result: LndSendResponse = serde_json::from_value(body)?;
+        if !result.payment_error.is_empty() {
+            return Err(anyhow!("LND payment failed: {}", result.payment_error));
+        }
+        if result.payment_preimage.is_empty() {
+            return Err(anyhow!("LND returned no preimage. Payment status unknown."));
+        }
+
+        Ok(PaymentResult {
+            preimage: STANDARD.decode(&result.payment_preimage)?.to_lower_hex_string(),
+        })
+    }
+
+    async fn lookup_payment(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        let hash = URL_SAFE.encode(Vec::<u8>::from_hex(payment_hash)?);
+        let mut response = self
+            .request(reqwest::Method::GET, &format!("/v2/router/track/{}", hash))
+            .send()
+            .await?;
+
+        // The payment's current state is the first line of an update stream that
+        // stays open while it is in flight, so only read that far
+        let mut line = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            line.extend_from_slice(&chunk);
+            if let Some(end) = line.iter().position(|&b| b == b'\n') {
+                line.truncate(end);
+                break;
+            }
+        }
+
+        let update: Value = serde_json::from_slice(&line)?;
+        if let Some(message) = error_message(&update) {
+            if message.contains("isn't initiated") {
+                return Ok(PaymentStatus::NotFound);
+            }
+            return Err(anyhow!("LND payment lookup failed: {}", message));
+        }
+
+        let payment: LndPayment = serde_json::from_value(update["result"].clone())?;
+        Ok(match payment.status.as_str() {
+            "SUCCEEDED" => PaymentStatus::Succeeded {
+                preimage: payment.payment_preimage,
+            },
+            "FAILED" => PaymentStatus::Failed,
+            _ => PaymentStatus::Pending,
+        })
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        let response = self.request(reqwest::Method::GET, "/v1/balance/channels").send().await?;
+        let body: Value = response.json().await?;
+        if let Some(message) = error_message(&body) {
+            return Err(anyhow!("LND balance request failed: {}", message));
+        }
+
+        let balance: LndChannelBalance = serde_json::from_value(body)?;
+        match balance.local_balance {
+            Some(amount) => Ok(amount.msat.parse()?),
+            None => Ok(0),
+        }
+    }
+}
+
+/// The message of a gRPC gateway error, sent either as the whole body or, on a
+/// stream, wrapped in an `error` field.
+fn error_message(body: &Value) -> Option<String> {
+    let error = match body.get("error") {
+        Some(error) => error,
+        None if body.get("code").is_some() => body,
+        None => return None,
+    };
+    Some(error["message"].as_str().unwrap_or("unknown error").to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::testing::*;
+    use axum::extract::Path;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+
+    /// LND answers with a gRPC gateway error when the macaroon is refused
+    fn authorized(headers: &HeaderMap) -> Result<(), (StatusCode, Json<Value>)> {
+        match headers.get("Grpc-Metadata-macaroon") {
+            Some(macaroon) if macaroon == TEST_SECRET => Ok(()),
+            _ => Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({ "code": 2, "message": "verification failed: signature mismatch" })),
+            )),
+        }
+    }
+
+    /// SendPaymentSync reports a failed payment in `payment_error` with a 200 status
+    async fn pay(headers: HeaderMap, Json(body): Json<Value>) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+        authorized(&headers)?;
+        Ok(Json(match body["payment_request"].as_str() {
+            Some(PAYABLE_INVOICE) => json!({
+                "payment_error": "",
+                "payment_preimage": STANDARD.encode(TEST_PREIMAGE),
+                "payment_hash": STANDARD.encode(SUCCEEDED_HASH),
+            }),
+            _ => json!({ "payment_error": "unable to find a path to destination" }),
+        }))
+    }
+
+    /// The first line of TrackPaymentV2's update stream
+    async fn track(headers: HeaderMap, Path(hash): Path<String>) -> Result<String, (StatusCode, Json<Value>)> {
+        authorized(&headers)?;
+        let update = match <[u8; 32]>::try_from(URL_SAFE.decode(&hash).unwrap()).unwrap() {
+            SUCCEEDED_HASH => json!({ "result": {
+                "status": "SUCCEEDED",
+                "payment_preimage": TEST_PREIMAGE.to_lower_hex_string(),
+            } }),
+            PENDING_HASH => json!({ "result": {
+                "status": "IN_FLIGHT",
+                "payment_preimage": "0000000000000000000000000000000000000000000000000000000000000000",
+            } }),
+            FAILED_HASH => json!({ "result": { "status": "FAILED", "failure_reason": "FAILURE_REASON_NO_ROUTE" } }),
+            _ => json!({ "error": { "code": 5, "message": "payment isn't initiated" } }),
+        };
+        Ok(format!("{}\n", update))
+    }
+
+    async fn balance(headers: HeaderMap) -> Result<Json<Value>, (StatusCode, Json<Value>)> {
+        authorized(&headers)?;
+        Ok(Json(json!({ "local_balance": { "sat": "1500", "msat": "1500123" } })))
+    }
+
+    async fn lnd(macaroon: &str) -> LndRestClient {
+        let app = Router::new()
+            .route("/v1/channels/transactions", post(pay))
+            .route("/v2/router/track/:hash", get(track))
+            .route("/v1/balance/channels", get(balance));
+        LndRestClient::new(&serve(app).await, macaroon, None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn pays_invoices() {
+        let lnd = lnd(TEST_SECRET).await;
+
+        let result = lnd.pay_invoice(PAYABLE_INVOICE).await.unwrap();
+        assert_eq!(result.preimage, TEST_PREIMAGE.to_lower_hex_string());
+
+        let error = lnd.pay_invoice(UNROUTABLE_INVOICE).await.unwrap_err().to_string();
+        assert_eq!(error, "LND payment failed: unable to find a path to destination");
+    }
+
+    #[tokio::test]
+    async fn looks_up_payments() {
+        let lnd = lnd(TEST_SECRET).await;
+        let lookup = |hash: [u8; 32]| {
+            let lnd = &lnd;
+            async move { lnd.lookup_payment(&hash.to_lower_hex_string()).await.unwrap() }
+        };
+
+        let preimage = TEST_PREIMAGE.to_lower_hex_string();
+        assert_eq!(lookup(SUCCEEDED_HASH).await, PaymentStatus::Succeeded { preimage });
+        assert_eq!(lookup(PENDING_HASH).await, PaymentStatus::Pending);
+        assert_eq!(lookup(FAILED_HASH).await, PaymentStatus::Failed);
+        assert_eq!(lookup(UNKNOWN_HASH).await, PaymentStatus::NotFound);
+    }
+
+    #[tokio::test]
+    async fn reports_the_balance_in_msat() {
+        assert_eq!(lnd(TEST_SECRET).await.get_balance().await.unwrap(), 1_500_123);
+    }
+
+    #[tokio::test]
+    async fn reports_refused_macaroons() {
+        let lnd = lnd("0201036c6e65").await;
+
+        let error = lnd.get_balance().await.unwrap_err().to_string();
+        assert_eq!(error, "LND balance request failed: verification failed: signature mismatch");
+        let error = lnd.lookup_payment(&UNKNOWN_HASH.to_lower_hex_string()).await.unwrap_err().to_string();
+        assert_eq!(error, "LND payment lookup failed: verification failed: signature mismatch");
+    }
+}
@@ -1,6 +1,11 @@
+use super::Bolt11Invoice;
 use anyhow::{anyhow, Result};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::Network;
+use chrono::Utc;
 use reqwest::Client;
 use serde::Deserialize;
+use std::str::FromStr;
 use url::Url;
 
 #[derive(Debug, Deserialize)]
@@ -9,6 +14,7 @@ pub struct LnurlPayResponse {
     pub callback: String,
     pub min_sendable: u64, // millisats
     pub max_sendable: u64, // millisats
+    /// JSON array the invoice's description hash must commit to (LUD-06)
     pub metadata: String,
     pub tag: String,
 }
@@ -16,17 +22,90 @@ pub struct LnurlPayResponse {
 #[derive(Debug, Deserialize)]
 pub struct LnurlInvoiceResponse {
     pub pr: String, // BOLT11 invoice
+    #[allow(dead_code)]
     pub routes: Option<Vec<serde_json::Value>>,
 }
 
+/// Why an invoice fetched from a lightning address must not be paid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvoiceRefusal {
+    /// It expired before it could be paid, e.g. after a slow LNURL server or a clock
+    /// that is off. Asking for a fresh invoice may well succeed.
+    Expired,
+    /// It isn't the invoice that was asked for, so the server can't be trusted with
+    /// the payout
+    Mismatch(String),
+}
+
+impl std::fmt::Display for InvoiceRefusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvoiceRefusal::Expired => f.write_str("invoice has already expired"),
+            InvoiceRefusal::Mismatch(reason) => f.write_str(reason),
+        }
+    }
+}
+
+/// An invoice fetched from a lightning address, with what it was requested for.
+#[derive(Debug, Clone)]
+pub struct LnurlInvoice {
+    pub bolt11: String,
+    pub amount_msats: u64,
+    pub metadata: String,
+}
+
+impl LnurlInvoice {
+    /// Decode the invoice and check it is the one that was asked for: the requested
+    /// amount, a description hash committing to the LNURL metadata, the right network
+    /// and not yet expired. Anything else could be a compromised or malicious server
+    /// (e.g. inflating the amount), so the error is the reason it must not be paid.
+    pub fn validate(&self, network: Network) -> std::result::Result<Bolt11Invoice, InvoiceRefusal> {
+        let invoice = Bolt11Invoice::from_str(&self.bolt11)
+            .map_err(|e| InvoiceRefusal::Mismatch(e.to_string()))?;
+
+        match invoice.amount_msat {
+            Some(amount) if amount == self.amount_msats => {}
+            Some(amount) => {
+                return Err(InvoiceRefusal::Mismatch(format!(
+                    "invoice amount {} msats doesn't match the requested {} msats",
+                    amount, self.amount_msats
+                )))
+            }
+            None => return Err(InvoiceRefusal::Mismatch("invoice has no amount".to_string())),
+        }
+
+        if invoice.description_hash != Some(sha256::Hash::hash(self.metadata.as_bytes())) {
+            return Err(InvoiceRefusal::Mismatch(
+                "invoice description hash doesn't match the LNURL metadata".to_string(),
+            ));
+        }
+
+        if !invoice.is_for_network(network) {
+            return Err(InvoiceRefusal::Mismatch(format!(
+                "invoice is for {} rather than {}",
+                invoice.currency, network
+            )));
+        }
+
+        if invoice.expires_at() <= Utc::now().timestamp().max(0) as u64 {
+            return Err(InvoiceRefusal::Expired);
+        }
+
+        Ok(invoice)
+    }
+}
+
 pub struct LnurlClient {
     client: Client,
+    /// Use plain HTTP for every domain (`LNURL_ALLOW_HTTP`, test networks only)
+    allow_http: bool,
 }
 
 impl LnurlClient {
-    pub fn new() -> Self {
+    pub fn new(allow_http: bool) -> Self {
         Self {
             client: Client::new(),
+            allow_http,
         }
     }
 
@@ -53,8 +132,10 @@ impl LnurlClient {
         true
     }
 
-    /// Convert a lightning address to its LNURL-pay endpoint
-    fn lightning_address_to_url(address: &str) -> Result<String> {
+    /// Convert a lightning address to its LNURL-pay endpoint.
+    /// Plain HTTP is only used for .onion domains (per LUD-16), or everywhere when
+    /// explicitly allowed for a local stand-in server.
+    fn lightning_address_to_url(&self, address: &str) -> Result<String> {
         let parts: Vec<&str> = address.split('@').collect();
         if parts.len() != 2 {
             return Err(anyhow!("Invalid lightning address format"));
@@ -63,12 +144,18 @@ impl LnurlClient {
         let user = parts[0];
         let domain = parts[1];
 
-        Ok(format!("https://{}/.well-known/lnurlp/{}", domain, user))
+        let scheme = if domain.ends_with(".onion") || self.allow_http {
+            "http"
+        } else {
+            "https"
+        };
+
+        Ok(format!("{}://{}/.well-known/lnurlp/{}", scheme, domain, user))
     }
 
     /// Fetch the LNURL-pay metadata for a lightning address
     pub async fn fetch_pay_params(&self, lightning_address: &str) -> Result<LnurlPayResponse> {
-        let url = Self::lightning_address_to_url(lightning_address)?;
+        let url = self.lightning_address_to_url(lightning_address)?;
 
         let response = self
             .client
@@ -118,12 +205,13 @@ impl LnurlClient {
         Ok(invoice)
     }
 
-    /// Get a BOLT11 invoice for a lightning address and amount (in sats)
+    /// Get a BOLT11 invoice for a lightning address and amount (in sats). It still
+    /// needs to be validated before it is paid.
     pub async fn get_invoice_for_address(
         &self,
         lightning_address: &str,
         amount_sats: u64,
-    ) -> Result<String> {
+    ) -> Result<LnurlInvoice> {
         let params = self.fetch_pay_params(lightning_address).await?;
 
         let amount_msats = amount_sats * 1000;
@@ -145,12 +233,135 @@ impl LnurlClient {
         }
 
         let invoice_response = self.fetch_invoice(&params.callback, amount_msats).await?;
-        Ok(invoice_response.pr)
+        Ok(LnurlInvoice {
+            bolt11: invoice_response.pr,
+            amount_msats,
+            metadata: params.metadata,
+        })
     }
 }
 
-impl Default for LnurlClient {
-    fn default() -> Self {
-        Self::new()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lightning::bolt11::tests::TestInvoice;
+    use bitcoin::secp256k1::SecretKey;
+    use lightning_invoice::Currency;
+
+    const METADATA: &str = r#"[["text/plain","Payment to satoshi@example.com"]]"#;
+
+    fn key() -> SecretKey {
+        SecretKey::from_slice(&[1; 32]).unwrap()
+    }
+
+    fn now() -> u64 {
+        Utc::now().timestamp() as u64
+    }
+
+    fn test_invoice(currency: Currency, amount_msat: Option<u64>, timestamp: u64, expiry_secs: u64) -> TestInvoice {
+        TestInvoice {
+            currency,
+            amount_msat,
+            timestamp,
+            expiry_secs,
+            description_hash: Some(sha256::Hash::hash(METADATA.as_bytes())),
+            payee: None,
+        }
+    }
+
+    /// What a server returned when asked for 1000 sats on regtest
+    fn requested(invoice: TestInvoice) -> LnurlInvoice {
+        LnurlInvoice {
+            bolt11: invoice.sign(&key()),
+            amount_msats: 1_000_000,
+            metadata: METADATA.to_string(),
+        }
+    }
+
+    /// An invoice as an honest server would return it for 1000 sats on regtest
+    fn honest(timestamp: u64, expiry_secs: u64) -> LnurlInvoice {
+        requested(test_invoice(Currency::Regtest, Some(1_000_000), timestamp, expiry_secs))
+    }
+
+    #[test]
+    fn accepts_the_requested_invoice() {
+        let invoice = honest(now(), 60).validate(Network::Regtest).unwrap();
+        assert_eq!(invoice.amount_msat, Some(1_000_000));
+    }
+
+    #[test]
+    fn refuses_a_different_amount() {
+        let invoice = requested(test_invoice(Currency::Regtest, Some(2_000_000), now(), 60));
+        let error = invoice.validate(Network::Regtest).unwrap_err().to_string();
+        assert_eq!(error, "invoice amount 2000000 msats doesn't match the requested 1000000 msats");
+    }
+
+    #[test]
+    fn refuses_a_missing_amount() {
+        let invoice = requested(test_invoice(Currency::Regtest, None, now(), 60));
+        let error = invoice.validate(Network::Regtest).unwrap_err().to_string();
+        assert_eq!(error, "invoice has no amount");
+    }
+
+    #[test]
+    fn refuses_a_description_hash_mismatch() {
+        let other = r#"[["text/plain","Payment to mallory@example.com"]]"#;
+        let invoice = requested(TestInvoice {
+            description_hash: Some(sha256::Hash::hash(other.as_bytes())),
+            ..test_invoice(Currency::Regtest, Some(1_000_000), now(), 60)
+        });
+        let error = invoice.validate(Network::Regtest).unwrap_err().to_string();
+        assert_eq!(error, "invoice description hash doesn't match the LNURL metadata");
+
+        // A plain description rather than a hash
+        let invoice = requested(TestInvoice {
+            description_hash: None,
+            ..test_invoice(Currency::Regtest, Some(1_000_000), now(), 60)
+        });
+        let error = invoice.validate(Network::Regtest).unwrap_err().to_string();
+        assert_eq!(error, "invoice description hash doesn't match the LNURL metadata");
+    }
+
+    #[test]
+    fn refuses_the_wrong_network() {
+        let error = honest(now(), 60).validate(Network::Bitcoin).unwrap_err().to_string();
+        assert_eq!(error, "invoice is for bcrt rather than bitcoin");
+
+        let invoice = requested(test_invoice(Currency::BitcoinTestnet, Some(1_000_000), now(), 60));
+        let error = invoice.validate(Network::Signet).unwrap_err().to_string();
+        assert_eq!(error, "invoice is for tb rather than signet");
+    }
+
+    #[test]
+    fn refuses_an_expired_invoice() {
+        let error = honest(now() - 120, 60).validate(Network::Regtest).unwrap_err();
+        assert_eq!(error, InvoiceRefusal::Expired);
+    }
+
+    #[test]
+    fn refuses_an_undecodable_invoice() {
+        let invoice = LnurlInvoice {
+            bolt11: "lnbcrt10u1notaninvoice".to_string(),
+            ..honest(now(), 60)
+        };
+        let error = invoice.validate(Network::Regtest).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid BOLT11 invoice"));
+    }
+
+    #[test]
+    fn plain_http_only_for_onion_or_when_allowed() {
+        let client = LnurlClient::new(false);
+        assert_eq!(
+            client.lightning_address_to_url("satoshi@example.com").unwrap(),
+            "https://example.com/.well-known/lnurlp/satoshi"
+        );
+        assert_eq!(
+            client.lightning_address_to_url("satoshi@example.onion").unwrap(),
+            "http://example.onion/.well-known/lnurlp/satoshi"
+        );
+        assert_eq!(
+            LnurlClient::new(true).lightning_address_to_url("satoshi@127.0.0.1").unwrap(),
+            "http://127.0.0.1/.well-known/lnurlp/satoshi"
+        );
     }
 }
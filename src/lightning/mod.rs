@@ -1,5 +1,17 @@
+pub mod backend;
+pub mod bolt11;
+pub mod cln;
+pub mod lnbits;
+pub mod lnd;
 pub mod lnurl;
 pub mod nwc;
+#[cfg(test)]
+pub mod testing;
 
+pub use backend::*;
+pub use bolt11::*;
+pub use cln::*;
+pub use lnbits::*;
+pub use lnd::*;
 pub use lnurl::*;
 pub use nwc::*;
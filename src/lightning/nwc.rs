@@ -1,36 +1,41 @@
+use super::{LightningBackend, PaymentResult, PaymentStatus};
 use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use nostr_sdk::prelude::*;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::time::Duration;
 
+/// How long to wait for the wallet to answer a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// How long to wait for the relay to return the wallet's info event at startup
+const INFO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outgoing transactions searched for a payment when the wallet can't look one up
+/// directly
+const LIST_TRANSACTIONS_LIMIT: usize = 100;
+
 pub struct NwcClient {
     client: Client,
     wallet_pubkey: PublicKey,
     secret_key: SecretKey,
-}
-
-#[derive(Debug, Clone)]
-pub struct PaymentResult {
-    pub preimage: String,
-    pub payment_hash: String,
+    /// Methods the wallet's info event permits for this connection, None if it
+    /// published none
+    methods: Option<Vec<String>>,
 }
 
 // NIP-47 request/response structures
 #[derive(Serialize)]
-struct Nip47Request {
-    method: String,
-    params: Nip47PayInvoiceParams,
-}
-
-#[derive(Serialize)]
-struct Nip47PayInvoiceParams {
-    invoice: String,
+struct Nip47Request<'a> {
+    method: &'a str,
+    params: Value,
 }
 
 #[derive(Deserialize)]
 struct Nip47Response {
     result_type: Option<String>,
-    result: Option<Nip47PayInvoiceResult>,
+    result: Option<Value>,
     error: Option<Nip47Error>,
 }
 
@@ -39,6 +44,37 @@ struct Nip47PayInvoiceResult {
     preimage: String,
 }
 
+/// A transaction as returned by `lookup_invoice` and `list_transactions`
+#[derive(Deserialize)]
+struct Nip47Transaction {
+    payment_hash: Option<String>,
+    preimage: Option<String>,
+    settled_at: Option<u64>,
+    /// Only sent by wallets implementing the newer revision of NIP-47
+    state: Option<String>,
+}
+
+impl Nip47Transaction {
+    fn payment_status(self) -> PaymentStatus {
+        match (self.state.as_deref(), self.settled_at, self.preimage) {
+            (Some("failed" | "expired"), _, _) => PaymentStatus::Failed,
+            (_, Some(_), Some(preimage)) if !preimage.is_empty() => PaymentStatus::Succeeded { preimage },
+            _ => PaymentStatus::Pending,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Nip47ListTransactionsResult {
+    transactions: Vec<Nip47Transaction>,
+}
+
+#[derive(Deserialize)]
+struct Nip47BalanceResult {
+    /// In millisatoshis
+    balance: u64,
+}
+
 #[derive(Deserialize)]
 struct Nip47Error {
     code: String,
@@ -85,21 +121,95 @@ impl NwcClient {
         client.add_relay(&relay_url).await?;
         client.connect().await;
 
+        // The wallet lists the methods this connection may call in its info event. One
+        // that can't pay invoices is useless for payouts, so refuse it up front.
+        let methods = Self::fetch_methods(&client, wallet_pubkey).await;
+        match &methods {
+            Some(methods) if !methods.iter().any(|method| method == "pay_invoice") => {
+                return Err(anyhow!(
+                    "NWC wallet doesn't permit pay_invoice for this connection (permitted: {})",
+                    methods.join(", ")
+                ));
+            }
+            Some(methods) => tracing::info!("NWC wallet permits: {}", methods.join(", ")),
+            None => tracing::warn!("No NWC info event found for the wallet, assuming it permits pay_invoice"),
+        }
+
         Ok(Self {
             client,
             wallet_pubkey,
             secret_key,
+            methods,
         })
     }
 
-    pub async fn pay_invoice(&self, bolt11: &str) -> Result<PaymentResult> {
-        // Create the NIP-47 pay_invoice request
-        let request = Nip47Request {
-            method: "pay_invoice".to_string(),
-            params: Nip47PayInvoiceParams {
-                invoice: bolt11.to_string(),
-            },
-        };
+    /// Methods listed in the wallet's NIP-47 info event (kind 13194), or None if the
+    /// relay has none for it.
+    async fn fetch_methods(client: &Client, wallet_pubkey: PublicKey) -> Option<Vec<String>> {
+        let filter = Filter::new().kind(Kind::WalletConnectInfo).author(wallet_pubkey);
+        match client.fetch_events(vec![filter], INFO_TIMEOUT).await {
+            Ok(events) => events
+                .first()
+                .map(|event| event.content.split_whitespace().map(str::to_string).collect()),
+            Err(e) => {
+                tracing::warn!("Failed to fetch NWC info event: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Whether the wallet permits `method`. Assumed so when it published no info event.
+    fn permits(&self, method: &str) -> bool {
+        match &self.methods {
+            Some(methods) => methods.iter().any(|permitted| permitted == method),
+            None => true,
+        }
+    }
+
+    /// The wallet's most recent outgoing transactions, newest first.
+    async fn list_transactions(&self, limit: usize) -> Result<Vec<Nip47Transaction>> {
+        if !self.permits("list_transactions") {
+            return Err(anyhow!("NWC wallet doesn't permit list_transactions"));
+        }
+
+        let response = self
+            .request("list_transactions", json!({ "limit": limit, "type": "outgoing" }))
+            .await?
+            .ok_or_else(|| anyhow!("No response from wallet within {:?}", RESPONSE_TIMEOUT))?;
+
+        if let Some(error) = response.error {
+            return Err(anyhow!("Transaction list failed: {} - {}", error.code, error.message));
+        }
+
+        let result = response.result.ok_or_else(|| anyhow!("Wallet sent an empty list_transactions response"))?;
+        let list: Nip47ListTransactionsResult = serde_json::from_value(result)?;
+        Ok(list.transactions)
+    }
+
+    /// Find a payment among the wallet's recent outgoing transactions, for wallets that
+    /// can list transactions but not look one up. Only a list shorter than the limit
+    /// proves the payment was never made.
+    async fn find_in_transactions(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        let transactions = self.list_transactions(LIST_TRANSACTIONS_LIMIT).await?;
+        let complete = transactions.len() < LIST_TRANSACTIONS_LIMIT;
+
+        match transactions
+            .into_iter()
+            .find(|transaction| transaction.payment_hash.as_deref() == Some(payment_hash))
+        {
+            Some(transaction) => Ok(transaction.payment_status()),
+            None if complete => Ok(PaymentStatus::NotFound),
+            None => Err(anyhow!(
+                "Payment isn't among the wallet's latest {} transactions",
+                LIST_TRANSACTIONS_LIMIT
+            )),
+        }
+    }
+
+    /// Send a NIP-47 request and wait for the wallet's response. Returns None if no
+    /// response arrived in time.
+    async fn request(&self, method: &str, params: Value) -> Result<Option<Nip47Response>> {
+        let request = Nip47Request { method, params };
         let request_json = serde_json::to_string(&request)?;
 
         // Encrypt using NIP-04
@@ -109,51 +219,128 @@ impl NwcClient {
         let keys = Keys::new(self.secret_key.clone());
         let our_pubkey = keys.public_key();
 
-        // Create and send the event
+        // Sign the request first so the response filter can reference its id
         let event_builder = EventBuilder::new(Kind::WalletConnectRequest, encrypted)
             .tag(Tag::public_key(self.wallet_pubkey));
+        let event = self.client.sign_event_builder(event_builder).await?;
+        let event_id = event.id;
 
-        let output = self.client.send_event_builder(event_builder).await?;
-        let event_id = *output.id();
-
-        tracing::debug!("Sent NWC payment request, event_id: {}", event_id);
-
-        // Wait for the response - try multiple times with short intervals
+        // Responses are ephemeral events that relays aren't required to store, so
+        // subscribe before sending rather than querying for the response afterwards
         let filter = Filter::new()
             .kind(Kind::WalletConnectResponse)
             .author(self.wallet_pubkey)
             .pubkey(our_pubkey)
             .event(event_id);
+        let mut notifications = self.client.notifications();
+        let subscription = self.client.subscribe(vec![filter.clone()], None).await?;
 
-        // Poll for response multiple times
-        for attempt in 1..=5 {
-            let timeout = Duration::from_secs(3);
-            let events = self.client.fetch_events(vec![filter.clone()], timeout).await?;
+        if let Err(e) = self.client.send_event(event).await {
+            self.client.unsubscribe(subscription.val).await;
+            return Err(e.into());
+        }
 
-            if let Some(response_event) = events.into_iter().next() {
-                // Decrypt the response
-                let decrypted = nip04::decrypt(&self.secret_key, &self.wallet_pubkey, &response_event.content)?;
-                let response: Nip47Response = serde_json::from_str(&decrypted)?;
+        tracing::debug!("Sent NWC {} request, event_id: {}", method, event_id);
 
-                if let Some(error) = response.error {
-                    return Err(anyhow!("Payment failed: {} - {}", error.code, error.message));
+        let response_event = tokio::time::timeout(RESPONSE_TIMEOUT, async {
+            while let Ok(notification) = notifications.recv().await {
+                if let RelayPoolNotification::Event { event, .. } = notification {
+                    if filter.match_event(&event) {
+                        return Some(*event);
+                    }
                 }
+            }
+            None
+        })
+        .await
+        .ok()
+        .flatten();
+        self.client.unsubscribe(subscription.val).await;
 
-                if let Some(result) = response.result {
-                    return Ok(PaymentResult {
-                        preimage: result.preimage.clone(),
-                        payment_hash: result.preimage,
-                    });
-                }
+        let Some(response_event) = response_event else {
+            tracing::warn!("No NWC response received within {:?} for event {}", RESPONSE_TIMEOUT, event_id);
+            return Ok(None);
+        };
+
+        let decrypted = nip04::decrypt(&self.secret_key, &self.wallet_pubkey, &response_event.content)?;
+        let response: Nip47Response = serde_json::from_str(&decrypted)?;
+
+        // The response names the method it answers
+        if let Some(result_type) = &response.result_type {
+            if result_type != method {
+                return Err(anyhow!("Wallet answered {} with a {} response", method, result_type));
+            }
+        }
+
+        Ok(Some(response))
+    }
+}
+
+#[async_trait]
+impl LightningBackend for NwcClient {
+    fn name(&self) -> &'static str {
+        "nwc"
+    }
+
+    async fn pay_invoice(&self, bolt11: &str) -> Result<PaymentResult> {
+        let response = self.request("pay_invoice", json!({ "invoice": bolt11 })).await?;
+
+        if let Some(response) = response {
+            if let Some(error) = response.error {
+                return Err(anyhow!("Payment failed: {} - {}", error.code, error.message));
+            }
+
+            if let Some(result) = response.result {
+                let result: Nip47PayInvoiceResult = serde_json::from_value(result)?;
+                return Ok(PaymentResult {
+                    preimage: result.preimage,
+                });
             }
+        }
+
+        // No response received - return an error so the payment processor looks the
+        // payment up before retrying. Do NOT assume success as this could cause fund loss.
+        Err(anyhow!("No response from wallet within {:?}. Payment status unknown.", RESPONSE_TIMEOUT))
+    }
+
+    async fn lookup_payment(&self, payment_hash: &str) -> Result<PaymentStatus> {
+        if !self.permits("lookup_invoice") {
+            return self.find_in_transactions(payment_hash).await;
+        }
+
+        let response = self
+            .request("lookup_invoice", json!({ "payment_hash": payment_hash }))
+            .await?
+            .ok_or_else(|| anyhow!("No response from wallet within {:?}", RESPONSE_TIMEOUT))?;
+
+        if let Some(error) = response.error {
+            if error.code == "NOT_FOUND" {
+                return Ok(PaymentStatus::NotFound);
+            }
+            return Err(anyhow!("Lookup failed: {} - {}", error.code, error.message));
+        }
+
+        let result = response.result.ok_or_else(|| anyhow!("Wallet sent an empty lookup_invoice response"))?;
+        let transaction: Nip47Transaction = serde_json::from_value(result)?;
+        Ok(transaction.payment_status())
+    }
+
+    async fn get_balance(&self) -> Result<u64> {
+        if !self.permits("get_balance") {
+            return Err(anyhow!("NWC wallet doesn't permit get_balance"));
+        }
+
+        let response = self
+            .request("get_balance", json!({}))
+            .await?
+            .ok_or_else(|| anyhow!("No response from wallet within {:?}", RESPONSE_TIMEOUT))?;
 
-            tracing::debug!("NWC response attempt {}/5 - no response yet", attempt);
-            tokio::time::sleep(Duration::from_secs(1)).await;
+        if let Some(error) = response.error {
+            return Err(anyhow!("Balance request failed: {} - {}", error.code, error.message));
         }
 
-        // No response received - return an error so the payment processor can decide
-        // whether to retry. Do NOT assume success as this could cause fund loss.
-        tracing::warn!("No NWC response received after 5 attempts for event {}", event_id);
-        Err(anyhow!("No response from wallet after 5 attempts (event_id: {}). Payment status unknown - will retry.", event_id))
+        let result = response.result.ok_or_else(|| anyhow!("Wallet sent an empty get_balance response"))?;
+        let balance: Nip47BalanceResult = serde_json::from_value(result)?;
+        Ok(balance.balance)
     }
 }
@@ -0,0 +1,30 @@
+//! Stand-ins for the Lightning node REST APIs in the backend tests: an axum router
+//! answering like the node would, served on a free local port.
+
+use axum::Router;
+
+/// Credential every stand-in expects, sent as the backend's macaroon, rune or API key
+pub const TEST_SECRET: &str = "0201036c6e64";
+
+/// The payment hash a stand-in reports as succeeded, with `TEST_PREIMAGE`
+pub const SUCCEEDED_HASH: [u8; 32] = [0xaa; 32];
+/// The payment hash a stand-in reports as still in flight
+pub const PENDING_HASH: [u8; 32] = [0xbb; 32];
+/// The payment hash a stand-in reports as failed
+pub const FAILED_HASH: [u8; 32] = [0xcc; 32];
+/// A payment hash no stand-in has a record of
+pub const UNKNOWN_HASH: [u8; 32] = [0xdd; 32];
+
+pub const TEST_PREIMAGE: [u8; 32] = [0x11; 32];
+
+/// The invoice every stand-in pays, and one none of them finds a route for
+pub const PAYABLE_INVOICE: &str = "lnbcrt10u1payable";
+pub const UNROUTABLE_INVOICE: &str = "lnbcrt10u1unroutable";
+
+/// Serve `app` on a free local port for the rest of the test and return its base URL.
+pub async fn serve(app: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    url
+}
@@ -1,34 +1,52 @@
 mod api;
 mod config;
 mod db;
+mod deposit;
 mod lightning;
 mod rate_limit;
+mod sweep;
 mod wallet;
 mod workers;
 
 use crate::api::create_router;
-use crate::config::Config;
-use crate::lightning::NwcClient;
+use crate::config::{database_url_from_env, ChainBackend, Config};
+use crate::db::{run_migrations, RecycleStore};
+use crate::lightning::{lightning_backend_from_config, LightningBackend};
 use crate::rate_limit::RateLimiter;
-use crate::wallet::BdkWallet;
-use crate::workers::{run_deposit_monitor, run_payment_processor};
+use crate::wallet::{chain_source_from_config, BdkWallet};
+use crate::workers::run_workers;
 use chrono::{DateTime, Utc};
 use rustls::crypto::ring::default_provider;
-use sqlx::sqlite::SqlitePoolOptions;
-use sqlx::SqlitePool;
+use sqlx::AnyPool;
 use std::net::SocketAddr;
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tower_http::services::ServeDir;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 pub struct AppState {
-    pub db: SqlitePool,
+    pub db: AnyPool,
+    pub recycles: Arc<dyn RecycleStore>,
     pub wallet: BdkWallet,
-    pub nwc: NwcClient,
+    pub lightning: Arc<dyn LightningBackend>,
     pub config: Config,
     pub last_sync: RwLock<Option<DateTime<Utc>>>,
     pub rate_limiter: RateLimiter,
+    /// Whether this instance runs the background workers and so keeps its wallet synced
+    pub runs_workers: AtomicBool,
+}
+
+async fn migrate() -> anyhow::Result<()> {
+    dotenvy::dotenv().ok();
+
+    let (db, backend) = db::connect(&database_url_from_env(), 1).await?;
+
+    tracing::info!("Running {} database migrations...", backend.as_str());
+    run_migrations(&db, backend).await?;
+    tracing::info!("Database is up to date");
+
+    Ok(())
 }
 
 #[tokio::main]
@@ -46,12 +64,28 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // `utxo-recycler migrate` applies pending migrations and exits, so the schema can be
+    // upgraded before deploying a new version
+    match std::env::args().nth(1).as_deref() {
+        Some("migrate") => return migrate().await,
+        Some(command) => return Err(anyhow::anyhow!("Unknown command: {} (expected \"migrate\")", command)),
+        None => {}
+    }
+
     tracing::info!("Starting UTXO Recycler...");
 
     // Load configuration
     let config = Config::from_env()?;
     tracing::info!("Configuration loaded");
-    tracing::info!("  - Electrum URL: {}", config.electrum_url);
+    tracing::info!("  - Network: {}", config.network);
+    tracing::info!("  - Chain backend: {}", config.chain_backend.as_str());
+    match config.chain_backend {
+        ChainBackend::Electrum => {
+            tracing::info!("  - Electrum servers: {}", config.electrum_urls.join(", "))
+        }
+        ChainBackend::Esplora => tracing::info!("  - Esplora URL: {}", config.esplora_url),
+        ChainBackend::BitcoindRpc => tracing::info!("  - bitcoind RPC URL: {}", config.bitcoind_rpc_url),
+    }
     if let Some(ref proxy) = config.tor_proxy {
         tracing::info!("  - Tor proxy: {}", proxy);
     }
@@ -59,54 +93,50 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("  - Required confirmations: {}", config.required_confirmations);
 
     // Initialize database
-    let db = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect(&config.database_url)
-        .await?;
+    let (db, backend) = db::connect(&config.database_url, 5).await?;
+    tracing::info!("  - Database: {}", backend.as_str());
 
-    // Run migrations
     tracing::info!("Running database migrations...");
-    sqlx::query(include_str!("../migrations/001_initial.sql"))
-        .execute(&db)
-        .await?;
-    // Run migrations - each statement individually to handle SQLite ALTER TABLE limitations
-    let migrations = [
-        ("002", include_str!("../migrations/002_blockheight_cutoff.sql")),
-        ("003", include_str!("../migrations/003_payment_attempts.sql")),
-    ];
-
-    for (name, migration) in migrations {
-        for statement in migration.split(';') {
-            // Remove comments and whitespace
-            let cleaned: String = statement
-                .lines()
-                .filter(|line| !line.trim().starts_with("--"))
-                .collect::<Vec<_>>()
-                .join("\n");
-            let cleaned = cleaned.trim();
-
-            if cleaned.is_empty() {
-                continue;
-            }
-
-            if let Err(e) = sqlx::query(cleaned).execute(&db).await {
-                let err_str = e.to_string();
-                // Ignore "duplicate column" and "already exists" errors
-                if !err_str.contains("duplicate column") && !err_str.contains("already exists") {
-                    tracing::warn!("Migration {} statement failed (may be expected): {}", name, e);
-                }
-            }
-        }
-    }
+    run_migrations(&db, backend).await?;
     tracing::info!("Database ready");
 
+    let recycles = backend.recycle_store(db.clone());
+
+    let repaired = recycles.repair_payment_hashes().await?;
+    if repaired > 0 {
+        tracing::info!("Replaced the stored payment hash of {} paid recycle(s) with the invoice's", repaired);
+    }
+
+    // Initialize Lightning backend
+    tracing::info!("Connecting to Lightning backend ({})...", config.lightning_backend.as_str());
+    let lightning = lightning_backend_from_config(&config).await?;
+    match lightning.get_balance().await {
+        Ok(balance) => tracing::info!("Lightning backend connected, balance {} sats", balance / 1000),
+        Err(e) => tracing::warn!("Lightning backend balance check failed (payouts may fail): {}", e),
+    }
+
     // Initialize BDK wallet
     tracing::info!("Initializing BDK wallet...");
-    let wallet = BdkWallet::new(&config.wallet_descriptor, &config.electrum_url, config.tor_proxy.clone()).await?;
+    let chain = chain_source_from_config(&config)?;
+    let wallet = BdkWallet::new(&config.wallet_descriptor, config.network, chain, db.clone()).await?;
+
+    // Every address a recycle was given is revealed, so syncs cover the whole range
+    // even if most of them were never funded
+    let highest_index = recycles.max_address_index().await?;
+    if let Some(index) = highest_index {
+        wallet.reveal_addresses_up_to(index).await?;
+    }
 
-    // Do initial full scan (non-fatal if it fails - background worker will retry)
-    tracing::info!("Performing initial wallet sync (this may take a moment)...");
-    let initial_sync_time = match wallet.full_scan().await {
+    // A restored wallet resumes from its last checkpoint with an incremental sync;
+    // a fresh one needs a full scan (non-fatal if it fails - background worker will retry)
+    let initial_sync = if wallet.is_restored() {
+        tracing::info!("Performing incremental wallet sync...");
+        wallet.sync().await
+    } else {
+        tracing::info!("Performing initial wallet scan (this may take a moment)...");
+        wallet.full_scan(highest_index).await
+    };
+    let initial_sync_time = match initial_sync {
         Ok(_) => {
             tracing::info!("Wallet synced");
             Some(Utc::now())
@@ -117,10 +147,15 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    // Initialize NWC client
-    tracing::info!("Connecting to Lightning wallet via NWC...");
-    let nwc = NwcClient::new(&config.nwc_uri).await?;
-    tracing::info!("NWC connected");
+    // Never hand out a deposit address that a recycle or the wallet has already used
+    let last_revealed = wallet.last_revealed_index().await;
+    if let Some((stored, next)) = recycles.reconcile_address_index(last_revealed).await? {
+        tracing::warn!(
+            "Stored next address index {} was behind used or revealed addresses, moved to {}",
+            stored,
+            next
+        );
+    }
 
     // Initialize rate limiter
     let rate_limiter = RateLimiter::new(
@@ -131,25 +166,17 @@ async fn main() -> anyhow::Result<()> {
     // Create shared state
     let state = Arc::new(AppState {
         db,
+        recycles,
         wallet,
-        nwc,
+        lightning,
         config: config.clone(),
         last_sync: RwLock::new(initial_sync_time),
         rate_limiter,
+        runs_workers: AtomicBool::new(false),
     });
 
     // Spawn background workers
-    let monitor_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        tracing::info!("Starting deposit monitor worker...");
-        run_deposit_monitor(monitor_state).await;
-    });
-
-    let processor_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        tracing::info!("Starting payment processor worker...");
-        run_payment_processor(processor_state).await;
-    });
+    tokio::spawn(run_workers(Arc::clone(&state), backend));
 
     // Create router
     let app = create_router()
@@ -0,0 +1,107 @@
+use crate::db::{Deposit, DepositRepository, NewSweep, Sweep, SweepRepository, SweepStatus};
+use crate::AppState;
+use anyhow::{anyhow, Result};
+use bdk_wallet::bitcoin::{OutPoint, Psbt, Txid};
+use std::str::FromStr;
+
+/// Build an unsigned sweep of every confirmed, settled deposit to the configured
+/// cold-storage address. The PSBT is exported for offline signing since the wallet
+/// descriptor only holds an xpub.
+pub async fn create_sweep(state: &AppState, fee_rate_sat_vb: Option<u64>) -> Result<Sweep> {
+    let destination = state
+        .config
+        .sweep_address
+        .as_deref()
+        .ok_or_else(|| anyhow!("SWEEP_ADDRESS is not configured"))?;
+    let fee_rate_sat_vb = fee_rate_sat_vb.unwrap_or(state.config.sweep_fee_rate);
+
+    // Settled deposits (paid out or donated) not claimed by another sweep
+    let candidates = DepositRepository::find_sweepable(&state.db).await?;
+    let mut outpoints = Vec::with_capacity(candidates.len());
+    for deposit in &candidates {
+        outpoints.push(OutPoint::new(Txid::from_str(&deposit.txid)?, deposit.vout));
+    }
+
+    // Only sweep UTXOs that are still unspent and sufficiently confirmed
+    let spendable = state
+        .wallet
+        .spendable_utxos(&outpoints, state.config.required_confirmations)
+        .await;
+    if spendable.is_empty() {
+        return Err(anyhow!("No confirmed, settled deposits to sweep"));
+    }
+
+    let selected: Vec<Deposit> = candidates
+        .into_iter()
+        .filter(|deposit| {
+            spendable.iter().any(|(outpoint, _)| {
+                outpoint.txid.to_string() == deposit.txid && outpoint.vout == deposit.vout
+            })
+        })
+        .collect();
+    let selected_outpoints: Vec<OutPoint> = spendable.iter().map(|(outpoint, _)| *outpoint).collect();
+    let total_input_sats: u64 = spendable.iter().map(|(_, value)| value).sum();
+
+    let psbt = state
+        .wallet
+        .build_sweep_psbt(&selected_outpoints, destination, fee_rate_sat_vb)
+        .await?;
+    let fee_sats = psbt.fee()?.to_sat();
+    let txid = psbt.unsigned_tx.compute_txid().to_string();
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let sweep = SweepRepository::create(
+        &state.db,
+        NewSweep {
+            id: &id,
+            destination_address: destination,
+            fee_rate_sat_vb,
+            total_input_sats,
+            fee_sats,
+            unsigned_psbt: &psbt.to_string(),
+            txid: &txid,
+        },
+        &selected,
+    )
+    .await?;
+
+    tracing::info!(
+        "Created sweep {}: {} inputs, {} sats, {} sats fee at {} sat/vB to {}",
+        sweep.id,
+        sweep.input_count,
+        total_input_sats,
+        fee_sats,
+        fee_rate_sat_vb,
+        destination
+    );
+
+    Ok(sweep)
+}
+
+/// Accept the signed PSBT for an unsigned sweep, finalize it and broadcast it.
+pub async fn broadcast_sweep(state: &AppState, id: &str, signed_psbt: &str) -> Result<Sweep> {
+    let sweep = SweepRepository::find_by_id(&state.db, id)
+        .await?
+        .ok_or_else(|| anyhow!("Sweep not found"))?;
+
+    if sweep.status != SweepStatus::Unsigned {
+        return Err(anyhow!("Sweep is {}, expected unsigned", sweep.status.as_str()));
+    }
+
+    let psbt = Psbt::from_str(signed_psbt.trim())?;
+
+    // The signed PSBT must spend exactly the transaction we exported
+    if psbt.unsigned_tx.compute_txid().to_string() != sweep.txid {
+        return Err(anyhow!("Signed PSBT does not match sweep transaction {}", sweep.txid));
+    }
+
+    let tx = state.wallet.finalize_psbt(psbt).await?;
+    let txid = state.wallet.broadcast(tx).await?;
+    SweepRepository::mark_broadcast(&state.db, &sweep.id).await?;
+
+    tracing::info!("Broadcast sweep {}: txid {}", sweep.id, txid);
+
+    SweepRepository::find_by_id(&state.db, id)
+        .await?
+        .ok_or_else(|| anyhow!("Sweep not found"))
+}
@@ -1,21 +1,66 @@
 use anyhow::Result;
-use bdk_electrum::electrum_client::{Client, ConfigBuilder, ElectrumApi, Socks5Config};
-use bdk_electrum::BdkElectrumClient;
-use bdk_wallet::bitcoin::{Network, Txid};
-use bdk_wallet::{KeychainKind, Wallet};
+use bdk_wallet::bitcoin::secp256k1::Secp256k1;
+use bdk_wallet::bitcoin::{
+    Address, FeeRate, Network, NetworkKind, OutPoint, Psbt, ScriptBuf, Transaction, Txid,
+};
+use bdk_wallet::miniscript::descriptor::{Descriptor, DescriptorPublicKey};
+use bdk_wallet::miniscript::ForEachKey;
+use bdk_wallet::{KeychainKind, SignOptions, Wallet};
+use sqlx::AnyPool;
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+use super::{ChainServerStatus, ChainSource, TxCache, TxStatus, WalletStore};
+
+/// Confirmations after which a parent transaction's height is cached
+const HEIGHT_CACHE_CONFIRMATIONS: u32 = 6;
+
+/// Consecutive unused addresses after which a full scan stops looking
+const STOP_GAP: usize = 20;
+
 pub struct BdkWallet {
     wallet: Arc<Mutex<Wallet>>,
-    electrum_url: String,
-    tor_proxy: Option<String>,
+    store: WalletStore,
+    /// Whether the wallet was restored from persisted state (vs. created fresh)
+    restored: bool,
+    /// Blockchain backend used for syncing, lookups and broadcasting
+    chain: Arc<dyn ChainSource>,
+    /// Parent transactions and heights fetched for eligibility checks
+    tx_cache: TxCache,
+    /// Public external descriptor, so deposit addresses derive without the wallet lock
+    descriptor: Descriptor<DescriptorPublicKey>,
+    network: Network,
+}
+
+/// Refuse a descriptor whose extended keys belong to a different network than the one
+/// configured, e.g. an xpub on testnet or a tpub on mainnet.
+fn check_descriptor_network(descriptor: &str, network: Network) -> Result<()> {
+    let secp = Secp256k1::new();
+    let (descriptor, _) = Descriptor::<DescriptorPublicKey>::parse_descriptor(&secp, descriptor)?;
+
+    let expected = NetworkKind::from(network);
+    let matches = descriptor.for_each_key(|key| match key {
+        DescriptorPublicKey::XPub(xkey) => xkey.xkey.network == expected,
+        DescriptorPublicKey::MultiXPub(xkey) => xkey.xkey.network == expected,
+        DescriptorPublicKey::Single(_) => true,
+    });
+
+    if !matches {
+        return Err(anyhow::anyhow!(
+            "WALLET_DESCRIPTOR keys are not valid for network {} (mainnet uses xpub, test networks use tpub)",
+            network
+        ));
+    }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
 pub struct DepositInfo {
     pub txid: String,
+    pub vout: u32,
     pub amount_sats: u64,
     pub confirmations: u32,
     /// The block height where this transaction was confirmed.
@@ -23,380 +68,516 @@ pub struct DepositInfo {
     pub block_height: Option<u32>,
 }
 
+/// One input of a deposit transaction, as seen by the eligibility check.
+#[derive(Debug, Clone)]
+pub struct InputDetails {
+    pub outpoint: OutPoint,
+    /// Value of the spent output, None if its parent transaction couldn't be fetched
+    pub value_sats: Option<u64>,
+    /// Height of the block the spent output was created in, None if unknown or unconfirmed
+    pub creation_height: Option<u32>,
+}
+
+impl InputDetails {
+    /// Why this input fails the payout rules, or None if it is eligible. The output it
+    /// spends must have been created before `cutoff_block_height` (checking when the
+    /// INPUT UTXO was created, not when the deposit confirmed) and be below
+    /// `max_input_sats`. An input that couldn't be verified is treated as ineligible.
+    pub fn ineligibility_reason(
+        &self,
+        cutoff_block_height: u32,
+        max_input_sats: u64,
+    ) -> Option<&'static str> {
+        match (self.creation_height, self.value_sats) {
+            (Some(height), _) if height >= cutoff_block_height => Some("block_height"),
+            (Some(_), Some(value)) if value >= max_input_sats => Some("input_too_large"),
+            (Some(_), Some(_)) => None,
+            (Some(_), None) => Some("value_unknown"),
+            (None, _) => Some("block_height_unknown"),
+        }
+    }
+}
+
 impl BdkWallet {
-    pub async fn new(descriptor: &str, electrum_url: &str, tor_proxy: Option<String>) -> Result<Self> {
-        let wallet = Wallet::create_single(descriptor.to_string())
-            .network(Network::Bitcoin)
-            .create_wallet_no_persist()?;
+    pub async fn new(
+        descriptor: &str,
+        network: Network,
+        chain: Arc<dyn ChainSource>,
+        db: AnyPool,
+    ) -> Result<Self> {
+        check_descriptor_network(descriptor, network)?;
+        let tx_cache = TxCache::new(db.clone());
+        let store = WalletStore::new(db);
+
+        // Restore from the database if we have persisted state, otherwise create fresh.
+        // The descriptor and network checks refuse to load a wallet persisted for a
+        // different WALLET_DESCRIPTOR.
+        let loaded = match store.load().await? {
+            Some(changeset) => Wallet::load()
+                .descriptor(KeychainKind::External, Some(descriptor.to_string()))
+                .check_network(network)
+                .load_wallet_no_persist(changeset)?,
+            None => None,
+        };
+
+        let (wallet, restored) = match loaded {
+            Some(wallet) => {
+                tracing::info!(
+                    "Restored wallet from database (checkpoint height {}, last revealed index {:?})",
+                    wallet.latest_checkpoint().height(),
+                    wallet.derivation_index(KeychainKind::External)
+                );
+                (wallet, true)
+            }
+            None => {
+                let mut wallet = Wallet::create_single(descriptor.to_string())
+                    .network(network)
+                    .create_wallet_no_persist()?;
+                if let Some(changeset) = wallet.take_staged() {
+                    store.persist(&changeset).await?;
+                }
+                (wallet, false)
+            }
+        };
+
+        let descriptor = wallet.public_descriptor(KeychainKind::External).clone();
 
         Ok(Self {
             wallet: Arc::new(Mutex::new(wallet)),
-            electrum_url: electrum_url.to_string(),
-            tor_proxy,
+            store,
+            restored,
+            chain,
+            tx_cache,
+            descriptor,
+            network,
         })
     }
 
-    fn create_client(&self) -> Result<BdkElectrumClient<Client>> {
-        let config = if let Some(ref proxy) = self.tor_proxy {
-            tracing::debug!("Connecting via Tor proxy: {}", proxy);
-            ConfigBuilder::new()
-                .socks5(Some(Socks5Config {
-                    addr: proxy.clone(),
-                    credentials: None,
-                }))
-                .timeout(Some(30))
-                .build()
-        } else {
-            ConfigBuilder::new()
-                .timeout(Some(30))
-                .build()
-        };
+    /// Whether the wallet was restored from persisted state. A restored wallet only
+    /// needs an incremental sync on startup rather than a full scan.
+    pub fn is_restored(&self) -> bool {
+        self.restored
+    }
 
-        let client = Client::from_config(&self.electrum_url, config)?;
-        Ok(BdkElectrumClient::new(client))
+    /// Name of the chain backend in use.
+    pub fn chain_backend(&self) -> &'static str {
+        self.chain.name()
     }
 
-    pub async fn get_address(&self, index: u32) -> Result<String> {
-        let wallet = self.wallet.lock().await;
-        let address = wallet.peek_address(KeychainKind::External, index);
-        Ok(address.address.to_string())
+    /// Health of each server behind the chain backend.
+    pub fn chain_server_status(&self) -> Vec<ChainServerStatus> {
+        self.chain.server_status()
     }
 
-    pub async fn full_scan(&self) -> Result<()> {
-        let electrum_url = self.electrum_url.clone();
-        let tor_proxy = self.tor_proxy.clone();
-        let wallet = self.wallet.clone();
+    /// Write any staged wallet changes to the database.
+    async fn persist_staged(&self) -> Result<()> {
+        let changeset = self.wallet.lock().await.take_staged();
+        if let Some(changeset) = changeset {
+            self.store.persist(&changeset).await?;
+        }
+        Ok(())
+    }
 
-        // Electrum client is synchronous, so run in blocking task
-        tokio::task::spawn_blocking(move || -> Result<()> {
-            let config = if let Some(ref proxy) = tor_proxy {
-                tracing::debug!("Connecting via Tor proxy: {}", proxy);
-                ConfigBuilder::new()
-                    .socks5(Some(Socks5Config {
-                        addr: proxy.clone(),
-                        credentials: None,
-                    }))
-                    .timeout(Some(60))
-                    .build()
-            } else {
-                ConfigBuilder::new()
-                    .timeout(Some(60))
-                    .build()
-            };
+    /// Deposit address at `index` of the external keychain. Doesn't reveal it.
+    pub fn address_at(&self, index: u32) -> Result<String> {
+        let address = self.descriptor.at_derivation_index(index)?.address(self.network)?;
+        Ok(address.to_string())
+    }
 
-            let client = Client::from_config(&electrum_url, config)?;
-            let electrum_client = BdkElectrumClient::new(client);
+    /// Highest external index revealed in the wallet, None if none has been.
+    pub async fn last_revealed_index(&self) -> Option<u32> {
+        self.wallet.lock().await.derivation_index(KeychainKind::External)
+    }
 
-            let mut wallet_guard = wallet.blocking_lock();
-            let request = wallet_guard.start_full_scan();
-            let update = electrum_client.full_scan(request, 20, 5, false)?;
-            wallet_guard.apply_update(update)?;
+    /// Scan the chain for every address of the wallet. Every address up to
+    /// `highest_index`, the highest one handed out to a recycle, is revealed and synced
+    /// whether or not it was used, so a run of abandoned recycles can't end the scan
+    /// before a funded address. Past those the scan stops after `STOP_GAP` unused ones.
+    pub async fn full_scan(&self, highest_index: Option<u32>) -> Result<()> {
+        if let Some(index) = highest_index {
+            self.reveal_addresses_up_to(index).await?;
+        }
+
+        let chain = self.chain.clone();
+        let wallet = self.wallet.clone();
 
-            Ok(())
+        // Chain backends are synchronous, so run in blocking task
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut wallet_guard = wallet.blocking_lock();
+            chain.sync(&mut wallet_guard)?;
+            chain.full_scan(&mut wallet_guard, STOP_GAP)
         })
         .await??;
 
-        Ok(())
+        self.persist_staged().await
     }
 
     pub async fn sync(&self) -> Result<()> {
-        let electrum_url = self.electrum_url.clone();
-        let tor_proxy = self.tor_proxy.clone();
+        let chain = self.chain.clone();
         let wallet = self.wallet.clone();
 
-        // Electrum client is synchronous, so run in blocking task
+        // Chain backends are synchronous, so run in blocking task
         tokio::task::spawn_blocking(move || -> Result<()> {
-            let config = if let Some(ref proxy) = tor_proxy {
-                ConfigBuilder::new()
-                    .socks5(Some(Socks5Config {
-                        addr: proxy.clone(),
-                        credentials: None,
-                    }))
-                    .timeout(Some(30))
-                    .build()
-            } else {
-                ConfigBuilder::new()
-                    .timeout(Some(30))
-                    .build()
-            };
-
-            let client = Client::from_config(&electrum_url, config)?;
-            let electrum_client = BdkElectrumClient::new(client);
-
             let mut wallet_guard = wallet.blocking_lock();
-            let request = wallet_guard.start_sync_with_revealed_spks();
-            let update = electrum_client.sync(request, 5, false)?;
-            wallet_guard.apply_update(update)?;
-
-            Ok(())
+            chain.sync(&mut wallet_guard)
         })
         .await??;
 
-        Ok(())
+        self.persist_staged().await
     }
 
-    pub async fn check_address_deposit(
-        &self,
-        _address: &str,
-        address_index: u32,
-    ) -> Result<Option<DepositInfo>> {
+    /// Watch the addresses with the given indices for incoming transactions.
+    /// Returns false if the chain backend doesn't support push notifications.
+    pub async fn watch_addresses(&self, indices: &[u32]) -> Result<bool> {
+        let scripts = self.address_scripts(indices).await;
+        let chain = self.chain.clone();
+
+        tokio::task::spawn_blocking(move || chain.watch(&scripts)).await?
+    }
+
+    /// Stop watching the addresses with the given indices.
+    pub async fn unwatch_addresses(&self, indices: &[u32]) -> Result<()> {
+        let scripts = self.address_scripts(indices).await;
+        let chain = self.chain.clone();
+
+        tokio::task::spawn_blocking(move || chain.unwatch(&scripts)).await?
+    }
+
+    async fn address_scripts(&self, indices: &[u32]) -> Vec<ScriptBuf> {
+        let wallet = self.wallet.lock().await;
+        indices
+            .iter()
+            .map(|index| wallet.peek_address(KeychainKind::External, *index).script_pubkey())
+            .collect()
+    }
+
+    /// Whether a watched address or the chain tip changed since the last poll.
+    pub async fn poll_chain_changes(&self) -> Result<bool> {
+        let chain = self.chain.clone();
+        tokio::task::spawn_blocking(move || chain.poll_changes()).await?
+    }
+
+    /// List every output received at the address with the given index, including
+    /// outputs that have since been spent.
+    pub async fn check_address_deposits(&self, address_index: u32) -> Result<Vec<DepositInfo>> {
         let wallet = self.wallet.lock().await;
+        let current_height = wallet.latest_checkpoint().height();
+
+        let deposits = wallet
+            .list_output()
+            .filter(|output| {
+                output.keychain == KeychainKind::External && output.derivation_index == address_index
+            })
+            .map(|output| {
+                let (confirmations, block_height) = match output.chain_position {
+                    bdk_wallet::chain::ChainPosition::Confirmed {
+                        anchor,
+                        transitively: _,
+                    } => {
+                        let confs = current_height.saturating_sub(anchor.block_id.height) + 1;
+                        (confs, Some(anchor.block_id.height))
+                    }
+                    bdk_wallet::chain::ChainPosition::Unconfirmed { .. } => (0, None),
+                };
 
-        // Get transactions for this wallet
-        for tx in wallet.transactions() {
-            let txid = tx.tx_node.txid.to_string();
-
-            // Check if any output goes to our address
-            for output in tx.tx_node.tx.output.iter() {
-                let script = &output.script_pubkey;
-
-                // Get the address for this index and compare
-                let expected_address = wallet.peek_address(KeychainKind::External, address_index);
-                if *script == expected_address.address.script_pubkey() {
-                    let (confirmations, block_height) = match tx.chain_position {
-                        bdk_wallet::chain::ChainPosition::Confirmed {
-                            anchor,
-                            transitively: _,
-                        } => {
-                            let current_height = wallet.latest_checkpoint().height();
-                            let confs = current_height.saturating_sub(anchor.block_id.height) + 1;
-                            (confs, Some(anchor.block_id.height))
-                        }
-                        bdk_wallet::chain::ChainPosition::Unconfirmed { .. } => (0, None),
-                    };
-
-                    return Ok(Some(DepositInfo {
-                        txid,
-                        amount_sats: output.value.to_sat(),
-                        confirmations,
-                        block_height,
-                    }));
+                DepositInfo {
+                    txid: output.outpoint.txid.to_string(),
+                    vout: output.outpoint.vout,
+                    amount_sats: output.txout.value.to_sat(),
+                    confirmations,
+                    block_height,
                 }
-            }
-        }
+            })
+            .collect();
 
-        Ok(None)
+        Ok(deposits)
     }
 
-    pub async fn reveal_addresses_up_to(&self, index: u32) -> Result<()> {
-        let mut wallet = self.wallet.lock().await;
+    /// Whether the address at `index` has never received an output, as far as the
+    /// wallet has synced.
+    pub async fn is_address_unused(&self, index: u32) -> bool {
+        let wallet = self.wallet.lock().await;
+        let used = wallet
+            .list_output()
+            .any(|output| output.keychain == KeychainKind::External && output.derivation_index == index);
+        !used
+    }
 
-        // Reveal addresses up to and including the given index
-        let _ = wallet.reveal_addresses_to(KeychainKind::External, index);
+    /// Ask the chain backend where a deposit transaction to the address with the given
+    /// index is now. Used to tell a deposit that lost its confirmation in a reorg apart
+    /// from one that was dropped or double-spent.
+    pub async fn deposit_tx_status(&self, txid: &str, address_index: u32) -> Result<TxStatus> {
+        let txid = Txid::from_str(txid)?;
+        let script_pubkey = {
+            let wallet = self.wallet.lock().await;
+            wallet.peek_address(KeychainKind::External, address_index).script_pubkey()
+        };
+        let chain = self.chain.clone();
 
-        Ok(())
+        // Chain backends are synchronous, so run in blocking task
+        tokio::task::spawn_blocking(move || chain.tx_status(&txid, &script_pubkey)).await?
     }
 
-    /// Get the maximum input UTXO value for a transaction.
-    /// This looks up each input's previous output to determine the original UTXO sizes.
-    /// Returns the largest input value, or None if the transaction couldn't be found/parsed.
-    pub async fn get_max_input_value(&self, txid_str: &str) -> Result<Option<u64>> {
-        let electrum_url = self.electrum_url.clone();
-        let tor_proxy = self.tor_proxy.clone();
-        let txid_string = txid_str.to_string();
-
-        // Electrum client is synchronous, so run in blocking task
-        tokio::task::spawn_blocking(move || -> Result<Option<u64>> {
-            let config = if let Some(ref proxy) = tor_proxy {
-                ConfigBuilder::new()
-                    .socks5(Some(Socks5Config {
-                        addr: proxy.clone(),
-                        credentials: None,
-                    }))
-                    .timeout(Some(30))
-                    .build()
-            } else {
-                ConfigBuilder::new()
-                    .timeout(Some(30))
-                    .build()
+    /// Whether a deposit transaction was replaced or double-spent: another transaction
+    /// that is confirmed or in the mempool spends one of its inputs.
+    pub async fn is_deposit_double_spent(&self, txid: &str) -> Result<bool> {
+        let txid = Txid::from_str(txid)?;
+        let known = self.wallet.lock().await.tx_graph().get_tx(txid);
+        let chain = self.chain.clone();
+
+        // Chain backends are synchronous, so run in blocking task
+        tokio::task::spawn_blocking(move || -> Result<bool> {
+            let tx = match known {
+                Some(tx) => tx,
+                None => Arc::new(chain.get_transaction(&txid)?),
             };
+            chain.is_double_spent(&tx)
+        })
+        .await?
+    }
 
-            let client = Client::from_config(&electrum_url, config)?;
+    pub async fn reveal_addresses_up_to(&self, index: u32) -> Result<()> {
+        {
+            let mut wallet = self.wallet.lock().await;
 
-            // Parse the txid
-            let txid = match Txid::from_str(&txid_string) {
-                Ok(t) => t,
-                Err(e) => {
-                    tracing::warn!("Failed to parse txid {}: {}", txid_string, e);
-                    return Ok(None);
-                }
-            };
+            // Reveal addresses up to and including the given index
+            let _ = wallet.reveal_addresses_to(KeychainKind::External, index);
+        }
 
-            // Fetch the transaction
-            let tx = match client.transaction_get(&txid) {
-                Ok(t) => t,
-                Err(e) => {
-                    tracing::warn!("Failed to fetch transaction {}: {}", txid_string, e);
-                    return Ok(None);
+        self.persist_staged().await
+    }
+
+    /// Filter the given outpoints down to unspent wallet UTXOs with at least
+    /// `min_confirmations` confirmations, returning each with its value in sats.
+    pub async fn spendable_utxos(
+        &self,
+        outpoints: &[OutPoint],
+        min_confirmations: u32,
+    ) -> Vec<(OutPoint, u64)> {
+        let wallet = self.wallet.lock().await;
+        let current_height = wallet.latest_checkpoint().height();
+
+        outpoints
+            .iter()
+            .filter_map(|outpoint| wallet.get_utxo(*outpoint))
+            .filter(|utxo| match utxo.chain_position {
+                bdk_wallet::chain::ChainPosition::Confirmed { anchor, .. } => {
+                    current_height.saturating_sub(anchor.block_id.height) + 1 >= min_confirmations
                 }
-            };
+                bdk_wallet::chain::ChainPosition::Unconfirmed { .. } => false,
+            })
+            .map(|utxo| (utxo.outpoint, utxo.txout.value.to_sat()))
+            .collect()
+    }
 
-            let mut max_input_value: u64 = 0;
-
-            // For each input, look up the previous output's value
-            for input in tx.input.iter() {
-                let prev_txid = input.previous_output.txid;
-                let prev_vout = input.previous_output.vout as usize;
-
-                // Fetch the previous transaction
-                let prev_tx = match client.transaction_get(&prev_txid) {
-                    Ok(t) => t,
-                    Err(e) => {
-                        tracing::warn!(
-                            "Failed to fetch parent tx {} for input: {}",
-                            prev_txid,
-                            e
-                        );
-                        // Can't verify this input - skip and continue
-                        // We'll be conservative and not reject based on unknown inputs
-                        continue;
-                    }
-                };
+    /// Build an unsigned PSBT spending exactly the given UTXOs to `destination`,
+    /// with no change output, at the given fee rate.
+    pub async fn build_sweep_psbt(
+        &self,
+        outpoints: &[OutPoint],
+        destination: &str,
+        fee_rate_sat_vb: u64,
+    ) -> Result<Psbt> {
+        let mut wallet = self.wallet.lock().await;
 
-                // Get the output value at the specified index
-                if let Some(output) = prev_tx.output.get(prev_vout) {
-                    let value = output.value.to_sat();
-                    if value > max_input_value {
-                        max_input_value = value;
-                    }
-                    tracing::debug!(
-                        "Input from {}:{} has value {} sats",
-                        prev_txid,
-                        prev_vout,
-                        value
-                    );
-                }
-            }
+        let destination = Address::from_str(destination)?.require_network(wallet.network())?;
+        let fee_rate = FeeRate::from_sat_per_vb(fee_rate_sat_vb)
+            .ok_or_else(|| anyhow::anyhow!("Invalid fee rate: {} sat/vB", fee_rate_sat_vb))?;
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_utxos(outpoints)?
+            .manually_selected_only()
+            .drain_to(destination.script_pubkey())
+            .fee_rate(fee_rate);
+
+        Ok(builder.finish()?)
+    }
+
+    /// Finalize a PSBT signed offline and extract the network-ready transaction.
+    /// Inputs the external signer already finalized are left untouched.
+    pub async fn finalize_psbt(&self, mut psbt: Psbt) -> Result<Transaction> {
+        let wallet = self.wallet.lock().await;
+
+        let finalized = wallet.finalize_psbt(&mut psbt, SignOptions::default())?;
+        if !finalized {
+            return Err(anyhow::anyhow!("PSBT is missing signatures for one or more inputs"));
+        }
+
+        Ok(psbt.extract_tx()?)
+    }
+
+    /// Broadcast a transaction and record it in the wallet as unconfirmed.
+    pub async fn broadcast(&self, tx: Transaction) -> Result<Txid> {
+        let chain = self.chain.clone();
+        let broadcast_tx = tx.clone();
+
+        // Chain backends are synchronous, so run in blocking task
+        let txid =
+            tokio::task::spawn_blocking(move || chain.broadcast(&broadcast_tx)).await??;
+
+        {
+            let mut wallet = self.wallet.lock().await;
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            wallet.apply_unconfirmed_txs([(tx, now)]);
+        }
+        self.persist_staged().await?;
 
-            if max_input_value == 0 && !tx.input.is_empty() {
-                // Couldn't determine any input values
-                tracing::warn!("Could not determine input values for tx {}", txid_string);
+        Ok(txid)
+    }
+
+    /// Look up every input of a deposit transaction: the value of the output it spends
+    /// (from the parent transaction) and the block height that output was created in
+    /// (from the backend's history lookup). This checks when the INPUT UTXOs were
+    /// created, NOT when the deposit transaction was confirmed.
+    /// Returns None if the deposit transaction itself couldn't be found.
+    pub async fn get_input_details(&self, txid_str: &str) -> Result<Option<Vec<InputDetails>>> {
+        let txid = match Txid::from_str(txid_str) {
+            Ok(t) => t,
+            Err(e) => {
+                tracing::warn!("Failed to parse txid {}: {}", txid_str, e);
                 return Ok(None);
             }
+        };
 
-            Ok(Some(max_input_value))
-        })
-        .await?
+        // Fetch the deposit transaction
+        let Some(tx) = self.fetch_transactions(&[txid]).await?.remove(&txid) else {
+            return Ok(None);
+        };
+
+        let outpoints = tx.input.iter().map(|input| input.previous_output).collect();
+        Ok(Some(self.get_outpoint_details(outpoints).await?))
     }
 
-    /// Get the maximum creation block height of input UTXOs.
-    /// This checks when the INPUT UTXOs were originally created (confirmed),
-    /// NOT when the deposit transaction was confirmed.
-    /// Used to verify UTXOs existed before the cutoff block.
-    pub async fn get_max_input_creation_height(&self, txid_str: &str) -> Result<Option<u32>> {
-        let electrum_url = self.electrum_url.clone();
-        let tor_proxy = self.tor_proxy.clone();
-        let txid_string = txid_str.to_string();
-
-        tokio::task::spawn_blocking(move || -> Result<Option<u32>> {
-            let config = if let Some(ref proxy) = tor_proxy {
-                ConfigBuilder::new()
-                    .socks5(Some(Socks5Config {
-                        addr: proxy.clone(),
-                        credentials: None,
-                    }))
-                    .timeout(Some(30))
-                    .build()
-            } else {
-                ConfigBuilder::new()
-                    .timeout(Some(30))
-                    .build()
-            };
+    /// Look up the value and creation height of arbitrary outputs, e.g. the inputs
+    /// of a transaction the user hasn't broadcast yet. Each parent transaction is
+    /// fetched once, from the cache where possible and otherwise in one batch.
+    /// Lookup failures are logged and leave the corresponding field as None.
+    pub async fn get_outpoint_details(&self, outpoints: Vec<OutPoint>) -> Result<Vec<InputDetails>> {
+        // Fetch the previous transactions for the spent outputs' values and scripts
+        let mut parent_txids: Vec<Txid> = outpoints.iter().map(|outpoint| outpoint.txid).collect();
+        parent_txids.sort();
+        parent_txids.dedup();
+        let parents = self.fetch_transactions(&parent_txids).await?;
+
+        let mut details = Vec::with_capacity(outpoints.len());
+        let mut lookups: HashMap<Txid, ScriptBuf> = HashMap::new();
+        for outpoint in outpoints {
+            let output = parents
+                .get(&outpoint.txid)
+                .and_then(|tx| tx.output.get(outpoint.vout as usize));
+            if let Some(output) = output {
+                lookups
+                    .entry(outpoint.txid)
+                    .or_insert_with(|| output.script_pubkey.clone());
+            } else if parents.contains_key(&outpoint.txid) {
+                tracing::warn!("Output {} not found in tx {}", outpoint.vout, outpoint.txid);
+            }
 
-            let client = Client::from_config(&electrum_url, config)?;
+            details.push(InputDetails {
+                outpoint,
+                value_sats: output.map(|o| o.value.to_sat()),
+                creation_height: None,
+            });
+        }
 
-            // Parse the txid
-            let txid = match Txid::from_str(&txid_string) {
-                Ok(t) => t,
-                Err(e) => {
-                    tracing::warn!("Failed to parse txid {}: {}", txid_string, e);
-                    return Ok(None);
-                }
-            };
+        // Find the block height that confirmed each parent transaction
+        let heights = self.confirmation_heights(lookups.into_iter().collect()).await?;
+        for input in details.iter_mut().filter(|input| input.value_sats.is_some()) {
+            input.creation_height = heights.get(&input.outpoint.txid).copied();
+            tracing::debug!(
+                "Input {} has value {:?} sats, created in block {:?}",
+                input.outpoint,
+                input.value_sats,
+                input.creation_height
+            );
+        }
 
-            // Fetch the deposit transaction
-            let tx = match client.transaction_get(&txid) {
-                Ok(t) => t,
-                Err(e) => {
-                    tracing::warn!("Failed to fetch transaction {}: {}", txid_string, e);
-                    return Ok(None);
-                }
-            };
+        Ok(details)
+    }
 
-            let mut max_creation_height: u32 = 0;
-
-            // For each input, find when the parent UTXO was created
-            for input in tx.input.iter() {
-                let prev_txid = input.previous_output.txid;
-                let prev_vout = input.previous_output.vout as usize;
-
-                // Fetch the previous transaction to get the output script
-                let prev_tx = match client.transaction_get(&prev_txid) {
-                    Ok(t) => t,
-                    Err(e) => {
-                        tracing::warn!(
-                            "Failed to fetch parent tx {} for input: {}",
-                            prev_txid,
-                            e
-                        );
-                        continue;
-                    }
-                };
+    /// Fetch transactions from the cache, then the chain backend for the rest. Ones
+    /// that couldn't be fetched are logged and left out of the map.
+    async fn fetch_transactions(&self, txids: &[Txid]) -> Result<HashMap<Txid, Transaction>> {
+        let mut found = self.tx_cache.transactions(txids).await?;
+        let missing: Vec<Txid> = txids
+            .iter()
+            .filter(|&txid| !found.contains_key(txid))
+            .copied()
+            .collect();
+        if missing.is_empty() {
+            return Ok(found);
+        }
 
-                // Get the output script that was spent
-                let output = match prev_tx.output.get(prev_vout) {
-                    Some(o) => o,
-                    None => {
-                        tracing::warn!("Output {} not found in tx {}", prev_vout, prev_txid);
-                        continue;
-                    }
-                };
+        let chain = self.chain.clone();
 
-                // Get history for this script to find the block height
-                let script = &output.script_pubkey;
-                let history = match client.script_get_history(script) {
-                    Ok(h) => h,
-                    Err(e) => {
-                        tracing::warn!(
-                            "Failed to get script history for parent tx {}: {}",
-                            prev_txid,
-                            e
-                        );
-                        continue;
-                    }
-                };
+        // Chain backends are synchronous, so run in blocking task
+        let (missing, results) = tokio::task::spawn_blocking(move || {
+            let results = chain.get_transactions(&missing);
+            (missing, results)
+        })
+        .await?;
 
-                // Find our parent transaction in the history
-                for entry in history {
-                    if entry.tx_hash == prev_txid && entry.height > 0 {
-                        let height = entry.height as u32;
-                        tracing::debug!(
-                            "Input UTXO {}:{} was created in block {}",
-                            prev_txid,
-                            prev_vout,
-                            height
-                        );
-                        if height > max_creation_height {
-                            max_creation_height = height;
-                        }
-                        break;
-                    }
-                }
+        let mut fetched = Vec::new();
+        for (txid, result) in missing.iter().zip(results) {
+            match result {
+                Ok(tx) => fetched.push(tx),
+                Err(e) => tracing::warn!("Failed to fetch transaction {}: {}", txid, e),
             }
+        }
+        self.tx_cache.insert_transactions(&fetched).await?;
 
-            if max_creation_height == 0 && !tx.input.is_empty() {
-                tracing::warn!("Could not determine input creation heights for tx {}", txid_string);
-                return Ok(None);
-            }
+        // Keyed by the computed txid, so a server answering with the wrong transaction
+        // just leaves the requested one missing
+        found.extend(fetched.into_iter().map(|tx| (tx.compute_txid(), tx)));
+        Ok(found)
+    }
 
-            tracing::info!(
-                "Max input UTXO creation height for tx {}: block {}",
-                txid_string,
-                max_creation_height
-            );
+    /// Confirmation heights of transactions, from the cache and then the chain backend.
+    /// `lookups` pairs each txid with one of its output scripts. Unconfirmed and failed
+    /// lookups are left out of the map.
+    async fn confirmation_heights(
+        &self,
+        mut lookups: Vec<(Txid, ScriptBuf)>,
+    ) -> Result<HashMap<Txid, u32>> {
+        let txids: Vec<Txid> = lookups.iter().map(|(txid, _)| *txid).collect();
+        let mut found = self.tx_cache.heights(&txids).await?;
+        lookups.retain(|(txid, _)| !found.contains_key(txid));
+        if lookups.is_empty() {
+            return Ok(found);
+        }
 
-            Ok(Some(max_creation_height))
+        let chain = self.chain.clone();
+
+        // Chain backends are synchronous, so run in blocking task
+        let (lookups, results) = tokio::task::spawn_blocking(move || {
+            let results = chain.confirmation_heights(&lookups);
+            (lookups, results)
         })
-        .await?
+        .await?;
+
+        // Only cache heights buried deep enough that a reorg won't move them
+        let tip = self.wallet.lock().await.latest_checkpoint().height();
+        let mut settled = Vec::new();
+        for ((txid, _), result) in lookups.iter().zip(results) {
+            match result {
+                Ok(Some(height)) => {
+                    found.insert(*txid, height);
+                    if tip.saturating_sub(height) + 1 >= HEIGHT_CACHE_CONFIRMATIONS {
+                        settled.push((*txid, height));
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to get confirmation height for parent tx {}: {}", txid, e)
+                }
+            }
+        }
+        self.tx_cache.insert_heights(&settled).await?;
+
+        Ok(found)
     }
 }
@@ -0,0 +1,123 @@
+use anyhow::Result;
+use bdk_wallet::bitcoin::consensus::encode::{deserialize, serialize};
+use bdk_wallet::bitcoin::{Transaction, Txid};
+use chrono::Utc;
+use sqlx::AnyPool;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Most txids bound in one query, well under the database's parameter limit
+const QUERY_CHUNK_SIZE: usize = 500;
+
+/// Persisted cache of the parent transactions and confirmation heights looked up for
+/// eligibility checks, kept in the service's database.
+///
+/// A dust sweep can spend hundreds of inputs, and the same parents are looked up again
+/// by pre-deposit checks, submitted deposits and re-checks after a reorg. Transactions
+/// are immutable by txid so they are cached as-is; callers only store confirmation
+/// heights buried deep enough that a reorg won't move them.
+#[derive(Clone)]
+pub struct TxCache {
+    db: AnyPool,
+}
+
+impl TxCache {
+    pub fn new(db: AnyPool) -> Self {
+        Self { db }
+    }
+
+    /// Cached transactions among `txids`. Missing ones are simply absent from the map.
+    pub async fn transactions(&self, txids: &[Txid]) -> Result<HashMap<Txid, Transaction>> {
+        let mut found = HashMap::new();
+
+        for chunk in txids.chunks(QUERY_CHUNK_SIZE) {
+            let sql = format!("SELECT txid, raw_tx FROM tx_cache WHERE txid IN ({})", placeholders(chunk.len()));
+            let mut query = sqlx::query_as(&sql);
+            for txid in chunk {
+                query = query.bind(txid.to_string());
+            }
+
+            let rows: Vec<(String, Vec<u8>)> = query.fetch_all(&self.db).await?;
+            for (txid, raw_tx) in rows {
+                found.insert(Txid::from_str(&txid)?, deserialize(&raw_tx)?);
+            }
+        }
+
+        Ok(found)
+    }
+
+    pub async fn insert_transactions(&self, txs: &[Transaction]) -> Result<()> {
+        if txs.is_empty() {
+            return Ok(());
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let mut tx = self.db.begin().await?;
+        for transaction in txs {
+            sqlx::query(
+                "INSERT INTO tx_cache (txid, raw_tx, created_at) VALUES ($1, $2, $3) ON CONFLICT (txid) DO NOTHING",
+            )
+                .bind(transaction.compute_txid().to_string())
+                .bind(serialize(transaction))
+                .bind(&now)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Cached confirmation heights among `txids`.
+    pub async fn heights(&self, txids: &[Txid]) -> Result<HashMap<Txid, u32>> {
+        let mut found = HashMap::new();
+
+        for chunk in txids.chunks(QUERY_CHUNK_SIZE) {
+            let sql = format!(
+                "SELECT txid, block_height FROM tx_heights WHERE txid IN ({})",
+                placeholders(chunk.len())
+            );
+            let mut query = sqlx::query_as(&sql);
+            for txid in chunk {
+                query = query.bind(txid.to_string());
+            }
+
+            let rows: Vec<(String, i64)> = query.fetch_all(&self.db).await?;
+            for (txid, height) in rows {
+                found.insert(Txid::from_str(&txid)?, height as u32);
+            }
+        }
+
+        Ok(found)
+    }
+
+    pub async fn insert_heights(&self, heights: &[(Txid, u32)]) -> Result<()> {
+        if heights.is_empty() {
+            return Ok(());
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let mut tx = self.db.begin().await?;
+        for (txid, height) in heights {
+            sqlx::query(
+                r#"
+                INSERT INTO tx_heights (txid, block_height, created_at) VALUES ($1, $2, $3)
+                ON CONFLICT (txid) DO UPDATE SET block_height = excluded.block_height, created_at = excluded.created_at
+                "#,
+            )
+            .bind(txid.to_string())
+            .bind(*height as i64)
+            .bind(&now)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Positional parameters `$1, $2, ...` for an IN list of `count` values
+fn placeholders(count: usize) -> String {
+    (1..=count).map(|i| format!("${}", i)).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,342 @@
+use crate::config::{ChainBackend, Config};
+use anyhow::{anyhow, Result};
+use bdk_bitcoind_rpc::bitcoincore_rpc::{self, RpcApi};
+use bdk_bitcoind_rpc::Emitter;
+use bdk_esplora::esplora_client;
+use bdk_esplora::EsploraExt;
+use bdk_wallet::bitcoin::{Script, ScriptBuf, Transaction, Txid};
+use bdk_wallet::chain::spk_client::FullScanRequestBuilder;
+use bdk_wallet::chain::SpkIterator;
+use bdk_wallet::{KeychainKind, Wallet};
+use serde::Serialize;
+use std::sync::Arc;
+
+use super::ElectrumPool;
+
+/// Number of scripts requested in parallel from Esplora
+const BATCH_SIZE: usize = 5;
+
+/// Health of one server behind a chain backend, reported by `/health`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainServerStatus {
+    pub url: String,
+    /// Whether this server handled the most recent successful request
+    pub active: bool,
+    pub healthy: bool,
+    pub latency_ms: Option<u64>,
+    pub tip_height: Option<u32>,
+    pub successes: u64,
+    pub errors: u64,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Where a transaction currently is, according to the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Confirmed in the best chain at this height
+    Confirmed(u32),
+    Mempool,
+    /// Neither confirmed nor in the mempool: dropped, replaced or double-spent
+    Unknown,
+}
+
+/// A blockchain data source the wallet syncs against and looks up transactions from.
+///
+/// Implementations are blocking; callers run them inside `spawn_blocking`.
+pub trait ChainSource: Send + Sync {
+    /// Human-readable backend name for logging
+    fn name(&self) -> &'static str;
+
+    /// Scan the keychain scripts past the last revealed one until `stop_gap` unused
+    /// scripts and apply the result. The revealed scripts are left to `sync`.
+    fn full_scan(&self, wallet: &mut Wallet, stop_gap: usize) -> Result<()>;
+
+    /// Sync the wallet's revealed scripts and apply the result.
+    fn sync(&self, wallet: &mut Wallet) -> Result<()>;
+
+    /// Fetch a transaction by txid.
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction>;
+
+    /// Fetch several transactions, in the order given. Backends that support batched
+    /// requests override this to save round-trips.
+    fn get_transactions(&self, txids: &[Txid]) -> Vec<Result<Transaction>> {
+        txids.iter().map(|txid| self.get_transaction(txid)).collect()
+    }
+
+    /// Whether `txid` is confirmed, in the mempool or unknown. `script_pubkey` is one
+    /// of the transaction's output scripts, used by backends that index history by script.
+    fn tx_status(&self, txid: &Txid, script_pubkey: &Script) -> Result<TxStatus>;
+
+    /// Whether an input of `tx` is spent by a different transaction that is confirmed
+    /// or in the mempool, i.e. `tx` was replaced or double-spent.
+    fn is_double_spent(&self, tx: &Transaction) -> Result<bool>;
+
+    /// The height of the block that confirmed `txid`, or None if unconfirmed.
+    fn confirmation_height(&self, txid: &Txid, script_pubkey: &Script) -> Result<Option<u32>> {
+        match self.tx_status(txid, script_pubkey)? {
+            TxStatus::Confirmed(height) => Ok(Some(height)),
+            TxStatus::Mempool | TxStatus::Unknown => Ok(None),
+        }
+    }
+
+    /// `confirmation_height` for several transactions, in the order given. Backends
+    /// that support batched requests override this to save round-trips.
+    fn confirmation_heights(&self, lookups: &[(Txid, ScriptBuf)]) -> Vec<Result<Option<u32>>> {
+        lookups
+            .iter()
+            .map(|(txid, script_pubkey)| self.confirmation_height(txid, script_pubkey))
+            .collect()
+    }
+
+    /// Broadcast a transaction to the network.
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid>;
+
+    /// Watch scripts for new transactions, and the chain for new blocks, via push
+    /// notifications. Returns false if the backend doesn't support notifications.
+    fn watch(&self, _scripts: &[ScriptBuf]) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Stop watching scripts that no longer need notifications.
+    fn unwatch(&self, _scripts: &[ScriptBuf]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether a watched script or the chain tip changed since the last poll.
+    fn poll_changes(&self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Per-server health, for backends that spread requests over several servers.
+    fn server_status(&self) -> Vec<ChainServerStatus> {
+        Vec::new()
+    }
+}
+
+/// A full scan request for the scripts past the last revealed one of each keychain.
+pub(super) fn full_scan_request(wallet: &Wallet) -> FullScanRequestBuilder<KeychainKind> {
+    let mut request = wallet.start_full_scan();
+    let index = wallet.spk_index();
+    for keychain in [KeychainKind::External, KeychainKind::Internal] {
+        let (Some(descriptor), Some(last)) = (index.get_descriptor(keychain), index.last_revealed_index(keychain))
+        else {
+            continue;
+        };
+        let spks = SpkIterator::new_with_range(descriptor.clone(), last + 1..);
+        request = request.spks_for_keychain(keychain, spks);
+    }
+    request
+}
+
+/// Build the chain source selected by `CHAIN_BACKEND`.
+pub fn chain_source_from_config(config: &Config) -> Result<Arc<dyn ChainSource>> {
+    let source: Arc<dyn ChainSource> = match config.chain_backend {
+        ChainBackend::Electrum => Arc::new(ElectrumPool::new(
+            config.electrum_urls.clone(),
+            config.tor_proxy.clone(),
+        )?),
+        ChainBackend::Esplora => Arc::new(EsploraSource::new(&config.esplora_url)),
+        ChainBackend::BitcoindRpc => Arc::new(BitcoindRpcSource::new(
+            &config.bitcoind_rpc_url,
+            config.bitcoind_rpc_auth(),
+            config.bitcoind_start_height,
+        )?),
+    };
+
+    Ok(source)
+}
+
+/// Esplora HTTP API backend (e.g., mempool.space or a self-hosted electrs/esplora).
+pub struct EsploraSource {
+    client: esplora_client::BlockingClient,
+}
+
+impl EsploraSource {
+    pub fn new(url: &str) -> Self {
+        let client = esplora_client::Builder::new(url).timeout(30).build_blocking();
+        Self { client }
+    }
+}
+
+impl ChainSource for EsploraSource {
+    fn name(&self) -> &'static str {
+        "esplora"
+    }
+
+    fn full_scan(&self, wallet: &mut Wallet, stop_gap: usize) -> Result<()> {
+        let update = self.client.full_scan(full_scan_request(wallet), stop_gap, BATCH_SIZE)?;
+        wallet.apply_update(update)?;
+        Ok(())
+    }
+
+    fn sync(&self, wallet: &mut Wallet) -> Result<()> {
+        let request = wallet.start_sync_with_revealed_spks();
+        let update = self.client.sync(request, BATCH_SIZE)?;
+        wallet.apply_update(update)?;
+        Ok(())
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        Ok(self.client.get_tx_no_opt(txid)?)
+    }
+
+    fn tx_status(&self, txid: &Txid, _script_pubkey: &Script) -> Result<TxStatus> {
+        let status = self.client.get_tx_status(txid)?;
+        if let Some(height) = status.block_height.filter(|_| status.confirmed) {
+            return Ok(TxStatus::Confirmed(height));
+        }
+
+        // Unconfirmed and unknown transactions share a status, so look the tx up
+        match self.client.get_tx(txid)? {
+            Some(_) => Ok(TxStatus::Mempool),
+            None => Ok(TxStatus::Unknown),
+        }
+    }
+
+    fn is_double_spent(&self, tx: &Transaction) -> Result<bool> {
+        let txid = tx.compute_txid();
+        for input in &tx.input {
+            let prevout = input.previous_output;
+            let status = self.client.get_output_status(&prevout.txid, prevout.vout as u64)?;
+            if let Some(spender) = status.filter(|status| status.spent).and_then(|status| status.txid) {
+                if spender != txid {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        self.client.broadcast(tx)?;
+        Ok(tx.compute_txid())
+    }
+}
+
+/// Bitcoin Core JSON-RPC backend. The wallet is synced by scanning blocks, and
+/// transaction lookups require the node to run with `txindex=1`.
+pub struct BitcoindRpcSource {
+    client: bitcoincore_rpc::Client,
+    /// Height to start scanning from when the wallet has no checkpoints yet
+    start_height: u32,
+}
+
+impl BitcoindRpcSource {
+    pub fn new(url: &str, auth: bitcoincore_rpc::Auth, start_height: u32) -> Result<Self> {
+        let client = bitcoincore_rpc::Client::new(url, auth)?;
+        Ok(Self {
+            client,
+            start_height,
+        })
+    }
+}
+
+impl ChainSource for BitcoindRpcSource {
+    fn name(&self) -> &'static str {
+        "bitcoind_rpc"
+    }
+
+    /// Block scanning matches the scripts up to the last revealed index plus the
+    /// wallet's lookahead, so a full scan is the same as a sync and `stop_gap` has no
+    /// effect. A gap wider than the lookahead can't be covered, which is logged.
+    fn full_scan(&self, wallet: &mut Wallet, stop_gap: usize) -> Result<()> {
+        let lookahead = wallet.spk_index().lookahead() as usize;
+        if stop_gap > lookahead {
+            tracing::warn!(
+                "bitcoind RPC only scans {} addresses past the last revealed one, not the requested stop gap of {}",
+                lookahead,
+                stop_gap
+            );
+        }
+        self.sync(wallet)
+    }
+
+    fn sync(&self, wallet: &mut Wallet) -> Result<()> {
+        let mut emitter = Emitter::new(&self.client, wallet.latest_checkpoint(), self.start_height);
+
+        while let Some(event) = emitter.next_block()? {
+            let height = event.block_height();
+            wallet.apply_block_connected_to(&event.block, height, event.connected_to())?;
+            if height % 1000 == 0 {
+                tracing::debug!("Scanned block {} via bitcoind RPC", height);
+            }
+        }
+
+        let mempool = emitter.mempool()?;
+        wallet.apply_unconfirmed_txs(mempool);
+
+        Ok(())
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        Ok(self.client.get_raw_transaction(txid, None)?)
+    }
+
+    fn tx_status(&self, txid: &Txid, _script_pubkey: &Script) -> Result<TxStatus> {
+        let info = match self.client.get_raw_transaction_info(txid, None) {
+            Ok(info) => info,
+            // RPC_INVALID_ADDRESS_OR_KEY: not in the mempool or the (tx-indexed) chain
+            Err(bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(ref e)))
+                if e.code == -5 =>
+            {
+                return Ok(TxStatus::Unknown);
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match info.blockhash {
+            Some(block_hash) => {
+                let header = self.client.get_block_header_info(&block_hash)?;
+                Ok(TxStatus::Confirmed(header.height as u32))
+            }
+            None => Ok(TxStatus::Mempool),
+        }
+    }
+
+    fn is_double_spent(&self, tx: &Transaction) -> Result<bool> {
+        // Only called for transactions the node doesn't know, so an input that is no
+        // longer unspent was spent by something else, provided its parent still exists
+        for input in &tx.input {
+            let prevout = input.previous_output;
+            let unspent = self.client.get_tx_out(&prevout.txid, prevout.vout, Some(true))?;
+            if unspent.is_none() && self.tx_status(&prevout.txid, Script::new())? != TxStatus::Unknown {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        self.client
+            .send_raw_transaction(tx)
+            .map_err(|e| anyhow!("bitcoind rejected transaction: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bdk_wallet::bitcoin::bip32::{Xpriv, Xpub};
+    use bdk_wallet::bitcoin::secp256k1::Secp256k1;
+    use bdk_wallet::bitcoin::Network;
+
+    #[test]
+    fn full_scan_starts_past_the_revealed_addresses() {
+        let xpriv = Xpriv::new_master(Network::Regtest, &[7; 32]).unwrap();
+        let descriptor = format!("wpkh({}/0/*)", Xpub::from_priv(&Secp256k1::new(), &xpriv));
+        let mut wallet = Wallet::create_single(descriptor)
+            .network(Network::Regtest)
+            .create_wallet_no_persist()
+            .unwrap();
+        let first_index = |wallet: &Wallet| {
+            let mut request = full_scan_request(wallet).build();
+            request.next_spk(KeychainKind::External).map(|(index, _)| index)
+        };
+
+        assert_eq!(first_index(&wallet), Some(0));
+
+        let _ = wallet.reveal_addresses_to(KeychainKind::External, 99);
+        assert_eq!(first_index(&wallet), Some(100));
+    }
+}
@@ -0,0 +1,668 @@
+use anyhow::{anyhow, Result};
+use bdk_electrum::electrum_client::{self, Client, ConfigBuilder, ElectrumApi, Socks5Config};
+use bdk_electrum::BdkElectrumClient;
+use bdk_wallet::bitcoin::{Script, ScriptBuf, Transaction, Txid};
+use bdk_wallet::Wallet;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use super::{full_scan_request, ChainServerStatus, ChainSource, TxStatus};
+
+/// Number of scripts requested in one Electrum batch
+const BATCH_SIZE: usize = 5;
+
+/// Socket timeout for Electrum connections, in seconds
+const TIMEOUT_SECS: u8 = 30;
+
+/// Most transactions or script histories requested in one batch
+const LOOKUP_BATCH_SIZE: usize = 100;
+
+/// How often tip heights are cross-checked between servers
+const TIP_CHECK_INTERVAL: Duration = Duration::from_secs(120);
+
+/// A server whose tip differs from the consensus tip by more than this many
+/// blocks is considered stale or lying and is only used as a last resort
+const TIP_TOLERANCE: u32 = 2;
+
+/// Score penalty per consecutive failure, in milliseconds of equivalent latency
+const FAILURE_PENALTY_MS: f64 = 10_000.0;
+
+/// Score penalty for a server whose tip disagrees with the others
+const TIP_MISMATCH_PENALTY_MS: f64 = 1_000_000.0;
+
+/// Weight of the newest sample in the latency moving average
+const LATENCY_EWMA_WEIGHT: f64 = 0.3;
+
+/// A pool of Electrum servers with health scoring and automatic failover.
+///
+/// Every request goes to the healthiest server first (lowest latency, fewest recent
+/// failures, tip in agreement with the other servers) and falls through to the next
+/// one on error. Clearnet and .onion servers can be mixed; all connections go through
+/// `TOR_PROXY` when it is set.
+///
+/// Connections are long-lived: each server keeps one client that reconnects on its own
+/// after a dropped socket, so a sync cycle doesn't pay a new (Tor circuit) handshake per
+/// request, and the client's transaction cache stays warm across eligibility checks.
+///
+/// Watched scripts are subscribed (along with block headers) on a dedicated connection,
+/// so new deposits and blocks are noticed without waiting for the next full sync. It
+/// doesn't reconnect on its own: a reconnected socket would silently lack the
+/// subscriptions, so after an error the next poll opens a fresh connection and
+/// subscribes every watched script again.
+pub struct ElectrumPool {
+    servers: Vec<ElectrumServer>,
+    tor_proxy: Option<String>,
+    /// Index of the server that served the last successful request
+    active: Mutex<Option<usize>>,
+    last_tip_check: Mutex<Option<Instant>>,
+    /// Scripts to keep subscribed to
+    watched: Mutex<HashSet<ScriptBuf>>,
+    subscription: Mutex<Option<Subscription>>,
+}
+
+/// A connection holding script and header subscriptions, used for nothing else
+struct Subscription {
+    server: usize,
+    client: Arc<Client>,
+    scripts: HashSet<ScriptBuf>,
+}
+
+struct ElectrumServer {
+    url: String,
+    /// Connected client, created on first use and dropped after a connection error
+    client: Mutex<Option<Arc<BdkElectrumClient<Client>>>>,
+    stats: Mutex<ServerStats>,
+}
+
+#[derive(Default)]
+struct ServerStats {
+    /// Moving average of connect and tip-check round-trip latency
+    latency_ms: Option<f64>,
+    successes: u64,
+    errors: u64,
+    consecutive_failures: u32,
+    last_error: Option<String>,
+    /// Tip height reported at the last cross-check
+    tip_height: Option<u32>,
+    /// Whether the reported tip disagreed with the other servers
+    tip_mismatch: bool,
+}
+
+impl ServerStats {
+    /// Lower is better. Unmeasured servers rank behind measured healthy ones.
+    fn score(&self) -> f64 {
+        let mut score = self.latency_ms.unwrap_or(1_000.0);
+        score += self.consecutive_failures as f64 * FAILURE_PENALTY_MS;
+        if self.tip_mismatch {
+            score += TIP_MISMATCH_PENALTY_MS;
+        }
+        score
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.consecutive_failures == 0 && !self.tip_mismatch
+    }
+
+    fn record_latency(&mut self, elapsed: Duration) {
+        let sample = elapsed.as_secs_f64() * 1_000.0;
+        self.latency_ms = Some(match self.latency_ms {
+            Some(avg) => avg + LATENCY_EWMA_WEIGHT * (sample - avg),
+            None => sample,
+        });
+    }
+
+    fn record_success(&mut self) {
+        self.successes += 1;
+        self.consecutive_failures = 0;
+    }
+
+    fn record_failure(&mut self, error: &electrum_client::Error) {
+        self.errors += 1;
+        self.consecutive_failures += 1;
+        self.last_error = Some(error.to_string());
+    }
+}
+
+impl ElectrumPool {
+    pub fn new(urls: Vec<String>, tor_proxy: Option<String>) -> Result<Self> {
+        if urls.is_empty() {
+            return Err(anyhow!("At least one Electrum server is required"));
+        }
+
+        let servers = urls
+            .into_iter()
+            .map(|url| ElectrumServer {
+                url,
+                client: Mutex::new(None),
+                stats: Mutex::new(ServerStats::default()),
+            })
+            .collect();
+
+        Ok(Self {
+            servers,
+            tor_proxy,
+            active: Mutex::new(None),
+            last_tip_check: Mutex::new(None),
+            watched: Mutex::new(HashSet::new()),
+            subscription: Mutex::new(None),
+        })
+    }
+
+    /// Connect to `url`, retrying (and reconnecting) a failed request `retry` times.
+    fn connect(&self, url: &str, retry: u8) -> Result<Client, electrum_client::Error> {
+        let config = if let Some(ref proxy) = self.tor_proxy {
+            tracing::debug!("Connecting to {} via Tor proxy: {}", url, proxy);
+            ConfigBuilder::new()
+                .socks5(Some(Socks5Config {
+                    addr: proxy.clone(),
+                    credentials: None,
+                }))
+                .timeout(Some(TIMEOUT_SECS))
+                .retry(retry)
+                .build()
+        } else {
+            ConfigBuilder::new()
+                .timeout(Some(TIMEOUT_SECS))
+                .retry(retry)
+                .build()
+        };
+
+        Client::from_config(url, config)
+    }
+
+    /// The server's long-lived client, connecting if there isn't one yet.
+    fn client(
+        &self,
+        server: &ElectrumServer,
+    ) -> Result<Arc<BdkElectrumClient<Client>>, electrum_client::Error> {
+        if let Some(client) = lock(&server.client).clone() {
+            return Ok(client);
+        }
+
+        // Connect without holding the lock, so requests don't queue behind a slow server
+        let started = Instant::now();
+        let client = Arc::new(BdkElectrumClient::new(self.connect(&server.url, 1)?));
+        lock(&server.stats).record_latency(started.elapsed());
+        tracing::debug!("Connected to Electrum server {}", server.url);
+
+        // Keep whichever client was stored first if another request connected meanwhile
+        Ok(lock(&server.client).get_or_insert(client).clone())
+    }
+
+    /// Drop a server's client so the next request reconnects from scratch.
+    fn disconnect(&self, server: &ElectrumServer) {
+        lock(&server.client).take();
+    }
+
+    /// Server indices ordered from healthiest to least healthy.
+    fn ranked_servers(&self) -> Vec<usize> {
+        let mut ranked: Vec<(usize, f64)> = self
+            .servers
+            .iter()
+            .enumerate()
+            .map(|(i, server)| (i, lock(&server.stats).score()))
+            .collect();
+        ranked.sort_by(|a, b| a.1.total_cmp(&b.1));
+        ranked.into_iter().map(|(i, _)| i).collect()
+    }
+
+    /// Run `op` against the healthiest server, failing over to the next one on error.
+    /// Protocol errors are the server's answer to the request (e.g. a rejected
+    /// broadcast), so they are returned as-is rather than retried elsewhere.
+    fn with_failover<T>(
+        &self,
+        mut op: impl FnMut(&BdkElectrumClient<Client>) -> Result<T, electrum_client::Error>,
+    ) -> Result<T> {
+        let mut last_error = None;
+
+        for index in self.ranked_servers() {
+            let server = &self.servers[index];
+
+            let result = self.client(server).and_then(|client| op(&client));
+
+            match result {
+                Ok(value) => {
+                    lock(&server.stats).record_success();
+                    self.set_active(index);
+                    return Ok(value);
+                }
+                Err(e @ electrum_client::Error::Protocol(_)) => {
+                    lock(&server.stats).record_success();
+                    self.set_active(index);
+                    return Err(e.into());
+                }
+                Err(e) => {
+                    tracing::warn!("Electrum server {} failed: {}", server.url, e);
+                    self.disconnect(server);
+                    lock(&server.stats).record_failure(&e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error
+            .map(anyhow::Error::from)
+            .unwrap_or_else(|| anyhow!("No Electrum servers configured")))
+    }
+
+    fn set_active(&self, index: usize) {
+        let mut active = lock(&self.active);
+        if *active != Some(index) {
+            if active.is_some() {
+                tracing::warn!("Switched to Electrum server {}", self.servers[index].url);
+            } else {
+                tracing::info!("Using Electrum server {}", self.servers[index].url);
+            }
+            *active = Some(index);
+        }
+    }
+
+    /// Open a subscription connection on the healthiest reachable server. It is never
+    /// retried, so an error reaches `close_subscription` instead of a reconnect that
+    /// would drop the subscriptions.
+    fn open_subscription(&self) -> Result<Subscription> {
+        for index in self.ranked_servers() {
+            let server = &self.servers[index];
+            let started = Instant::now();
+            let result = self.connect(&server.url, 0).and_then(|client| {
+                client.block_headers_subscribe()?;
+                Ok(client)
+            });
+
+            match result {
+                Ok(client) => {
+                    lock(&server.stats).record_latency(started.elapsed());
+                    tracing::info!("Subscribed to Electrum notifications on {}", server.url);
+                    return Ok(Subscription {
+                        server: index,
+                        client: Arc::new(client),
+                        scripts: HashSet::new(),
+                    });
+                }
+                Err(e) => {
+                    tracing::warn!("Electrum server {} subscription failed: {}", server.url, e);
+                    lock(&server.stats).record_failure(&e);
+                }
+            }
+        }
+
+        Err(anyhow!("No Electrum server available for subscriptions"))
+    }
+
+    /// Make sure a subscription connection exists and covers every watched script.
+    /// Returns true if a new connection was opened, in which case notifications sent
+    /// to the previous one may have been missed.
+    ///
+    /// The subscription lock is only held to read or update the state, never across a
+    /// request, so a slow server doesn't hold up `watch` callers.
+    fn ensure_subscribed(&self) -> Result<bool> {
+        let current = lock(&self.subscription)
+            .as_ref()
+            .map(|subscription| (subscription.server, subscription.client.clone()));
+        let (server, client, opened) = match current {
+            Some((server, client)) => (server, client, false),
+            None => {
+                let subscription = self.open_subscription()?;
+                let mut guard = lock(&self.subscription);
+                // Another caller may have opened one meanwhile, then ours is dropped
+                let opened = guard.is_none();
+                let subscription = guard.get_or_insert(subscription);
+                (subscription.server, subscription.client.clone(), opened)
+            }
+        };
+
+        let new: Vec<ScriptBuf> = {
+            let watched = lock(&self.watched);
+            let guard = lock(&self.subscription);
+            let subscribed = guard
+                .as_ref()
+                .filter(|subscription| Arc::ptr_eq(&subscription.client, &client))
+                .map(|subscription| &subscription.scripts);
+            watched
+                .iter()
+                .filter(|script| !subscribed.is_some_and(|scripts| scripts.contains(*script)))
+                .cloned()
+                .collect()
+        };
+        if !new.is_empty() {
+            let result = client.batch_script_subscribe(new.iter().map(|script| script.as_script()));
+            if let Err(e) = result {
+                lock(&self.servers[server].stats).record_failure(&e);
+                self.close_subscription(&client);
+                return Err(e.into());
+            }
+
+            let mut guard = lock(&self.subscription);
+            if let Some(subscription) = guard
+                .as_mut()
+                .filter(|subscription| Arc::ptr_eq(&subscription.client, &client))
+            {
+                subscription.scripts.extend(new);
+            }
+        }
+
+        Ok(opened)
+    }
+
+    /// Drop the subscription connection `client` after it failed, unless it was replaced
+    /// already. The next `ensure_subscribed` opens a new one.
+    fn close_subscription(&self, client: &Arc<Client>) {
+        let mut guard = lock(&self.subscription);
+        if guard
+            .as_ref()
+            .is_some_and(|subscription| Arc::ptr_eq(&subscription.client, client))
+        {
+            *guard = None;
+        }
+    }
+
+    /// Cross-check tip heights between servers, at most once per `TIP_CHECK_INTERVAL`.
+    /// Servers that disagree with the consensus tip are deprioritised.
+    fn check_tips_if_due(&self) {
+        if self.servers.len() < 2 {
+            return;
+        }
+
+        {
+            let mut last_check = lock(&self.last_tip_check);
+            if last_check.is_some_and(|t| t.elapsed() < TIP_CHECK_INTERVAL) {
+                return;
+            }
+            *last_check = Some(Instant::now());
+        }
+
+        let mut tips = Vec::new();
+        for server in &self.servers {
+            let started = Instant::now();
+            let tip = self
+                .client(server)
+                .and_then(|client| client.inner.block_headers_subscribe())
+                .map(|header| header.height as u32);
+
+            let mut stats = lock(&server.stats);
+            match tip {
+                Ok(height) => {
+                    stats.record_latency(started.elapsed());
+                    stats.record_success();
+                    stats.tip_height = Some(height);
+                    tips.push(height);
+                }
+                Err(e) => {
+                    tracing::warn!("Electrum server {} tip check failed: {}", server.url, e);
+                    self.disconnect(server);
+                    stats.record_failure(&e);
+                    stats.tip_height = None;
+                }
+            }
+        }
+
+        if tips.is_empty() {
+            return;
+        }
+
+        // Upper median, so a single stale server can't drag the reference down
+        tips.sort_unstable();
+        let consensus = tips[tips.len() / 2];
+
+        for server in &self.servers {
+            let mut stats = lock(&server.stats);
+            let mismatch = stats
+                .tip_height
+                .is_some_and(|height| height.abs_diff(consensus) > TIP_TOLERANCE);
+            if mismatch && !stats.tip_mismatch {
+                tracing::warn!(
+                    "Electrum server {} reports tip {} but consensus is {}; deprioritising it",
+                    server.url,
+                    stats.tip_height.unwrap_or_default(),
+                    consensus
+                );
+            }
+            stats.tip_mismatch = mismatch;
+        }
+    }
+}
+
+impl ChainSource for ElectrumPool {
+    fn name(&self) -> &'static str {
+        "electrum"
+    }
+
+    fn full_scan(&self, wallet: &mut Wallet, stop_gap: usize) -> Result<()> {
+        self.check_tips_if_due();
+
+        let update = self.with_failover(|client| {
+            populate_tx_cache(client, wallet);
+            client.full_scan(full_scan_request(wallet), stop_gap, BATCH_SIZE, false)
+        })?;
+        wallet.apply_update(update)?;
+        Ok(())
+    }
+
+    fn sync(&self, wallet: &mut Wallet) -> Result<()> {
+        self.check_tips_if_due();
+
+        let update = self.with_failover(|client| {
+            populate_tx_cache(client, wallet);
+            let request = wallet.start_sync_with_revealed_spks();
+            client.sync(request, BATCH_SIZE, false)
+        })?;
+        wallet.apply_update(update)?;
+        Ok(())
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        let tx = self.with_failover(|client| client.fetch_tx(*txid))?;
+        Ok(tx.as_ref().clone())
+    }
+
+    fn get_transactions(&self, txids: &[Txid]) -> Vec<Result<Transaction>> {
+        let mut results = Vec::with_capacity(txids.len());
+        for chunk in txids.chunks(LOOKUP_BATCH_SIZE) {
+            let batch = self.with_failover(|client| {
+                let txs = client.inner.batch_transaction_get(chunk.iter())?;
+                client.populate_tx_cache(txs.iter().cloned());
+                Ok(txs)
+            });
+            match batch {
+                Ok(txs) => results.extend(txs.into_iter().map(Ok)),
+                Err(e) => {
+                    // One unknown txid fails the whole batch, so fetch each on its own
+                    tracing::debug!("Batched transaction lookup failed, fetching one by one: {}", e);
+                    results.extend(chunk.iter().map(|txid| self.get_transaction(txid)));
+                }
+            }
+        }
+        results
+    }
+
+    fn tx_status(&self, txid: &Txid, script_pubkey: &Script) -> Result<TxStatus> {
+        // Electrum indexes by script, so find the transaction in the script's history.
+        // Mempool entries have height 0, or -1 when they spend unconfirmed outputs.
+        let history = self.with_failover(|client| client.inner.script_get_history(script_pubkey))?;
+        Ok(match history.into_iter().find(|entry| entry.tx_hash == *txid) {
+            Some(entry) if entry.height > 0 => TxStatus::Confirmed(entry.height as u32),
+            Some(_) => TxStatus::Mempool,
+            None => TxStatus::Unknown,
+        })
+    }
+
+    fn confirmation_heights(&self, lookups: &[(Txid, ScriptBuf)]) -> Vec<Result<Option<u32>>> {
+        // One history request per distinct script, batched
+        let scripts: Vec<&Script> = lookups
+            .iter()
+            .map(|(_, script_pubkey)| script_pubkey.as_script())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let mut histories = HashMap::new();
+        for chunk in scripts.chunks(LOOKUP_BATCH_SIZE) {
+            match self.with_failover(|client| client.inner.batch_script_get_history(chunk.iter())) {
+                Ok(batch) => histories.extend(chunk.iter().copied().zip(batch)),
+                Err(e) => tracing::debug!("Batched history lookup failed, looking up one by one: {}", e),
+            }
+        }
+
+        lookups
+            .iter()
+            .map(|(txid, script_pubkey)| match histories.get(script_pubkey.as_script()) {
+                Some(history) => Ok(history
+                    .iter()
+                    .find(|entry| entry.tx_hash == *txid && entry.height > 0)
+                    .map(|entry| entry.height as u32)),
+                None => self.confirmation_height(txid, script_pubkey),
+            })
+            .collect()
+    }
+
+    fn is_double_spent(&self, tx: &Transaction) -> Result<bool> {
+        // Electrum can't look up who spends an output, so search the history of each
+        // spent output's script for another transaction spending the same outpoint
+        let txid = tx.compute_txid();
+        for input in &tx.input {
+            let prevout = input.previous_output;
+            let prev_tx = self.get_transaction(&prevout.txid)?;
+            let Some(prev_output) = prev_tx.output.get(prevout.vout as usize) else {
+                continue;
+            };
+
+            let script_pubkey = &prev_output.script_pubkey;
+            let history = self.with_failover(|client| client.inner.script_get_history(script_pubkey))?;
+            for entry in history {
+                if entry.tx_hash == txid || entry.tx_hash == prevout.txid {
+                    continue;
+                }
+                let other = self.get_transaction(&entry.tx_hash)?;
+                if other.input.iter().any(|other_input| other_input.previous_output == prevout) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+        self.with_failover(|client| client.transaction_broadcast(tx))
+    }
+
+    fn watch(&self, scripts: &[ScriptBuf]) -> Result<bool> {
+        lock(&self.watched).extend(scripts.iter().cloned());
+        self.ensure_subscribed()?;
+        Ok(true)
+    }
+
+    fn unwatch(&self, scripts: &[ScriptBuf]) -> Result<()> {
+        {
+            let mut watched = lock(&self.watched);
+            for script in scripts {
+                watched.remove(script);
+            }
+        }
+
+        let Some((server, client, subscribed)) = lock(&self.subscription).as_ref().map(|subscription| {
+            let subscribed: Vec<ScriptBuf> = scripts
+                .iter()
+                .filter(|script| subscription.scripts.contains(*script))
+                .cloned()
+                .collect();
+            (subscription.server, subscription.client.clone(), subscribed)
+        }) else {
+            return Ok(());
+        };
+
+        for script in &subscribed {
+            if let Err(e) = client.script_unsubscribe(script) {
+                lock(&self.servers[server].stats).record_failure(&e);
+                // The next subscription only covers the scripts still watched
+                self.close_subscription(&client);
+                return Err(e.into());
+            }
+        }
+
+        let mut guard = lock(&self.subscription);
+        if let Some(subscription) = guard
+            .as_mut()
+            .filter(|subscription| Arc::ptr_eq(&subscription.client, &client))
+        {
+            for script in &subscribed {
+                subscription.scripts.remove(script);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn poll_changes(&self) -> Result<bool> {
+        let reopened = self.ensure_subscribed()?;
+
+        let Some((server, client, scripts)) = lock(&self.subscription).as_ref().map(|subscription| {
+            let scripts: Vec<ScriptBuf> = subscription.scripts.iter().cloned().collect();
+            (subscription.server, subscription.client.clone(), scripts)
+        }) else {
+            return Ok(false);
+        };
+
+        // Notifications are only read off the socket alongside a response, so ping first
+        let result = (|| -> Result<bool, electrum_client::Error> {
+            client.ping()?;
+
+            let mut changed = false;
+            while client.block_headers_pop()?.is_some() {
+                changed = true;
+            }
+            for script in &scripts {
+                if client.script_pop(script)?.is_some() {
+                    changed = true;
+                }
+            }
+            Ok(changed)
+        })();
+
+        match result {
+            Ok(changed) => Ok(changed || reopened),
+            Err(e) => {
+                let server = &self.servers[server];
+                tracing::warn!("Electrum subscription on {} failed: {}", server.url, e);
+                lock(&server.stats).record_failure(&e);
+                self.close_subscription(&client);
+                Err(e.into())
+            }
+        }
+    }
+
+    fn server_status(&self) -> Vec<ChainServerStatus> {
+        let active = *lock(&self.active);
+
+        self.servers
+            .iter()
+            .enumerate()
+            .map(|(i, server)| {
+                let stats = lock(&server.stats);
+                ChainServerStatus {
+                    url: server.url.clone(),
+                    active: active == Some(i),
+                    healthy: stats.is_healthy(),
+                    latency_ms: stats.latency_ms.map(|ms| ms.round() as u64),
+                    tip_height: stats.tip_height,
+                    successes: stats.successes,
+                    errors: stats.errors,
+                    consecutive_failures: stats.consecutive_failures,
+                    last_error: stats.last_error.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Seed the client's transaction cache with the wallet's own transactions so a
+/// sync only downloads transactions it hasn't seen before.
+fn populate_tx_cache(client: &BdkElectrumClient<Client>, wallet: &Wallet) {
+    client.populate_tx_cache(wallet.tx_graph().full_txs().map(|tx_node| tx_node.tx));
+}
+
+/// Lock `mutex`, carrying on if a thread panicked while holding it. The state behind
+/// these locks is only ever updated in single statements, so it is still consistent,
+/// and one panic shouldn't take every later chain request down with it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
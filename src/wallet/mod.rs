@@ -1,3 +1,11 @@
 pub mod bdk;
+pub mod cache;
+pub mod chain;
+pub mod electrum;
+pub mod store;
 
 pub use bdk::*;
+pub use cache::*;
+pub use chain::*;
+pub use electrum::*;
+pub use store::*;
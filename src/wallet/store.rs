@@ -0,0 +1,84 @@
+use anyhow::Result;
+use bdk_wallet::chain::Merge;
+use bdk_wallet::ChangeSet;
+use chrono::Utc;
+use sqlx::AnyPool;
+
+/// Rows deleted per statement when compacting
+const COMPACT_BATCH: usize = 500;
+
+/// Durable storage for the BDK wallet, kept in the service's database.
+///
+/// Every staged wallet change (revealed indices, checkpoints, transactions, anchors)
+/// is appended as a JSON-encoded changeset. On load the rows are merged back into a
+/// single changeset and compacted so the table doesn't grow without bound.
+#[derive(Clone)]
+pub struct WalletStore {
+    db: AnyPool,
+}
+
+impl WalletStore {
+    pub fn new(db: AnyPool) -> Self {
+        Self { db }
+    }
+
+    /// Load the aggregate changeset, or None if the wallet has never been persisted.
+    ///
+    /// The rows are read and compacted in one transaction, and only the rows that were
+    /// read are replaced, so changesets another replica appends meanwhile are kept.
+    pub async fn load(&self) -> Result<Option<ChangeSet>> {
+        let mut tx = self.db.begin().await?;
+
+        let rows: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id, changeset FROM bdk_changesets ORDER BY id")
+                .fetch_all(&mut *tx)
+                .await?;
+
+        let Some(&(max_id, _)) = rows.last() else {
+            return Ok(None);
+        };
+
+        let mut aggregate = ChangeSet::default();
+        for (_, json) in &rows {
+            let changeset: ChangeSet = serde_json::from_str(json)?;
+            aggregate.merge(changeset);
+        }
+
+        if rows.len() > 1 {
+            // The aggregate takes the place of the newest row read, so it still merges
+            // before any changeset appended after it. The older rows are deleted by id,
+            // since on Postgres a row with a lower id may commit after a higher one.
+            let read: Vec<String> = rows[..rows.len() - 1].iter().map(|(id, _)| id.to_string()).collect();
+            for ids in read.chunks(COMPACT_BATCH) {
+                sqlx::query(&format!("DELETE FROM bdk_changesets WHERE id IN ({})", ids.join(", ")))
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query("UPDATE bdk_changesets SET changeset = $1, created_at = $2 WHERE id = $3")
+                .bind(serde_json::to_string(&aggregate)?)
+                .bind(Utc::now().to_rfc3339())
+                .bind(max_id)
+                .execute(&mut *tx)
+                .await?;
+            tracing::debug!("Compacted {} persisted wallet changesets", rows.len());
+        }
+
+        tx.commit().await?;
+        Ok(Some(aggregate))
+    }
+
+    /// Append a changeset. Empty changesets are skipped.
+    pub async fn persist(&self, changeset: &ChangeSet) -> Result<()> {
+        if changeset.is_empty() {
+            return Ok(());
+        }
+
+        sqlx::query("INSERT INTO bdk_changesets (changeset, created_at) VALUES ($1, $2)")
+            .bind(serde_json::to_string(changeset)?)
+            .bind(Utc::now().to_rfc3339())
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
-use crate::db::{RecycleRepository, RecycleStatus};
+use crate::db::{Actor, Deposit, DepositInput, DepositRepository, DepositStatus};
+use crate::wallet::{DepositInfo, TxStatus};
 use crate::AppState;
 use chrono::Utc;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time;
@@ -8,8 +10,26 @@ use tokio::time;
 const BASE_INTERVAL_SECS: u64 = 30;
 const MAX_BACKOFF_SECS: u64 = 300; // 5 minutes max
 
+/// With push notifications active, a full sync still runs this often in case a
+/// notification is missed
+const SAFETY_NET_INTERVAL_SECS: u64 = 300;
+
+/// How often to check the subscription connection for notifications
+const NOTIFICATION_POLL_SECS: u64 = 2;
+
+/// Expired and cancelled recycles are only checked for late deposits this often,
+/// and their addresses aren't subscribed to
+const IDLE_CHECK_INTERVAL_SECS: u64 = 1800;
+
+/// Paid, donated and double-spent recycles are checked every cycle for this long after
+/// settling, then only as often as expired ones
+const SETTLED_WATCH_SECS: i64 = 7 * 24 * 3600;
+
 pub async fn run_deposit_monitor(state: Arc<AppState>) {
     let mut consecutive_errors: u32 = 0;
+    let mut last_idle_check: Option<time::Instant> = None;
+    let mut watched = HashSet::new();
+    let mut watching = watch_pending_addresses(&state, &mut watched).await;
 
     loop {
         // Calculate delay with exponential backoff on errors
@@ -19,11 +39,23 @@ pub async fn run_deposit_monitor(state: Arc<AppState>) {
             (BASE_INTERVAL_SECS * 2u64.pow(consecutive_errors.min(4))).min(MAX_BACKOFF_SECS)
         };
 
-        time::sleep(Duration::from_secs(delay_secs)).await;
+        if watching && consecutive_errors == 0 {
+            wait_for_chain_changes(&state).await;
+        } else {
+            time::sleep(Duration::from_secs(delay_secs)).await;
+        }
+
+        let check_idle = match last_idle_check {
+            Some(at) => at.elapsed() >= Duration::from_secs(IDLE_CHECK_INTERVAL_SECS),
+            None => true,
+        };
 
-        match check_deposits(&state).await {
+        match check_deposits(&state, check_idle).await {
             Ok(_) => {
                 consecutive_errors = 0;
+                if check_idle {
+                    last_idle_check = Some(time::Instant::now());
+                }
             }
             Err(e) => {
                 consecutive_errors += 1;
@@ -39,10 +71,80 @@ pub async fn run_deposit_monitor(state: Arc<AppState>) {
                 }
             }
         }
+
+        watching = watch_pending_addresses(&state, &mut watched).await;
     }
 }
 
-async fn check_deposits(state: &AppState) -> anyhow::Result<()> {
+/// Settled recycles older than this are checked at the idle cadence only
+fn settled_since() -> chrono::DateTime<Utc> {
+    Utc::now() - chrono::Duration::seconds(SETTLED_WATCH_SECS)
+}
+
+/// Subscribe to notifications for every address that may still receive deposits, and
+/// drop the subscriptions of those in `watched` that no longer do.
+/// Returns false if the chain backend has no push notifications or subscribing failed,
+/// in which case the monitor falls back to polling every `BASE_INTERVAL_SECS`.
+async fn watch_pending_addresses(state: &AppState, watched: &mut HashSet<u32>) -> bool {
+    let indices: HashSet<u32> = match state.recycles.find_pending_deposits(false, settled_since()).await {
+        Ok(pending) => pending.iter().map(|recycle| recycle.address_index).collect(),
+        Err(e) => {
+            tracing::warn!("Failed to load addresses to watch: {}", e);
+            return false;
+        }
+    };
+
+    let stale: Vec<u32> = watched.difference(&indices).copied().collect();
+    if !stale.is_empty() {
+        if let Err(e) = state.wallet.unwatch_addresses(&stale).await {
+            tracing::warn!("Failed to unsubscribe from settled addresses: {}", e);
+        }
+    }
+    *watched = indices;
+
+    let indices: Vec<u32> = watched.iter().copied().collect();
+    match state.wallet.watch_addresses(&indices).await {
+        Ok(watching) => watching,
+        Err(e) => {
+            tracing::warn!("Failed to subscribe to address notifications: {}", e);
+            false
+        }
+    }
+}
+
+/// Wait until a watched address or the chain tip changes, or until the safety-net
+/// interval elapses. Returns early if the subscription connection fails.
+async fn wait_for_chain_changes(state: &AppState) {
+    let deadline = time::Instant::now() + Duration::from_secs(SAFETY_NET_INTERVAL_SECS);
+
+    while time::Instant::now() < deadline {
+        time::sleep(Duration::from_secs(NOTIFICATION_POLL_SECS)).await;
+
+        match state.wallet.poll_chain_changes().await {
+            Ok(true) => {
+                tracing::debug!("Chain notification received, checking deposits");
+                return;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                tracing::warn!("Chain notification poll failed: {}", e);
+                return;
+            }
+        }
+    }
+
+    tracing::debug!("No chain notifications, running safety-net sync");
+}
+
+/// Sync the wallet and process the deposits of every pending recycle, including
+/// expired and cancelled ones if `check_idle` is set.
+async fn check_deposits(state: &AppState, check_idle: bool) -> anyhow::Result<()> {
+    // Other replicas sharing the database hand out addresses this wallet hasn't
+    // revealed, which the sync would otherwise skip
+    if let Some(index) = state.recycles.max_address_index().await? {
+        state.wallet.reveal_addresses_up_to(index).await?;
+    }
+
     // Sync the wallet with the blockchain
     tracing::debug!("Syncing wallet with blockchain...");
     state.wallet.sync().await?;
@@ -53,250 +155,451 @@ async fn check_deposits(state: &AppState) -> anyhow::Result<()> {
         *last_sync = Some(Utc::now());
     }
 
-    // Get all pending recycles (awaiting_deposit or confirming)
-    let pending = RecycleRepository::find_pending_deposits(&state.db).await?;
+    // Get all recycles whose address may still receive deposits
+    let pending = state.recycles.find_pending_deposits(check_idle, settled_since()).await?;
 
     for recycle in pending {
-        match state
-            .wallet
-            .check_address_deposit(&recycle.deposit_address, recycle.address_index)
-            .await
-        {
-            Ok(Some(deposit)) => {
-                tracing::info!(
-                    "Found deposit for recycle {}: {} sats, {} confirmations, block {:?}",
+        let deposits = match state.wallet.check_address_deposits(recycle.address_index).await {
+            Ok(deposits) => deposits,
+            Err(e) => {
+                tracing::warn!(
+                    "Error checking deposits for recycle {}: {}",
                     recycle.id,
-                    deposit.amount_sats,
-                    deposit.confirmations,
-                    deposit.block_height
+                    e
                 );
+                continue;
+            }
+        };
 
-                match recycle.status {
-                    RecycleStatus::AwaitingDeposit => {
-                        // First time seeing this deposit - check eligibility
-                        if let Some(deposit_block_height) = deposit.block_height {
-                            // Check 1: Block height cutoff - check when INPUT UTXOs were created
-                            // (not when the deposit tx was confirmed)
-                            let input_creation_height = state
-                                .wallet
-                                .get_max_input_creation_height(&deposit.txid)
-                                .await?;
-
-                            if let Some(creation_height) = input_creation_height {
-                                if creation_height >= state.config.cutoff_block_height {
-                                    tracing::info!(
-                                        "Recycle {} input UTXO created at block {} is AFTER cutoff {} - marking as donation",
-                                        recycle.id,
-                                        creation_height,
-                                        state.config.cutoff_block_height
-                                    );
-                                    RecycleRepository::update_as_donation(
-                                        &state.db,
-                                        &recycle.id,
-                                        &deposit.txid,
-                                        deposit.amount_sats,
-                                        Some(creation_height),
-                                        None,
-                                        "block_height",
-                                    )
-                                    .await?;
-                                    continue;
-                                }
-                            } else {
-                                // Couldn't determine input creation height - be conservative, reject
-                                tracing::warn!(
-                                    "Recycle {} - couldn't verify input UTXO creation height, marking as donation",
-                                    recycle.id
-                                );
-                                RecycleRepository::update_as_donation(
-                                    &state.db,
-                                    &recycle.id,
-                                    &deposit.txid,
-                                    deposit.amount_sats,
-                                    Some(deposit_block_height),
-                                    None,
-                                    "block_height_unknown",
-                                )
-                                .await?;
-                                continue;
-                            }
-
-                            // Check 2: Input UTXO sizes - are they actually dust?
-                            let max_input = state.wallet.get_max_input_value(&deposit.txid).await?;
-                            if let Some(max_input_value) = max_input {
-                                if max_input_value >= state.config.max_input_sats {
-                                    tracing::info!(
-                                        "Recycle {} has input of {} sats (>= {} limit) - marking as donation",
-                                        recycle.id,
-                                        max_input_value,
-                                        state.config.max_input_sats
-                                    );
-                                    RecycleRepository::update_as_donation(
-                                        &state.db,
-                                        &recycle.id,
-                                        &deposit.txid,
-                                        deposit.amount_sats,
-                                        input_creation_height,
-                                        Some(max_input_value),
-                                        "input_too_large",
-                                    )
-                                    .await?;
-                                    continue;
-                                }
-
-                                // Both checks passed - eligible for payout
-                                tracing::info!(
-                                    "Recycle {} passed all checks (input UTXO from block {}, max input {} sats) - eligible for payout",
-                                    recycle.id,
-                                    input_creation_height.unwrap_or(0),
-                                    max_input_value
-                                );
-                                RecycleRepository::update_deposit_detected(
-                                    &state.db,
-                                    &recycle.id,
-                                    &deposit.txid,
-                                    deposit.amount_sats,
-                                    deposit.confirmations,
-                                    input_creation_height,
-                                    Some(max_input_value),
-                                    state.config.required_confirmations,
-                                )
-                                .await?;
-                            } else {
-                                // Couldn't determine input values - allow it (benefit of doubt)
-                                tracing::warn!(
-                                    "Recycle {} - couldn't verify input values, allowing",
-                                    recycle.id
-                                );
-                                RecycleRepository::update_deposit_detected(
-                                    &state.db,
-                                    &recycle.id,
-                                    &deposit.txid,
-                                    deposit.amount_sats,
-                                    deposit.confirmations,
-                                    input_creation_height,
-                                    None,
-                                    state.config.required_confirmations,
-                                )
-                                .await?;
-                            }
-
-                            if deposit.confirmations >= state.config.required_confirmations {
-                                tracing::info!("Recycle {} confirmed immediately!", recycle.id);
-                            }
-                        } else {
-                            // Unconfirmed - can't determine eligibility yet, just track the deposit
-                            tracing::debug!(
-                                "Recycle {} deposit is unconfirmed, waiting for confirmation to determine eligibility",
-                                recycle.id
-                            );
-                            RecycleRepository::update_deposit_detected(
-                                &state.db,
-                                &recycle.id,
-                                &deposit.txid,
-                                deposit.amount_sats,
-                                deposit.confirmations,
-                                None,
-                                None,
-                                state.config.required_confirmations,
-                            )
-                            .await?;
-                        }
-                    }
-                    RecycleStatus::Confirming => {
-                        // Check if we now have block height info (tx just confirmed)
-                        if deposit.block_height.is_some() {
-                            // Check if we've already determined eligibility
-                            if recycle.deposit_block_height.is_none() {
-                                // First time confirmed - run full eligibility checks
-                                // Check when INPUT UTXOs were created (not deposit tx)
-                                let input_creation_height = state
-                                    .wallet
-                                    .get_max_input_creation_height(&deposit.txid)
-                                    .await?;
-
-                                // Check 1: Block height cutoff
-                                if let Some(creation_height) = input_creation_height {
-                                    if creation_height >= state.config.cutoff_block_height {
-                                        tracing::info!(
-                                            "Recycle {} input UTXO created at block {} is AFTER cutoff {} - marking as donation",
-                                            recycle.id,
-                                            creation_height,
-                                            state.config.cutoff_block_height
-                                        );
-                                        RecycleRepository::update_as_donation(
-                                            &state.db,
-                                            &recycle.id,
-                                            &deposit.txid,
-                                            deposit.amount_sats,
-                                            Some(creation_height),
-                                            None,
-                                            "block_height",
-                                        )
-                                        .await?;
-                                        continue;
-                                    }
-                                }
-
-                                // Check 2: Input UTXO sizes
-                                let max_input = state.wallet.get_max_input_value(&deposit.txid).await?;
-                                if let Some(max_input_value) = max_input {
-                                    if max_input_value >= state.config.max_input_sats {
-                                        tracing::info!(
-                                            "Recycle {} has input of {} sats (>= {} limit) - marking as donation",
-                                            recycle.id,
-                                            max_input_value,
-                                            state.config.max_input_sats
-                                        );
-                                        RecycleRepository::update_as_donation(
-                                            &state.db,
-                                            &recycle.id,
-                                            &deposit.txid,
-                                            deposit.amount_sats,
-                                            input_creation_height,
-                                            Some(max_input_value),
-                                            "input_too_large",
-                                        )
-                                        .await?;
-                                        continue;
-                                    }
-                                }
-                            }
-                        }
-
-                        // Update confirmation count (already known to be eligible)
-                        RecycleRepository::update_confirmations(
-                            &state.db,
-                            &recycle.id,
-                            deposit.confirmations,
-                            state.config.required_confirmations,
-                        )
-                        .await?;
-
-                        if deposit.confirmations >= state.config.required_confirmations {
-                            tracing::info!(
-                                "Recycle {} reached {} confirmations!",
-                                recycle.id,
-                                deposit.confirmations
-                            );
-                        }
+        // Deposits recorded on earlier passes, to compare against what the wallet sees now
+        let mut known: HashMap<String, Deposit> =
+            DepositRepository::find_by_recycle(&state.db, &recycle.id)
+                .await?
+                .into_iter()
+                .map(|deposit| (deposit.outpoint(), deposit))
+                .collect();
+
+        if deposits.is_empty() && known.is_empty() {
+            // No deposit yet
+            tracing::debug!("No deposit found for recycle {}", recycle.id);
+            continue;
+        }
+
+        for info in deposits {
+            tracing::debug!(
+                "Deposit {}:{} for recycle {}: {} sats, {} confirmations, block {:?}",
+                info.txid,
+                info.vout,
+                recycle.id,
+                info.amount_sats,
+                info.confirmations,
+                info.block_height
+            );
+
+            let previous = known.remove(&format!("{}:{}", info.txid, info.vout));
+            let deposit = DepositRepository::record(
+                &state.db,
+                &recycle.id,
+                &info.txid,
+                info.vout,
+                info.amount_sats,
+                info.confirmations,
+                info.block_height,
+            )
+            .await?;
+
+            let deposit =
+                match check_reorg(state, recycle.address_index, previous, deposit, &info).await {
+                    Ok(Some(deposit)) => deposit,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::warn!("Error checking deposit {}:{} for reorgs: {}", info.txid, info.vout, e);
+                        continue;
                     }
-                    _ => {}
+                };
+            process_deposit(state, deposit).await?;
+        }
+
+        // The wallet no longer lists these outputs, meaning a conflicting transaction
+        // replaced the deposit. Confirm with the backend before giving up on them.
+        for deposit in known.into_values() {
+            if deposit.vout_known() && is_unpaid(deposit.status) {
+                if let Err(e) = check_missing(state, recycle.address_index, &deposit).await {
+                    tracing::warn!("Error checking missing deposit {}: {}", deposit.outpoint(), e);
                 }
             }
-            Ok(None) => {
-                // No deposit yet
-                tracing::debug!("No deposit found for recycle {}", recycle.id);
+        }
+
+        if let Err(e) =
+            state.recycles.refresh_from_deposits(&recycle.id, Actor::DepositMonitor).await
+        {
+            tracing::warn!("Error refreshing recycle {}: {}", recycle.id, e);
+        }
+    }
+
+    // Runs after this pass recorded any deposits, so a recycle funded just before its
+    // deadline isn't expired
+    let expiry_secs = state.config.recycle_expiry_secs;
+    let created_before = Utc::now() - chrono::Duration::seconds(expiry_secs as i64);
+    let reason = format!("no deposit within {}s", expiry_secs);
+    let expired = state.recycles.expire_unfunded(created_before, &reason).await?;
+    if expired > 0 {
+        tracing::info!("Expired {} recycle(s) without a deposit", expired);
+    }
+
+    Ok(())
+}
+
+/// Deposits the monitor keeps tracking for reorgs. Paid deposits are settled already and
+/// donations never pay out, so only these statuses can still change the payout.
+fn is_unpaid(status: DepositStatus) -> bool {
+    matches!(
+        status,
+        DepositStatus::Confirming
+            | DepositStatus::Confirmed
+            | DepositStatus::Reorged
+            | DepositStatus::DoubleSpent
+    )
+}
+
+/// Compare the wallet's view of a deposit with what was recorded on the previous pass
+/// and react to reorgs. A deposit that moved to another block, lost its block but is
+/// still in the mempool, or reappeared goes back to `confirming`. One that is gone from
+/// the mempool too is `double_spent` if a conflicting transaction spends its inputs, or
+/// held as `reorged` if it had confirmed. Returns the deposit to keep processing, or
+/// None if it can't be paid for now.
+async fn check_reorg(
+    state: &AppState,
+    address_index: u32,
+    previous: Option<Deposit>,
+    deposit: Deposit,
+    info: &DepositInfo,
+) -> anyhow::Result<Option<Deposit>> {
+    let Some(previous) = previous.filter(|previous| is_unpaid(previous.status)) else {
+        return Ok(Some(deposit));
+    };
+    let unsettled = matches!(previous.status, DepositStatus::Reorged | DepositStatus::DoubleSpent);
+
+    let reason = match info.block_height {
+        Some(height) => {
+            if unsettled {
+                format!("reappeared in block {}", height)
+            } else if previous.block_height.is_some_and(|previous| previous != height) {
+                format!("moved to block {} in a reorg", height)
+            } else {
+                return Ok(Some(deposit));
             }
-            Err(e) => {
-                tracing::warn!(
-                    "Error checking deposit for recycle {}: {}",
-                    recycle.id,
-                    e
-                );
+        }
+        None => {
+            // Unconfirmed in the wallet. Ask the backend whether the transaction is
+            // still around, since the wallet keeps transactions it last saw in the mempool.
+            let was_confirmed = previous.block_height.is_some() || previous.status == DepositStatus::Reorged;
+            let tx_status = state.wallet.deposit_tx_status(&deposit.txid, address_index).await?;
+            let double_spent = tx_status == TxStatus::Unknown
+                && state.wallet.is_deposit_double_spent(&deposit.txid).await?;
+            match tx_status {
+                TxStatus::Mempool if unsettled => "reappeared in the mempool".to_string(),
+                TxStatus::Mempool if previous.block_height.is_some() => {
+                    "lost its confirmation in a reorg".to_string()
+                }
+                TxStatus::Unknown if double_spent || was_confirmed => {
+                    let status = if double_spent {
+                        DepositStatus::DoubleSpent
+                    } else {
+                        DepositStatus::Reorged
+                    };
+                    if previous.status != status {
+                        tracing::warn!(
+                            "Deposit {} for recycle {} is no longer confirmed or in the mempool - marking as {}",
+                            deposit.outpoint(),
+                            deposit.recycle_id,
+                            status.as_str()
+                        );
+                    }
+                    DepositRepository::mark_unsettled(&state.db, &deposit, status).await?;
+                    return Ok(None);
+                }
+                // Still unconfirmed as before, dropped from the mempool without a conflict
+                // (it may be rebroadcast), or the wallet hasn't caught up with the backend
+                _ => return Ok(Some(deposit).filter(|_| !unsettled)),
             }
         }
+    };
+
+    tracing::warn!(
+        "Deposit {} for recycle {} {} (was {}) - back to confirming",
+        deposit.outpoint(),
+        deposit.recycle_id,
+        reason,
+        previous.status.as_str()
+    );
+    DepositRepository::reset_to_confirming(&state.db, &deposit).await?;
+
+    Ok(Some(Deposit {
+        status: DepositStatus::Confirming,
+        is_eligible: None,
+        donation_reason: None,
+        input_creation_height: None,
+        max_input_sats: None,
+        eligible_sats: None,
+        ..deposit
+    }))
+}
+
+/// Handle a recorded deposit the wallet no longer lists. If the backend doesn't know the
+/// transaction either, it was replaced by a conflicting one and won't be paid.
+async fn check_missing(state: &AppState, address_index: u32, deposit: &Deposit) -> anyhow::Result<()> {
+    if deposit.status == DepositStatus::DoubleSpent {
+        return Ok(());
+    }
+
+    match state.wallet.deposit_tx_status(&deposit.txid, address_index).await? {
+        TxStatus::Unknown => {
+            tracing::warn!(
+                "Deposit {} for recycle {} was replaced by a conflicting transaction - marking as double_spent",
+                deposit.outpoint(),
+                deposit.recycle_id
+            );
+            DepositRepository::mark_unsettled(&state.db, deposit, DepositStatus::DoubleSpent).await
+        }
+        TxStatus::Confirmed(_) | TxStatus::Mempool => {
+            tracing::debug!(
+                "Deposit {} is missing from the wallet but known to the backend, waiting for the next sync",
+                deposit.outpoint()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Advance a single deposit: run the eligibility checks once its transaction confirms,
+/// then mark it confirmed when it reaches the required number of confirmations.
+async fn process_deposit(state: &AppState, mut deposit: Deposit) -> anyhow::Result<()> {
+    if deposit.status != DepositStatus::Confirming {
+        return Ok(());
+    }
+
+    if deposit.is_eligible.is_none() {
+        if deposit.block_height.is_none() {
+            // Unconfirmed - can't determine eligibility yet, just track the deposit
+            tracing::debug!(
+                "Deposit {} is unconfirmed, waiting for confirmation to determine eligibility",
+                deposit.outpoint()
+            );
+            return Ok(());
+        }
+
+        // First time confirmed - run full eligibility checks
+        let eligibility = check_eligibility(state, &deposit).await?;
+        DepositRepository::update_eligibility(
+            &state.db,
+            &deposit,
+            eligibility.eligible_sats,
+            eligibility.donation_reason,
+            &eligibility.inputs,
+        )
+        .await?;
+
+        if eligibility.donation_reason.is_some() {
+            return Ok(());
+        }
+
+        tracing::info!(
+            "Deposit {} for recycle {}: {}/{} inputs passed all checks, {} of {} sats eligible for payout",
+            deposit.outpoint(),
+            deposit.recycle_id,
+            eligibility.inputs.iter().filter(|input| input.is_eligible).count(),
+            eligibility.inputs.len(),
+            eligibility.eligible_sats,
+            deposit.amount_sats
+        );
+        deposit.is_eligible = Some(true);
+    }
+
+    if deposit.is_eligible == Some(true)
+        && deposit.confirmations >= state.config.required_confirmations
+    {
+        DepositRepository::mark_confirmed(&state.db, &deposit).await?;
+        tracing::info!(
+            "Deposit {} for recycle {} reached {} confirmations!",
+            deposit.outpoint(),
+            deposit.recycle_id,
+            deposit.confirmations
+        );
     }
 
     Ok(())
 }
+
+/// Outcome of the eligibility checks for a deposit.
+struct Eligibility {
+    /// Verdict for each input of the deposit transaction
+    inputs: Vec<DepositInput>,
+    /// Share of the deposit amount attributable to eligible inputs
+    eligible_sats: u64,
+    /// Set when no part of the deposit is eligible and it is kept as a donation
+    donation_reason: Option<&'static str>,
+}
+
+/// Check each input of the deposit transaction on its own against the payout rules (see
+/// `InputDetails::ineligibility_reason`). The deposit is then eligible pro rata (see
+/// `eligible_share`).
+async fn check_eligibility(state: &AppState, deposit: &Deposit) -> anyhow::Result<Eligibility> {
+    let Some(details) = state.wallet.get_input_details(&deposit.txid).await? else {
+        // Couldn't fetch the deposit transaction - be conservative, reject
+        tracing::warn!(
+            "Deposit {} - couldn't look up its inputs, marking as donation",
+            deposit.outpoint()
+        );
+        return Ok(Eligibility {
+            inputs: Vec::new(),
+            eligible_sats: 0,
+            donation_reason: Some("block_height_unknown"),
+        });
+    };
+
+    let reasons: Vec<Option<&'static str>> = details
+        .iter()
+        .map(|input| {
+            let reason = input.ineligibility_reason(
+                state.config.cutoff_block_height,
+                state.config.max_input_sats,
+            );
+            match reason {
+                Some("block_height") => tracing::info!(
+                    "Deposit {} input {} created at block {:?} is AFTER cutoff {} - ineligible",
+                    deposit.outpoint(),
+                    input.outpoint,
+                    input.creation_height,
+                    state.config.cutoff_block_height
+                ),
+                Some("input_too_large") => tracing::info!(
+                    "Deposit {} input {} is {:?} sats (>= {} limit) - ineligible",
+                    deposit.outpoint(),
+                    input.outpoint,
+                    input.value_sats,
+                    state.config.max_input_sats
+                ),
+                // Couldn't verify the input - be conservative, reject it
+                Some(_) => tracing::warn!(
+                    "Deposit {} input {} - couldn't verify creation height or value, ineligible",
+                    deposit.outpoint(),
+                    input.outpoint
+                ),
+                None => {}
+            }
+            reason
+        })
+        .collect();
+
+    let inputs: Vec<DepositInput> = details
+        .iter()
+        .zip(&reasons)
+        .enumerate()
+        .map(|(index, (input, reason))| DepositInput {
+            deposit_txid: deposit.txid.clone(),
+            deposit_vout: deposit.vout,
+            input_index: index as u32,
+            prev_txid: input.outpoint.txid.to_string(),
+            prev_vout: input.outpoint.vout,
+            value_sats: input.value_sats,
+            creation_height: input.creation_height,
+            is_eligible: reason.is_none(),
+            reason: reason.map(str::to_string),
+        })
+        .collect();
+
+    let eligible_sats = eligible_share(deposit.amount_sats, &inputs);
+    if eligible_sats == 0 && inputs.iter().any(|input| input.value_sats.is_none()) {
+        tracing::warn!(
+            "Deposit {} has inputs of unknown value, so its eligible share can't be worked out - marking as donation",
+            deposit.outpoint()
+        );
+    }
+
+    // With nothing eligible, report why the first ineligible input failed
+    let donation_reason = (eligible_sats == 0)
+        .then(|| reasons.iter().find_map(|reason| *reason).unwrap_or("block_height_unknown"));
+
+    Ok(Eligibility {
+        inputs,
+        eligible_sats,
+        donation_reason,
+    })
+}
+
+/// The share of a deposit's amount attributable to its eligible inputs: the amount
+/// times the eligible inputs' share of the total input value. If any input's value is
+/// unknown the total isn't either, and leaving that input out would inflate the share,
+/// so nothing is eligible.
+fn eligible_share(amount_sats: u64, inputs: &[DepositInput]) -> u64 {
+    let Some(total_input_sats) = inputs.iter().map(|input| input.value_sats).sum::<Option<u64>>() else {
+        return 0;
+    };
+    let eligible_input_sats: u64 = inputs
+        .iter()
+        .filter(|input| input.is_eligible)
+        .filter_map(|input| input.value_sats)
+        .sum();
+
+    (amount_sats as u128 * eligible_input_sats as u128)
+        .checked_div(total_input_sats as u128)
+        .map_or(0, |share| share as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(value_sats: Option<u64>, is_eligible: bool) -> DepositInput {
+        DepositInput {
+            deposit_txid: String::new(),
+            deposit_vout: 0,
+            input_index: 0,
+            prev_txid: String::new(),
+            prev_vout: 0,
+            value_sats,
+            creation_height: Some(100),
+            is_eligible,
+            reason: (!is_eligible).then(|| "input_too_large".to_string()),
+        }
+    }
+
+    #[test]
+    fn fully_eligible_deposit_is_paid_in_full() {
+        let inputs = [input(Some(600), true), input(Some(400), true)];
+        assert_eq!(eligible_share(900, &inputs), 900);
+    }
+
+    #[test]
+    fn partly_eligible_deposit_is_paid_pro_rata() {
+        let inputs = [input(Some(600), true), input(Some(1_400), false)];
+        assert_eq!(eligible_share(1_800, &inputs), 540);
+    }
+
+    #[test]
+    fn share_rounds_down() {
+        let inputs = [input(Some(1), true), input(Some(2), false)];
+        assert_eq!(eligible_share(100, &inputs), 33);
+    }
+
+    #[test]
+    fn unknown_input_value_makes_nothing_eligible() {
+        // Leaving the unknown input out would make the known one look like all of it
+        let inputs = [input(Some(600), true), input(None, false)];
+        assert_eq!(eligible_share(600, &inputs), 0);
+    }
+
+    #[test]
+    fn no_eligible_inputs_or_no_inputs_pays_nothing() {
+        assert_eq!(eligible_share(1_000, &[input(Some(5_000), false)]), 0);
+        assert_eq!(eligible_share(1_000, &[]), 0);
+        assert_eq!(eligible_share(1_000, &[input(Some(0), true)]), 0);
+    }
+
+    #[test]
+    fn large_amounts_do_not_overflow() {
+        let inputs = [input(Some(u64::MAX / 2), true), input(Some(u64::MAX / 2), false)];
+        assert_eq!(eligible_share(u64::MAX, &inputs), u64::MAX / 2);
+    }
+}
@@ -0,0 +1,79 @@
+use crate::db::{AdvisoryLock, DatabaseBackend};
+use crate::workers::{run_deposit_monitor, run_payment_processor};
+use crate::AppState;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time;
+
+/// Key of the Postgres advisory lock held by the replica running the workers ("utxowork")
+const WORKER_LOCK: i64 = 0x7574_786f_776f_726b;
+
+/// How often a replica without the worker lock tries to take it, and how often the
+/// replica holding it checks it still does
+const LOCK_CHECK_SECS: u64 = 15;
+
+/// Run the deposit monitor and payment processor.
+///
+/// Replicas sharing a Postgres database each have their own wallet, so only the one
+/// holding the worker lock syncs it and moves recycles along; the others serve
+/// requests and take over if it goes away. With SQLite there is a single instance,
+/// which always runs them.
+pub async fn run_workers(state: Arc<AppState>, backend: DatabaseBackend) {
+    if backend == DatabaseBackend::Sqlite {
+        state.runs_workers.store(true, Ordering::Relaxed);
+        let workers = spawn_workers(&state);
+        for worker in workers {
+            let _ = worker.await;
+        }
+        return;
+    }
+
+    loop {
+        let mut lock = match AdvisoryLock::try_acquire(&state.config.database_url, WORKER_LOCK).await {
+            Ok(Some(lock)) => lock,
+            Ok(None) => {
+                tracing::debug!("Another replica runs the workers");
+                time::sleep(Duration::from_secs(LOCK_CHECK_SECS)).await;
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to take the worker lock (will retry): {}", e);
+                time::sleep(Duration::from_secs(LOCK_CHECK_SECS)).await;
+                continue;
+            }
+        };
+
+        tracing::info!("Took the worker lock, this replica runs the workers");
+        state.runs_workers.store(true, Ordering::Relaxed);
+        let workers = spawn_workers(&state);
+
+        while lock.is_held().await {
+            time::sleep(Duration::from_secs(LOCK_CHECK_SECS)).await;
+        }
+
+        // Another replica may take over as soon as the lock is gone, so stop at once
+        tracing::warn!("Lost the worker lock, stopping the workers");
+        state.runs_workers.store(false, Ordering::Relaxed);
+        for worker in workers {
+            worker.abort();
+        }
+    }
+}
+
+fn spawn_workers(state: &Arc<AppState>) -> Vec<JoinHandle<()>> {
+    let monitor_state = Arc::clone(state);
+    let monitor = tokio::spawn(async move {
+        tracing::info!("Starting deposit monitor worker...");
+        run_deposit_monitor(monitor_state).await;
+    });
+
+    let processor_state = Arc::clone(state);
+    let processor = tokio::spawn(async move {
+        tracing::info!("Starting payment processor worker...");
+        run_payment_processor(processor_state).await;
+    });
+
+    vec![monitor, processor]
+}
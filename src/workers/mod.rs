@@ -1,5 +1,7 @@
 pub mod deposit_monitor;
+pub mod leader;
 pub mod payment_processor;
 
 pub use deposit_monitor::*;
+pub use leader::*;
 pub use payment_processor::*;
@@ -1,6 +1,10 @@
-use crate::db::{RecycleRepository, RecycleStatus};
-use crate::lightning::LnurlClient;
+use crate::db::{
+    Deposit, DepositRepository, DepositStatus, NewPayment, Payment, PaymentRepository, RecycleStatus,
+};
+use crate::lightning::{Bolt11Invoice, InvoiceRefusal, LnurlClient, PaymentStatus};
 use crate::AppState;
+use chrono::{DateTime, Utc};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::time;
@@ -22,8 +26,30 @@ pub async fn run_payment_processor(state: Arc<AppState>) {
 }
 
 async fn process_confirmed_recycles(state: &AppState) -> anyhow::Result<()> {
+    // Settle payments whose outcome is still unknown first. Their recycles stay paying
+    // until then, so none of them is paid again below.
+    for payment in PaymentRepository::find_pending(&state.db).await? {
+        if let Err(e) = reconcile_payment(state, &payment).await {
+            if Utc::now() >= payment.expires_at {
+                tracing::error!(
+                    "Payment {} for recycle {} can't be looked up and its invoice has expired - it needs to be checked by hand: {}",
+                    payment.payment_hash,
+                    payment.recycle_id,
+                    e
+                );
+            } else {
+                tracing::warn!(
+                    "Failed to look up payment {} for recycle {} (will try again): {}",
+                    payment.payment_hash,
+                    payment.recycle_id,
+                    e
+                );
+            }
+        }
+    }
+
     // Get all confirmed recycles ready for payout
-    let confirmed = RecycleRepository::find_by_status(&state.db, RecycleStatus::Confirmed).await?;
+    let confirmed = state.recycles.find_by_status(RecycleStatus::Confirmed).await?;
 
     for recycle in confirmed {
         // Check if we've exceeded max attempts
@@ -33,35 +59,88 @@ async fn process_confirmed_recycles(state: &AppState) -> anyhow::Result<()> {
                 recycle.id,
                 MAX_PAYMENT_ATTEMPTS
             );
-            RecycleRepository::mark_failed(&state.db, &recycle.id).await?;
+            let reason = format!("payment failed after {} attempts", recycle.payment_attempts);
+            state.recycles.mark_failed(&recycle.id, &reason).await?;
             continue;
         }
 
-        let deposit_amount = match recycle.deposit_amount_sats {
-            Some(amount) => amount,
-            None => {
-                tracing::warn!("Recycle {} is confirmed but has no deposit amount", recycle.id);
+        // Pay out every eligible deposit that hasn't been paid yet
+        let deposits: Vec<Deposit> = DepositRepository::find_by_recycle(&state.db, &recycle.id)
+            .await?
+            .into_iter()
+            .filter(|d| d.status == DepositStatus::Confirmed)
+            .collect();
+
+        if deposits.is_empty() {
+            tracing::warn!("Recycle {} is confirmed but has no eligible deposits", recycle.id);
+            continue;
+        }
+
+        // A reorg since the deposit monitor's last pass could have unconfirmed a deposit.
+        // Only pay while the wallet still sees every deposit with enough confirmations;
+        // otherwise leave it to the monitor to move the recycle back.
+        match deposits_still_confirmed(state, recycle.address_index, &deposits).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!(
+                    "Recycle {} has deposits that are no longer sufficiently confirmed - holding payout",
+                    recycle.id
+                );
                 continue;
             }
-        };
+            Err(e) => {
+                tracing::warn!("Failed to re-check deposits for recycle {}: {}", recycle.id, e);
+                continue;
+            }
+        }
+
+        // Only the share attributable to eligible inputs is paid out; the rest of a
+        // partly eligible deposit is kept as a donation
+        let deposit_amount: u64 = deposits.iter().map(|d| d.amount_sats).sum();
+        let eligible_amount: u64 = deposits.iter().map(|d| d.payable_sats()).sum();
 
         // Calculate payout amount (101% or configured multiplier)
-        let payout_amount = (deposit_amount as f64 * state.config.payout_multiplier) as u64;
+        let payout_amount = (eligible_amount as f64 * state.config.payout_multiplier) as u64;
+
+        // Hold the payout while the backend can't cover it, rather than spend one of the
+        // recycle's attempts on a payment that can't succeed. A backend that can't report
+        // its balance is tried anyway.
+        match state.lightning.get_balance().await {
+            Ok(balance_msat) if balance_msat < payout_amount.saturating_mul(1000) => {
+                tracing::warn!(
+                    "Lightning balance of {} sats can't cover the {} sats payout for recycle {} - holding payout",
+                    balance_msat / 1000,
+                    payout_amount,
+                    recycle.id
+                );
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to check the Lightning balance before paying recycle {} (paying anyway): {}",
+                    recycle.id,
+                    e
+                );
+            }
+        }
 
         tracing::info!(
-            "Processing payout for recycle {} (attempt {}/{}): {} sats deposit -> {} sats payout",
+            "Processing payout for recycle {} (attempt {}/{}): {} deposit(s), {} of {} sats eligible -> {} sats payout",
             recycle.id,
             recycle.payment_attempts + 1,
             MAX_PAYMENT_ATTEMPTS,
+            deposits.len(),
+            eligible_amount,
             deposit_amount,
             payout_amount
         );
 
         // Increment attempt counter before trying payment
-        let attempts = RecycleRepository::increment_payment_attempts(&state.db, &recycle.id).await?;
+        let attempts = state.recycles.increment_payment_attempts(&recycle.id).await?;
 
         // Get invoice from lightning address
-        let lnurl_client = LnurlClient::new();
+        let lnurl_client = LnurlClient::new(state.config.lnurl_allow_http);
         let invoice = match lnurl_client
             .get_invoice_for_address(&recycle.lightning_address, payout_amount)
             .await
@@ -79,10 +158,60 @@ async fn process_confirmed_recycles(state: &AppState) -> anyhow::Result<()> {
             }
         };
 
-        tracing::debug!("Got invoice for recycle {}: {}", recycle.id, invoice);
+        tracing::debug!("Got invoice for recycle {}: {}", recycle.id, invoice.bolt11);
+
+        // Only pay the invoice that was asked for. A server handing out anything else
+        // can't be trusted with this recycle, so it needs an operator to look at it. One
+        // that merely expired is asked for again (up to MAX_PAYMENT_ATTEMPTS).
+        let decoded = match invoice.validate(state.config.network) {
+            Ok(decoded) => decoded,
+            Err(InvoiceRefusal::Expired) => {
+                tracing::warn!(
+                    "Invoice for recycle {} expired before it could be paid (attempt {}/{})",
+                    recycle.id,
+                    attempts,
+                    MAX_PAYMENT_ATTEMPTS
+                );
+                continue;
+            }
+            Err(reason) => {
+                tracing::error!("Refusing invoice for recycle {}: {}", recycle.id, reason);
+                let reason = format!("LNURL invoice refused: {}", reason);
+                state.recycles.mark_failed(&recycle.id, &reason).await?;
+                continue;
+            }
+        };
+
+        // Record the payment before sending it. If its outcome is never learned (a
+        // timeout, a restart), the recycle stays paying until the backend is asked about
+        // it, rather than a fresh invoice being paid on the next attempt.
+        let payment_hash = decoded.payment_hash.to_string();
+        let payment = NewPayment {
+            recycle_id: &recycle.id,
+            bolt11: &invoice.bolt11,
+            payment_hash: &payment_hash,
+            amount_msats: invoice.amount_msats,
+            payout_amount_sats: payout_amount,
+            expires_at: DateTime::from_timestamp(decoded.expires_at() as i64, 0).unwrap_or_else(Utc::now),
+        };
+        if let Err(e) = state.recycles.start_payment(&payment, &deposits).await {
+            tracing::warn!("Failed to record payment for recycle {}: {}", recycle.id, e);
+            continue;
+        }
 
-        // Pay the invoice via NWC
-        match state.nwc.pay_invoice(&invoice).await {
+        // Pay the invoice from the configured Lightning backend
+        match state.lightning.pay_invoice(&invoice.bolt11).await {
+            Ok(result) if !decoded.is_preimage(&result.preimage) => {
+                // Not proof of payment, so the outcome is as unknown as after any other error
+                tracing::warn!(
+                    "Payment attempt {}/{} for recycle {} returned preimage {} which doesn't match payment hash {}. Payment status unknown - will look it up before retrying.",
+                    attempts,
+                    MAX_PAYMENT_ATTEMPTS,
+                    recycle.id,
+                    result.preimage,
+                    payment_hash
+                );
+            }
             Ok(result) => {
                 tracing::info!(
                     "Payment successful for recycle {}: preimage={}",
@@ -90,27 +219,94 @@ async fn process_confirmed_recycles(state: &AppState) -> anyhow::Result<()> {
                     result.preimage
                 );
 
-                RecycleRepository::mark_paid(
-                    &state.db,
-                    &recycle.id,
-                    payout_amount,
-                    &result.preimage,
-                    &result.payment_hash,
-                )
-                .await?;
+                state.recycles.mark_paid(&payment_hash, &result.preimage).await?;
             }
             Err(e) => {
                 tracing::warn!(
-                    "Payment attempt {}/{} failed for recycle {}: {}",
+                    "Payment attempt {}/{} failed for recycle {}: {}. Will look it up before retrying.",
                     attempts,
                     MAX_PAYMENT_ATTEMPTS,
                     recycle.id,
                     e
                 );
-                // Will retry on next loop (up to MAX_PAYMENT_ATTEMPTS)
             }
         }
     }
 
     Ok(())
 }
+
+/// Settle a pending payment from what the Lightning backend knows about it. One the
+/// backend has no record of is only given up once its invoice has expired, since until
+/// then a request that was delayed on its way could still be paid.
+async fn reconcile_payment(state: &AppState, payment: &Payment) -> anyhow::Result<()> {
+    let invoice = Bolt11Invoice::from_str(&payment.bolt11)?;
+
+    match state.lightning.lookup_payment(&payment.payment_hash).await? {
+        PaymentStatus::Succeeded { preimage } if invoice.is_preimage(&preimage) => {
+            tracing::info!(
+                "Payment {} for recycle {} succeeded: preimage={}",
+                payment.payment_hash,
+                payment.recycle_id,
+                preimage
+            );
+            state.recycles.mark_paid(&payment.payment_hash, &preimage).await?;
+        }
+        PaymentStatus::Succeeded { preimage } => {
+            tracing::warn!(
+                "{} reports payment {} for recycle {} as paid, but preimage {} doesn't match - leaving it pending",
+                state.lightning.name(),
+                payment.payment_hash,
+                payment.recycle_id,
+                preimage
+            );
+        }
+        PaymentStatus::Pending => {
+            tracing::debug!(
+                "Payment {} for recycle {} is still in flight",
+                payment.payment_hash,
+                payment.recycle_id
+            );
+        }
+        PaymentStatus::Failed => {
+            let error = format!("{} reported the payment as failed", state.lightning.name());
+            tracing::warn!("Payment {} for recycle {}: {}", payment.payment_hash, payment.recycle_id, error);
+            state.recycles.fail_payment(&payment.payment_hash, &error).await?;
+        }
+        PaymentStatus::NotFound if Utc::now() >= payment.expires_at => {
+            let error = format!("{} has no record of the payment and its invoice expired", state.lightning.name());
+            tracing::warn!("Payment {} for recycle {}: {}", payment.payment_hash, payment.recycle_id, error);
+            state.recycles.fail_payment(&payment.payment_hash, &error).await?;
+        }
+        PaymentStatus::NotFound => {
+            tracing::debug!(
+                "Payment {} for recycle {} isn't known to {} yet, waiting until its invoice expires at {}",
+                payment.payment_hash,
+                payment.recycle_id,
+                state.lightning.name(),
+                payment.expires_at
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the wallet still lists every deposit at the recycle's address with at least
+/// the required number of confirmations.
+async fn deposits_still_confirmed(
+    state: &AppState,
+    address_index: u32,
+    deposits: &[Deposit],
+) -> anyhow::Result<bool> {
+    let current = state.wallet.check_address_deposits(address_index).await?;
+
+    Ok(deposits.iter().all(|deposit| {
+        current.iter().any(|info| {
+            info.txid == deposit.txid
+                && info.vout == deposit.vout
+                && info.block_height == deposit.block_height
+                && info.confirmations >= state.config.required_confirmations
+        })
+    }))
+}
@@ -0,0 +1,964 @@
+//! Regtest harness for end-to-end tests.
+//!
+//! Launches a `bitcoind` in regtest, an `electrs` Electrum server on top of it, a
+//! stand-in LNURL-pay server, a local Nostr relay with a mock NWC wallet or a mock
+//! LND / Core Lightning / LNbits REST node, and finally the service binary itself
+//! configured against all of them. Every process and
+//! directory is torn down when its handle is dropped.
+//!
+//! The node binaries aren't bundled: point `BITCOIND_EXE` at a Bitcoin Core `bitcoind`
+//! (v25 or newer) and `ELECTRS_EXE` at an `electrs` (v0.10 or newer) before running
+//! `cargo test -- --ignored`.
+
+use anyhow::{anyhow, Context, Result};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+use base64::Engine;
+use bdk_bitcoind_rpc::bitcoincore_rpc::json::CreateRawTransactionInput;
+use bdk_bitcoind_rpc::bitcoincore_rpc::{Auth, Client as RpcClient, RpcApi};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hex::{DisplayHex, FromHex};
+use bitcoin::{Address, Amount, Network, OutPoint, Txid};
+use electrum_client::ElectrumApi;
+use lightning_invoice::{Bolt11InvoiceDescription, Currency, InvoiceBuilder, PaymentSecret, Sha256};
+use nostr_relay_builder::MockRelay;
+use nostr_sdk::prelude::*;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const RPC_USER: &str = "recycler";
+const RPC_PASS: &str = "recycler";
+
+/// Admin token the service is started with
+const ADMIN_TOKEN: &str = "e2e-admin";
+
+/// Regtest descriptor for the service's deposit addresses. Only the xpub is needed;
+/// deposits are never spent in these tests.
+pub const TEST_DESCRIPTOR: &str = "wpkh(tpubD6NzVbkrYhZ4XgiXtGrdW5XDAPFCL9h7we1vwNCpn8tGbBcgfVYjXyhWo4E1xkh56hjod1RhGjxbaTLV3X4FyWuejifB9jusQ46QzG87VKp/0/*)";
+
+/// Path to a required external binary, taken from the environment.
+pub fn exe_from_env(var: &str) -> Result<PathBuf> {
+    let path = std::env::var(var).with_context(|| format!("{} is not set", var))?;
+    Ok(PathBuf::from(path))
+}
+
+/// Reserve a free localhost port.
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("no free port")
+}
+
+/// Poll `check` until it returns Some, or fail after `timeout`.
+pub async fn wait_for<T, F, Fut>(what: &str, timeout: Duration, mut check: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(value) = check().await {
+            return Ok(value);
+        }
+        if Instant::now() > deadline {
+            return Err(anyhow!("Timed out waiting for {}", what));
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
+
+/// A child process killed on drop.
+struct Process(Child);
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A regtest `bitcoind` with a funded wallet.
+pub struct Bitcoind {
+    _process: Process,
+    pub datadir: TempDir,
+    pub rpc: RpcClient,
+    pub rpc_port: u16,
+    pub p2p_port: u16,
+    mining_address: Address,
+}
+
+impl Bitcoind {
+    pub async fn start(exe: &PathBuf) -> Result<Self> {
+        let datadir = TempDir::new()?;
+        let rpc_port = free_port();
+        let p2p_port = free_port();
+
+        let process = Command::new(exe)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", datadir.path().display()))
+            .arg(format!("-rpcport={}", rpc_port))
+            .arg(format!("-port={}", p2p_port))
+            .arg(format!("-bind=127.0.0.1:{}", p2p_port))
+            .arg(format!("-rpcuser={}", RPC_USER))
+            .arg(format!("-rpcpassword={}", RPC_PASS))
+            .args(["-server", "-txindex=1", "-fallbackfee=0.0001", "-printtoconsole=0"])
+            .stdout(Stdio::null())
+            .spawn()
+            .context("failed to launch bitcoind")?;
+        let process = Process(process);
+
+        let url = format!("http://127.0.0.1:{}", rpc_port);
+        let rpc = RpcClient::new(&url, Auth::UserPass(RPC_USER.into(), RPC_PASS.into()))?;
+        wait_for("bitcoind RPC", Duration::from_secs(30), || async {
+            rpc.get_block_count().ok()
+        })
+        .await?;
+
+        rpc.create_wallet("e2e", None, None, None, None)?;
+        let mining_address = rpc.get_new_address(None, None)?.require_network(Network::Regtest)?;
+
+        Ok(Self {
+            _process: process,
+            datadir,
+            rpc,
+            rpc_port,
+            p2p_port,
+            mining_address,
+        })
+    }
+
+    pub fn height(&self) -> Result<u32> {
+        Ok(self.rpc.get_block_count()? as u32)
+    }
+
+    pub fn mine(&self, blocks: u64) -> Result<()> {
+        self.rpc.generate_to_address(blocks, &self.mining_address)?;
+        Ok(())
+    }
+
+    /// Mine until the tip is at `height`.
+    pub fn mine_to(&self, height: u32) -> Result<()> {
+        let current = self.height()?;
+        if height > current {
+            self.mine((height - current) as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Invalidate the tip block, returning its transactions to the mempool.
+    pub fn invalidate_tip(&self) -> Result<()> {
+        let tip = self.rpc.get_best_block_hash()?;
+        self.rpc.invalidate_block(&tip)?;
+        Ok(())
+    }
+
+    pub fn new_address(&self) -> Result<Address> {
+        Ok(self.rpc.get_new_address(None, None)?.require_network(Network::Regtest)?)
+    }
+
+    /// Create one wallet output per amount, funded from the wallet, and return their outpoints.
+    pub fn create_outputs(&self, amounts: &[Amount]) -> Result<Vec<(OutPoint, Amount)>> {
+        let addresses: Vec<Address> = amounts
+            .iter()
+            .map(|_| self.new_address())
+            .collect::<Result<_>>()?;
+        let outs: HashMap<String, Amount> = addresses
+            .iter()
+            .zip(amounts)
+            .map(|(address, amount)| (address.to_string(), *amount))
+            .collect();
+
+        let raw = self.rpc.create_raw_transaction_hex(&[], &outs, None, None)?;
+        let funded = self.rpc.fund_raw_transaction(raw, None, None)?;
+        let txid = self.sign_and_send(&funded.hex)?;
+
+        let tx = self.rpc.get_raw_transaction(&txid, None)?;
+        addresses
+            .iter()
+            .zip(amounts)
+            .map(|(address, amount)| {
+                let vout = tx
+                    .output
+                    .iter()
+                    .position(|out| out.script_pubkey == address.script_pubkey())
+                    .ok_or_else(|| anyhow!("output for {} not found", address))?;
+                Ok((OutPoint::new(txid, vout as u32), *amount))
+            })
+            .collect()
+    }
+
+    /// Spend exactly `inputs` to the given outputs. Whatever isn't assigned is the fee.
+    /// The transaction signals replaceability so tests can double-spend it.
+    pub fn spend(&self, inputs: &[OutPoint], outputs: &[(String, Amount)]) -> Result<Txid> {
+        let signed = self.sign(inputs, outputs)?;
+        Ok(self.rpc.send_raw_transaction(signed.as_str())?)
+    }
+
+    /// Build and sign a transaction like `spend`, returning its hex without broadcasting it.
+    pub fn sign(&self, inputs: &[OutPoint], outputs: &[(String, Amount)]) -> Result<String> {
+        let inputs: Vec<CreateRawTransactionInput> = inputs
+            .iter()
+            .map(|outpoint| CreateRawTransactionInput {
+                txid: outpoint.txid,
+                vout: outpoint.vout,
+                sequence: None,
+            })
+            .collect();
+        let outs: HashMap<String, Amount> = outputs.iter().cloned().collect();
+
+        let raw = self.rpc.create_raw_transaction_hex(&inputs, &outs, None, Some(true))?;
+        let signed = self.sign_raw(&bitcoin::hex::FromHex::from_hex(&raw)?)?;
+        Ok(signed.to_lower_hex_string())
+    }
+
+    fn sign_and_send(&self, raw: &Vec<u8>) -> Result<Txid> {
+        let signed = self.sign_raw(raw)?;
+        Ok(self.rpc.send_raw_transaction(&signed)?)
+    }
+
+    fn sign_raw(&self, raw: &Vec<u8>) -> Result<Vec<u8>> {
+        let signed = self.rpc.sign_raw_transaction_with_wallet(raw, None, None)?;
+        if !signed.complete {
+            return Err(anyhow!("bitcoind could not sign transaction"));
+        }
+        Ok(signed.hex)
+    }
+
+    /// Whether `txid` is in the node's mempool.
+    pub fn in_mempool(&self, txid: &Txid) -> Result<bool> {
+        Ok(self.rpc.get_raw_mempool()?.contains(txid))
+    }
+}
+
+/// An `electrs` Electrum server indexing the regtest node.
+pub struct Electrs {
+    _process: Process,
+    _db_dir: TempDir,
+    pub url: String,
+}
+
+impl Electrs {
+    pub async fn start(exe: &PathBuf, bitcoind: &Bitcoind) -> Result<Self> {
+        let db_dir = TempDir::new()?;
+        let port = free_port();
+
+        let process = Command::new(exe)
+            .args(["--network", "regtest"])
+            .arg("--daemon-dir")
+            .arg(bitcoind.datadir.path())
+            .arg("--db-dir")
+            .arg(db_dir.path())
+            .args(["--daemon-rpc-addr", &format!("127.0.0.1:{}", bitcoind.rpc_port)])
+            .args(["--daemon-p2p-addr", &format!("127.0.0.1:{}", bitcoind.p2p_port)])
+            .args(["--electrum-rpc-addr", &format!("127.0.0.1:{}", port)])
+            .args(["--auth", &format!("{}:{}", RPC_USER, RPC_PASS)])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("failed to launch electrs")?;
+
+        let electrs = Self {
+            _process: Process(process),
+            _db_dir: db_dir,
+            url: format!("tcp://127.0.0.1:{}", port),
+        };
+        electrs.wait_for_height(bitcoind.height()?).await?;
+
+        Ok(electrs)
+    }
+
+    /// Wait until the server has indexed up to `height`.
+    pub async fn wait_for_height(&self, height: u32) -> Result<()> {
+        wait_for("electrs to index", Duration::from_secs(60), || async {
+            let client = electrum_client::Client::new(&self.url).ok()?;
+            let tip = client.block_headers_subscribe().ok()?.height as u32;
+            (tip >= height).then_some(())
+        })
+        .await
+    }
+}
+
+/// A stand-in LNURL-pay server for `<user>@127.0.0.1:<port>` lightning addresses.
+/// Every invoice it hands out is recorded with its amount. The `inflated` user gets
+/// invoices for twice the requested amount, as a compromised server might hand out.
+pub struct LnurlServer {
+    pub port: u16,
+    pub invoices: Arc<Mutex<Vec<(String, u64)>>>,
+    _task: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Clone)]
+struct LnurlState {
+    base_url: String,
+    invoices: Arc<Mutex<Vec<(String, u64)>>>,
+}
+
+impl LnurlServer {
+    pub async fn start() -> Result<Self> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let invoices = Arc::new(Mutex::new(Vec::new()));
+
+        let state = LnurlState {
+            base_url: format!("http://127.0.0.1:{}", port),
+            invoices: invoices.clone(),
+        };
+        let app = Router::new()
+            .route("/.well-known/lnurlp/:user", get(lnurlp))
+            .route("/callback/:user", get(callback))
+            .with_state(state);
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app).await.expect("LNURL server failed");
+        });
+
+        Ok(Self {
+            port,
+            invoices,
+            _task: task,
+        })
+    }
+
+    pub fn lightning_address(&self, user: &str) -> String {
+        format!("{}@127.0.0.1:{}", user, self.port)
+    }
+
+    /// Amounts (in msats) of the invoices handed out so far.
+    pub fn invoice_amounts(&self) -> Vec<u64> {
+        self.invoices.lock().unwrap().iter().map(|(_, amount)| *amount).collect()
+    }
+}
+
+async fn lnurlp(State(state): State<LnurlState>, Path(user): Path<String>) -> Json<Value> {
+    Json(json!({
+        "callback": format!("{}/callback/{}", state.base_url, user),
+        "minSendable": 1_000,
+        "maxSendable": 100_000_000,
+        "metadata": lnurl_metadata(&user),
+        "tag": "payRequest",
+    }))
+}
+
+fn lnurl_metadata(user: &str) -> String {
+    json!([["text/plain", format!("Pay to {}", user)]]).to_string()
+}
+
+async fn callback(
+    State(state): State<LnurlState>,
+    Path(user): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Json<Value> {
+    let mut amount: u64 = query.get("amount").and_then(|a| a.parse().ok()).unwrap_or(0);
+    if user == "inflated" {
+        amount *= 2;
+    }
+    let invoice = issue_invoice(amount, &lnurl_metadata(&user));
+    state.invoices.lock().unwrap().push((invoice.clone(), amount));
+
+    Json(json!({ "pr": invoice, "routes": [] }))
+}
+
+/// Preimages of every invoice the LNURL stand-in has issued. A real payer learns the
+/// preimage by paying; the mock wallets and nodes look it up here instead.
+static ISSUED_PREIMAGES: Mutex<Vec<(String, [u8; 32])>> = Mutex::new(Vec::new());
+
+fn issued_preimage(invoice: &str) -> Option<[u8; 32]> {
+    ISSUED_PREIMAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(issued, _)| issued == invoice)
+        .map(|(_, preimage)| *preimage)
+}
+
+/// Issue a signed regtest BOLT11 invoice for `amount_msat` whose description hash
+/// commits to the LNURL `metadata`, as LUD-06 requires.
+fn issue_invoice(amount_msat: u64, metadata: &str) -> String {
+    let preimage = Keys::generate().secret_key().secret_bytes();
+    let payment_hash = sha256::Hash::hash(&preimage);
+    let description_hash = sha256::Hash::hash(metadata.as_bytes());
+
+    let secp = bitcoin::secp256k1::Secp256k1::new();
+    let node_key = bitcoin::secp256k1::SecretKey::from_slice(&Keys::generate().secret_key().secret_bytes())
+        .expect("valid key");
+    let invoice = InvoiceBuilder::new(Currency::Regtest)
+        .amount_milli_satoshis(amount_msat)
+        .invoice_description(Bolt11InvoiceDescription::Hash(Sha256(description_hash)))
+        .payment_hash(payment_hash)
+        .payment_secret(PaymentSecret(Keys::generate().secret_key().secret_bytes()))
+        .duration_since_epoch(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("clock before 1970"))
+        .min_final_cltv_expiry_delta(18)
+        .build_signed(|message| secp.sign_ecdsa_recoverable(message, &node_key))
+        .expect("valid invoice")
+        .to_string();
+    ISSUED_PREIMAGES.lock().unwrap().push((invoice.clone(), preimage));
+    invoice
+}
+
+/// A local Nostr relay with a mock NWC wallet that pays every invoice the LNURL
+/// stand-in issued.
+pub struct MockNwcWallet {
+    _relay: MockRelay,
+    client: Client,
+    wallet_keys: Keys,
+    app_keys: Keys,
+    relay_url: String,
+    /// Invoices paid so far, with the preimage returned for each
+    pub payments: Arc<Mutex<Vec<(String, String)>>>,
+    /// Whether `pay_invoice` requests are answered, or only paid
+    answer_payments: Arc<AtomicBool>,
+}
+
+impl MockNwcWallet {
+    pub async fn start() -> Result<Self> {
+        let relay = MockRelay::run().await?;
+        let relay_url = relay.url();
+
+        let wallet_keys = Keys::generate();
+        let app_keys = Keys::generate();
+        let payments = Arc::new(Mutex::new(Vec::new()));
+        let answer_payments = Arc::new(AtomicBool::new(true));
+
+        let client = Client::new(wallet_keys.clone());
+        client.add_relay(&relay_url).await?;
+        client.connect().await;
+        client
+            .send_event_builder(EventBuilder::new(Kind::WalletConnectInfo, NWC_METHODS.join(" ")))
+            .await?;
+        client
+            .subscribe(
+                vec![Filter::new()
+                    .kind(Kind::WalletConnectRequest)
+                    .pubkey(wallet_keys.public_key())],
+                None,
+            )
+            .await?;
+
+        let handler_client = client.clone();
+        let handler_keys = wallet_keys.clone();
+        let handler_payments = payments.clone();
+        let handler_answer = answer_payments.clone();
+        tokio::spawn(async move {
+            let _ = handler_client
+                .handle_notifications(|notification| {
+                    let client = handler_client.clone();
+                    let keys = handler_keys.clone();
+                    let payments = handler_payments.clone();
+                    let answer = handler_answer.clone();
+                    async move {
+                        if let RelayPoolNotification::Event { event, .. } = notification {
+                            if let Err(e) = respond(&client, &keys, &event, &payments, &answer).await {
+                                eprintln!("mock NWC wallet error: {}", e);
+                            }
+                        }
+                        Ok(false)
+                    }
+                })
+                .await;
+        });
+
+        Ok(Self {
+            _relay: relay,
+            client,
+            wallet_keys,
+            app_keys,
+            relay_url,
+            payments,
+            answer_payments,
+        })
+    }
+
+    /// Keep paying invoices but stop answering `pay_invoice`, as when the response is
+    /// lost on the way back and the service times out.
+    pub fn stop_answering_payments(&self) {
+        self.answer_payments.store(false, Ordering::SeqCst);
+    }
+
+    /// Connection string for the service.
+    pub fn uri(&self) -> String {
+        format!(
+            "nostr+walletconnect://{}?relay={}&secret={}",
+            self.wallet_keys.public_key().to_hex(),
+            self.relay_url,
+            self.app_keys.secret_key().to_secret_hex()
+        )
+    }
+
+    pub fn paid_invoices(&self) -> Vec<String> {
+        self.payments.lock().unwrap().iter().map(|(invoice, _)| invoice.clone()).collect()
+    }
+}
+
+impl Drop for MockNwcWallet {
+    fn drop(&mut self) {
+        let client = self.client.clone();
+        tokio::spawn(async move { client.disconnect().await });
+    }
+}
+
+/// Answer a NIP-47 request. `pay_invoice` succeeds for invoices the LNURL stand-in
+/// issued, `lookup_invoice` finds the ones paid so far and `get_balance` reports a
+/// balance that covers every payout.
+async fn respond(
+    client: &Client,
+    keys: &Keys,
+    request: &Event,
+    payments: &Mutex<Vec<(String, String)>>,
+    answer_payments: &AtomicBool,
+) -> Result<()> {
+    if request.kind != Kind::WalletConnectRequest {
+        return Ok(());
+    }
+
+    let decrypted = nip04::decrypt(keys.secret_key(), &request.pubkey, &request.content)?;
+    let body: Value = serde_json::from_str(&decrypted)?;
+
+    let response = match body["method"].as_str() {
+        Some("pay_invoice") => {
+            let invoice = body["params"]["invoice"].as_str().unwrap_or_default().to_string();
+            match issued_preimage(&invoice) {
+                Some(preimage) => {
+                    let preimage = preimage.to_lower_hex_string();
+                    payments.lock().unwrap().push((invoice, preimage.clone()));
+                    if !answer_payments.load(Ordering::SeqCst) {
+                        return Ok(());
+                    }
+                    json!({ "result_type": "pay_invoice", "result": { "preimage": preimage } })
+                }
+                None => json!({
+                    "result_type": "pay_invoice",
+                    "error": { "code": "PAYMENT_FAILED", "message": "no route to unknown invoice" },
+                }),
+            }
+        }
+        Some("lookup_invoice") => {
+            let payment_hash = body["params"]["payment_hash"].as_str().unwrap_or_default();
+            let transactions = nwc_transactions(payments);
+            match transactions.into_iter().find(|transaction| transaction["payment_hash"] == payment_hash) {
+                Some(transaction) => json!({ "result_type": "lookup_invoice", "result": transaction }),
+                None => json!({
+                    "result_type": "lookup_invoice",
+                    "error": { "code": "NOT_FOUND", "message": "no such payment" },
+                }),
+            }
+        }
+        Some("list_transactions") => json!({
+            "result_type": "list_transactions",
+            "result": { "transactions": nwc_transactions(payments) },
+        }),
+        Some("get_balance") => json!({
+            "result_type": "get_balance",
+            "result": { "balance": 1_000_000_000u64 },
+        }),
+        other => json!({
+            "result_type": other,
+            "error": { "code": "NOT_IMPLEMENTED", "message": "unsupported method" },
+        }),
+    };
+
+    let encrypted = nip04::encrypt(keys.secret_key(), &request.pubkey, response.to_string())?;
+    let builder = EventBuilder::new(Kind::WalletConnectResponse, encrypted)
+        .tag(Tag::public_key(request.pubkey))
+        .tag(Tag::event(request.id));
+    client.send_event_builder(builder).await?;
+
+    Ok(())
+}
+
+/// The mock NWC wallet's payments as NIP-47 transactions, newest first
+fn nwc_transactions(payments: &Mutex<Vec<(String, String)>>) -> Vec<Value> {
+    payments
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .map(|(invoice, preimage)| {
+            let preimage_bytes = Vec::<u8>::from_hex(preimage).unwrap_or_default();
+            json!({
+                "type": "outgoing",
+                "state": "settled",
+                "invoice": invoice,
+                "payment_hash": sha256::Hash::hash(&preimage_bytes).to_string(),
+                "preimage": preimage,
+                "settled_at": Timestamp::now().as_u64(),
+            })
+        })
+        .collect()
+}
+
+/// Methods the mock NWC wallet advertises in its info event
+const NWC_METHODS: &[&str] = &["pay_invoice", "lookup_invoice", "list_transactions", "get_balance"];
+
+/// Credential every mock Lightning node API expects
+const MOCK_NODE_SECRET: &str = "0201036c6e64";
+
+/// A stand-in Lightning node answering the parts of the LND, Core Lightning (clnrest)
+/// and LNbits REST APIs the service uses. It pays every invoice the LNURL stand-in issued.
+pub struct MockLightningNode {
+    pub url: String,
+    payments: Arc<Mutex<Vec<MockPayment>>>,
+    _task: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Clone)]
+struct MockPayment {
+    invoice: String,
+    preimage: [u8; 32],
+    payment_hash: [u8; 32],
+}
+
+type MockNodeState = Arc<Mutex<Vec<MockPayment>>>;
+
+impl MockLightningNode {
+    pub async fn start() -> Result<Self> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://127.0.0.1:{}", listener.local_addr()?.port());
+        let payments: MockNodeState = Arc::new(Mutex::new(Vec::new()));
+
+        let app = Router::new()
+            .route("/v1/channels/transactions", post(lnd_pay))
+            .route("/v2/router/track/:hash", get(lnd_track))
+            .route("/v1/balance/channels", get(lnd_balance))
+            .route("/v1/pay", post(cln_pay))
+            .route("/v1/listpays", post(cln_listpays))
+            .route("/v1/listfunds", post(cln_listfunds))
+            .route("/api/v1/payments", post(lnbits_pay))
+            .route("/api/v1/payments/:hash", get(lnbits_payment))
+            .route("/api/v1/wallet", get(lnbits_wallet))
+            .with_state(payments.clone());
+        let task = tokio::spawn(async move {
+            axum::serve(listener, app).await.expect("mock Lightning node failed");
+        });
+
+        Ok(Self {
+            url,
+            payments,
+            _task: task,
+        })
+    }
+
+    /// Service configuration for paying through this node as `backend` (lnd, cln or lnbits).
+    pub fn env(&self, backend: &str) -> Vec<(&'static str, String)> {
+        let mut env = vec![("LIGHTNING_BACKEND", backend.to_string())];
+        match backend {
+            "lnd" => {
+                env.push(("LND_REST_URL", self.url.clone()));
+                env.push(("LND_MACAROON", MOCK_NODE_SECRET.to_string()));
+            }
+            "cln" => {
+                env.push(("CLN_REST_URL", self.url.clone()));
+                env.push(("CLN_RUNE", MOCK_NODE_SECRET.to_string()));
+            }
+            _ => {
+                env.push(("LNBITS_URL", self.url.clone()));
+                env.push(("LNBITS_API_KEY", MOCK_NODE_SECRET.to_string()));
+            }
+        }
+        env
+    }
+
+    /// Invoices paid so far, with the hex preimage returned for each
+    pub fn payments(&self) -> Vec<(String, String)> {
+        self.payments
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|payment| (payment.invoice.clone(), payment.preimage.to_lower_hex_string()))
+            .collect()
+    }
+}
+
+fn mock_pay(payments: &MockNodeState, invoice: &str) -> Result<MockPayment, (StatusCode, Json<Value>)> {
+    let preimage = issued_preimage(invoice).ok_or_else(|| {
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(json!({ "code": 2, "message": "no route", "detail": "no route" })))
+    })?;
+    let payment = MockPayment {
+        invoice: invoice.to_string(),
+        preimage,
+        payment_hash: sha256::Hash::hash(&preimage).to_byte_array(),
+    };
+    payments.lock().unwrap().push(payment.clone());
+    Ok(payment)
+}
+
+fn mock_find(payments: &MockNodeState, payment_hash: &[u8]) -> Option<MockPayment> {
+    payments
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|payment| payment.payment_hash[..] == *payment_hash)
+        .cloned()
+}
+
+fn mock_authorized(headers: &HeaderMap, header: &str) -> Result<(), (StatusCode, Json<Value>)> {
+    match headers.get(header).and_then(|value| value.to_str().ok()) {
+        Some(MOCK_NODE_SECRET) => Ok(()),
+        _ => Err((StatusCode::UNAUTHORIZED, Json(json!({ "code": 2, "message": "bad credentials" })))),
+    }
+}
+
+type MockResponse = Result<Json<Value>, (StatusCode, Json<Value>)>;
+
+async fn lnd_pay(State(payments): State<MockNodeState>, headers: HeaderMap, Json(body): Json<Value>) -> MockResponse {
+    mock_authorized(&headers, "Grpc-Metadata-macaroon")?;
+    let payment = mock_pay(&payments, body["payment_request"].as_str().unwrap_or_default())?;
+    Ok(Json(json!({
+        "payment_error": "",
+        "payment_preimage": STANDARD.encode(payment.preimage),
+        "payment_hash": STANDARD.encode(payment.payment_hash),
+    })))
+}
+
+async fn lnd_track(State(payments): State<MockNodeState>, headers: HeaderMap, Path(hash): Path<String>) -> MockResponse {
+    mock_authorized(&headers, "Grpc-Metadata-macaroon")?;
+    let hash = URL_SAFE.decode(&hash).unwrap_or_default();
+    Ok(Json(match mock_find(&payments, &hash) {
+        Some(payment) => json!({ "result": {
+            "status": "SUCCEEDED",
+            "payment_preimage": payment.preimage.to_lower_hex_string(),
+        } }),
+        None => json!({ "error": { "code": 5, "message": "payment isn't initiated" } }),
+    }))
+}
+
+async fn lnd_balance(headers: HeaderMap) -> MockResponse {
+    mock_authorized(&headers, "Grpc-Metadata-macaroon")?;
+    Ok(Json(json!({ "local_balance": { "sat": "1000000", "msat": "1000000000" } })))
+}
+
+async fn cln_pay(State(payments): State<MockNodeState>, headers: HeaderMap, Json(body): Json<Value>) -> MockResponse {
+    mock_authorized(&headers, "Rune")?;
+    let payment = mock_pay(&payments, body["bolt11"].as_str().unwrap_or_default())?;
+    Ok(Json(json!({
+        "status": "complete",
+        "payment_preimage": payment.preimage.to_lower_hex_string(),
+        "payment_hash": payment.payment_hash.to_lower_hex_string(),
+    })))
+}
+
+async fn cln_listpays(State(payments): State<MockNodeState>, headers: HeaderMap, Json(body): Json<Value>) -> MockResponse {
+    mock_authorized(&headers, "Rune")?;
+    let hash = Vec::<u8>::from_hex(body["payment_hash"].as_str().unwrap_or_default()).unwrap_or_default();
+    let pays: Vec<Value> = mock_find(&payments, &hash)
+        .into_iter()
+        .map(|payment| json!({ "status": "complete", "preimage": payment.preimage.to_lower_hex_string() }))
+        .collect();
+    Ok(Json(json!({ "pays": pays })))
+}
+
+async fn cln_listfunds(headers: HeaderMap) -> MockResponse {
+    mock_authorized(&headers, "Rune")?;
+    Ok(Json(json!({
+        "outputs": [],
+        "channels": [{ "state": "CHANNELD_NORMAL", "our_amount_msat": 1_000_000_000u64 }],
+    })))
+}
+
+async fn lnbits_pay(State(payments): State<MockNodeState>, headers: HeaderMap, Json(body): Json<Value>) -> MockResponse {
+    mock_authorized(&headers, "X-Api-Key")?;
+    let payment = mock_pay(&payments, body["bolt11"].as_str().unwrap_or_default())?;
+    Ok(Json(json!({ "payment_hash": payment.payment_hash.to_lower_hex_string() })))
+}
+
+async fn lnbits_payment(State(payments): State<MockNodeState>, headers: HeaderMap, Path(hash): Path<String>) -> MockResponse {
+    mock_authorized(&headers, "X-Api-Key")?;
+    let hash = Vec::<u8>::from_hex(&hash).unwrap_or_default();
+    match mock_find(&payments, &hash) {
+        Some(payment) => Ok(Json(json!({
+            "paid": true,
+            "status": "success",
+            "preimage": payment.preimage.to_lower_hex_string(),
+        }))),
+        None => Err((StatusCode::NOT_FOUND, Json(json!({ "detail": "Payment does not exist." })))),
+    }
+}
+
+async fn lnbits_wallet(headers: HeaderMap) -> MockResponse {
+    mock_authorized(&headers, "X-Api-Key")?;
+    Ok(Json(json!({ "name": "recycler", "balance": 1_000_000_000u64 })))
+}
+
+/// The service binary running against the harness.
+pub struct Service {
+    _process: Process,
+    _workdir: TempDir,
+    pub base_url: String,
+    http: reqwest::Client,
+}
+
+impl Service {
+    /// Start the service. `extra_env` overrides or adds configuration.
+    pub async fn start(
+        electrum_url: &str,
+        nwc_uri: &str,
+        extra_env: &[(&str, String)],
+    ) -> Result<Self> {
+        // Run from an empty directory so a developer's .env isn't picked up
+        let workdir = TempDir::new()?;
+        let port = free_port();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_utxo-recycler"));
+        command
+            .current_dir(workdir.path())
+            .env_clear()
+            .env("NETWORK", "regtest")
+            .env("LNURL_ALLOW_HTTP", "true")
+            .env("WALLET_DESCRIPTOR", TEST_DESCRIPTOR)
+            .env("NWC_URI", nwc_uri)
+            .env("ELECTRUM_URL", electrum_url)
+            .env(
+                "DATABASE_URL",
+                format!("sqlite:{}?mode=rwc", workdir.path().join("e2e.db").display()),
+            )
+            .env("SERVER_HOST", "127.0.0.1")
+            .env("SERVER_PORT", port.to_string())
+            .env("REQUIRED_CONFIRMATIONS", "1")
+            .env("ADMIN_TOKEN", ADMIN_TOKEN)
+            .env("RUST_LOG", std::env::var("RUST_LOG").unwrap_or_else(|_| "warn".into()));
+        for (key, value) in extra_env {
+            command.env(key, value);
+        }
+        let process = Process(command.spawn().context("failed to launch service")?);
+
+        let service = Self {
+            _process: process,
+            _workdir: workdir,
+            base_url: format!("http://127.0.0.1:{}", port),
+            http: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+        };
+
+        let health_url = format!("{}/health", service.base_url);
+        wait_for("service to start", Duration::from_secs(60), || async {
+            service.http.get(&health_url).send().await.ok()
+        })
+        .await?;
+
+        Ok(service)
+    }
+
+    /// Create a recycle through the public form endpoint and return its id and deposit address.
+    pub async fn create_recycle(&self, lightning_address: &str) -> Result<(String, Address)> {
+        let response = self
+            .http
+            .post(format!("{}/api/recycle", self.base_url))
+            .form(&[("lightning_address", lightning_address), ("confirmed", "on")])
+            .send()
+            .await?;
+
+        let location = response
+            .headers()
+            .get("Location")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| anyhow!("create_recycle failed: {}", response.status()))?;
+        let id = location
+            .strip_prefix("/recycle/")
+            .ok_or_else(|| anyhow!("unexpected redirect to {}", location))?
+            .to_string();
+
+        let recycle = self.recycle(&id).await?;
+        let address = Address::from_str(recycle["deposit_address"].as_str().unwrap_or_default())?
+            .require_network(Network::Regtest)?;
+
+        Ok((id, address))
+    }
+
+    /// Submit a signed deposit transaction for verification and broadcast, returning the
+    /// HTTP status and response body.
+    pub async fn submit_deposit(&self, id: &str, transaction: &str) -> Result<(u16, Value)> {
+        let response = self
+            .http
+            .post(format!("{}/api/recycle/{}/deposit", self.base_url, id))
+            .json(&json!({ "transaction": transaction }))
+            .send()
+            .await?;
+        let status = response.status().as_u16();
+        Ok((status, response.json().await?))
+    }
+
+    pub async fn recycle(&self, id: &str) -> Result<Value> {
+        Ok(self
+            .http
+            .get(format!("{}/api/recycle/{}", self.base_url, id))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// The recycle's status history from the admin API.
+    pub async fn events(&self, id: &str) -> Result<Value> {
+        Ok(self
+            .http
+            .get(format!("{}/admin/recycles/{}/events", self.base_url, id))
+            .query(&[("token", ADMIN_TOKEN)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Service totals and the Lightning balance from the admin API.
+    pub async fn stats(&self) -> Result<Value> {
+        Ok(self
+            .http
+            .get(format!("{}/admin/stats", self.base_url))
+            .query(&[("token", ADMIN_TOKEN)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// The Lightning payments attempted for the recycle, from the admin API.
+    pub async fn payments(&self, id: &str) -> Result<Value> {
+        Ok(self
+            .http
+            .get(format!("{}/admin/recycles/{}/payments", self.base_url, id))
+            .query(&[("token", ADMIN_TOKEN)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Wait until the recycle reaches `status`, returning its API representation.
+    pub async fn wait_for_status(&self, id: &str, status: &str, timeout: Duration) -> Result<Value> {
+        self.wait_for_recycle(id, &format!("be {}", status), timeout, |recycle| {
+            recycle["status"] == status
+        })
+        .await
+    }
+
+    /// Wait until `check` holds for the recycle's API representation.
+    pub async fn wait_for_recycle(
+        &self,
+        id: &str,
+        what: &str,
+        timeout: Duration,
+        check: impl Fn(&Value) -> bool,
+    ) -> Result<Value> {
+        wait_for(&format!("recycle {} to {}", id, what), timeout, || async {
+            let recycle = self.recycle(id).await.ok()?;
+            check(&recycle).then_some(recycle)
+        })
+        .await
+    }
+}
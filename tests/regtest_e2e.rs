@@ -0,0 +1,357 @@
+//! End-to-end recycle flows against a regtest node, an Electrum server, a stand-in
+//! LNURL server and a mock NWC wallet or REST Lightning node. See `tests/common/mod.rs` for requirements.
+//!
+//! Run with: `BITCOIND_EXE=... ELECTRS_EXE=... cargo test --test regtest_e2e -- --ignored`
+
+mod common;
+
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hex::FromHex;
+use bitcoin::Amount;
+use common::{exe_from_env, Bitcoind, Electrs, LnurlServer, MockLightningNode, MockNwcWallet, Service};
+use std::time::Duration;
+
+/// Inputs created at or after this height are kept as donations
+const CUTOFF_BLOCK_HEIGHT: u32 = 200;
+
+/// Inputs at or above this value are kept as donations
+const MAX_INPUT_SATS: u64 = 1_000;
+
+/// The payment processor runs every 30 seconds, so allow a few cycles
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(150);
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires BITCOIND_EXE and ELECTRS_EXE"]
+async fn recycles_are_paid_or_kept_as_donations() -> anyhow::Result<()> {
+    let bitcoind = Bitcoind::start(&exe_from_env("BITCOIND_EXE")?).await?;
+
+    // Mature a coinbase, then create dust and one large UTXO well before the cutoff
+    bitcoind.mine(101)?;
+    let old_dust = bitcoind.create_outputs(&[Amount::from_sat(600); 3])?;
+    let old_large = bitcoind.create_outputs(&[Amount::from_sat(50_000)])?;
+    bitcoind.mine(1)?;
+
+    // Dust created after the cutoff
+    bitcoind.mine_to(CUTOFF_BLOCK_HEIGHT)?;
+    let new_dust = bitcoind.create_outputs(&[Amount::from_sat(600); 3])?;
+    bitcoind.mine(1)?;
+
+    let electrs = Electrs::start(&exe_from_env("ELECTRS_EXE")?, &bitcoind).await?;
+    let lnurl = LnurlServer::start().await?;
+    let wallet = MockNwcWallet::start().await?;
+    let service = Service::start(
+        &electrs.url,
+        &wallet.uri(),
+        &[
+            ("CUTOFF_BLOCK_HEIGHT", CUTOFF_BLOCK_HEIGHT.to_string()),
+            ("MAX_INPUT_SATS", MAX_INPUT_SATS.to_string()),
+        ],
+    )
+    .await?;
+
+    let (paid_id, paid_address) = service.create_recycle(&lnurl.lightning_address("paid")).await?;
+    let (late_id, late_address) = service.create_recycle(&lnurl.lightning_address("late")).await?;
+    let (large_id, large_address) = service.create_recycle(&lnurl.lightning_address("large")).await?;
+
+    // Eligible: three pre-cutoff dust inputs, 300 sats of fee
+    let outpoints: Vec<_> = old_dust.iter().map(|(outpoint, _)| *outpoint).collect();
+    bitcoind.spend(&outpoints, &[(paid_address.to_string(), Amount::from_sat(1_500))])?;
+
+    // Donation (block_height): the same shape, but the inputs were created after the cutoff
+    let outpoints: Vec<_> = new_dust.iter().map(|(outpoint, _)| *outpoint).collect();
+    bitcoind.spend(&outpoints, &[(late_address.to_string(), Amount::from_sat(1_500))])?;
+
+    // Donation (input_too_large): a small deposit carved out of a large pre-cutoff UTXO
+    let change = bitcoind.new_address()?;
+    bitcoind.spend(
+        &[old_large[0].0],
+        &[
+            (large_address.to_string(), Amount::from_sat(1_000)),
+            (change.to_string(), Amount::from_sat(48_500)),
+        ],
+    )?;
+
+    bitcoind.mine(1)?;
+    electrs.wait_for_height(bitcoind.height()?).await?;
+
+    let paid = service.wait_for_status(&paid_id, "paid", SETTLE_TIMEOUT).await?;
+    assert_eq!(paid["deposit_amount_sats"], 1_500);
+    assert_eq!(paid["payout_amount_sats"], 1_515);
+    let preimage = Vec::<u8>::from_hex(paid["payment_preimage"].as_str().unwrap_or_default())?;
+    assert_eq!(paid["payment_hash"], sha256::Hash::hash(&preimage).to_string());
+    assert_eq!(paid["deposits"][0]["status"], "paid");
+    assert_eq!(lnurl.invoice_amounts(), vec![1_515_000]);
+    assert_eq!(wallet.paid_invoices().len(), 1);
+
+    let late = service.wait_for_status(&late_id, "donation", SETTLE_TIMEOUT).await?;
+    assert_eq!(late["deposits"][0]["donation_reason"], "block_height");
+
+    let large = service.wait_for_status(&large_id, "donation", SETTLE_TIMEOUT).await?;
+    assert_eq!(large["deposits"][0]["donation_reason"], "input_too_large");
+
+    // Donations never reach the LNURL server or the wallet
+    assert_eq!(lnurl.invoice_amounts().len(), 1);
+    assert_eq!(wallet.paid_invoices().len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires BITCOIND_EXE and ELECTRS_EXE"]
+async fn double_spent_deposit_is_not_paid() -> anyhow::Result<()> {
+    let bitcoind = Bitcoind::start(&exe_from_env("BITCOIND_EXE")?).await?;
+    bitcoind.mine(101)?;
+    let dust = bitcoind.create_outputs(&[Amount::from_sat(600); 3])?;
+    bitcoind.mine(1)?;
+
+    let electrs = Electrs::start(&exe_from_env("ELECTRS_EXE")?, &bitcoind).await?;
+    let lnurl = LnurlServer::start().await?;
+    let wallet = MockNwcWallet::start().await?;
+    let service = Service::start(
+        &electrs.url,
+        &wallet.uri(),
+        &[
+            ("CUTOFF_BLOCK_HEIGHT", CUTOFF_BLOCK_HEIGHT.to_string()),
+            ("MAX_INPUT_SATS", MAX_INPUT_SATS.to_string()),
+            // Keep the deposit short of payout while the reorg happens
+            ("REQUIRED_CONFIRMATIONS", "3".to_string()),
+        ],
+    )
+    .await?;
+
+    let (id, address) = service.create_recycle(&lnurl.lightning_address("reorged")).await?;
+    let outpoints: Vec<_> = dust.iter().map(|(outpoint, _)| *outpoint).collect();
+    bitcoind.spend(&outpoints, &[(address.to_string(), Amount::from_sat(1_500))])?;
+    bitcoind.mine(1)?;
+    electrs.wait_for_height(bitcoind.height()?).await?;
+
+    service
+        .wait_for_recycle(&id, "see one confirmation", SETTLE_TIMEOUT, |recycle| {
+            recycle["deposit_confirmations"] == 1
+        })
+        .await?;
+
+    // Reorg the deposit out and confirm a conflicting spend of its inputs instead
+    bitcoind.invalidate_tip()?;
+    let elsewhere = bitcoind.new_address()?;
+    bitcoind.spend(&outpoints, &[(elsewhere.to_string(), Amount::from_sat(1_000))])?;
+    bitcoind.mine(3)?;
+    electrs.wait_for_height(bitcoind.height()?).await?;
+
+    let recycle = service.wait_for_status(&id, "double_spent", SETTLE_TIMEOUT).await?;
+    assert_eq!(recycle["deposits"][0]["status"], "double_spent");
+    assert!(recycle["payment_preimage"].is_null());
+    assert!(lnurl.invoice_amounts().is_empty());
+    assert!(wallet.paid_invoices().is_empty());
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires BITCOIND_EXE and ELECTRS_EXE"]
+async fn submitted_deposits_are_checked_before_broadcast() -> anyhow::Result<()> {
+    let bitcoind = Bitcoind::start(&exe_from_env("BITCOIND_EXE")?).await?;
+    bitcoind.mine(101)?;
+    let dust = bitcoind.create_outputs(&[Amount::from_sat(600); 3])?;
+    let large = bitcoind.create_outputs(&[Amount::from_sat(50_000)])?;
+    let spare = bitcoind.create_outputs(&[Amount::from_sat(600); 2])?;
+    bitcoind.mine(1)?;
+
+    let electrs = Electrs::start(&exe_from_env("ELECTRS_EXE")?, &bitcoind).await?;
+    let lnurl = LnurlServer::start().await?;
+    let wallet = MockNwcWallet::start().await?;
+    let service = Service::start(
+        &electrs.url,
+        &wallet.uri(),
+        &[
+            ("CUTOFF_BLOCK_HEIGHT", CUTOFF_BLOCK_HEIGHT.to_string()),
+            ("MAX_INPUT_SATS", MAX_INPUT_SATS.to_string()),
+        ],
+    )
+    .await?;
+
+    let (id, address) = service.create_recycle(&lnurl.lightning_address("submitted")).await?;
+
+    // A deposit spending a large input is rejected and never reaches the mempool
+    let change = bitcoind.new_address()?;
+    let ineligible = bitcoind.sign(
+        &[large[0].0],
+        &[
+            (address.to_string(), Amount::from_sat(1_000)),
+            (change.to_string(), Amount::from_sat(48_500)),
+        ],
+    )?;
+    let (status, rejection) = service.submit_deposit(&id, &ineligible).await?;
+    assert_eq!(status, 422);
+    assert_eq!(rejection["eligibility"]["inputs"][0]["reason"], "input_too_large");
+    assert!(!bitcoind.in_mempool(&large[0].0.txid)?);
+
+    // An all-dust deposit is broadcast by the service and paid as usual
+    let outpoints: Vec<_> = dust.iter().map(|(outpoint, _)| *outpoint).collect();
+    let eligible = bitcoind.sign(&outpoints, &[(address.to_string(), Amount::from_sat(1_500))])?;
+    let (status, accepted) = service.submit_deposit(&id, &eligible).await?;
+    assert_eq!(status, 200, "{}", accepted);
+    assert_eq!(accepted["amount_sats"], 1_500);
+    let txid = accepted["txid"].as_str().unwrap_or_default().parse()?;
+    assert!(bitcoind.in_mempool(&txid)?);
+
+    bitcoind.mine(1)?;
+    electrs.wait_for_height(bitcoind.height()?).await?;
+
+    let paid = service.wait_for_status(&id, "paid", SETTLE_TIMEOUT).await?;
+    assert_eq!(paid["deposits"].as_array().map(Vec::len), Some(1));
+    assert_eq!(paid["deposits"][0]["txid"], txid.to_string());
+    assert_eq!(paid["payout_amount_sats"], 1_515);
+
+    // A paid recycle takes no more deposits through the service, eligible or not
+    let outpoints: Vec<_> = spare.iter().map(|(outpoint, _)| *outpoint).collect();
+    let late = bitcoind.sign(&outpoints, &[(address.to_string(), Amount::from_sat(1_000))])?;
+    let (status, rejection) = service.submit_deposit(&id, &late).await?;
+    assert_eq!(status, 400);
+    assert_eq!(rejection["error"], "Recycle is paid, it is no longer accepting deposits");
+    let late: bitcoin::Transaction = bitcoin::consensus::encode::deserialize_hex(&late)?;
+    assert!(!bitcoind.in_mempool(&late.compute_txid())?);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires BITCOIND_EXE and ELECTRS_EXE"]
+async fn recycles_are_paid_through_rest_lightning_backends() -> anyhow::Result<()> {
+    let bitcoind = Bitcoind::start(&exe_from_env("BITCOIND_EXE")?).await?;
+    bitcoind.mine(101)?;
+    let mut dust = Vec::new();
+    for _ in 0..3 {
+        dust.push(bitcoind.create_outputs(&[Amount::from_sat(600); 3])?);
+    }
+    bitcoind.mine(1)?;
+
+    let electrs = Electrs::start(&exe_from_env("ELECTRS_EXE")?, &bitcoind).await?;
+    let lnurl = LnurlServer::start().await?;
+
+    for (backend, dust) in ["lnd", "cln", "lnbits"].into_iter().zip(dust) {
+        let node = MockLightningNode::start().await?;
+        let mut env = node.env(backend);
+        env.push(("CUTOFF_BLOCK_HEIGHT", CUTOFF_BLOCK_HEIGHT.to_string()));
+        env.push(("MAX_INPUT_SATS", MAX_INPUT_SATS.to_string()));
+        // Each run gets a fresh database, so it finds the previous runs' deposits on
+        // the shared descriptor and hands out the next unused address
+        let service = Service::start(&electrs.url, "", &env).await?;
+
+        let (id, address) = service.create_recycle(&lnurl.lightning_address(backend)).await?;
+        let outpoints: Vec<_> = dust.iter().map(|(outpoint, _)| *outpoint).collect();
+        bitcoind.spend(&outpoints, &[(address.to_string(), Amount::from_sat(1_500))])?;
+        bitcoind.mine(1)?;
+        electrs.wait_for_height(bitcoind.height()?).await?;
+
+        let paid = service.wait_for_status(&id, "paid", SETTLE_TIMEOUT).await?;
+        assert_eq!(paid["payout_amount_sats"], 1_515, "{}", backend);
+
+        let payments = node.payments();
+        assert_eq!(payments.len(), 1, "{}", backend);
+        assert_eq!(paid["payment_preimage"], payments[0].1, "{}", backend);
+
+        let stats = service.stats().await?;
+        assert_eq!(stats["lightning"]["backend"], backend);
+        assert_eq!(stats["lightning"]["balance_sats"], 1_000_000, "{}", backend);
+    }
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires BITCOIND_EXE and ELECTRS_EXE"]
+async fn inflated_invoice_is_refused() -> anyhow::Result<()> {
+    let bitcoind = Bitcoind::start(&exe_from_env("BITCOIND_EXE")?).await?;
+    bitcoind.mine(101)?;
+    let dust = bitcoind.create_outputs(&[Amount::from_sat(600); 3])?;
+    bitcoind.mine(1)?;
+
+    let electrs = Electrs::start(&exe_from_env("ELECTRS_EXE")?, &bitcoind).await?;
+    let lnurl = LnurlServer::start().await?;
+    let wallet = MockNwcWallet::start().await?;
+    let service = Service::start(
+        &electrs.url,
+        &wallet.uri(),
+        &[
+            ("CUTOFF_BLOCK_HEIGHT", CUTOFF_BLOCK_HEIGHT.to_string()),
+            ("MAX_INPUT_SATS", MAX_INPUT_SATS.to_string()),
+        ],
+    )
+    .await?;
+
+    // The LNURL server answers with an invoice for twice the payout
+    let (id, address) = service.create_recycle(&lnurl.lightning_address("inflated")).await?;
+    let outpoints: Vec<_> = dust.iter().map(|(outpoint, _)| *outpoint).collect();
+    bitcoind.spend(&outpoints, &[(address.to_string(), Amount::from_sat(1_500))])?;
+    bitcoind.mine(1)?;
+    electrs.wait_for_height(bitcoind.height()?).await?;
+
+    let failed = service.wait_for_status(&id, "failed", SETTLE_TIMEOUT).await?;
+    assert!(failed["payment_preimage"].is_null());
+    assert_eq!(lnurl.invoice_amounts(), vec![3_030_000]);
+    assert!(wallet.paid_invoices().is_empty());
+
+    let events = service.events(&id).await?;
+    let last = events.as_array().and_then(|events| events.last()).cloned().unwrap_or_default();
+    assert_eq!(last["to_status"], "failed");
+    assert_eq!(
+        last["reason"],
+        "LNURL invoice refused: invoice amount 3030000 msats doesn't match the requested 1515000 msats"
+    );
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires BITCOIND_EXE and ELECTRS_EXE"]
+async fn unanswered_payment_is_looked_up_not_paid_twice() -> anyhow::Result<()> {
+    let bitcoind = Bitcoind::start(&exe_from_env("BITCOIND_EXE")?).await?;
+    bitcoind.mine(101)?;
+    let dust = bitcoind.create_outputs(&[Amount::from_sat(600); 3])?;
+    bitcoind.mine(1)?;
+
+    let electrs = Electrs::start(&exe_from_env("ELECTRS_EXE")?, &bitcoind).await?;
+    let lnurl = LnurlServer::start().await?;
+    let wallet = MockNwcWallet::start().await?;
+    let service = Service::start(
+        &electrs.url,
+        &wallet.uri(),
+        &[
+            ("CUTOFF_BLOCK_HEIGHT", CUTOFF_BLOCK_HEIGHT.to_string()),
+            ("MAX_INPUT_SATS", MAX_INPUT_SATS.to_string()),
+        ],
+    )
+    .await?;
+
+    // The wallet pays but its response never arrives, so the service times out
+    wallet.stop_answering_payments();
+
+    let (id, address) = service.create_recycle(&lnurl.lightning_address("unanswered")).await?;
+    let outpoints: Vec<_> = dust.iter().map(|(outpoint, _)| *outpoint).collect();
+    bitcoind.spend(&outpoints, &[(address.to_string(), Amount::from_sat(1_500))])?;
+    bitcoind.mine(1)?;
+    electrs.wait_for_height(bitcoind.height()?).await?;
+
+    service.wait_for_status(&id, "paying", SETTLE_TIMEOUT).await?;
+    let paid = service.wait_for_status(&id, "paid", SETTLE_TIMEOUT).await?;
+
+    // Settled from the wallet's record of the first payment, without a second invoice
+    let payments = wallet.payments.lock().unwrap().clone();
+    assert_eq!(payments.len(), 1);
+    assert_eq!(lnurl.invoice_amounts(), vec![1_515_000]);
+    assert_eq!(paid["payment_preimage"], payments[0].1);
+    assert_eq!(paid["payout_amount_sats"], 1_515);
+
+    let recorded = service.payments(&id).await?;
+    assert_eq!(recorded.as_array().map(Vec::len), Some(1));
+    assert_eq!(recorded[0]["status"], "succeeded");
+    assert_eq!(recorded[0]["bolt11"], payments[0].0);
+    assert_eq!(recorded[0]["payment_hash"], paid["payment_hash"]);
+
+    let stats = service.stats().await?;
+    assert_eq!(stats["lightning"]["backend"], "nwc");
+    assert_eq!(stats["lightning"]["balance_sats"], 1_000_000);
+
+    Ok(())
+}